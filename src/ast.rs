@@ -0,0 +1,214 @@
+use crate::location::Span;
+
+/// Owned syntax tree of a Lox program, as produced by the parser. Every node keeps the span of
+/// the code it was parsed from, so later passes can report errors and attribute instructions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub declarations: Vec<Decl>,
+    /// Location of the end of the code, where the implicit return of the script is.
+    pub end: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decl {
+    Fun(FunDecl),
+    Var(VarDecl),
+    Stmt(Stmt),
+}
+
+impl Decl {
+    pub fn span(&self) -> Span {
+        match self {
+            Decl::Fun(decl) => decl.span,
+            Decl::Var(decl) => decl.span,
+            Decl::Stmt(stmt) => stmt.span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunDecl {
+    pub name: Identifier,
+    pub params: Vec<Identifier>,
+    pub body: Vec<Decl>,
+    /// Span of the closing brace of the body.
+    pub end: Span,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarDecl {
+    pub name: Identifier,
+    pub initializer: Option<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Return(Option<Expr>),
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    For {
+        initializer: Option<Box<ForInit>>,
+        condition: Option<Box<Expr>>,
+        increment: Option<Box<Expr>>,
+        body: Box<Stmt>,
+    },
+    Block(Vec<Decl>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForInit {
+    Var(VarDecl),
+    Expression(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Literal(Literal),
+    Variable(Identifier),
+    Assign {
+        target: Identifier,
+        value: Box<Expr>,
+    },
+    Unary {
+        operator: UnaryOp,
+        operator_span: Span,
+        operand: Box<Expr>,
+    },
+    Binary {
+        operator: BinaryOp,
+        operator_span: Span,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Logical {
+        operator: LogicalOp,
+        operator_span: Span,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        /// Span of the opening parenthesis, where call errors are reported.
+        paren: Span,
+        arguments: Vec<Expr>,
+    },
+    /// `object.name`
+    Get {
+        object: Box<Expr>,
+        name: Identifier,
+    },
+    /// `object.name = value`
+    Set {
+        object: Box<Expr>,
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    Grouping(Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+}
+
+impl Literal {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Literal::Number(_) => "number",
+            Literal::String(_) => "string",
+            Literal::Bool(_) => "boolean",
+            Literal::Nil => "nil",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+impl UnaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOp::Negate => "-",
+            UnaryOp::Not => "!",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+impl BinaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+        }
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        !matches!(
+            self,
+            BinaryOp::Add | BinaryOp::Subtract | BinaryOp::Multiply | BinaryOp::Divide
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
@@ -0,0 +1,261 @@
+use std::fmt::Write;
+
+use crate::{
+    chunk::{Chunk, Instruction},
+    debug::Disassembler,
+    heap::Heap,
+};
+
+/// Instructions `start..end` of a chunk, which always run one after the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+}
+
+/// How control can leave an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flow {
+    /// Indices of the instructions it can jump to.
+    pub targets: Vec<usize>,
+    /// Whether the next instruction can run after it.
+    pub falls_through: bool,
+}
+
+impl Flow {
+    fn next() -> Self {
+        Self {
+            targets: Vec::new(),
+            falls_through: true,
+        }
+    }
+}
+
+/// Control-flow graph of a function, its blocks being in the order of their instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    /// Graph of the `len` instructions of some code, `flow` telling where each one can go.
+    /// Blocks start at the first instruction, at jump targets and after jumps.
+    pub fn build(len: usize, flow: impl Fn(usize) -> Flow) -> Self {
+        let flows: Vec<Flow> = (0..len).map(flow).collect();
+
+        let mut leaders = vec![false; len];
+        if len > 0 {
+            leaders[0] = true;
+        }
+        for (index, flow) in flows.iter().enumerate() {
+            for target in flow.targets.iter() {
+                leaders[*target] = true;
+            }
+            if (!flow.targets.is_empty() || !flow.falls_through) && index + 1 < len {
+                leaders[index + 1] = true;
+            }
+        }
+
+        let starts: Vec<usize> = (0..len).filter(|index| leaders[*index]).collect();
+        let mut block_of = vec![0; len];
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(block, start)| {
+                let end = starts.get(block + 1).copied().unwrap_or(len);
+                block_of[*start..end].fill(block);
+                BasicBlock {
+                    start: *start,
+                    end,
+                    successors: Vec::new(),
+                    predecessors: Vec::new(),
+                }
+            })
+            .collect();
+
+        for block in 0..blocks.len() {
+            let last = &flows[blocks[block].end - 1];
+
+            let mut successors: Vec<usize> = Vec::new();
+            if last.falls_through && blocks[block].end < len {
+                successors.push(block + 1);
+            }
+            for target in last.targets.iter() {
+                successors.push(block_of[*target]);
+            }
+            successors.dedup();
+
+            for successor in successors.iter() {
+                blocks[*successor].predecessors.push(block);
+            }
+            blocks[block].successors = successors;
+        }
+
+        Self { blocks }
+    }
+
+    pub fn from_chunk(chunk: &Chunk) -> Self {
+        Self::build(chunk.code.len(), |index| {
+            chunk_flow(chunk.code[index], index)
+        })
+    }
+}
+
+fn chunk_flow(instruction: Instruction, index: usize) -> Flow {
+    match instruction {
+        Instruction::Jump(offset) => Flow {
+            targets: vec![index + 1 + offset as usize],
+            falls_through: false,
+        },
+        Instruction::JumpIfFalse(offset) => Flow {
+            targets: vec![index + 1 + offset as usize],
+            falls_through: true,
+        },
+        Instruction::Loop(offset) => Flow {
+            targets: vec![index + 1 - offset as usize],
+            falls_through: false,
+        },
+        Instruction::Return => Flow {
+            targets: Vec::new(),
+            falls_through: false,
+        },
+        _ => Flow::next(),
+    }
+}
+
+/// Graphviz graph of the given functions, each one drawn as a cluster of basic blocks that
+/// list their instructions along with the line they come from.
+pub fn to_dot(functions: &[(&str, &Chunk)], heap: &Heap) -> String {
+    let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+
+    for (function, (name, chunk)) in functions.iter().enumerate() {
+        let cfg = Cfg::from_chunk(chunk);
+        let disassembler = Disassembler::new(chunk).with_heap(heap);
+
+        writeln!(dot, "    subgraph cluster_{} {{", function).unwrap();
+        writeln!(dot, "        label=\"{}\";", escape(name)).unwrap();
+
+        for (index, block) in cfg.blocks.iter().enumerate() {
+            let mut label = format!("B{}\\l", index);
+            for offset in block.start..block.end {
+                let line = chunk.get_line(offset);
+                let line = if offset > block.start && line == chunk.get_line(offset - 1) {
+                    String::from("   |")
+                } else {
+                    format!("{:4}", line + 1)
+                };
+
+                let text = disassembler.instruction_text(offset, chunk.code[offset]);
+                label += &format!("{:04} {} {}\\l", offset, line, escape(&text));
+            }
+            writeln!(
+                dot,
+                "        f{}_b{} [label=\"{}\"];",
+                function, index, label
+            )
+            .unwrap();
+        }
+
+        for (index, block) in cfg.blocks.iter().enumerate() {
+            let branches = matches!(chunk.code[block.end - 1], Instruction::JumpIfFalse(_));
+
+            for successor in block.successors.iter() {
+                let label = match (branches, *successor == index + 1) {
+                    (true, true) => " [label=\"true\"]",
+                    (true, false) => " [label=\"false\"]",
+                    (false, _) => "",
+                };
+                writeln!(
+                    dot,
+                    "        f{}_b{} -> f{}_b{}{};",
+                    function, index, function, successor, label
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(dot, "    }}").unwrap();
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{heap::Heap, lints::Lints, optimizer::OptLevel};
+
+    use super::{to_dot, Cfg};
+
+    fn cfg(code: &str) -> Cfg {
+        let mut heap = Heap::new();
+        let function = crate::compiler::compile(
+            code,
+            "test.lox",
+            &mut heap,
+            &Lints::new(),
+            OptLevel::O0,
+            &mut Vec::new(),
+        )
+        .expect("Program should compile");
+
+        Cfg::from_chunk(&heap.deref(function).chunk)
+    }
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize)> {
+        cfg.blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(block, data)| data.successors.iter().map(move |s| (block, *s)))
+            .collect()
+    }
+
+    #[test]
+    fn splits_branches_into_blocks() {
+        let cfg = cfg("var a = 1;\nif (a) print 1; else print 2;\nprint 3;");
+
+        // Condition, then branch, else branch, and the code after both.
+        assert_eq!(cfg.blocks.len(), 4);
+        assert_eq!(edges(&cfg), vec![(0, 1), (0, 2), (1, 3), (2, 3)]);
+        assert_eq!(cfg.blocks[3].predecessors, vec![1, 2]);
+    }
+
+    #[test]
+    fn loops_have_back_edges() {
+        let cfg = cfg("var i = 0;\nwhile (i < 3) i = i + 1;\nprint i;");
+
+        // Initializer, condition, body and the exit.
+        assert_eq!(cfg.blocks.len(), 4);
+        assert_eq!(edges(&cfg), vec![(0, 1), (1, 2), (1, 3), (2, 1)]);
+        assert_eq!(cfg.blocks[1].predecessors, vec![0, 2]);
+    }
+
+    #[test]
+    fn exports_branches_with_labels() {
+        let mut heap = Heap::new();
+        let function = crate::compiler::compile(
+            "if (true) print \"a\";",
+            "test.lox",
+            &mut heap,
+            &Lints::new(),
+            OptLevel::O0,
+            &mut Vec::new(),
+        )
+        .expect("Program should compile");
+
+        let dot = to_dot(&[("<script>", &heap.deref(function).chunk)], &heap);
+
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("label=\"<script>\";"));
+        assert!(dot.contains("f0_b0 -> f0_b1 [label=\"true\"];"));
+        assert!(dot.contains("f0_b0 -> f0_b2 [label=\"false\"];"));
+        assert!(dot.contains("0000    1 OP_TRUE\\l"));
+        assert!(dot.contains("OP_CONSTANT         0 (a)\\l"));
+    }
+}
@@ -1,8 +1,13 @@
 use std::convert::TryFrom;
 
-use crate::heap::Ref;
+use crate::{
+    heap::{Heap, Ref, Tracer},
+    location::Span,
+    objects::{Function, List, Native},
+    userdata::{BoundMethod, Instance},
+};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Instruction {
     Return,
     Constant(u16),
@@ -18,14 +23,48 @@ pub enum Instruction {
     Equal,
     Greater,
     Less,
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
+    Print,
+    Pop,
+    DefineGlobal(u16),
+    GetGlobal(u16),
+    SetGlobal(u16),
+    GetLocal(u16),
+    SetLocal(u16),
+    Jump(u16),
+    JumpIfFalse(u16),
+    Loop(u16),
+    Call(u16),
+    /// `Constant` followed by `Add`, fused by the optimizer to be dispatched once.
+    AddConstant(u16),
+    /// `Constant` followed by `Subtract`.
+    SubtractConstant(u16),
+    /// `GetLocal` of both slots followed by `Add`, only for the first 256 slots so instructions
+    /// stay 32 bits.
+    AddLocals(u8, u8),
+    /// `SetLocal` followed by `Pop`.
+    SetLocalPop(u16),
+    /// Replaces the object on top of the stack by its property named by the constant.
+    GetProperty(u16),
+    /// Assigns the value on top of the stack to the property of the object below it, leaving
+    /// only the value.
+    SetProperty(u16),
 }
 
+/// Value scripts work with, objects being referred to in the heap of the virtual machine.
 #[derive(Copy, Clone, Debug)]
 pub enum Value {
     Number(f64),
     Bool(bool),
     Nil,
     String(Ref<String>),
+    Function(Ref<Function>),
+    Native(Ref<Native>),
+    List(Ref<List>),
+    Instance(Ref<Instance>),
+    BoundMethod(Ref<BoundMethod>),
 }
 
 impl Value {
@@ -35,10 +74,96 @@ impl Value {
             Value::Bool(val) => !val,
             Value::Nil => true,
             Value::String(_) => false,
+            Value::Function(_) => false,
+            Value::Native(_) => false,
+            Value::List(_) => false,
+            Value::Instance(_) => false,
+            Value::BoundMethod(_) => false,
+        }
+    }
+
+    /// Whether two values are equal for `==`, strings being compared by content.
+    pub fn equals(&self, other: &Value, heap: &Heap) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::String(a), Value::String(b)) => heap.deref(*a) == heap.deref(*b),
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Instance(a), Value::Instance(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Name of the value's type, as shown to users in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::Bool(_) => "boolean",
+            Value::Nil => "nil",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Native(_) | Value::BoundMethod(_) => "function",
+            Value::List(_) => "list",
+            Value::Instance(_) => "object",
+        }
+    }
+
+    /// Like `type_name`, objects being named by their class.
+    pub fn type_name_in(&self, heap: &Heap) -> &'static str {
+        match self {
+            Value::Instance(instance) => heap.deref(heap.deref(*instance).class).name,
+            value => value.type_name(),
+        }
+    }
+
+    /// Marks the object the value refers to, if any.
+    pub fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::Number(_) | Value::Bool(_) | Value::Nil => {}
+            Value::String(string) => tracer.mark(*string),
+            Value::Function(function) => tracer.mark(*function),
+            Value::Native(native) => tracer.mark(*native),
+            Value::List(list) => tracer.mark(*list),
+            Value::Instance(instance) => tracer.mark(*instance),
+            Value::BoundMethod(method) => tracer.mark(*method),
+        }
+    }
+
+    /// Formats the value the way it is shown to scripts, e.g. by `print`.
+    pub fn format(&self, heap: &Heap) -> String {
+        match self {
+            Value::Number(val) => format!("{}", val),
+            Value::Bool(val) => format!("{}", val),
+            Value::Nil => String::from("nil"),
+            Value::String(val) => heap.deref(*val).clone(),
+            Value::Function(val) => match heap.deref(*val).name {
+                Some(name) => format!("<fn {}>", heap.deref(name)),
+                None => String::from("<script>"),
+            },
+            Value::Native(val) => format!("<native fn {}>", heap.deref(heap.deref(*val).name)),
+            Value::List(val) => {
+                let items: Vec<String> = heap
+                    .deref(*val)
+                    .items
+                    .iter()
+                    .map(|item| item.format(heap))
+                    .collect();
+                format!("[{}]", items.join(", "))
+            }
+            Value::Instance(val) => {
+                format!("{} instance", heap.deref(heap.deref(*val).class).name)
+            }
+            Value::BoundMethod(val) => {
+                let method = heap.deref(*val);
+                let class = heap.deref(heap.deref(method.receiver).class).name;
+                let name = heap.deref(heap.deref(method.method).name);
+                format!("<method {}.{}>", class, name)
+            }
         }
     }
 }
 
+#[derive(Debug)]
 pub struct LineStart {
     offset: usize,
     line: usize,
@@ -50,10 +175,12 @@ impl LineStart {
     }
 }
 
+#[derive(Debug, Default)]
 pub struct Chunk {
     pub code: Vec<Instruction>,
     pub constants: Vec<Value>,
     pub lines: Vec<LineStart>,
+    pub spans: Vec<Span>,
 }
 
 impl Chunk {
@@ -62,13 +189,16 @@ impl Chunk {
             code: Vec::new(),
             constants: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
         }
     }
 
-    pub fn write(&mut self, instruction: Instruction, line: usize) -> usize {
+    pub fn write(&mut self, instruction: Instruction, span: Span) -> usize {
         let index = self.code.len();
+        let line = span.start.line();
 
         self.code.push(instruction);
+        self.spans.push(span);
 
         match self.lines.last() {
             Some(cur_line) if cur_line.line == line => {}
@@ -78,7 +208,7 @@ impl Chunk {
         index
     }
 
-    pub fn add_constant(&mut self, value: Value) -> Result<u16, ()> {
+    pub(crate) fn add_constant(&mut self, value: Value) -> Result<u16, ()> {
         let index = self.constants.len();
 
         match u16::try_from(index) {
@@ -90,6 +220,15 @@ impl Chunk {
         }
     }
 
+    pub fn get_span(&self, instruction_idx: usize) -> Span {
+        assert!(
+            instruction_idx < self.spans.len(),
+            "Do not try to get span of instruction not added to chunk"
+        );
+
+        self.spans[instruction_idx]
+    }
+
     pub fn get_line(&self, instruction_idx: usize) -> usize {
         assert!(
             instruction_idx < self.code.len(),
@@ -103,7 +242,7 @@ impl Chunk {
         let mut left = 0;
         let mut right = self.lines.len() - 1;
 
-        let mut line = self.lines.last().expect("Lines is empty").line;
+        let mut line = self.lines.first().expect("Lines is empty").line;
 
         while left <= right {
             let mid = (left + right) / 2;
@@ -112,13 +251,12 @@ impl Chunk {
                 Some(mid_line) => {
                     if instruction_idx >= mid_line.offset {
                         line = mid_line.line;
-
+                        left = mid + 1;
+                    } else {
                         if mid == 0 {
                             break;
                         }
                         right = mid - 1;
-                    } else {
-                        left = mid + 1;
                     }
                 }
                 None => panic!("Invalid mid index when looking for line"),
@@ -133,7 +271,10 @@ impl Chunk {
 mod test {
     use std::mem::size_of;
 
-    use crate::chunk::{Instruction, Value};
+    use crate::{
+        chunk::{Chunk, Instruction, Value},
+        location::{Location, Span},
+    };
 
     #[test]
     fn instruction_is_at_most_32_bits() {
@@ -148,4 +289,20 @@ mod test {
         // variant
         assert!(size_of::<Value>() <= 16);
     }
+
+    #[test]
+    fn finds_line_of_each_instruction() {
+        let mut chunk = Chunk::new();
+        let mut location = Location::default();
+
+        for line in [0, 0, 1, 3, 3, 4].iter() {
+            while location.line() < *line {
+                location.ln();
+            }
+            chunk.write(Instruction::Nil, Span::new(location, location));
+        }
+
+        let lines: Vec<usize> = (0..chunk.code.len()).map(|i| chunk.get_line(i)).collect();
+        assert_eq!(lines, vec![0, 0, 1, 3, 3, 4]);
+    }
 }
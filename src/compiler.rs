@@ -1,379 +1,1058 @@
+use std::convert::TryFrom;
+
+#[cfg(feature = "debug_trace_execution")]
+use crate::debug::Disassembler;
 use crate::{
+    ast::{
+        BinaryOp, Decl, Expr, ExprKind, ForInit, FunDecl, Identifier, Literal, LogicalOp, Program,
+        Stmt, StmtKind, UnaryOp, VarDecl,
+    },
     chunk::{Chunk, Instruction, Value},
-    debug::Disassembler,
-    error::{CompilationError, RoxError, RoxErrorKind, RoxResult},
-    heap::Heap,
-    scanner::{scanner::TokenIter, token::TokenErrorKind, Scanner, Token, TokenKind},
+    error::{CompilationError, CompilationWarning, RoxError, RoxErrorKind, RoxResult, Severity},
+    heap::{Heap, Ref},
+    lints::{LintLevel, Lints},
+    location::Span,
+    objects::Function,
+    optimizer::{self, OptLevel},
+    parser, ssa,
 };
 
-#[derive(Copy, Clone, PartialOrd, PartialEq)]
-enum Precedence {
-    None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
-    Primary,
+struct Local<'ast> {
+    name: &'ast str,
+    span: Span,
+    /// Scope depth of the variable, `None` while its initializer is being compiled.
+    depth: Option<usize>,
+    used: bool,
 }
 
-impl Precedence {
-    fn next(&self) -> Precedence {
-        match self {
-            Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
-            Precedence::Or => Precedence::And,
-            Precedence::And => Precedence::Equality,
-            Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
-            Precedence::Term => Precedence::Factor,
-            Precedence::Factor => Precedence::Unary,
-            Precedence::Unary => Precedence::Call,
-            Precedence::Call => Precedence::Primary,
-            Precedence::Primary => Precedence::None,
-        }
-    }
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
 }
 
-type ParseFn<'sourcecode> = fn(&mut Parser<'sourcecode>) -> RoxResult<()>;
-
-#[derive(Copy, Clone)]
-struct ParseRule<'sourcecode> {
-    prefix: Option<ParseFn<'sourcecode>>,
-    infix: Option<ParseFn<'sourcecode>>,
-    precedence: Precedence,
+/// State of the function currently being compiled, functions declared inside of it push a new
+/// scope on top of it.
+struct FunctionScope<'ast> {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local<'ast>>,
+    scope_depth: usize,
 }
 
-impl<'sourcecode>
-    From<(
-        Option<ParseFn<'sourcecode>>,
-        Option<ParseFn<'sourcecode>>,
-        Precedence,
-    )> for ParseRule<'sourcecode>
-{
-    fn from(
-        (prefix, infix, precedence): (
-            Option<ParseFn<'sourcecode>>,
-            Option<ParseFn<'sourcecode>>,
-            Precedence,
-        ),
-    ) -> Self {
+impl<'ast> FunctionScope<'ast> {
+    fn new(function: Function, kind: FunctionKind) -> Self {
+        // The first slot is reserved for the function being called.
+        let reserved = Local {
+            name: "",
+            span: Span::default(),
+            depth: Some(0),
+            used: true,
+        };
+
         Self {
-            prefix,
-            infix,
-            precedence,
+            function,
+            kind,
+            locals: vec![reserved],
+            scope_depth: 0,
         }
     }
 }
 
-struct Parser<'sourcecode> {
-    scanner: TokenIter<'sourcecode>,
-    current: Token<'sourcecode>,
-    previous: Token<'sourcecode>,
-    heap: &'sourcecode mut Heap,
-    chunks: Vec<Chunk>,
+/// Lowers the syntax tree of a program to bytecode, resolving variables and reporting the
+/// errors and warnings that need to know about scopes.
+struct Compiler<'ast> {
+    heap: &'ast mut Heap,
+    file: Ref<String>,
+    functions: Vec<FunctionScope<'ast>>,
+    lints: &'ast Lints,
+    opt_level: OptLevel,
     errors: Vec<RoxError>,
+    warnings: Vec<RoxError>,
 }
 
-impl<'sourcecode> Parser<'sourcecode> {
-    pub fn new(code: &'sourcecode str, heap: &'sourcecode mut Heap) -> Self {
+impl<'ast> Compiler<'ast> {
+    pub fn new(file: &str, heap: &'ast mut Heap, lints: &'ast Lints, opt_level: OptLevel) -> Self {
+        let file = heap.alloc_string(String::from(file));
+
         Self {
-            scanner: Scanner::new(code).into_iter(),
-            previous: Token::synthetic(""),
-            current: Token::synthetic(""),
-            chunks: Vec::new(),
+            functions: Vec::new(),
             errors: Vec::new(),
+            warnings: Vec::new(),
+            file,
             heap,
+            lints,
+            opt_level,
         }
     }
 
-    pub fn compile(mut self) -> Result<Chunk, Vec<RoxError>> {
-        self.chunks.push(Chunk::new());
+    /// Compiles the whole program, returning the warnings found whether it succeeds or not.
+    /// Syntax errors found while parsing it are reported along with the ones found while
+    /// compiling, in the order they appear in the code.
+    pub fn compile(
+        mut self,
+        program: &'ast Program,
+        syntax_errors: Vec<RoxError>,
+    ) -> (Result<Ref<Function>, Vec<RoxError>>, Vec<RoxError>) {
+        self.errors = syntax_errors;
+        self.functions.push(FunctionScope::new(
+            Function::new(None, self.file),
+            FunctionKind::Script,
+        ));
 
-        match self.advance().and_then(|_| self.expression()) {
-            Ok(()) => {}
-            Err(err) => self.errors.push(err),
+        self.declarations(&program.declarations);
+        let function = self.end_compiler(program.end);
+
+        if self.errors.is_empty() {
+            (Ok(self.heap.alloc(function)), self.warnings)
+        } else {
+            self.errors.sort_by_key(|err| err.span.start.offset());
+            (Err(self.errors), self.warnings)
         }
+    }
 
-        // if !matches!(self.current.kind(), TokenKind::Eof) {
-        //     let error = self.error_at_current(CompilationError::UnexpectedToken(
-        //         self.current.lexeme().to_string(),
-        //     ));
-        //     self.errors.push(error);
-        // }
+    /// Compiles a sequence of declarations, returning whether it never completes normally.
+    fn declarations(&mut self, declarations: &'ast [Decl]) -> bool {
+        let mut diverges = false;
+        let mut warned = false;
 
-        self.end_compiler();
+        for decl in declarations {
+            if diverges && !warned {
+                self.warn(CompilationWarning::UnreachableCode, decl.span());
+                warned = true;
+            }
 
-        if self.errors.is_empty() {
-            Ok(self.chunks.pop().unwrap())
-        } else {
-            Err(self.errors)
+            diverges |= self.declaration(decl);
         }
+
+        diverges
     }
 
-    fn expression(&mut self) -> RoxResult<()> {
-        self.parse_precedence(Precedence::Assignment)
+    /// Compiles a declaration, returning whether it never completes normally, e.g. a `return`.
+    fn declaration(&mut self, decl: &'ast Decl) -> bool {
+        let result = match decl {
+            Decl::Fun(decl) => self.fun_declaration(decl).map(|_| false),
+            Decl::Var(decl) => self.var_declaration(decl).map(|_| false),
+            Decl::Stmt(stmt) => self.statement(stmt),
+        };
+
+        result.unwrap_or_else(|err| {
+            self.errors.push(err);
+            false
+        })
     }
 
-    fn parse_precedence(&mut self, precedence: Precedence) -> RoxResult<()> {
-        self.advance()?;
+    fn fun_declaration(&mut self, decl: &'ast FunDecl) -> RoxResult<()> {
+        let global = self.declare_variable(&decl.name)?;
+        self.mark_initialized();
+        self.function(decl, FunctionKind::Function)?;
+        self.define_variable(global, decl.name.span);
 
-        let rule = self.get_rule(self.previous.kind());
-        let prefix_rule = rule.prefix;
+        Ok(())
+    }
 
-        let prefix_rule = match prefix_rule {
-            Some(rule) => rule,
-            None => {
-                return Err(self.error(CompilationError::MissingExpression));
+    fn function(&mut self, decl: &'ast FunDecl, kind: FunctionKind) -> RoxResult<()> {
+        let name = self.heap.alloc_string(decl.name.name.clone());
+        let mut function = Function::new(Some(name), self.file);
+        function.arity = decl.params.len();
+        self.functions.push(FunctionScope::new(function, kind));
+
+        // The scope must be popped even if compiling the function fails, otherwise the code
+        // that follows would be compiled into it.
+        let result = self.function_body(decl);
+        let function = self.end_compiler(decl.end);
+        result?;
+
+        let function = self.heap.alloc(function);
+        self.emit_constant(Value::Function(function), decl.name.span)
+    }
+
+    fn function_body(&mut self, decl: &'ast FunDecl) -> RoxResult<()> {
+        self.begin_scope();
+
+        for param in &decl.params {
+            let parameter = self.declare_variable(param)?;
+            self.define_variable(parameter, param.span);
+        }
+
+        self.declarations(&decl.body);
+
+        Ok(())
+    }
+
+    fn var_declaration(&mut self, decl: &'ast VarDecl) -> RoxResult<()> {
+        let global = self.declare_variable(&decl.name)?;
+
+        match &decl.initializer {
+            Some(initializer) => self.expression(initializer)?,
+            None => self.emit(Instruction::Nil, decl.name.span),
+        }
+
+        self.define_variable(global, decl.name.span);
+
+        Ok(())
+    }
+
+    /// Compiles a statement, returning whether it never completes normally.
+    fn statement(&mut self, stmt: &'ast Stmt) -> RoxResult<bool> {
+        let span = stmt.span;
+
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr)?;
+                self.emit(Instruction::Pop, span);
             }
-        };
+            StmtKind::Print(expr) => {
+                self.expression(expr)?;
+                self.emit(Instruction::Print, span);
+            }
+            StmtKind::Return(value) => {
+                self.return_statement(value.as_ref(), span)?;
+                return Ok(true);
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => return self.if_statement(condition, then_branch, else_branch.as_deref(), span),
+            StmtKind::While { condition, body } => self.while_statement(condition, body, span)?,
+            StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.begin_scope();
+                let result = self.for_statement(
+                    initializer.as_deref(),
+                    condition.as_deref(),
+                    increment.as_deref(),
+                    body,
+                    span,
+                );
+                self.end_scope(span);
+                result?;
+            }
+            StmtKind::Block(declarations) => {
+                self.begin_scope();
+                let diverges = self.declarations(declarations);
+                self.end_scope(span);
+                return Ok(diverges);
+            }
+        }
 
-        prefix_rule(self)?;
+        Ok(false)
+    }
 
-        while precedence <= self.get_rule(self.current.kind()).precedence {
-            self.advance()?;
-            println!("{:?} at main loop", self.previous.kind());
-            println!("{:?} at main loop", self.current.kind());
-            let infix_rule = self
-                .get_rule(self.previous.kind())
-                .infix
-                .expect("Expect infix rule");
-            infix_rule(self)?;
+    fn return_statement(&mut self, value: Option<&'ast Expr>, span: Span) -> RoxResult<()> {
+        if self.scope().kind == FunctionKind::Script {
+            return Err(self.error(CompilationError::ReturnFromTopLevel, span));
+        }
+
+        match value {
+            Some(value) => {
+                self.expression(value)?;
+                self.emit(Instruction::Return, span);
+            }
+            None => self.emit_return(span),
         }
 
         Ok(())
     }
 
-    fn grouping(&mut self) -> RoxResult<()> {
-        self.expression()?;
-        self.consume(
-            TokenKind::RightParen,
-            CompilationError::MissingClosingParenthesis,
-        )
+    fn if_statement(
+        &mut self,
+        condition: &'ast Expr,
+        then_branch: &'ast Stmt,
+        else_branch: Option<&'ast Stmt>,
+        span: Span,
+    ) -> RoxResult<bool> {
+        self.expression(condition)?;
+
+        let then_jump = self.emit_jump(Instruction::JumpIfFalse(0), span);
+        self.emit(Instruction::Pop, span);
+        let then_diverges = self.statement(then_branch)?;
+
+        let else_jump = self.emit_jump(Instruction::Jump(0), span);
+        self.patch_jump(then_jump, span)?;
+        self.emit(Instruction::Pop, span);
+
+        let else_diverges = match else_branch {
+            Some(else_branch) => self.statement(else_branch)?,
+            None => false,
+        };
+
+        self.patch_jump(else_jump, span)?;
+
+        Ok(then_diverges && else_diverges)
     }
 
-    fn binary(&mut self) -> RoxResult<()> {
-        let operator = self.previous.kind();
-        let rule = self.get_rule(operator);
-        self.parse_precedence(rule.precedence.next())?;
+    /// Loops never diverge, as their body might never run.
+    fn while_statement(
+        &mut self,
+        condition: &'ast Expr,
+        body: &'ast Stmt,
+        span: Span,
+    ) -> RoxResult<()> {
+        let loop_start = self.current_chunk().code.len();
+        self.expression(condition)?;
 
-        match operator {
-            TokenKind::Plus => self.emit(Instruction::Add),
-            TokenKind::Minus => self.emit(Instruction::Subtract),
-            TokenKind::Star => self.emit(Instruction::Multiply),
-            TokenKind::Slash => self.emit(Instruction::Divide),
-            TokenKind::BangEqual => self.emit_many(&[Instruction::Equal, Instruction::Not]),
-            TokenKind::EqualEqual => self.emit(Instruction::Equal),
-            TokenKind::Greater => self.emit(Instruction::Greater),
-            TokenKind::GreaterEqual => self.emit_many(&[Instruction::Less, Instruction::Not]),
-            TokenKind::Less => self.emit(Instruction::Less),
-            TokenKind::LessEqual => self.emit_many(&[Instruction::Greater, Instruction::Not]),
-            _ => panic!("Invalid binary operator"),
+        let exit_jump = self.emit_jump(Instruction::JumpIfFalse(0), span);
+        self.emit(Instruction::Pop, span);
+        self.statement(body)?;
+        self.emit_loop(loop_start, span)?;
+
+        self.patch_jump(exit_jump, span)?;
+        self.emit(Instruction::Pop, span);
+
+        Ok(())
+    }
+
+    fn for_statement(
+        &mut self,
+        initializer: Option<&'ast ForInit>,
+        condition: Option<&'ast Expr>,
+        increment: Option<&'ast Expr>,
+        body: &'ast Stmt,
+        span: Span,
+    ) -> RoxResult<()> {
+        match initializer {
+            Some(ForInit::Var(decl)) => self.var_declaration(decl)?,
+            Some(ForInit::Expression(expr)) => {
+                self.expression(expr)?;
+                self.emit(Instruction::Pop, expr.span);
+            }
+            None => {}
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+
+        let mut exit_jump = None;
+        if let Some(condition) = condition {
+            self.expression(condition)?;
+            exit_jump = Some(self.emit_jump(Instruction::JumpIfFalse(0), span));
+            self.emit(Instruction::Pop, span);
+        }
+
+        if let Some(increment) = increment {
+            let body_jump = self.emit_jump(Instruction::Jump(0), span);
+            let increment_start = self.current_chunk().code.len();
+
+            self.expression(increment)?;
+            self.emit(Instruction::Pop, increment.span);
+
+            self.emit_loop(loop_start, span)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump, span)?;
+        }
+
+        self.statement(body)?;
+        self.emit_loop(loop_start, span)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump, span)?;
+            self.emit(Instruction::Pop, span);
         }
 
         Ok(())
     }
 
-    fn unary(&mut self) -> RoxResult<()> {
-        let kind = self.previous.kind();
+    fn begin_scope(&mut self) {
+        self.scope().scope_depth += 1;
+    }
 
-        self.parse_precedence(Precedence::Unary)?;
+    fn end_scope(&mut self, span: Span) {
+        let scope = self.scope();
+        scope.scope_depth -= 1;
+        let scope_depth = scope.scope_depth;
 
-        match kind {
-            TokenKind::Minus => self.emit(Instruction::Negate),
-            TokenKind::Bang => self.emit(Instruction::Not),
-            _ => panic!("Invalid unary operator"),
+        while let Some(local) = self.scope().locals.last() {
+            if matches!(local.depth, Some(depth) if depth <= scope_depth) {
+                break;
+            }
+
+            self.emit(Instruction::Pop, span);
+            if let Some(local) = self.scope().locals.pop() {
+                self.check_unused(&local);
+            }
+        }
+    }
+
+    fn check_unused(&mut self, local: &Local) {
+        if !local.used && !local.name.starts_with('_') {
+            self.warn(
+                CompilationWarning::UnusedVariable(local.name.into()),
+                local.span,
+            );
+        }
+    }
+
+    fn expression(&mut self, expr: &'ast Expr) -> RoxResult<()> {
+        match &expr.kind {
+            ExprKind::Literal(literal) => self.literal(literal, expr.span)?,
+            ExprKind::Variable(name) => self.named_variable(name, None)?,
+            ExprKind::Assign { target, value } => self.named_variable(target, Some(value))?,
+            ExprKind::Unary {
+                operator,
+                operator_span,
+                operand,
+            } => {
+                self.expression(operand)?;
+                let instruction = match operator {
+                    UnaryOp::Negate => Instruction::Negate,
+                    UnaryOp::Not => Instruction::Not,
+                };
+                self.emit(instruction, *operator_span);
+            }
+            ExprKind::Binary {
+                operator,
+                operator_span,
+                left,
+                right,
+            } => self.binary(*operator, *operator_span, left, right)?,
+            ExprKind::Logical {
+                operator,
+                operator_span,
+                left,
+                right,
+            } => self.logical(*operator, *operator_span, left, right)?,
+            ExprKind::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                self.expression(callee)?;
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                self.emit(Instruction::Call(arguments.len() as u16), *paren);
+            }
+            ExprKind::Get { object, name } => {
+                self.expression(object)?;
+                let index = self.identifier_constant(name)?;
+                self.emit(Instruction::GetProperty(index), name.span);
+            }
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object)?;
+                self.expression(value)?;
+                let index = self.identifier_constant(name)?;
+                self.emit(Instruction::SetProperty(index), name.span);
+            }
+            ExprKind::Grouping(expr) => self.expression(expr)?,
         }
 
         Ok(())
     }
 
-    fn literal(&mut self) -> RoxResult<()> {
-        match self.previous.kind() {
-            TokenKind::False => self.emit(Instruction::False),
-            TokenKind::True => self.emit(Instruction::True),
-            TokenKind::Nil => self.emit(Instruction::Nil),
-            _ => panic!("Invalid literal token"),
+    fn binary(
+        &mut self,
+        operator: BinaryOp,
+        span: Span,
+        left: &'ast Expr,
+        right: &'ast Expr,
+    ) -> RoxResult<()> {
+        self.expression(left)?;
+        self.expression(right)?;
+
+        if operator.is_comparison() {
+            self.check_comparison(operator, left, right);
+        }
+
+        let instructions: &[Instruction] = match operator {
+            BinaryOp::Add => &[Instruction::Add],
+            BinaryOp::Subtract => &[Instruction::Subtract],
+            BinaryOp::Multiply => &[Instruction::Multiply],
+            BinaryOp::Divide => &[Instruction::Divide],
+            BinaryOp::NotEqual => &[Instruction::Equal, Instruction::Not],
+            BinaryOp::Equal => &[Instruction::Equal],
+            BinaryOp::Greater => &[Instruction::Greater],
+            BinaryOp::GreaterEqual => &[Instruction::Less, Instruction::Not],
+            BinaryOp::Less => &[Instruction::Less],
+            BinaryOp::LessEqual => &[Instruction::Greater, Instruction::Not],
+        };
+
+        for inst in instructions {
+            self.emit(*inst, span);
         }
 
         Ok(())
     }
 
-    fn number(&mut self) -> RoxResult<()> {
-        assert!(matches!(self.previous.kind(), TokenKind::Number));
+    /// Warns about comparisons whose result is known at compile time.
+    fn check_comparison(&mut self, operator: BinaryOp, left: &Expr, right: &Expr) {
+        let span = Span::new(left.span.start, right.span.end);
 
-        match self.previous.lexeme().parse::<f64>() {
-            Ok(value) => {
-                self.emit_constant(Value::Number(value))?;
-                Ok(())
+        match (&left.kind, &right.kind) {
+            (ExprKind::Variable(left), ExprKind::Variable(right)) if left.name == right.name => {
+                self.warn(CompilationWarning::SelfComparison(left.name.clone()), span);
             }
-            Err(_) => Err(self.error(CompilationError::InvalidNumberLiteral(
-                self.previous.lexeme().into(),
-            ))),
+            (ExprKind::Literal(left), ExprKind::Literal(right)) if left == right => {
+                self.warn(CompilationWarning::SelfComparison(literal_text(left)), span);
+            }
+            (ExprKind::Literal(left), ExprKind::Literal(right))
+                if matches!(operator, BinaryOp::Equal | BinaryOp::NotEqual)
+                    && left.type_name() != right.type_name() =>
+            {
+                let warning = CompilationWarning::LiteralTypeComparison {
+                    operator: operator.symbol(),
+                    left: left.type_name(),
+                    right: right.type_name(),
+                    result: operator == BinaryOp::NotEqual,
+                };
+                self.warn(warning, span);
+            }
+            _ => {}
         }
     }
 
-    fn string(&mut self) -> RoxResult<()> {
-        assert!(matches!(self.previous.kind(), TokenKind::String));
+    fn logical(
+        &mut self,
+        operator: LogicalOp,
+        span: Span,
+        left: &'ast Expr,
+        right: &'ast Expr,
+    ) -> RoxResult<()> {
+        self.expression(left)?;
 
-        let lexeme = self.previous.lexeme();
-        let value = &lexeme[1..(lexeme.len() - 1)];
+        match operator {
+            LogicalOp::And => {
+                let end_jump = self.emit_jump(Instruction::JumpIfFalse(0), span);
+
+                self.emit(Instruction::Pop, span);
+                self.expression(right)?;
+
+                self.patch_jump(end_jump, span)
+            }
+            LogicalOp::Or => {
+                let else_jump = self.emit_jump(Instruction::JumpIfFalse(0), span);
+                let end_jump = self.emit_jump(Instruction::Jump(0), span);
 
-        let reference = self.heap.alloc_string(String::from(value));
-        self.emit_constant(Value::String(reference))?;
+                self.patch_jump(else_jump, span)?;
+                self.emit(Instruction::Pop, span);
+
+                self.expression(right)?;
+                self.patch_jump(end_jump, span)
+            }
+        }
+    }
+
+    fn literal(&mut self, literal: &Literal, span: Span) -> RoxResult<()> {
+        match literal {
+            Literal::Bool(false) => self.emit(Instruction::False, span),
+            Literal::Bool(true) => self.emit(Instruction::True, span),
+            Literal::Nil => self.emit(Instruction::Nil, span),
+            Literal::Number(value) => self.emit_constant(Value::Number(*value), span)?,
+            Literal::String(value) => {
+                let reference = self.heap.alloc_string(value.clone());
+                self.emit_constant(Value::String(reference), span)?;
+            }
+        }
 
         Ok(())
     }
 
-    fn get_rule(&mut self, kind: TokenKind) -> ParseRule<'sourcecode> {
-        let rule: (
-            Option<ParseFn<'sourcecode>>,
-            Option<ParseFn<'sourcecode>>,
-            Precedence,
-        ) = match kind {
-            TokenKind::LeftParen => (Some(Self::grouping), None, Precedence::None),
-            TokenKind::RightParen => (None, None, Precedence::None),
-            TokenKind::LeftBrace => (None, None, Precedence::None),
-            TokenKind::RightBrace => (None, None, Precedence::None),
-            TokenKind::Comma => (None, None, Precedence::None),
-            TokenKind::Dot => (None, None, Precedence::None),
-            TokenKind::Minus => (Some(Self::unary), Some(Self::binary), Precedence::Term),
-            TokenKind::Plus => (None, Some(Self::binary), Precedence::Term),
-            TokenKind::Semicolon => (None, None, Precedence::None),
-            TokenKind::Slash => (None, Some(Self::binary), Precedence::Factor),
-            TokenKind::Star => (None, Some(Self::binary), Precedence::Factor),
-            TokenKind::Bang => (Some(Self::unary), None, Precedence::None),
-            TokenKind::BangEqual => (None, Some(Self::binary), Precedence::Equality),
-            TokenKind::Equal => (None, None, Precedence::None),
-            TokenKind::EqualEqual => (None, Some(Self::binary), Precedence::Equality),
-            TokenKind::Greater => (None, Some(Self::binary), Precedence::Comparison),
-            TokenKind::GreaterEqual => (None, Some(Self::binary), Precedence::Comparison),
-            TokenKind::Less => (None, Some(Self::binary), Precedence::Comparison),
-            TokenKind::LessEqual => (None, Some(Self::binary), Precedence::Comparison),
-            TokenKind::Identifier => (None, None, Precedence::None),
-            TokenKind::String => (Some(Self::string), None, Precedence::None),
-            TokenKind::Number => (Some(Self::number), None, Precedence::None),
-            TokenKind::And => (None, None, Precedence::None),
-            TokenKind::Class => (None, None, Precedence::None),
-            TokenKind::Else => (None, None, Precedence::None),
-            TokenKind::False => (Some(Self::literal), None, Precedence::None),
-            TokenKind::Fun => (None, None, Precedence::None),
-            TokenKind::For => (None, None, Precedence::None),
-            TokenKind::If => (None, None, Precedence::None),
-            TokenKind::Nil => (Some(Self::literal), None, Precedence::None),
-            TokenKind::Or => (None, None, Precedence::None),
-            TokenKind::Print => (None, None, Precedence::None),
-            TokenKind::Return => (None, None, Precedence::None),
-            TokenKind::Super => (None, None, Precedence::None),
-            TokenKind::This => (None, None, Precedence::None),
-            TokenKind::True => (Some(Self::literal), None, Precedence::None),
-            TokenKind::Var => (None, None, Precedence::None),
-            TokenKind::While => (None, None, Precedence::None),
-            TokenKind::Error(_) => (None, None, Precedence::None),
-            TokenKind::Eof => (None, None, Precedence::None),
+    /// Reads the variable `name`, or assigns `value` to it.
+    fn named_variable(&mut self, name: &Identifier, value: Option<&'ast Expr>) -> RoxResult<()> {
+        let (get, set) = match self.resolve_local(name)? {
+            Some(slot) => (Instruction::GetLocal(slot), Instruction::SetLocal(slot)),
+            None => {
+                let index = self.identifier_constant(name)?;
+                (Instruction::GetGlobal(index), Instruction::SetGlobal(index))
+            }
         };
 
-        ParseRule::from(rule)
+        match value {
+            Some(value) => {
+                self.expression(value)?;
+                self.emit(set, name.span);
+            }
+            None => {
+                if let Instruction::GetLocal(slot) = get {
+                    self.scope().locals[slot as usize].used = true;
+                }
+                self.emit(get, name.span);
+            }
+        }
+
+        Ok(())
     }
 
-    fn emit(&mut self, instruction: Instruction) {
-        let line = self.previous.location().line();
-        self.current_chunk().write(instruction, line);
+    fn identifier_constant(&mut self, name: &Identifier) -> RoxResult<u16> {
+        let reference = self.heap.alloc_string(name.name.clone());
+        self.make_constant(Value::String(reference), name.span)
     }
 
-    fn emit_many(&mut self, instructions: &[Instruction]) {
-        for inst in instructions {
-            self.emit(*inst);
+    /// Declares a variable in the current scope, returning the index of its name in the
+    /// constant table if it is a global.
+    fn declare_variable(&mut self, name: &'ast Identifier) -> RoxResult<u16> {
+        let scope_depth = self.scope().scope_depth;
+        if scope_depth == 0 {
+            return self.identifier_constant(name);
+        }
+
+        let redeclared = self
+            .scope()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name.name);
+
+        if redeclared {
+            return Err(self.error(
+                CompilationError::VariableAlreadyDeclared(name.name.clone()),
+                name.span,
+            ));
+        }
+
+        let shadowed = self
+            .scope()
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.name == name.name)
+            .map(|local| local.span.start);
+
+        if let Some(shadowed) = shadowed {
+            let warning = RoxError::new(
+                RoxErrorKind::CompilationWarning(CompilationWarning::ShadowedVariable(
+                    name.name.clone(),
+                )),
+                name.span,
+            )
+            .with_note(format!(
+                "Shadowed variable declared at line {}",
+                shadowed.line() + 1
+            ));
+            self.report_warning(warning);
+        }
+
+        self.add_local(name)?;
+
+        Ok(0)
+    }
+
+    fn add_local(&mut self, name: &'ast Identifier) -> RoxResult<()> {
+        if self.scope().locals.len() > u16::MAX as usize {
+            return Err(self.error(
+                CompilationError::TooManyLocals(u16::MAX as u64 + 1),
+                name.span,
+            ));
+        }
+
+        self.scope().locals.push(Local {
+            name: &name.name,
+            span: name.span,
+            depth: None,
+            used: false,
+        });
+
+        Ok(())
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope = self.scope();
+        if scope.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = scope.locals.last_mut() {
+            local.depth = Some(scope.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: u16, span: Span) {
+        if self.scope().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit(Instruction::DefineGlobal(global), span);
+    }
+
+    fn resolve_local(&mut self, name: &Identifier) -> RoxResult<Option<u16>> {
+        let found = self
+            .scope()
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name.name)
+            .map(|(slot, local)| (slot, local.depth));
+
+        match found {
+            Some((_, None)) => Err(self.error(
+                CompilationError::ReadLocalInOwnInitializer(name.name.clone()),
+                name.span,
+            )),
+            Some((slot, Some(_))) => Ok(Some(slot as u16)),
+            None => Ok(None),
         }
     }
 
-    fn emit_constant(&mut self, value: Value) -> RoxResult<()> {
-        let index = self.make_constant(value)?;
-        self.emit(Instruction::Constant(index));
+    fn emit(&mut self, instruction: Instruction, span: Span) {
+        self.current_chunk().write(instruction, span);
+    }
+
+    fn emit_constant(&mut self, value: Value, span: Span) -> RoxResult<()> {
+        let index = self.make_constant(value, span)?;
+        self.emit(Instruction::Constant(index), span);
 
         Ok(())
     }
 
-    fn emit_return(&mut self) {
-        self.emit(Instruction::Return)
+    /// Emits a jump instruction with a placeholder offset, returning its index so that it can
+    /// later be patched by [`Compiler::patch_jump`].
+    fn emit_jump(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.emit(instruction, span);
+        self.current_chunk().code.len() - 1
     }
 
-    fn end_compiler(&mut self) {
-        if !self.errors.is_empty() {
+    fn patch_jump(&mut self, index: usize, span: Span) -> RoxResult<()> {
+        let distance = self.current_chunk().code.len() - index - 1;
+        let offset = match u16::try_from(distance) {
+            Ok(offset) => offset,
+            Err(_) => return Err(self.error(CompilationError::JumpTooLarge(u16::MAX as u64), span)),
+        };
+
+        let chunk = self.current_chunk();
+        chunk.code[index] = match chunk.code[index] {
+            Instruction::Jump(_) => Instruction::Jump(offset),
+            Instruction::JumpIfFalse(_) => Instruction::JumpIfFalse(offset),
+            inst => panic!("Tried to patch non-jump instruction {:?}", inst),
+        };
+
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, span: Span) -> RoxResult<()> {
+        let distance = self.current_chunk().code.len() - loop_start + 1;
+        let offset = match u16::try_from(distance) {
+            Ok(offset) => offset,
+            Err(_) => return Err(self.error(CompilationError::JumpTooLarge(u16::MAX as u64), span)),
+        };
+
+        self.emit(Instruction::Loop(offset), span);
+
+        Ok(())
+    }
+
+    fn emit_return(&mut self, span: Span) {
+        self.emit(Instruction::Nil, span);
+        self.emit(Instruction::Return, span);
+    }
+
+    /// Finishes compiling the current function, popping its scope.
+    fn end_compiler(&mut self, end: Span) -> Function {
+        self.emit_return(end);
+
+        let mut scope = self.functions.pop().expect("Function scope stack is empty");
+
+        // Locals of the function's outermost scope are never popped by `end_scope`.
+        for local in scope.locals.iter().skip(1) {
+            self.check_unused(local);
+        }
+
+        if self.errors.is_empty() {
+            if self.opt_level >= OptLevel::O2 {
+                let arity = scope.function.arity;
+                ssa::optimize(&mut scope.function.chunk, arity, self.heap);
+            }
+            if self.opt_level >= OptLevel::O1 {
+                optimizer::optimize(&mut scope.function.chunk, self.heap);
+            }
+
             #[cfg(feature = "debug_trace_execution")]
             {
-                let dis = Disassembler::new(self.current_chunk(), None);
-                dis.run("Finished compiling");
+                let name = match scope.function.name {
+                    Some(name) => self.heap.deref(name).as_str(),
+                    None => "<script>",
+                };
+                let dis = Disassembler::new(&scope.function.chunk);
+                dis.run(name);
             }
         }
-        self.emit_return();
+
+        scope.function
     }
 
-    fn make_constant(&mut self, value: Value) -> RoxResult<u16> {
+    fn make_constant(&mut self, value: Value, span: Span) -> RoxResult<u16> {
         match self.current_chunk().add_constant(value) {
             Ok(index) => Ok(index),
-            Err(_) => Err(self.error(CompilationError::TooManyConstants(u16::MAX as u64))),
+            Err(_) => Err(self.error(CompilationError::TooManyConstants(u16::MAX as u64), span)),
         }
     }
 
+    fn scope(&mut self) -> &mut FunctionScope<'ast> {
+        self.functions
+            .last_mut()
+            .expect("Function scope stack is empty")
+    }
+
     fn current_chunk(&mut self) -> &mut Chunk {
-        self.chunks.last_mut().unwrap()
+        &mut self.scope().function.chunk
+    }
+
+    fn warn(&mut self, warning: CompilationWarning, span: Span) {
+        let warning = RoxError::new(RoxErrorKind::CompilationWarning(warning), span);
+        self.report_warning(warning);
     }
 
-    fn consume(&mut self, kind: TokenKind, error: CompilationError) -> RoxResult<()> {
-        if self.current.kind() == kind {
-            self.advance()?;
-            return Ok(());
+    /// Records a warning according to the level configured for its kind.
+    fn report_warning(&mut self, warning: RoxError) {
+        let kind = match &warning.src {
+            RoxErrorKind::CompilationWarning(kind) => kind.kind(),
+            _ => panic!("Tried to report an error as a warning"),
+        };
+
+        match self.lints.level(kind) {
+            LintLevel::Allow => {}
+            LintLevel::Warn => self.warnings.push(warning.with_severity(Severity::Warning)),
+            LintLevel::Deny => self.errors.push(warning),
         }
+    }
 
-        Err(self.error_at_current(error))
+    fn error(&mut self, kind: CompilationError, span: Span) -> RoxError {
+        RoxError::new(RoxErrorKind::CompilationError(kind), span)
     }
+}
 
-    fn advance(&mut self) -> RoxResult<()> {
-        self.previous = self.current;
+/// Compiles `code` into the function for its top-level script, warnings are appended to
+/// `warnings` even when compilation fails.
+pub fn compile(
+    code: &str,
+    file: &str,
+    heap: &mut Heap,
+    lints: &Lints,
+    opt_level: OptLevel,
+    warnings: &mut Vec<RoxError>,
+) -> Result<Ref<Function>, Vec<RoxError>> {
+    let (program, syntax_errors) = parser::parse(code);
+    compile_program(
+        &program,
+        syntax_errors,
+        file,
+        heap,
+        lints,
+        opt_level,
+        warnings,
+    )
+}
 
-        if let Some(token) = self.scanner.next() {
-            self.current = token;
+/// Compiles a program that was already parsed, `syntax_errors` being the errors found while
+/// parsing it. Warnings are appended in the order of the code they are about.
+pub fn compile_program(
+    program: &Program,
+    syntax_errors: Vec<RoxError>,
+    file: &str,
+    heap: &mut Heap,
+    lints: &Lints,
+    opt_level: OptLevel,
+    warnings: &mut Vec<RoxError>,
+) -> Result<Ref<Function>, Vec<RoxError>> {
+    let (result, mut found) =
+        Compiler::new(file, heap, lints, opt_level).compile(program, syntax_errors);
+    found.sort_by_key(|warning| warning.span.start.offset());
+    warnings.append(&mut found);
+    result
+}
 
-            match token.kind() {
-                TokenKind::Error(TokenErrorKind::InvalidLexeme) => {
-                    return Err(self.error(CompilationError::InvalidLexeme(token.lexeme().into())))
-                }
-                TokenKind::Error(TokenErrorKind::SyntheticToken) => {
-                    panic!("Unexpected synthetic token, this is a bug in the compiler");
-                }
-                TokenKind::Error(TokenErrorKind::UnterminatedString) => {
-                    return Err(self.error(CompilationError::UnterminatedString))
-                }
-                _ => {}
-            }
+/// Literal as it could be written in the code.
+fn literal_text(literal: &Literal) -> String {
+    match literal {
+        Literal::Number(value) => value.to_string(),
+        Literal::String(value) => format!("\"{}\"", value),
+        Literal::Bool(value) => value.to_string(),
+        Literal::Nil => "nil".into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::{CompilationError, CompilationWarning, RoxError, RoxErrorKind, Severity},
+        heap::Heap,
+        lints::{LintLevel, Lints, WarningKind},
+        optimizer::OptLevel,
+    };
 
-            println!("{:?} {:?}", self.previous.kind(), self.current.kind());
+    use super::compile;
+
+    fn compile_errors(code: &str) -> Vec<(usize, CompilationError)> {
+        let mut heap = Heap::new();
+        match compile(
+            code,
+            "test.lox",
+            &mut heap,
+            &Lints::new(),
+            OptLevel::O0,
+            &mut Vec::new(),
+        ) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors
+                .into_iter()
+                .map(|err| match err.src {
+                    RoxErrorKind::CompilationError(kind) => (err.span.start.line(), kind),
+                    kind => panic!("Unexpected error {}", kind),
+                })
+                .collect(),
         }
+    }
 
-        Ok(())
+    fn compile_warnings(code: &str) -> Vec<(usize, CompilationWarning)> {
+        let mut heap = Heap::new();
+        let mut warnings = Vec::new();
+        compile(
+            code,
+            "test.lox",
+            &mut heap,
+            &Lints::new(),
+            OptLevel::O0,
+            &mut warnings,
+        )
+        .expect("Program should compile");
+
+        warnings
+            .into_iter()
+            .map(|warning| match warning.src {
+                RoxErrorKind::CompilationWarning(kind) => (warning.span.start.line(), kind),
+                kind => panic!("Unexpected warning {}", kind),
+            })
+            .collect()
     }
 
-    fn error_at_current(&mut self, kind: CompilationError) -> RoxError {
-        self.error_at(self.current, kind)
+    #[test]
+    fn valid_program_has_no_errors() {
+        let errors =
+            compile_errors("var a = 1;\n{ var b = a; print b; }\nwhile (a < 3) a = a + 1;");
+        assert!(errors.is_empty());
     }
 
-    fn error(&mut self, kind: CompilationError) -> RoxError {
-        self.error_at(self.previous, kind)
+    #[test]
+    fn reports_every_independent_error() {
+        let errors = compile_errors("var = 1;\nprint 1 +;\nvar ok = 2;\nprint (ok;\nprint ok;");
+
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(
+            errors[0],
+            (0, CompilationError::MissingVariableName)
+        ));
+        assert!(matches!(
+            errors[1],
+            (1, CompilationError::MissingExpression)
+        ));
+        assert!(matches!(
+            errors[2],
+            (3, CompilationError::MissingClosingParenthesis)
+        ));
     }
 
-    fn error_at(&mut self, token: Token, kind: CompilationError) -> RoxError {
-        RoxError::new(
-            RoxErrorKind::CompilationError(kind),
-            token.location().line(),
-        )
+    #[test]
+    fn synchronizes_on_statement_keywords() {
+        let errors = compile_errors("print 1 print 2;\nvar x = ;\nif (x) print x;");
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0],
+            (0, CompilationError::MissingSemicolon(_))
+        ));
+        assert!(matches!(
+            errors[1],
+            (1, CompilationError::MissingExpression)
+        ));
     }
-}
 
-pub fn compile(code: &str, heap: &mut Heap) -> Result<Chunk, Vec<RoxError>> {
-    Parser::new(code, heap).compile()
+    #[test]
+    fn lexical_errors_do_not_abort_statement() {
+        let errors = compile_errors("print 1 + $ 2;\nprint 3;");
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], (0, CompilationError::InvalidLexeme(_))));
+    }
+
+    #[test]
+    fn warns_about_unused_and_shadowed_locals() {
+        let warnings = compile_warnings(
+            "{\n  var a = 1;\n  var _b = 2;\n  {\n    var a = 3;\n    print a;\n  }\n}",
+        );
+
+        // Unused variables are found at the end of their scope, but reported in code order.
+        assert_eq!(warnings.len(), 2);
+        assert!(matches!(
+            &warnings[0],
+            (1, CompilationWarning::UnusedVariable(name)) if name == "a"
+        ));
+        assert!(matches!(
+            &warnings[1],
+            (4, CompilationWarning::ShadowedVariable(name)) if name == "a"
+        ));
+    }
+
+    #[test]
+    fn warns_about_unreachable_code() {
+        let warnings = compile_warnings(
+            "fun f(x) {\n  if (x) return 1; else return 2;\n  print x;\n  print x;\n}",
+        );
+
+        assert_eq!(warnings.len(), 1);
+        assert!(matches!(
+            warnings[0],
+            (2, CompilationWarning::UnreachableCode)
+        ));
+    }
+
+    #[test]
+    fn warns_about_comparisons_with_known_result() {
+        let warnings =
+            compile_warnings("var x = 1;\nprint x == x;\nprint 1 != \"1\";\nprint x + 1 == x;");
+
+        assert_eq!(warnings.len(), 2);
+        assert!(matches!(
+            &warnings[0],
+            (1, CompilationWarning::SelfComparison(name)) if name == "x"
+        ));
+        assert!(matches!(
+            warnings[1],
+            (
+                2,
+                CompilationWarning::LiteralTypeComparison {
+                    operator: "!=",
+                    left: "number",
+                    right: "string",
+                    result: true,
+                }
+            )
+        ));
+    }
+
+    #[test]
+    fn lint_levels_allow_and_deny_warnings() {
+        let mut lints = Lints::new();
+        lints.set(WarningKind::UnusedVariable, LintLevel::Deny);
+        lints.set(WarningKind::SelfComparison, LintLevel::Allow);
+
+        let mut heap = Heap::new();
+        let mut warnings = Vec::new();
+        let errors: Vec<RoxError> = compile(
+            "{ var a = 1; }\nprint 1 == 1;",
+            "test.lox",
+            &mut heap,
+            &lints,
+            OptLevel::O0,
+            &mut warnings,
+        )
+        .expect_err("Denied warnings should fail compilation");
+
+        assert!(warnings.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].severity, Severity::Error);
+        assert!(matches!(
+            errors[0].src,
+            RoxErrorKind::CompilationWarning(CompilationWarning::UnusedVariable(_))
+        ));
+    }
 }
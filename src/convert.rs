@@ -0,0 +1,346 @@
+use crate::{chunk::Value, error::RuntimeError, heap::Heap, objects::List};
+
+/// Rust value that can be given to scripts.
+pub trait IntoValue {
+    fn into_value(self, heap: &mut Heap) -> Value;
+}
+
+/// Rust value that can be made from what scripts give, e.g. the arguments of natives.
+pub trait FromValue: Sized {
+    /// Values that can be converted, as shown in errors, e.g. `a number`.
+    fn expected() -> String;
+
+    /// Converts `value`, `None` if it is not one of the values `expected` describes.
+    fn from_value(value: Value, heap: &Heap) -> Option<Self>;
+}
+
+/// Arguments of a native function, converted from the values it was called with.
+pub trait FromArgs: Sized {
+    /// Number of arguments the function takes.
+    const ARITY: usize;
+
+    /// Converts the arguments, failing with the first that has the wrong type. There are always
+    /// `ARITY` of them.
+    fn from_args(args: &[Value], heap: &Heap) -> Result<Self, RuntimeError>;
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _: &mut Heap) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn expected() -> String {
+        String::from("any value")
+    }
+
+    fn from_value(value: Value, _: &Heap) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &mut Heap) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl FromValue for f64 {
+    fn expected() -> String {
+        String::from("a number")
+    }
+
+    fn from_value(value: Value, _: &Heap) -> Option<Self> {
+        match value {
+            Value::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+}
+
+/// Integers are numbers to scripts, which can only give numbers without a fractional part that
+/// are in the integer type's range. Those too big to be represented exactly are rounded when
+/// given to scripts.
+macro_rules! integer_conversions {
+    ($($integer:ty),*) => {
+        $(
+            impl IntoValue for $integer {
+                fn into_value(self, _: &mut Heap) -> Value {
+                    Value::Number(self as f64)
+                }
+            }
+
+            impl FromValue for $integer {
+                fn expected() -> String {
+                    format!("an integer between {} and {}", <$integer>::MIN, <$integer>::MAX)
+                }
+
+                fn from_value(value: Value, _: &Heap) -> Option<Self> {
+                    // The maximum may be rounded up to the next power of two, which is then the
+                    // first number out of range.
+                    match value {
+                        Value::Number(number)
+                            if number.fract() == 0.0
+                                && number >= <$integer>::MIN as f64
+                                && number < <$integer>::MAX as f64 + 1.0 =>
+                        {
+                            Some(number as $integer)
+                        }
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoValue for bool {
+    fn into_value(self, _: &mut Heap) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for bool {
+    fn expected() -> String {
+        String::from("a boolean")
+    }
+
+    fn from_value(value: Value, _: &Heap) -> Option<Self> {
+        match value {
+            Value::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _: &mut Heap) -> Value {
+        Value::Nil
+    }
+}
+
+impl FromValue for () {
+    fn expected() -> String {
+        String::from("nil")
+    }
+
+    fn from_value(value: Value, _: &Heap) -> Option<Self> {
+        match value {
+            Value::Nil => Some(()),
+            _ => None,
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        Value::String(heap.alloc_string(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        Value::String(heap.alloc_string(self.to_owned()))
+    }
+}
+
+impl FromValue for String {
+    fn expected() -> String {
+        String::from("a string")
+    }
+
+    fn from_value(value: Value, heap: &Heap) -> Option<Self> {
+        match value {
+            Value::String(string) => Some(heap.deref(string).clone()),
+            _ => None,
+        }
+    }
+}
+
+/// `None` is `nil`.
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        match self {
+            Some(value) => value.into_value(heap),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn expected() -> String {
+        format!("{} or nil", T::expected())
+    }
+
+    fn from_value(value: Value, heap: &Heap) -> Option<Self> {
+        match value {
+            Value::Nil => Some(None),
+            value => T::from_value(value, heap).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        let items = self.into_iter().map(|item| item.into_value(heap)).collect();
+        Value::List(heap.alloc(List { items }))
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn expected() -> String {
+        format!("a list where each item is {}", T::expected())
+    }
+
+    fn from_value(value: Value, heap: &Heap) -> Option<Self> {
+        match value {
+            Value::List(list) => heap
+                .deref(list)
+                .items
+                .iter()
+                .map(|item| T::from_value(*item, heap))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+/// Converts the argument at `index`.
+fn argument<T: FromValue>(args: &[Value], index: usize, heap: &Heap) -> Result<T, RuntimeError> {
+    T::from_value(args[index], heap).ok_or_else(|| RuntimeError::InvalidArgument {
+        position: index + 1,
+        expected: T::expected(),
+        got: args[index].type_name_in(heap),
+    })
+}
+
+macro_rules! tuple_args {
+    ($arity:expr; $($name:ident $index:tt),*) => {
+        impl<$($name: FromValue),*> FromArgs for ($($name,)*) {
+            const ARITY: usize = $arity;
+
+            #[allow(unused_variables)]
+            fn from_args(args: &[Value], heap: &Heap) -> Result<Self, RuntimeError> {
+                Ok(($(argument::<$name>(args, $index, heap)?,)*))
+            }
+        }
+    };
+}
+
+tuple_args!(0;);
+tuple_args!(1; A 0);
+tuple_args!(2; A 0, B 1);
+tuple_args!(3; A 0, B 1, C 2);
+tuple_args!(4; A 0, B 1, C 2, D 3);
+tuple_args!(5; A 0, B 1, C 2, D 3, E 4);
+tuple_args!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use crate::{chunk::Value, error::RuntimeError, heap::Heap, vm::Vm};
+
+    use super::{FromValue, IntoValue};
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn convert<T: IntoValue, U: FromValue>(value: T) -> Option<U> {
+        let mut heap = Heap::new();
+        let value = value.into_value(&mut heap);
+        U::from_value(value, &heap)
+    }
+
+    #[test]
+    fn converts_values_both_ways() {
+        assert_eq!(convert::<_, f64>(1.5), Some(1.5));
+        assert_eq!(convert::<_, bool>(true), Some(true));
+        assert_eq!(convert::<_, ()>(()), Some(()));
+        assert_eq!(convert::<_, String>("rox"), Some(String::from("rox")));
+        assert_eq!(convert::<_, Option<String>>(None::<String>), Some(None));
+        assert_eq!(
+            convert::<_, Vec<Option<i32>>>(vec![Some(1), None]),
+            Some(vec![Some(1), None])
+        );
+
+        assert_eq!(convert::<_, f64>("1"), None);
+        assert_eq!(convert::<_, Vec<bool>>(vec![Some(true), None]), None);
+    }
+
+    #[test]
+    fn integers_must_be_whole_and_in_range() {
+        assert_eq!(convert::<_, i8>(-128.0), Some(-128));
+        assert_eq!(convert::<_, i8>(128.0), None);
+        assert_eq!(convert::<_, u8>(-1.0), None);
+        assert_eq!(convert::<_, i32>(1.5), None);
+        assert_eq!(convert::<_, u64>(2f64.powi(63)), Some(1 << 63));
+        assert_eq!(convert::<_, i64>(2f64.powi(63)), None);
+        assert_eq!(convert::<_, u64>(2f64.powi(64)), None);
+        assert_eq!(convert::<_, usize>(f64::NAN), None);
+        assert_eq!(convert::<_, i64>(f64::INFINITY), None);
+    }
+
+    #[test]
+    fn natives_take_and_return_rust_types() {
+        let output = Output::default();
+        let mut vm = Vm::new();
+        vm.set_output(output.clone());
+        vm.register_fn("range", |_, (count,): (usize,)| {
+            Ok((0..count).collect::<Vec<usize>>())
+        });
+        vm.register_fn("sum", |_, (items,): (Vec<f64>,)| {
+            Ok(items.iter().sum::<f64>())
+        });
+        vm.register_fn(
+            "greet",
+            |_, (name, punctuation): (String, Option<String>)| {
+                Ok(format!("hi {}{}", name, punctuation.unwrap_or_default()))
+            },
+        );
+
+        let code = "print range(3); print sum(range(5)); print greet(\"a\", nil); print greet(\"b\", \"!\");";
+        assert!(vm.interpret(code, "convert.lox").is_ok());
+        assert_eq!(*output.0.borrow(), b"[0, 1, 2]\n10\nhi a\nhi b!\n");
+
+        let cases = [
+            (
+                "range(-1);",
+                "Argument 1 must be an integer between 0 and 18446744073709551615, got number",
+            ),
+            (
+                "greet(\"a\", 1);",
+                "Argument 2 must be a string or nil, got number",
+            ),
+            (
+                "sum(1);",
+                "Argument 1 must be a list where each item is a number, got number",
+            ),
+            ("greet(\"a\");", "Expected 2 arguments but got 1"),
+        ];
+        for (code, message) in cases.iter() {
+            let errors = vm.interpret(code, "convert.lox").unwrap_err();
+            assert_eq!(errors[0].to_string(), *message);
+        }
+
+        vm.register_fn("fail", |_, ()| -> Result<(), _> {
+            Err(RuntimeError::Native("Failed".into()))
+        });
+        let fail = vm.get_global("fail").unwrap();
+        assert!(matches!(fail, Value::Native(_)));
+        assert_eq!(vm.call(fail, &[]).unwrap_err()[0].to_string(), "Failed");
+    }
+}
@@ -1,15 +1,41 @@
-use crate::chunk::{Chunk, Instruction, Value};
+#[cfg(feature = "debug_trace_execution")]
+use crate::chunk::Value;
+use crate::{
+    chunk::{Chunk, Instruction},
+    heap::Heap,
+};
 
 pub struct Disassembler<'vm> {
     chunk: &'vm Chunk,
+    #[cfg(feature = "debug_trace_execution")]
     stack: Option<&'vm Vec<Value>>,
+    heap: Option<&'vm Heap>,
 }
 
 impl<'vm> Disassembler<'vm> {
-    pub fn new(chunk: &'vm Chunk, stack: Option<&'vm Vec<Value>>) -> Self {
-        Self { chunk, stack }
+    pub fn new(chunk: &'vm Chunk) -> Self {
+        Self {
+            chunk,
+            #[cfg(feature = "debug_trace_execution")]
+            stack: None,
+            heap: None,
+        }
+    }
+
+    /// Prints the stack before each traced instruction.
+    #[cfg(feature = "debug_trace_execution")]
+    pub fn with_stack(mut self, stack: &'vm Vec<Value>) -> Self {
+        self.stack = Some(stack);
+        self
     }
 
+    /// Shows constants the way scripts see them instead of as raw references.
+    pub fn with_heap(mut self, heap: &'vm Heap) -> Self {
+        self.heap = Some(heap);
+        self
+    }
+
+    #[cfg(feature = "debug_trace_execution")]
     pub fn run(&self, name: &str) {
         println!("== {} ==", name);
 
@@ -18,6 +44,7 @@ impl<'vm> Disassembler<'vm> {
         }
     }
 
+    #[cfg(feature = "debug_trace_execution")]
     pub fn instruction(&self, offset: usize, inst: Instruction) {
         self.stack();
 
@@ -31,6 +58,11 @@ impl<'vm> Disassembler<'vm> {
             print!("{:4} ", line);
         }
 
+        println!("{}", self.instruction_text(offset, inst));
+    }
+
+    /// Text of an instruction as printed by `instruction`, without its offset and line.
+    pub fn instruction_text(&self, offset: usize, inst: Instruction) -> String {
         match inst {
             Instruction::Return => self.simple_instruction("OP_RETURN"),
             Instruction::Negate => self.simple_instruction("OP_NEGATE"),
@@ -46,18 +78,53 @@ impl<'vm> Disassembler<'vm> {
             Instruction::Equal => self.simple_instruction("OP_EQUAL"),
             Instruction::Greater => self.simple_instruction("OP_GREATER"),
             Instruction::Less => self.simple_instruction("OP_LESS"),
+            Instruction::NotEqual => self.simple_instruction("OP_NOT_EQUAL"),
+            Instruction::GreaterEqual => self.simple_instruction("OP_GREATER_EQUAL"),
+            Instruction::LessEqual => self.simple_instruction("OP_LESS_EQUAL"),
+            Instruction::Print => self.simple_instruction("OP_PRINT"),
+            Instruction::Pop => self.simple_instruction("OP_POP"),
+            Instruction::DefineGlobal(idx) => self.constant_instruction("OP_DEFINE_GLOBAL", idx),
+            Instruction::GetGlobal(idx) => self.constant_instruction("OP_GET_GLOBAL", idx),
+            Instruction::SetGlobal(idx) => self.constant_instruction("OP_SET_GLOBAL", idx),
+            Instruction::GetLocal(slot) => self.byte_instruction("OP_GET_LOCAL", slot),
+            Instruction::SetLocal(slot) => self.byte_instruction("OP_SET_LOCAL", slot),
+            Instruction::Jump(jump) => self.jump_instruction("OP_JUMP", offset, jump, 1),
+            Instruction::JumpIfFalse(jump) => {
+                self.jump_instruction("OP_JUMP_IF_FALSE", offset, jump, 1)
+            }
+            Instruction::Loop(jump) => self.jump_instruction("OP_LOOP", offset, jump, -1),
+            Instruction::Call(arg_count) => self.byte_instruction("OP_CALL", arg_count),
+            Instruction::AddConstant(idx) => self.constant_instruction("OP_ADD_CONSTANT", idx),
+            Instruction::SubtractConstant(idx) => self.constant_instruction("OP_SUB_CONSTANT", idx),
+            Instruction::AddLocals(a, b) => format!("{:<16} {:4} {:4}", "OP_ADD_LOCALS", a, b),
+            Instruction::SetLocalPop(slot) => self.byte_instruction("OP_SET_LOCAL_POP", slot),
+            Instruction::GetProperty(idx) => self.constant_instruction("OP_GET_PROPERTY", idx),
+            Instruction::SetProperty(idx) => self.constant_instruction("OP_SET_PROPERTY", idx),
         }
     }
 
-    fn simple_instruction(&self, msg: &'static str) {
-        println!("{}", msg);
+    fn simple_instruction(&self, msg: &'static str) -> String {
+        msg.to_string()
     }
 
-    fn constant_instruction(&self, msg: &'static str, idx: u16) {
+    fn constant_instruction(&self, msg: &'static str, idx: u16) -> String {
         let value = self.chunk.constants[idx as usize];
-        println!("{:<16} {:4} ({:?})", msg, idx, value);
+        match self.heap {
+            Some(heap) => format!("{:<16} {:4} ({})", msg, idx, value.format(heap)),
+            None => format!("{:<16} {:4} ({:?})", msg, idx, value),
+        }
+    }
+
+    fn byte_instruction(&self, msg: &'static str, operand: u16) -> String {
+        format!("{:<16} {:4}", msg, operand)
+    }
+
+    fn jump_instruction(&self, msg: &'static str, offset: usize, jump: u16, sign: isize) -> String {
+        let target = offset as isize + 1 + sign * jump as isize;
+        format!("{:<16} {:4} -> {}", msg, offset, target)
     }
 
+    #[cfg(feature = "debug_trace_execution")]
     fn stack(&self) {
         if let Some(stack) = self.stack {
             print!(" S: ");
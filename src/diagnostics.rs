@@ -0,0 +1,161 @@
+use serde::Serialize;
+
+use crate::{
+    error::{RoxError, RoxErrorKind, Severity, TraceFrame},
+    location::Location,
+};
+
+/// How errors are reported to the user.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// `[line N] Error: message`, meant to be read by people.
+    Human,
+    /// One JSON object per line, meant to be consumed by editors and CI.
+    Json,
+}
+
+/// Position of a diagnostic in the source file, both line and column are 1-based.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+impl From<Location> for Position {
+    fn from(location: Location) -> Self {
+        Self {
+            line: location.line() + 1,
+            column: location.column() + 1,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Frame<'a> {
+    function: &'a str,
+    file: &'a str,
+    line: usize,
+    column: usize,
+    repeated: usize,
+    cycle: usize,
+}
+
+impl<'a> From<&'a TraceFrame> for Frame<'a> {
+    fn from(frame: &'a TraceFrame) -> Self {
+        let position = Position::from(frame.span.start);
+
+        Self {
+            function: &frame.function,
+            file: &frame.file,
+            line: position.line,
+            column: position.column,
+            repeated: frame.repeated,
+            cycle: frame.cycle,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Diagnostic<'a> {
+    code: &'static str,
+    message: String,
+    severity: &'static str,
+    file: &'a str,
+    start: Position,
+    end: Position,
+    notes: &'a [String],
+    trace: Vec<Frame<'a>>,
+}
+
+impl<'a> Diagnostic<'a> {
+    fn new(error: &'a RoxError, file: &'a str) -> Self {
+        Self {
+            code: error.src.code(),
+            message: error.to_string(),
+            severity: error.severity.name(),
+            file,
+            start: error.span.start.into(),
+            end: error.span.end.into(),
+            notes: &error.notes,
+            trace: error.trace.iter().map(Frame::from).collect(),
+        }
+    }
+}
+
+pub fn report(errors: &[RoxError], file: &str, format: ErrorFormat) {
+    for err in errors {
+        match format {
+            ErrorFormat::Human => {
+                match (&err.severity, &err.src) {
+                    (Severity::Warning, RoxErrorKind::CompilationWarning(warning)) => eprintln!(
+                        "[line {}] Warning: {} ({})",
+                        err.line() + 1,
+                        err.src,
+                        warning.kind().name()
+                    ),
+                    _ => eprintln!("[line {}] Error: {}", err.line() + 1, err.src),
+                }
+                for note in &err.notes {
+                    eprintln!("  note: {}", note);
+                }
+                for frame in err.trace.iter().map(Frame::from) {
+                    eprintln!(
+                        "  at {} ({}:{}:{})",
+                        frame.function, frame.file, frame.line, frame.column
+                    );
+                    match (frame.repeated, frame.cycle) {
+                        (0, _) => {}
+                        (repeated, 1) => eprintln!("  ... repeated {} more times", repeated),
+                        (repeated, cycle) => eprintln!(
+                            "  ... last {} calls repeated {} more times",
+                            cycle, repeated
+                        ),
+                    }
+                }
+            }
+            ErrorFormat::Json => {
+                let diagnostic = Diagnostic::new(err, file);
+                match serde_json::to_string(&diagnostic) {
+                    Ok(json) => eprintln!("{}", json),
+                    Err(err) => panic!("Could not serialize diagnostic: {}", err),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::Diagnostic;
+    use crate::{
+        error::{CompilationError, RoxError, RoxErrorKind},
+        location::{Location, Span},
+    };
+
+    #[test]
+    fn json_diagnostic_has_stable_fields() {
+        let error = RoxError::new(
+            RoxErrorKind::CompilationError(CompilationError::MissingClosingParenthesis),
+            Span::new(Location::from((7, 1, 2)), Location::from((8, 1, 3))),
+        )
+        .with_note("Unclosed parenthesis opened at line 1".into());
+
+        let value = serde_json::to_value(Diagnostic::new(&error, "script.lox")).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "code": "E0104",
+                "message": "Missing closing parenthesis",
+                "severity": "error",
+                "file": "script.lox",
+                "start": { "line": 2, "column": 3 },
+                "end": { "line": 2, "column": 4 },
+                "notes": ["Unclosed parenthesis opened at line 1"],
+                "trace": [],
+            })
+        );
+    }
+}
@@ -1,6 +1,8 @@
-use std::{fmt::Display, usize};
+use std::fmt::Display;
 use thiserror::Error;
 
+use crate::{lints::WarningKind, location::Span};
+
 #[derive(Error, Debug)]
 pub enum CompilationError {
     #[error("Invalid lexeme \"{0}\"")]
@@ -20,16 +22,333 @@ pub enum CompilationError {
 
     #[error("Missing expression")]
     MissingExpression,
+
+    #[error("Missing ';' after {0}")]
+    MissingSemicolon(String),
+
+    #[error("Missing '(' after '{0}'")]
+    MissingOpeningParenthesis(String),
+
+    #[error("Missing '}}' after block")]
+    MissingClosingBrace,
+
+    #[error("Missing variable name")]
+    MissingVariableName,
+
+    #[error("Invalid assignment target")]
+    InvalidAssignmentTarget,
+
+    #[error("Variable \"{0}\" is already declared in this scope")]
+    VariableAlreadyDeclared(String),
+
+    #[error("Can not read local variable \"{0}\" in its own initializer")]
+    ReadLocalInOwnInitializer(String),
+
+    #[error("Too many local variables in scope, limit is {0}")]
+    TooManyLocals(u64),
+
+    #[error("Too much code to jump over, limit is {0} instructions")]
+    JumpTooLarge(u64),
+
+    #[error("Missing function name")]
+    MissingFunctionName,
+
+    #[error("Missing parameter name")]
+    MissingParameterName,
+
+    #[error("Missing '{{' before function body")]
+    MissingOpeningBrace,
+
+    #[error("Too many parameters, limit is {0}")]
+    TooManyParameters(u64),
+
+    #[error("Too many arguments, limit is {0}")]
+    TooManyArguments(u64),
+
+    #[error("Can not return from top-level code")]
+    ReturnFromTopLevel,
+
+    #[error("Invalid escape sequence '{0}'")]
+    InvalidEscape(String),
+
+    #[error(
+        "Invalid unicode escape '{0}', expected 1 to 6 hex digits of a code point in '\\u{{...}}'"
+    )]
+    InvalidUnicodeEscape(String),
+
+    #[error("Block comment is not terminated")]
+    UnterminatedBlockComment,
+
+    #[error("Missing property name after '.'")]
+    MissingPropertyName,
+
+    #[error("Could not read the code: {0}")]
+    UnreadableCode(String),
+
+    #[error("Code is nested too deeply, limit is {0} levels")]
+    TooDeeplyNested(u64),
+}
+
+impl CompilationError {
+    /// Stable identifier of the error, meant to be consumed by tools.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompilationError::InvalidLexeme(_) => "E0101",
+            CompilationError::InvalidNumberLiteral(_) => "E0102",
+            CompilationError::UnterminatedString => "E0103",
+            CompilationError::MissingClosingParenthesis => "E0104",
+            CompilationError::TooManyConstants(_) => "E0105",
+            CompilationError::MissingExpression => "E0106",
+            CompilationError::MissingSemicolon(_) => "E0107",
+            CompilationError::MissingOpeningParenthesis(_) => "E0108",
+            CompilationError::MissingClosingBrace => "E0109",
+            CompilationError::MissingVariableName => "E0110",
+            CompilationError::InvalidAssignmentTarget => "E0111",
+            CompilationError::VariableAlreadyDeclared(_) => "E0112",
+            CompilationError::ReadLocalInOwnInitializer(_) => "E0113",
+            CompilationError::TooManyLocals(_) => "E0114",
+            CompilationError::JumpTooLarge(_) => "E0115",
+            CompilationError::MissingFunctionName => "E0116",
+            CompilationError::MissingParameterName => "E0117",
+            CompilationError::MissingOpeningBrace => "E0118",
+            CompilationError::TooManyParameters(_) => "E0119",
+            CompilationError::TooManyArguments(_) => "E0120",
+            CompilationError::ReturnFromTopLevel => "E0121",
+            CompilationError::InvalidEscape(_) => "E0122",
+            CompilationError::InvalidUnicodeEscape(_) => "E0123",
+            CompilationError::UnterminatedBlockComment => "E0124",
+            CompilationError::MissingPropertyName => "E0125",
+            CompilationError::UnreadableCode(_) => "E0126",
+            CompilationError::TooDeeplyNested(_) => "E0127",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CompilationWarning {
+    #[error("Local variable \"{0}\" is never used")]
+    UnusedVariable(String),
+
+    #[error("Unreachable code")]
+    UnreachableCode,
+
+    #[error("Local variable \"{0}\" shadows a variable from an enclosing scope")]
+    ShadowedVariable(String),
+
+    #[error("Comparison of \"{0}\" with itself")]
+    SelfComparison(String),
+
+    #[error("Comparison between {left} and {right} with '{operator}' is always {result}")]
+    LiteralTypeComparison {
+        operator: &'static str,
+        left: &'static str,
+        right: &'static str,
+        result: bool,
+    },
+}
+
+impl CompilationWarning {
+    /// Stable identifier of the warning, meant to be consumed by tools.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompilationWarning::UnusedVariable(_) => "W0101",
+            CompilationWarning::UnreachableCode => "W0102",
+            CompilationWarning::ShadowedVariable(_) => "W0103",
+            CompilationWarning::SelfComparison(_) => "W0104",
+            CompilationWarning::LiteralTypeComparison { .. } => "W0105",
+        }
+    }
+
+    pub fn kind(&self) -> WarningKind {
+        match self {
+            CompilationWarning::UnusedVariable(_) => WarningKind::UnusedVariable,
+            CompilationWarning::UnreachableCode => WarningKind::UnreachableCode,
+            CompilationWarning::ShadowedVariable(_) => WarningKind::ShadowedVariable,
+            CompilationWarning::SelfComparison(_) => WarningKind::SelfComparison,
+            CompilationWarning::LiteralTypeComparison { .. } => WarningKind::LiteralTypeComparison,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum RuntimeError {
-    #[error("Missing operand for operation")]
-    MissingOperand,
-    #[error("Invalid operand type for operation")]
-    InvalidOperand,
+    #[error("Operand of '{operator}' must be a number, got {got}")]
+    InvalidOperand {
+        operator: &'static str,
+        got: &'static str,
+    },
+    #[error("Operands of '{operator}' must be {expected}, got {left} and {right}")]
+    InvalidOperands {
+        operator: &'static str,
+        expected: &'static str,
+        left: &'static str,
+        right: &'static str,
+    },
     #[error("Invalid constant address")]
     InvalidConstantAddress,
+    #[error("Undefined variable \"{0}\"")]
+    UndefinedVariable(String),
+    #[error("Can only call functions, got {0}")]
+    NotCallable(&'static str),
+    #[error("Expected {expected} arguments but got {got}")]
+    ArityMismatch { expected: usize, got: usize },
+    #[error("Call passes {expected} arguments but only {got} were given with 'param'")]
+    MissingParams { expected: usize, got: usize },
+    #[error("Instruction budget exhausted")]
+    BudgetExhausted,
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("Stack overflow")]
+    StackOverflow,
+    /// Raised by a native function.
+    #[error("{0}")]
+    Native(String),
+    #[error("Argument {position} must be {expected}, got {got}")]
+    InvalidArgument {
+        position: usize,
+        expected: String,
+        got: &'static str,
+    },
+    #[error("Only objects have properties, got {0}")]
+    NoProperties(&'static str),
+    #[error("Undefined property \"{name}\" of {class}")]
+    UndefinedProperty { class: &'static str, name: String },
+    #[error("Property \"{name}\" of {class} can not be assigned")]
+    ReadOnlyProperty { class: &'static str, name: String },
+    #[error("Property \"{name}\" must be {expected}, got {got}")]
+    InvalidPropertyValue {
+        name: String,
+        expected: String,
+        got: &'static str,
+    },
+}
+
+impl RuntimeError {
+    /// Stable identifier of the error, meant to be consumed by tools.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RuntimeError::InvalidOperand { .. } => "E0202",
+            RuntimeError::InvalidConstantAddress => "E0203",
+            RuntimeError::UndefinedVariable(_) => "E0204",
+            RuntimeError::NotCallable(_) => "E0205",
+            RuntimeError::ArityMismatch { .. } => "E0206",
+            RuntimeError::InvalidOperands { .. } => "E0207",
+            RuntimeError::MissingParams { .. } => "E0208",
+            RuntimeError::BudgetExhausted => "E0209",
+            RuntimeError::DeadlineExceeded => "E0210",
+            RuntimeError::Interrupted => "E0211",
+            RuntimeError::StackOverflow => "E0212",
+            RuntimeError::Native(_) => "E0213",
+            RuntimeError::InvalidArgument { .. } => "E0214",
+            RuntimeError::NoProperties(_) => "E0215",
+            RuntimeError::UndefinedProperty { .. } => "E0216",
+            RuntimeError::ReadOnlyProperty { .. } => "E0217",
+            RuntimeError::InvalidPropertyValue { .. } => "E0218",
+        }
+    }
+
+    /// Whether execution stopped before an instruction and can be resumed from there.
+    pub fn is_resumable(&self) -> bool {
+        matches!(
+            self,
+            RuntimeError::BudgetExhausted | RuntimeError::DeadlineExceeded
+        )
+    }
+}
+
+/// Reasons bytecode can not be run without checks, found by `verifier::verify`. The compiler
+/// never generates such code, so these are bugs rather than errors of scripts.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum VerificationError {
+    #[error("Instruction {0} jumps out of the code")]
+    JumpOutOfBounds(usize),
+    #[error("Instruction {0} reads a constant that does not exist")]
+    ConstantOutOfBounds(usize),
+    #[error("Instruction {0} names a global or property with a constant that is not a string")]
+    InvalidName(usize),
+    #[error("Instruction {0} uses more values than its frame has")]
+    StackUnderflow(usize),
+    #[error("Instruction {0} is reached with different stack depths")]
+    InconsistentDepth(usize),
+    #[error("Execution can continue past the last instruction")]
+    FallsOffEnd,
+}
+
+/// Errors found while parsing and validating a three-address code program.
+#[derive(Error, Debug)]
+pub enum TacError {
+    #[error("Invalid token \"{0}\"")]
+    InvalidToken(String),
+
+    #[error("Invalid number literal {0}")]
+    InvalidNumberLiteral(String),
+
+    #[error("String literal is not terminated")]
+    UnterminatedString,
+
+    #[error("Block comment is not terminated")]
+    UnterminatedBlockComment,
+
+    #[error("Invalid escape sequence '{0}'")]
+    InvalidEscape(String),
+
+    #[error("Expected {expected}, found {found}")]
+    Expected {
+        expected: &'static str,
+        found: String,
+    },
+
+    #[error("Invalid label \"{0}\", labels are written as 'L' followed by a number")]
+    InvalidLabel(String),
+
+    #[error("Label {0} is already defined")]
+    DuplicateLabel(String),
+
+    #[error("Label {0} is not defined")]
+    UndefinedLabel(String),
+
+    #[error("Function \"{0}\" is already defined")]
+    DuplicateFunction(String),
+
+    #[error("Function \"{0}\" is not defined")]
+    UndefinedFunction(String),
+
+    #[error("Parameter \"{0}\" is already declared")]
+    DuplicateParameter(String),
+
+    #[error("Functions can not be declared inside other functions")]
+    NestedFunction,
+
+    #[error("Function \"{0}\" is missing 'endfunc'")]
+    MissingEndfunc(String),
+
+    #[error("'endfunc' outside of a function")]
+    UnexpectedEndfunc,
+}
+
+impl TacError {
+    /// Stable identifier of the error, meant to be consumed by tools.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TacError::InvalidToken(_) => "E0301",
+            TacError::InvalidNumberLiteral(_) => "E0302",
+            TacError::UnterminatedString => "E0303",
+            TacError::UnterminatedBlockComment => "E0304",
+            TacError::InvalidEscape(_) => "E0305",
+            TacError::Expected { .. } => "E0306",
+            TacError::InvalidLabel(_) => "E0307",
+            TacError::DuplicateLabel(_) => "E0308",
+            TacError::UndefinedLabel(_) => "E0309",
+            TacError::DuplicateFunction(_) => "E0310",
+            TacError::UndefinedFunction(_) => "E0311",
+            TacError::DuplicateParameter(_) => "E0312",
+            TacError::NestedFunction => "E0313",
+            TacError::MissingEndfunc(_) => "E0314",
+            TacError::UnexpectedEndfunc => "E0315",
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -38,18 +357,132 @@ pub enum RoxErrorKind {
     CompilationError(#[from] CompilationError),
     #[error("{0}")]
     RuntimeError(#[from] RuntimeError),
+    #[error("{0}")]
+    CompilationWarning(#[from] CompilationWarning),
+    #[error("{0}")]
+    TacError(#[from] TacError),
+}
+
+impl RoxErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RoxErrorKind::CompilationError(err) => err.code(),
+            RoxErrorKind::RuntimeError(err) => err.code(),
+            RoxErrorKind::CompilationWarning(warning) => warning.code(),
+            RoxErrorKind::TacError(err) => err.code(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// Most calls in a cycle that a trace collapses when it is repeated.
+const MAX_TRACE_CYCLE: usize = 16;
+
+/// Function call that was active when a runtime error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub function: String,
+    pub file: String,
+    pub span: Span,
+    /// Number of times the calls of the cycle ending with this one are repeated right after it
+    /// in the trace, collapsed into it.
+    pub repeated: usize,
+    /// Number of calls in the repeated cycle, 1 for a function calling itself.
+    pub cycle: usize,
+}
+
+impl TraceFrame {
+    fn same_call(&self, other: &TraceFrame) -> bool {
+        self.function == other.function && self.file == other.file && self.span == other.span
+    }
 }
 
 #[derive(Error, Debug)]
 pub struct RoxError {
     #[source]
     pub src: RoxErrorKind,
-    pub line: usize,
+    /// Warnings are reported with `Severity::Error` when they are denied.
+    pub severity: Severity,
+    pub span: Span,
+    pub notes: Vec<String>,
+    /// Active calls when the error happened, innermost first. Empty for compilation errors.
+    pub trace: Vec<TraceFrame>,
 }
 
 impl RoxError {
-    pub fn new(src: RoxErrorKind, line: usize) -> Self {
-        Self { src, line }
+    pub fn new(src: RoxErrorKind, span: Span) -> Self {
+        Self {
+            src,
+            severity: Severity::Error,
+            span,
+            notes: Vec::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    /// Sets the active calls, collapsing repeated cycles of the same calls made from the same
+    /// places, as recursion can make thousands of them.
+    pub fn with_trace(mut self, trace: Vec<TraceFrame>) -> Self {
+        self.trace = Vec::with_capacity(trace.len());
+        let mut start = 0;
+        while start < trace.len() {
+            // The cycle collapsing the most calls is kept, the shortest one when tied.
+            let (cycle, repeats) = (1..=MAX_TRACE_CYCLE)
+                .map(|cycle| {
+                    let same = trace
+                        .get(start + cycle..)
+                        .unwrap_or_default()
+                        .iter()
+                        .zip(&trace[start..])
+                        .take_while(|(frame, earlier)| frame.same_call(earlier))
+                        .count();
+                    (cycle, same / cycle)
+                })
+                .fold((1, 0), |best, (cycle, repeats)| {
+                    if cycle * repeats > best.0 * best.1 {
+                        (cycle, repeats)
+                    } else {
+                        best
+                    }
+                });
+
+            self.trace
+                .extend(trace[start..start + cycle].iter().cloned());
+            let last = self.trace.last_mut().expect("Cycles have a call");
+            last.repeated = repeats;
+            last.cycle = cycle;
+            start += cycle * (repeats + 1);
+        }
+        self
+    }
+
+    #[inline]
+    pub fn line(&self) -> usize {
+        self.span.start.line()
     }
 }
 
@@ -1,15 +1,24 @@
 use std::{
-    any::{type_name, Any},
+    any::{type_name, Any, TypeId},
     collections::HashMap,
     fmt::{self, Debug},
+    hash::{Hash, Hasher},
     marker::PhantomData,
     mem,
 };
 
+use crate::userdata::{Class, Handle, Instance, UserData};
+
+/// Number of bytes allocated before the first collection.
+const INITIAL_COLLECTION: usize = 1024 * 1024;
+
 pub trait Object {
     fn size(&self) -> usize;
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    /// Marks the objects this one refers to, which are freed by the collector otherwise.
+    fn trace(&self, _tracer: &mut Tracer) {}
 }
 
 pub struct Allocation {
@@ -19,9 +28,31 @@ pub struct Allocation {
 
 pub struct Heap {
     bytes_allocated: usize,
+    /// Number of bytes allocated after which the next collection is due.
+    next_collection: usize,
     objects: Vec<Option<Allocation>>,
     free_slots: Vec<usize>,
     strings: HashMap<String, Ref<String>>,
+    /// Class of each `UserData` type, kept as long as the heap.
+    classes: HashMap<TypeId, Ref<Class>>,
+}
+
+/// Finds the objects reachable from the roots of a collection, each object marked being traced
+/// in turn.
+pub struct Tracer {
+    marked: Vec<bool>,
+    /// Objects marked but not traced yet.
+    pending: Vec<usize>,
+}
+
+impl Tracer {
+    /// Keeps the object `reference` refers to, along with those it refers to.
+    pub fn mark<T: Object>(&mut self, reference: Ref<T>) {
+        if !self.marked[reference.index] {
+            self.marked[reference.index] = true;
+            self.pending.push(reference.index);
+        }
+    }
 }
 
 pub struct Ref<T: Object> {
@@ -38,6 +69,20 @@ impl<T: Object> Clone for Ref<T> {
     }
 }
 
+impl<T: Object> PartialEq for Ref<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T: Object> Eq for Ref<T> {}
+
+impl<T: Object> Hash for Ref<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
 impl<T: Object> Debug for Ref<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let full_name = type_name::<T>();
@@ -46,16 +91,77 @@ impl<T: Object> Debug for Ref<T> {
     }
 }
 
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Heap {
     pub fn new() -> Self {
         Self {
             bytes_allocated: 0,
+            next_collection: INITIAL_COLLECTION,
             objects: Vec::new(),
             free_slots: Vec::new(),
             strings: HashMap::new(),
+            classes: HashMap::new(),
+        }
+    }
+
+    /// Number of bytes the objects were estimated to take when allocated.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Whether enough was allocated since the last collection for another one to be due.
+    pub(crate) fn should_collect(&self) -> bool {
+        self.bytes_allocated > self.next_collection
+    }
+
+    /// Starts a collection, the roots being marked with the tracer before it is given back to
+    /// `collect`. No object may be allocated in between.
+    pub(crate) fn tracer(&self) -> Tracer {
+        Tracer {
+            marked: vec![false; self.objects.len()],
+            pending: Vec::new(),
         }
     }
 
+    /// Frees the objects that are not reachable from the roots marked with `tracer` nor from
+    /// the classes, strings no longer being interned once freed. The next collection is due
+    /// once the heap doubled.
+    pub(crate) fn collect(&mut self, mut tracer: Tracer) {
+        for class in self.classes.values() {
+            tracer.mark(*class);
+        }
+        while let Some(index) = tracer.pending.pop() {
+            if let Some(allocation) = &self.objects[index] {
+                allocation.obj.trace(&mut tracer);
+            }
+        }
+
+        for (index, marked) in tracer.marked.into_iter().enumerate() {
+            if marked {
+                continue;
+            }
+            let allocation = match self.objects[index].take() {
+                Some(allocation) => allocation,
+                None => continue,
+            };
+
+            if let Some(string) = allocation.obj.as_any().downcast_ref::<String>() {
+                if self.strings.get(string).map(|interned| interned.index) == Some(index) {
+                    self.strings.remove(string);
+                }
+            }
+            self.bytes_allocated -= allocation.size;
+            self.free_slots.push(index);
+        }
+
+        self.next_collection = INITIAL_COLLECTION.max(self.bytes_allocated * 2);
+    }
+
     pub fn alloc<T: Object + 'static + Debug>(&mut self, object: T) -> Ref<T> {
         let size = object.size() + mem::size_of::<Allocation>();
         self.bytes_allocated += size;
@@ -91,6 +197,43 @@ impl Heap {
         }
     }
 
+    /// The string equal to `string`, if one was allocated.
+    pub fn find_string(&self, string: &str) -> Option<Ref<String>> {
+        self.strings.get(string).copied()
+    }
+
+    /// Allocates an object holding `data`, which scripts see as an instance of the class
+    /// registered for `T`. Instances of types without one have no properties.
+    pub fn alloc_instance<T: UserData>(&mut self, data: T) -> Handle<T> {
+        let class = self.class::<T>();
+        Handle::new(self.alloc(Instance::new(class, data)))
+    }
+
+    /// Class of the instances of `T`, made empty if it was not registered.
+    pub(crate) fn class<T: UserData>(&mut self) -> Ref<Class> {
+        match self.classes.get(&TypeId::of::<T>()) {
+            Some(class) => *class,
+            None => {
+                let class = self.alloc(Class::new(T::NAME));
+                self.classes.insert(TypeId::of::<T>(), class);
+                class
+            }
+        }
+    }
+
+    /// Value held by the instance `handle` refers to.
+    pub fn get<T: UserData>(&self, handle: Handle<T>) -> &T {
+        self.deref(handle.instance())
+            .downcast_ref()
+            .expect("Handles refer to instances of their type")
+    }
+
+    pub fn get_mut<T: UserData>(&mut self, handle: Handle<T>) -> &mut T {
+        self.deref_mut(handle.instance())
+            .downcast_mut()
+            .expect("Handles refer to instances of their type")
+    }
+
     pub fn deref<T: Object + 'static>(&self, reference: Ref<T>) -> &T {
         self.objects[reference.index]
             .as_ref()
@@ -0,0 +1,164 @@
+//! Lox interpreter that can be embedded in Rust programs.
+//!
+//! A [`Vm`] compiles and runs scripts, keeping their globals between runs. The host can read
+//! and write those globals, call script functions and give scripts functions implemented in
+//! Rust:
+//!
+//! ```
+//! use rox::{FromValue, Value, Vm};
+//!
+//! let mut vm = Vm::new();
+//! vm.register_fn("half", |_, (n,): (f64,)| Ok(n / 2.0));
+//! vm.interpret("fun quarter(n) { return half(half(n)); }", "example.lox").unwrap();
+//!
+//! let quarter = vm.get_global("quarter").unwrap();
+//! let result = vm.call(quarter, &[Value::Number(10.0)]).unwrap();
+//! assert_eq!(f64::from_value(result, vm.heap()), Some(2.5));
+//! ```
+//!
+//! Values are converted from and to Rust types with [`FromValue`] and [`IntoValue`], which is
+//! also how natives registered with [`Vm::register_fn`] get their arguments, while
+//! [`Vm::register_native`] gives them as they are.
+//!
+//! Rust types implementing [`UserData`] can be given to scripts as objects, whose methods and
+//! properties are registered with a [`ClassBuilder`]:
+//!
+//! ```
+//! use rox::{ClassBuilder, FromValue, Handle, UserData, Vm};
+//!
+//! struct Counter {
+//!     count: u32,
+//! }
+//!
+//! impl UserData for Counter {
+//!     const NAME: &'static str = "Counter";
+//! }
+//!
+//! let mut vm = Vm::new();
+//! vm.register_class(
+//!     ClassBuilder::<Counter>::new()
+//!         .getter("count", |heap, this| Ok(heap.get(this).count))
+//!         .method("add", |heap, this, (n,): (u32,)| {
+//!             heap.get_mut(this).count += n;
+//!             Ok(())
+//!         }),
+//! );
+//! vm.register_fn("counter", |heap, ()| Ok(heap.alloc_instance(Counter { count: 0 })));
+//! vm.interpret("var c = counter(); c.add(2); c.add(3);", "example.lox").unwrap();
+//!
+//! let counter = vm.get_global("c").unwrap();
+//! let counter = Handle::<Counter>::from_value(counter, vm.heap()).unwrap();
+//! assert_eq!(vm.heap().get(counter).count, 5);
+//! ```
+//!
+//! Objects no longer reachable from the globals or the running code are freed by a garbage
+//! collector, so values the host keeps between runs should be stored in globals.
+//!
+//! Tools such as formatters can scan scripts with [`Scanner::next_lossless_token`], which keeps
+//! the whitespace and comments around each token as [`Trivia`] so the code can be rebuilt
+//! exactly:
+//!
+//! ```
+//! use rox::{Scanner, TokenKind};
+//!
+//! let code = "print 1; // one\n";
+//! let mut scanner = Scanner::new(code);
+//! let mut rebuilt = String::new();
+//! loop {
+//!     let token = scanner.next_lossless_token();
+//!     rebuilt += &token.to_string();
+//!     if token.token.kind() == TokenKind::Eof {
+//!         break;
+//!     }
+//! }
+//! assert_eq!(rebuilt, code);
+//! ```
+//!
+//! The analyses of the optimizer's [`ssa`] form, liveness, reaching definitions and dominators,
+//! can be computed on the bytecode of any compiled function:
+//!
+//! ```
+//! use rox::{ssa, Value, Vm};
+//!
+//! let mut vm = Vm::new();
+//! let script = match vm.compile("var a = 1; if (a > 0) print a;", "example.lox") {
+//!     Ok(Value::Function(script)) => vm.heap().deref(script),
+//!     _ => unreachable!(),
+//! };
+//! let function = ssa::lift(script.chunk(), script.arity()).unwrap();
+//!
+//! let dominators = ssa::Dominators::compute(&function);
+//! let exit = function.blocks.len() - 1;
+//! assert!(function.blocks.len() > 1 && dominators.dominates(0, exit));
+//! assert!(ssa::Liveness::compute(&function).live_in[0].is_empty());
+//! ```
+//!
+//! Scripts can also be lowered to [`tac`], three-address code that can be printed, parsed back
+//! and run on its own:
+//!
+//! ```
+//! use rox::{tac, Lints};
+//!
+//! let program = tac::compile("print 1 + 2;", "example.lox", &Lints::new(), &mut Vec::new());
+//! let code = program.unwrap().to_string();
+//! assert_eq!(code, "    t1 = 1 + 2\n    print t1\n");
+//!
+//! let program = tac::parse(&code).unwrap();
+//! tac::Interpreter::new(&program, "example.tac").run().unwrap();
+//! ```
+//!
+//! Every instruction is traced to the standard output unless the default
+//! `debug_trace_execution` feature is disabled.
+
+// Errors are only built on the failure path and carry spans, notes and stack traces, so their
+// size is not a concern.
+#![allow(clippy::result_large_err)]
+
+mod ast;
+/// Control-flow graphs of bytecode, which can be written in Graphviz format.
+pub mod cfg;
+mod chunk;
+mod compiler;
+mod convert;
+mod debug;
+mod diagnostics;
+mod error;
+mod heap;
+mod lints;
+mod location;
+mod objects;
+mod optimizer;
+mod parser;
+mod register;
+mod scanner;
+/// Static single assignment form of bytecode, with the analyses and passes the optimizer runs at
+/// `-O2`.
+pub mod ssa;
+/// Three-address code, the intermediate representation taught in compiler courses, with lowering
+/// from scripts, a parser and an interpreter.
+pub mod tac;
+mod userdata;
+mod verifier;
+mod vm;
+
+pub use crate::{
+    chunk::{Chunk, Instruction, Value},
+    convert::{FromArgs, FromValue, IntoValue},
+    diagnostics::{report, ErrorFormat},
+    error::{
+        CompilationError, CompilationWarning, RoxError, RoxErrorKind, RuntimeError, Severity,
+        TacError, TraceFrame, VerificationError,
+    },
+    heap::{Heap, Object, Ref, Tracer},
+    lints::{LintLevel, Lints, WarningKind},
+    location::{Location, Span},
+    objects::{functions, Function, List, Native, NativeFn},
+    optimizer::OptLevel,
+    scanner::{
+        scanner::TokenIter,
+        token::{LosslessToken, TokenErrorKind, Trivia, TriviaKind},
+        OwnedToken, Scanner, StreamScanner, Token, TokenKind, TokenSource,
+    },
+    userdata::{BoundMethod, Class, ClassBuilder, Handle, Instance, UserData},
+    vm::{Mode, Vm, DEFAULT_MAX_FRAMES, DEFAULT_MAX_STACK},
+};
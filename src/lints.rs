@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+/// Kinds of warnings reported by the compiler, each one can be configured independently.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    /// Local variable that is never read.
+    UnusedVariable,
+    /// Statement that can never be executed, e.g. after a `return`.
+    UnreachableCode,
+    /// Local variable with the same name as one from an enclosing scope.
+    ShadowedVariable,
+    /// Comparison of a value with itself, e.g. `x == x`.
+    SelfComparison,
+    /// Equality comparison between literals of different types, e.g. `1 != "1"`.
+    LiteralTypeComparison,
+}
+
+impl WarningKind {
+    /// Name of the warning as accepted on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::UnusedVariable => "unused-variable",
+            WarningKind::UnreachableCode => "unreachable-code",
+            WarningKind::ShadowedVariable => "shadowed-variable",
+            WarningKind::SelfComparison => "self-comparison",
+            WarningKind::LiteralTypeComparison => "literal-type-comparison",
+        }
+    }
+}
+
+/// What to do when the compiler finds a given kind of warning.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LintLevel {
+    /// Ignore it.
+    Allow,
+    /// Report it, compilation still succeeds.
+    Warn,
+    /// Report it as an error, failing compilation.
+    Deny,
+}
+
+/// Level of every kind of warning, those not explicitly configured are reported as warnings.
+#[derive(Clone, Debug, Default)]
+pub struct Lints {
+    levels: HashMap<WarningKind, LintLevel>,
+}
+
+impl Lints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, kind: WarningKind, level: LintLevel) {
+        self.levels.insert(kind, level);
+    }
+
+    pub fn level(&self, kind: WarningKind) -> LintLevel {
+        match self.levels.get(&kind) {
+            Some(level) => *level,
+            None => LintLevel::Warn,
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use std::fmt::{Display, Formatter};
 
+// Positions are stored as `u32` to keep spans, which are attached to every instruction and
+// error, small.
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct Location {
-    offset: usize,
-    position: (usize, usize),
+    offset: u32,
+    position: (u32, u32),
 }
 
 impl Location {
@@ -16,9 +18,13 @@ impl Location {
         self.position = (self.position.0.saturating_add(1), 0);
     }
 
+    /// Moves past the byte `c`. Offsets count bytes while columns count characters, so the
+    /// continuation bytes of a UTF-8 sequence do not move the column.
     pub fn advance(&mut self, c: u8) {
         self.offset = self.offset.saturating_add(1);
-        self.position = (self.position.0, self.position.1.saturating_add(1));
+        if !is_utf8_continuation(c) {
+            self.position = (self.position.0, self.position.1.saturating_add(1));
+        }
         if c == b'\n' {
             self.ln();
         }
@@ -26,15 +32,25 @@ impl Location {
 
     #[inline]
     pub fn offset(&self) -> usize {
-        self.offset
+        self.offset as usize
     }
 
     #[inline]
     pub fn line(&self) -> usize {
-        self.position.0
+        self.position.0 as usize
+    }
+
+    #[inline]
+    pub fn column(&self) -> usize {
+        self.position.1 as usize
     }
 }
 
+#[inline]
+fn is_utf8_continuation(c: u8) -> bool {
+    c & 0b1100_0000 == 0b1000_0000
+}
+
 impl Display for Location {
     fn fmt(&self, fmt: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         if *self == Location::EOF {
@@ -48,8 +64,21 @@ impl Display for Location {
 impl From<(usize, usize, usize)> for Location {
     fn from((offset, line, col): (usize, usize, usize)) -> Self {
         Self {
-            offset,
-            position: (line, col),
+            offset: offset as u32,
+            position: (line as u32, col as u32),
         }
     }
 }
+
+/// Region of source code delimited by two locations, `end` being exclusive.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub start: Location,
+    pub end: Location,
+}
+
+impl Span {
+    pub fn new(start: Location, end: Location) -> Self {
+        Self { start, end }
+    }
+}
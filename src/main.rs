@@ -1,24 +1,60 @@
+use std::time::{Duration, Instant};
+
 use clap::Clap;
-use opts::Opts;
+use opts::{Command, Opts};
+use rox::Vm;
 
-mod chunk;
-mod compiler;
-mod debug;
-mod error;
-mod heap;
-mod location;
-mod objects;
 mod opts;
 mod repl;
 mod runner;
-mod scanner;
-mod vm;
 
 fn main() {
     let opts: Opts = Opts::parse();
+    let format = rox::ErrorFormat::from(opts.error_format);
+
+    let mut vm = Vm::new();
+    vm.set_lints(opts.lints());
+    vm.set_opt_level(opts.opt_level);
+    vm.set_mode(opts.mode.into());
+    vm.set_fuel(opts.fuel);
+    if let Some(max_frames) = opts.max_frames {
+        vm.set_max_frames(max_frames);
+    }
+    if let Some(max_stack) = opts.max_stack {
+        vm.set_max_stack(max_stack);
+    }
+
+    match &opts.command {
+        Some(Command::Tac(tac)) => {
+            return runner::run_tac(&tac.file, tac.env, format);
+        }
+        Some(Command::Cfg(cfg)) => {
+            let output = cfg.output.clone().unwrap_or_else(|| {
+                let path = std::path::Path::new(&cfg.script).with_extension("dot");
+                path.to_string_lossy().into_owned()
+            });
+            return runner::write_cfg(&mut vm, &cfg.script, &output, format);
+        }
+        Some(Command::Bench(bench)) => {
+            return runner::bench(
+                &bench.scripts,
+                bench.runs,
+                &opts.lints(),
+                opts.opt_level,
+                format,
+            );
+        }
+        None => {}
+    }
 
-    match opts.script {
-        Some(path) => runner::eval_file(&path),
-        None => repl::repl().unwrap(),
+    match &opts.script {
+        Some(path) if opts.emit_tac => runner::emit_tac(path, &opts.lints(), format),
+        Some(path) if opts.emit_ssa => runner::emit_ssa(&mut vm, path, opts.opt_level, format),
+        Some(path) => {
+            let timeout = opts.timeout.map(Duration::from_millis);
+            vm.set_deadline(timeout.map(|timeout| Instant::now() + timeout));
+            runner::eval_file(&mut vm, path, format)
+        }
+        None => repl::repl(vm, format).unwrap(),
     }
 }
@@ -1,6 +1,16 @@
-use std::{any::Any, mem};
+use std::{
+    any::Any,
+    fmt::{self, Debug},
+    mem,
+    rc::Rc,
+};
 
-use crate::heap::Object;
+use crate::{
+    chunk::{Chunk, Instruction, Value},
+    error::RuntimeError,
+    heap::{Heap, Object, Ref, Tracer},
+    register,
+};
 
 impl Object for String {
     fn size(&self) -> usize {
@@ -15,3 +25,160 @@ impl Object for String {
         self
     }
 }
+
+/// Function declared in a script, or the script itself.
+///
+/// Its code is only changed by the compiler, as the virtual machine relies on it being valid.
+#[derive(Debug)]
+pub struct Function {
+    pub(crate) arity: usize,
+    pub(crate) chunk: Chunk,
+    /// Name of the function, `None` for the top-level script.
+    pub(crate) name: Option<Ref<String>>,
+    /// Name of the file the function was compiled from.
+    pub(crate) file: Ref<String>,
+    /// Translation of `chunk` run by the register machine, made before the function is run in
+    /// that mode.
+    pub(crate) registers: Option<register::Chunk>,
+    /// Most values the function has on the stack at once, counting from the function itself,
+    /// known once `chunk` was checked by `verifier::verify` so it can be run without checks.
+    pub(crate) max_depth: Option<usize>,
+}
+
+impl Function {
+    pub(crate) fn new(name: Option<Ref<String>>, file: Ref<String>) -> Self {
+        Self {
+            arity: 0,
+            chunk: Chunk::new(),
+            name,
+            file,
+            registers: None,
+            max_depth: None,
+        }
+    }
+
+    /// Number of arguments the function takes.
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Bytecode of the function.
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+}
+
+/// The script along with the functions declared in it, in the order they are found.
+pub fn functions(heap: &Heap, script: Ref<Function>) -> Vec<Ref<Function>> {
+    let mut functions = vec![script];
+    let mut index = 0;
+    while index < functions.len() {
+        for constant in heap.deref(functions[index]).chunk.constants.iter() {
+            if let Value::Function(function) = constant {
+                functions.push(*function);
+            }
+        }
+        index += 1;
+    }
+
+    functions
+}
+
+impl Object for Function {
+    fn size(&self) -> usize {
+        mem::size_of::<Function>()
+            + self.chunk.code.capacity() * mem::size_of::<Instruction>()
+            + self.chunk.constants.capacity() * mem::size_of::<Value>()
+            + self.registers.as_ref().map_or(0, |registers| {
+                registers.code.capacity() * mem::size_of::<register::Instruction>()
+            })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(name) = self.name {
+            tracer.mark(name);
+        }
+        tracer.mark(self.file);
+        for constant in self.chunk.constants.iter() {
+            constant.trace(tracer);
+        }
+    }
+}
+
+/// Function implemented in Rust, given the heap the values it is called with live in.
+pub type NativeFn = dyn Fn(&mut Heap, &[Value]) -> Result<Value, RuntimeError>;
+
+/// Function implemented by the host, which scripts call like their own.
+pub struct Native {
+    pub(crate) name: Ref<String>,
+    pub(crate) arity: usize,
+    pub(crate) function: Rc<NativeFn>,
+}
+
+impl Native {
+    /// Number of arguments the function takes.
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Native")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+impl Object for Native {
+    fn size(&self) -> usize {
+        mem::size_of::<Native>()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.name);
+    }
+}
+
+/// Values given to scripts as one, which scripts can pass around but not look into.
+#[derive(Debug, Default)]
+pub struct List {
+    pub items: Vec<Value>,
+}
+
+impl Object for List {
+    fn size(&self) -> usize {
+        mem::size_of::<List>() + self.items.capacity() * mem::size_of::<Value>()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        for item in self.items.iter() {
+            item.trace(tracer);
+        }
+    }
+}
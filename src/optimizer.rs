@@ -0,0 +1,431 @@
+use std::{cmp::Ordering, collections::HashSet, convert::TryFrom, str::FromStr};
+
+use crate::{
+    chunk::{Chunk, Instruction, Value},
+    heap::Heap,
+    location::Span,
+};
+
+/// How much work the compiler puts into optimizing the code it generates.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// Run the code exactly as generated.
+    O0,
+    /// Fold constant expressions and rewrite instruction sequences into cheaper ones.
+    #[default]
+    O1,
+    /// Also propagate constants, eliminate common subexpressions and dead code in SSA form.
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            _ => Err(format!(
+                "Invalid optimization level {}, expected 0, 1 or 2",
+                level
+            )),
+        }
+    }
+}
+
+/// Instruction of the optimized code, jumps point at the index of their target in the original
+/// code until the chunk is rebuilt.
+struct Entry {
+    instruction: Instruction,
+    span: Span,
+    target: Option<usize>,
+    /// Whether some jump lands on this instruction, which must then be kept in place.
+    is_target: bool,
+}
+
+/// Folds constant expressions and applies peephole rewrites to a chunk.
+///
+/// Instructions are pushed one at a time and the end of the optimized code is reduced as long
+/// as it matches a pattern, so folds cascade, e.g. `-(1 + 2) * 3` becomes a single constant.
+/// Patterns never span a jump target, as the stack there depends on where the jump came from.
+pub fn optimize(chunk: &mut Chunk, heap: &mut Heap) {
+    let original = std::mem::take(&mut chunk.code);
+    let targets: HashSet<usize> = (0..original.len())
+        .filter_map(|index| jump_target(original[index], index))
+        .collect();
+
+    let mut code: Vec<Entry> = Vec::with_capacity(original.len());
+    let mut new_index = vec![0; original.len()];
+
+    for (index, instruction) in original.iter().enumerate() {
+        new_index[index] = code.len();
+        code.push(Entry {
+            instruction: *instruction,
+            span: chunk.spans[index],
+            target: jump_target(*instruction, index),
+            is_target: targets.contains(&index),
+        });
+
+        while reduce(&mut code, chunk, heap) {}
+    }
+
+    let mut optimized = Chunk::new();
+    optimized.constants = std::mem::take(&mut chunk.constants);
+
+    for (index, entry) in code.iter().enumerate() {
+        let instruction = match (entry.instruction, entry.target) {
+            (Instruction::Jump(_), Some(target)) => {
+                Instruction::Jump(forward(index, new_index[target]))
+            }
+            (Instruction::JumpIfFalse(_), Some(target)) => {
+                Instruction::JumpIfFalse(forward(index, new_index[target]))
+            }
+            (Instruction::Loop(_), Some(target)) => {
+                Instruction::Loop((index + 1 - new_index[target]) as u16)
+            }
+            (instruction, _) => instruction,
+        };
+        optimized.write(instruction, entry.span);
+    }
+
+    *chunk = optimized;
+}
+
+/// Rewrites the end of `code` if it matches a pattern, returning whether it did.
+fn reduce(code: &mut Vec<Entry>, chunk: &mut Chunk, heap: &mut Heap) -> bool {
+    let len = code.len();
+    let tail = |n: usize| -> Option<&[Entry]> {
+        let window = code.get(len.checked_sub(n)?..)?;
+        // Only the first instruction of a pattern can be a jump target.
+        if window[1..].iter().any(|entry| entry.is_target) {
+            None
+        } else {
+            Some(window)
+        }
+    };
+
+    // `!=`, `>=` and `<=` are emitted as the opposite comparison followed by a `Not` for the
+    // same operator.
+    if let Some([comparison, not]) = tail(2) {
+        let rewritten = match (comparison.instruction, not.instruction) {
+            (Instruction::Equal, Instruction::Not) => Some(Instruction::NotEqual),
+            (Instruction::Less, Instruction::Not) => Some(Instruction::GreaterEqual),
+            (Instruction::Greater, Instruction::Not) => Some(Instruction::LessEqual),
+            _ => None,
+        };
+
+        if let Some(instruction) = rewritten.filter(|_| comparison.span == not.span) {
+            code.pop();
+            code[len - 2].instruction = instruction;
+            return true;
+        }
+    }
+
+    if let Some([operand, operator]) = tail(2) {
+        let folded = literal(operand, chunk).and_then(|value| match operator.instruction {
+            Instruction::Negate => match value {
+                Value::Number(value) => Some(Value::Number(-value)),
+                _ => None,
+            },
+            Instruction::Not => Some(Value::Bool(value.is_falsey())),
+            _ => None,
+        });
+
+        if let Some(value) = folded {
+            return replace(code, 2, value, chunk);
+        }
+    }
+
+    if let Some([left, right, operator]) = tail(3) {
+        let folded = match (literal(left, chunk), literal(right, chunk)) {
+            (Some(left), Some(right)) => fold_binary(operator.instruction, left, right, heap),
+            _ => None,
+        };
+
+        if let Some(value) = folded {
+            return replace(code, 3, value, chunk);
+        }
+    }
+
+    // Sequences common in loops and assignments are fused into superinstructions, which are
+    // dispatched once. Errors are reported where the fused operator was.
+    if let Some([first, second]) = tail(2) {
+        let fused = match (first.instruction, second.instruction) {
+            (Instruction::Constant(index), Instruction::Add) => {
+                Some((Instruction::AddConstant(index), second.span))
+            }
+            (Instruction::Constant(index), Instruction::Subtract) => {
+                Some((Instruction::SubtractConstant(index), second.span))
+            }
+            (Instruction::SetLocal(slot), Instruction::Pop) => {
+                Some((Instruction::SetLocalPop(slot), first.span))
+            }
+            _ => None,
+        };
+
+        if let Some((instruction, span)) = fused {
+            code.pop();
+            code[len - 2].instruction = instruction;
+            code[len - 2].span = span;
+            return true;
+        }
+    }
+
+    if let Some([left, right, add]) = tail(3) {
+        if let (Instruction::GetLocal(a), Instruction::GetLocal(b), Instruction::Add) =
+            (left.instruction, right.instruction, add.instruction)
+        {
+            let (a, b) = match (u8::try_from(a), u8::try_from(b)) {
+                (Ok(a), Ok(b)) => (a, b),
+                _ => return false,
+            };
+            let span = add.span;
+            code.truncate(len - 2);
+            code[len - 3].instruction = Instruction::AddLocals(a, b);
+            code[len - 3].span = span;
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Result of a binary operator applied to two constants, `None` if it can not be computed at
+/// compile time, e.g. because it would be a runtime error.
+pub fn fold_binary(
+    operator: Instruction,
+    left: Value,
+    right: Value,
+    heap: &mut Heap,
+) -> Option<Value> {
+    let value = match (operator, left, right) {
+        (Instruction::Equal, a, b) => Value::Bool(a.equals(&b, heap)),
+        (Instruction::NotEqual, a, b) => Value::Bool(!a.equals(&b, heap)),
+        (Instruction::Add, Value::String(a), Value::String(b)) => {
+            let result = format!("{}{}", heap.deref(a), heap.deref(b));
+            Value::String(heap.alloc_string(result))
+        }
+        (operator, Value::Number(a), Value::Number(b)) => match operator {
+            Instruction::Add => Value::Number(a + b),
+            Instruction::Subtract => Value::Number(a - b),
+            Instruction::Multiply => Value::Number(a * b),
+            Instruction::Divide => Value::Number(a / b),
+            Instruction::Greater => Value::Bool(a > b),
+            Instruction::Less => Value::Bool(a < b),
+            // Same semantics as the `Not` they replace, including for NaN.
+            Instruction::GreaterEqual => Value::Bool(a.partial_cmp(&b) != Some(Ordering::Less)),
+            Instruction::LessEqual => Value::Bool(a.partial_cmp(&b) != Some(Ordering::Greater)),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+/// Value pushed by an instruction that loads a constant.
+fn literal(entry: &Entry, chunk: &Chunk) -> Option<Value> {
+    match entry.instruction {
+        Instruction::Constant(index) => match chunk.constants.get(index as usize) {
+            Some(value @ Value::Number(_)) | Some(value @ Value::String(_)) => Some(*value),
+            _ => None,
+        },
+        Instruction::True => Some(Value::Bool(true)),
+        Instruction::False => Some(Value::Bool(false)),
+        Instruction::Nil => Some(Value::Nil),
+        _ => None,
+    }
+}
+
+/// Replaces the last `n` instructions of `code` by one that loads `value`.
+fn replace(code: &mut Vec<Entry>, n: usize, value: Value, chunk: &mut Chunk) -> bool {
+    let instruction = match value {
+        Value::Bool(true) => Instruction::True,
+        Value::Bool(false) => Instruction::False,
+        Value::Nil => Instruction::Nil,
+        value => match chunk.add_constant(value) {
+            Ok(index) => Instruction::Constant(index),
+            Err(_) => return false,
+        },
+    };
+
+    let start = code.len() - n;
+    let span = code[start..]
+        .iter()
+        .map(|entry| entry.span)
+        .reduce(cover)
+        .expect("Replaced instructions are not empty");
+
+    code.truncate(start + 1);
+    code[start].instruction = instruction;
+    code[start].span = span;
+
+    true
+}
+
+/// Smallest span containing both `a` and `b`.
+fn cover(a: Span, b: Span) -> Span {
+    let start = if a.start.offset() <= b.start.offset() {
+        a.start
+    } else {
+        b.start
+    };
+    let end = if a.end.offset() >= b.end.offset() {
+        a.end
+    } else {
+        b.end
+    };
+
+    Span::new(start, end)
+}
+
+/// Index of the instruction a jump at `index` lands on.
+pub fn jump_target(instruction: Instruction, index: usize) -> Option<usize> {
+    match instruction {
+        Instruction::Jump(offset) | Instruction::JumpIfFalse(offset) => {
+            Some(index + 1 + offset as usize)
+        }
+        Instruction::Loop(offset) => Some(index + 1 - offset as usize),
+        _ => None,
+    }
+}
+
+/// Offset of a forward jump at `index` that lands on `target`.
+fn forward(index: usize, target: usize) -> u16 {
+    (target - index - 1) as u16
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::{Instruction, Value},
+        heap::{Heap, Ref},
+        lints::Lints,
+        objects::Function,
+    };
+
+    use super::OptLevel;
+
+    fn compile(code: &str, opt_level: OptLevel) -> (Heap, Ref<Function>) {
+        let mut heap = Heap::new();
+        let function = crate::compiler::compile(
+            code,
+            "test.lox",
+            &mut heap,
+            &Lints::new(),
+            opt_level,
+            &mut Vec::new(),
+        )
+        .expect("Program should compile");
+
+        (heap, function)
+    }
+
+    fn instructions(code: &str, opt_level: OptLevel) -> Vec<Instruction> {
+        let (heap, function) = compile(code, opt_level);
+        heap.deref(function).chunk.code.clone()
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        let (heap, function) = compile("print -(1 + 2) * 3;", OptLevel::O1);
+        let chunk = &heap.deref(function).chunk;
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                Instruction::Constant(5),
+                Instruction::Print,
+                Instruction::Nil,
+                Instruction::Return
+            ]
+        );
+        assert!(matches!(chunk.constants[5], Value::Number(n) if n == -9.0));
+
+        let (heap, function) = compile("print \"a\" + \"b\" == \"ab\";", OptLevel::O1);
+        assert_eq!(heap.deref(function).chunk.code[0], Instruction::True);
+    }
+
+    #[test]
+    fn keeps_runtime_errors_and_jump_targets() {
+        assert_eq!(
+            instructions("print 1 + nil;", OptLevel::O1)[..3],
+            [Instruction::Constant(0), Instruction::Nil, Instruction::Add]
+        );
+
+        // The `false` operand of `or` is a jump target, so it can not be folded with `!`.
+        let code = instructions("print !(nil or false);", OptLevel::O1);
+        assert!(code.contains(&Instruction::JumpIfFalse(1)));
+        assert!(code.contains(&Instruction::Not));
+    }
+
+    #[test]
+    fn rewrites_negated_comparisons() {
+        let code = instructions(
+            "var a = 1; print a != a; print a >= 2; print !(a < 2);",
+            OptLevel::O1,
+        );
+
+        assert!(code.contains(&Instruction::NotEqual));
+        assert!(code.contains(&Instruction::GreaterEqual));
+        // `!(a < 2)` is not a single operator, its errors must still mention `<`.
+        assert!(code.contains(&Instruction::Less));
+
+        let code = instructions("var a = 1; print a >= 2;", OptLevel::O0);
+        assert!(!code.contains(&Instruction::GreaterEqual));
+    }
+
+    #[test]
+    fn remaps_jumps_around_folded_code() {
+        let code = instructions(
+            "var i = 0; while (i < 2 * 3) { i = i + (1 + 1); } print i;",
+            OptLevel::O1,
+        );
+
+        // The condition is folded to `i < 6`, and the loop still jumps back to its start.
+        let loop_index = code
+            .iter()
+            .position(|inst| matches!(inst, Instruction::Loop(_)))
+            .expect("Loop should be kept");
+        let offset = match code[loop_index] {
+            Instruction::Loop(offset) => offset as usize,
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            code[loop_index + 1 - offset..loop_index + 4 - offset],
+            [
+                Instruction::GetGlobal(_),
+                Instruction::Constant(_),
+                Instruction::Less
+            ]
+        ));
+    }
+
+    #[test]
+    fn fuses_common_sequences() {
+        let code = instructions(
+            "{ var a = 1; var b = 2; a = a + b; print a + 1 - 2; }",
+            OptLevel::O1,
+        );
+
+        assert!(code.contains(&Instruction::AddLocals(1, 2)));
+        assert!(code.contains(&Instruction::SetLocalPop(1)));
+        assert!(matches!(
+            code[..],
+            [
+                ..,
+                Instruction::AddConstant(_),
+                Instruction::SubtractConstant(_),
+                Instruction::Print,
+                _,
+                _,
+                _,
+                _
+            ]
+        ));
+
+        let code = instructions("{ var a = 1; a = a + 1; }", OptLevel::O0);
+        assert!(!code.contains(&Instruction::SetLocalPop(1)));
+    }
+}
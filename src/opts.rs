@@ -1,9 +1,196 @@
-use clap::{AppSettings, Clap};
+use clap::{AppSettings, ArgEnum, Clap};
+
+use rox::{LintLevel, Lints, OptLevel};
 
 /// lox interpreter written in Rust
 #[derive(Clap)]
 #[clap(setting = AppSettings::ColoredHelp)]
 pub struct Opts {
-    /// File path for script to be run
+    /// File path for script to be run, `-` to read it from the standard input
     pub script: Option<String>,
+
+    /// Format used to report errors
+    #[clap(long, arg_enum, default_value = "human")]
+    pub error_format: ErrorFormat,
+
+    /// Warnings to ignore
+    #[clap(
+        short = 'A',
+        long,
+        arg_enum,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub allow: Vec<WarningKind>,
+
+    /// Warnings to report, which is the default for all of them
+    #[clap(
+        short = 'W',
+        long,
+        arg_enum,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub warn: Vec<WarningKind>,
+
+    /// Warnings to report as errors, failing compilation
+    #[clap(
+        short = 'D',
+        long,
+        arg_enum,
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    pub deny: Vec<WarningKind>,
+
+    /// Optimization level, 0 runs the code exactly as compiled
+    #[clap(short = 'O', default_value = "1", possible_values = &["0", "1", "2"])]
+    pub opt_level: OptLevel,
+
+    /// Instruction set the virtual machine runs
+    #[clap(long = "vm", arg_enum, default_value = "stack")]
+    pub mode: Mode,
+
+    /// Maximum number of instructions the script may run
+    #[clap(long, requires = "script")]
+    pub fuel: Option<u64>,
+
+    /// Maximum number of milliseconds the script may run for
+    #[clap(long, requires = "script")]
+    pub timeout: Option<u64>,
+
+    /// Maximum number of nested calls, 1024 by default
+    #[clap(long)]
+    pub max_frames: Option<usize>,
+
+    /// Maximum number of values on the stack, 262144 by default
+    #[clap(long)]
+    pub max_stack: Option<usize>,
+
+    /// Print the three-address code of the script instead of running it
+    #[clap(long, requires = "script")]
+    pub emit_tac: bool,
+
+    /// Print the SSA form of the script's functions instead of running it, optimized at -O2
+    #[clap(long, requires = "script", conflicts_with = "emit-tac")]
+    pub emit_ssa: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Run a program written in three-address code
+    Tac(TacOpts),
+    /// Write the control-flow graph of a script in Graphviz format
+    Cfg(CfgOpts),
+    /// Time scripts in every virtual machine mode, best built with `--release` and without the
+    /// `debug_trace_execution` feature
+    Bench(BenchOpts),
+}
+
+#[derive(Clap)]
+pub struct TacOpts {
+    /// File path of the program
+    pub file: String,
+
+    /// Print the variables and temporaries of the top-level code once the program stops
+    #[clap(long)]
+    pub env: bool,
+}
+
+#[derive(Clap)]
+pub struct CfgOpts {
+    /// File path for the script
+    pub script: String,
+
+    /// File to write the graph to, the script with a `.dot` extension by default
+    #[clap(short, long)]
+    pub output: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct BenchOpts {
+    /// File paths of the scripts
+    #[clap(required = true)]
+    pub scripts: Vec<String>,
+
+    /// Number of times each script is run in each mode, the median time being reported
+    #[clap(short, long, default_value = "5")]
+    pub runs: usize,
+}
+
+/// Values of `--error-format`, see `rox::ErrorFormat`.
+#[derive(ArgEnum, Copy, Clone)]
+pub enum ErrorFormat {
+    Human,
+    Json,
+}
+
+impl From<ErrorFormat> for rox::ErrorFormat {
+    fn from(format: ErrorFormat) -> Self {
+        match format {
+            ErrorFormat::Human => rox::ErrorFormat::Human,
+            ErrorFormat::Json => rox::ErrorFormat::Json,
+        }
+    }
+}
+
+/// Values of `-A`, `-W` and `-D`, see `rox::WarningKind`.
+#[derive(ArgEnum, Copy, Clone)]
+pub enum WarningKind {
+    UnusedVariable,
+    UnreachableCode,
+    ShadowedVariable,
+    SelfComparison,
+    LiteralTypeComparison,
+}
+
+impl From<WarningKind> for rox::WarningKind {
+    fn from(kind: WarningKind) -> Self {
+        match kind {
+            WarningKind::UnusedVariable => rox::WarningKind::UnusedVariable,
+            WarningKind::UnreachableCode => rox::WarningKind::UnreachableCode,
+            WarningKind::ShadowedVariable => rox::WarningKind::ShadowedVariable,
+            WarningKind::SelfComparison => rox::WarningKind::SelfComparison,
+            WarningKind::LiteralTypeComparison => rox::WarningKind::LiteralTypeComparison,
+        }
+    }
+}
+
+/// Values of `--vm`, see `rox::Mode`.
+#[derive(ArgEnum, Copy, Clone)]
+pub enum Mode {
+    Stack,
+    Register,
+}
+
+impl From<Mode> for rox::Mode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Stack => rox::Mode::Stack,
+            Mode::Register => rox::Mode::Register,
+        }
+    }
+}
+
+impl Opts {
+    /// Lint levels from the command line, `deny` taking precedence over `warn` and `allow`.
+    pub fn lints(&self) -> Lints {
+        let mut lints = Lints::new();
+        let levels = [
+            (&self.allow, LintLevel::Allow),
+            (&self.warn, LintLevel::Warn),
+            (&self.deny, LintLevel::Deny),
+        ];
+
+        for (kinds, level) in levels.iter() {
+            for kind in kinds.iter() {
+                lints.set((*kind).into(), *level);
+            }
+        }
+
+        lints
+    }
 }
@@ -0,0 +1,1005 @@
+use std::io::Read;
+
+use crate::{
+    ast::{
+        BinaryOp, Decl, Expr, ExprKind, ForInit, FunDecl, Identifier, Literal, LogicalOp, Program,
+        Stmt, StmtKind, UnaryOp, VarDecl,
+    },
+    error::{CompilationError, RoxError, RoxErrorKind, RoxResult},
+    location::Span,
+    scanner::{
+        escape::EscapeErrorKind, parse_number, token::TokenErrorKind, unescape, OwnedToken,
+        Scanner, StreamScanner, Token, TokenKind, TokenSource,
+    },
+};
+
+#[derive(Copy, Clone, PartialOrd, PartialEq)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn next(&self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call => Precedence::Primary,
+            Precedence::Primary => Precedence::None,
+        }
+    }
+}
+
+pub const MAX_ARITY: usize = 255;
+
+/// Levels of statements and expressions that can be nested in each other. Passes over the tree
+/// are recursive, so deeper code would overflow the stack.
+pub const MAX_NESTING: usize = 128;
+
+type PrefixFn<'sourcecode> = fn(&mut Parser<'sourcecode>, bool) -> RoxResult<Expr>;
+type InfixFn<'sourcecode> = fn(&mut Parser<'sourcecode>, Expr, bool) -> RoxResult<Expr>;
+
+#[derive(Copy, Clone)]
+struct ParseRule<'sourcecode> {
+    prefix: Option<PrefixFn<'sourcecode>>,
+    infix: Option<InfixFn<'sourcecode>>,
+    precedence: Precedence,
+}
+
+impl<'sourcecode>
+    From<(
+        Option<PrefixFn<'sourcecode>>,
+        Option<InfixFn<'sourcecode>>,
+        Precedence,
+    )> for ParseRule<'sourcecode>
+{
+    fn from(
+        (prefix, infix, precedence): (
+            Option<PrefixFn<'sourcecode>>,
+            Option<InfixFn<'sourcecode>>,
+            Precedence,
+        ),
+    ) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+/// Builds the syntax tree of a program. Syntax errors are recorded and parsing resumes at the
+/// next statement, so the tree only lacks the declarations that could not be parsed.
+pub struct Parser<'sourcecode> {
+    /// Source of the tokens, `None` once it could not be read anymore.
+    tokens: Option<Box<dyn TokenSource + 'sourcecode>>,
+    current: OwnedToken,
+    previous: OwnedToken,
+    errors: Vec<RoxError>,
+    /// Statements and expressions being parsed that contain the current token.
+    depth: usize,
+    /// Whether parsing stopped before the end of the code.
+    stopped: bool,
+}
+
+impl<'sourcecode> Parser<'sourcecode> {
+    pub fn new(code: &'sourcecode str) -> Self {
+        Self::from_tokens(Scanner::new(code))
+    }
+
+    /// Parser of the code read from `input`, which is parsed as it is read.
+    pub fn from_reader(input: impl Read + 'sourcecode) -> Self {
+        Self::from_tokens(StreamScanner::new(input))
+    }
+
+    fn from_tokens(tokens: impl TokenSource + 'sourcecode) -> Self {
+        Self {
+            tokens: Some(Box::new(tokens)),
+            previous: OwnedToken::default(),
+            current: OwnedToken::default(),
+            errors: Vec::new(),
+            depth: 0,
+            stopped: false,
+        }
+    }
+
+    pub fn parse(mut self) -> (Program, Vec<RoxError>) {
+        let mut declarations = Vec::new();
+
+        self.advance();
+        while !self.matches(TokenKind::Eof) {
+            if let Some(decl) = self.declaration() {
+                declarations.push(decl);
+            }
+        }
+
+        let program = Program {
+            declarations,
+            end: self.previous.span(),
+        };
+
+        (program, self.errors)
+    }
+
+    fn declaration(&mut self) -> Option<Decl> {
+        let result = if self.matches(TokenKind::Fun) {
+            self.fun_declaration().map(Decl::Fun)
+        } else if self.matches(TokenKind::Var) {
+            self.var_declaration().map(Decl::Var)
+        } else {
+            self.statement().map(Decl::Stmt)
+        };
+
+        match result {
+            Ok(decl) => Some(decl),
+            Err(err) => {
+                // Code cut short by parsing stopping only adds errors about what is missing.
+                if !self.stopped {
+                    self.errors.push(err);
+                }
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    /// Skips tokens until a statement boundary is reached, so that a single syntax error does
+    /// not produce a cascade of errors in the code that follows it.
+    fn synchronize(&mut self) {
+        while self.current.kind() != TokenKind::Eof {
+            if self.previous.kind() == TokenKind::Semicolon {
+                return;
+            }
+
+            match self.current.kind() {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn fun_declaration(&mut self) -> RoxResult<FunDecl> {
+        self.nested(|parser| {
+            let start = parser.previous.span();
+            let name = parser.identifier(CompilationError::MissingFunctionName)?;
+
+            parser.consume(
+                TokenKind::LeftParen,
+                CompilationError::MissingOpeningParenthesis(name.name.clone()),
+            )?;
+
+            let mut params = Vec::new();
+            if !parser.check(TokenKind::RightParen) {
+                loop {
+                    if params.len() == MAX_ARITY {
+                        return Err(parser.error_at_current(CompilationError::TooManyParameters(
+                            MAX_ARITY as u64,
+                        )));
+                    }
+
+                    params.push(parser.identifier(CompilationError::MissingParameterName)?);
+
+                    if !parser.matches(TokenKind::Comma) {
+                        break;
+                    }
+                }
+            }
+
+            parser.consume(
+                TokenKind::RightParen,
+                CompilationError::MissingClosingParenthesis,
+            )?;
+            parser.consume(TokenKind::LeftBrace, CompilationError::MissingOpeningBrace)?;
+
+            let body = parser.block()?;
+            let end = parser.previous.span();
+
+            Ok(FunDecl {
+                name,
+                params,
+                body,
+                end,
+                span: parser.span_from(start),
+            })
+        })
+    }
+
+    fn var_declaration(&mut self) -> RoxResult<VarDecl> {
+        let start = self.previous.span();
+        let name = self.identifier(CompilationError::MissingVariableName)?;
+
+        let initializer = if self.matches(TokenKind::Equal) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+
+        self.consume(
+            TokenKind::Semicolon,
+            CompilationError::MissingSemicolon("variable declaration".into()),
+        )?;
+
+        Ok(VarDecl {
+            name,
+            initializer,
+            span: self.span_from(start),
+        })
+    }
+
+    fn statement(&mut self) -> RoxResult<Stmt> {
+        self.nested(|parser| {
+            let start = parser.current.span();
+
+            let kind = if parser.matches(TokenKind::Print) {
+                let value = parser.expression()?;
+                parser.consume(
+                    TokenKind::Semicolon,
+                    CompilationError::MissingSemicolon("value".into()),
+                )?;
+                StmtKind::Print(value)
+            } else if parser.matches(TokenKind::Return) {
+                parser.return_statement()?
+            } else if parser.matches(TokenKind::If) {
+                parser.if_statement()?
+            } else if parser.matches(TokenKind::While) {
+                let condition = parser.condition("while")?;
+                let body = Box::new(parser.statement()?);
+                StmtKind::While { condition, body }
+            } else if parser.matches(TokenKind::For) {
+                parser.for_statement()?
+            } else if parser.matches(TokenKind::LeftBrace) {
+                StmtKind::Block(parser.block()?)
+            } else {
+                StmtKind::Expression(parser.expression_statement()?)
+            };
+
+            Ok(Stmt {
+                kind,
+                span: parser.span_from(start),
+            })
+        })
+    }
+
+    fn return_statement(&mut self) -> RoxResult<StmtKind> {
+        if self.matches(TokenKind::Semicolon) {
+            return Ok(StmtKind::Return(None));
+        }
+
+        let value = self.expression()?;
+        self.consume(
+            TokenKind::Semicolon,
+            CompilationError::MissingSemicolon("return value".into()),
+        )?;
+
+        Ok(StmtKind::Return(Some(value)))
+    }
+
+    fn expression_statement(&mut self) -> RoxResult<Expr> {
+        let expr = self.expression()?;
+        self.consume(
+            TokenKind::Semicolon,
+            CompilationError::MissingSemicolon("expression".into()),
+        )?;
+
+        Ok(expr)
+    }
+
+    fn if_statement(&mut self) -> RoxResult<StmtKind> {
+        let condition = self.condition("if")?;
+        let then_branch = Box::new(self.statement()?);
+
+        let else_branch = if self.matches(TokenKind::Else) {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+
+        Ok(StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+
+    fn for_statement(&mut self) -> RoxResult<StmtKind> {
+        self.consume(
+            TokenKind::LeftParen,
+            CompilationError::MissingOpeningParenthesis("for".into()),
+        )?;
+
+        let initializer = if self.matches(TokenKind::Semicolon) {
+            None
+        } else if self.matches(TokenKind::Var) {
+            Some(Box::new(ForInit::Var(self.var_declaration()?)))
+        } else {
+            Some(Box::new(ForInit::Expression(self.expression_statement()?)))
+        };
+
+        let condition = if self.matches(TokenKind::Semicolon) {
+            None
+        } else {
+            let condition = self.expression()?;
+            self.consume(
+                TokenKind::Semicolon,
+                CompilationError::MissingSemicolon("loop condition".into()),
+            )?;
+            Some(Box::new(condition))
+        };
+
+        let increment = if self.matches(TokenKind::RightParen) {
+            None
+        } else {
+            let increment = self.expression()?;
+            self.consume(
+                TokenKind::RightParen,
+                CompilationError::MissingClosingParenthesis,
+            )?;
+            Some(Box::new(increment))
+        };
+
+        let body = Box::new(self.statement()?);
+
+        Ok(StmtKind::For {
+            initializer,
+            condition,
+            increment,
+            body,
+        })
+    }
+
+    /// Parses the parenthesized condition of an `if` or `while` statement.
+    fn condition(&mut self, keyword: &str) -> RoxResult<Expr> {
+        self.consume(
+            TokenKind::LeftParen,
+            CompilationError::MissingOpeningParenthesis(keyword.into()),
+        )?;
+        let condition = self.expression()?;
+        self.consume(
+            TokenKind::RightParen,
+            CompilationError::MissingClosingParenthesis,
+        )?;
+
+        Ok(condition)
+    }
+
+    /// Parses the declarations of a block, after its opening brace.
+    fn block(&mut self) -> RoxResult<Vec<Decl>> {
+        let mut declarations = Vec::new();
+
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            if let Some(decl) = self.declaration() {
+                declarations.push(decl);
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, CompilationError::MissingClosingBrace)?;
+
+        Ok(declarations)
+    }
+
+    fn expression(&mut self) -> RoxResult<Expr> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> RoxResult<Expr> {
+        self.nested(|parser| {
+            parser.advance();
+
+            let prefix_rule = match parser.get_rule(parser.previous.kind()).prefix {
+                Some(rule) => rule,
+                None => {
+                    return Err(parser.error(CompilationError::MissingExpression));
+                }
+            };
+
+            let can_assign = precedence <= Precedence::Assignment;
+            let mut expr = prefix_rule(parser, can_assign)?;
+
+            while precedence <= parser.get_rule(parser.current.kind()).precedence {
+                parser.advance();
+                let infix_rule = parser
+                    .get_rule(parser.previous.kind())
+                    .infix
+                    .expect("Expect infix rule");
+                expr = infix_rule(parser, expr, can_assign)?;
+            }
+
+            if can_assign && parser.matches(TokenKind::Equal) {
+                return Err(parser.error(CompilationError::InvalidAssignmentTarget));
+            }
+
+            Ok(expr)
+        })
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> RoxResult<Expr> {
+        let opening = self.previous.span();
+
+        let expr = self.expression()?;
+        self.consume(
+            TokenKind::RightParen,
+            CompilationError::MissingClosingParenthesis,
+        )
+        .map_err(|err| {
+            err.with_note(format!(
+                "Unclosed parenthesis opened at line {}",
+                opening.start.line() + 1
+            ))
+        })?;
+
+        Ok(Expr {
+            kind: ExprKind::Grouping(Box::new(expr)),
+            span: self.span_from(opening),
+        })
+    }
+
+    fn binary(&mut self, left: Expr, _can_assign: bool) -> RoxResult<Expr> {
+        let operator = self.previous.kind();
+        let operator_span = self.previous.span();
+        let rule = self.get_rule(operator);
+        let right = self.parse_precedence(rule.precedence.next())?;
+
+        let op = match operator {
+            TokenKind::Plus => BinaryOp::Add,
+            TokenKind::Minus => BinaryOp::Subtract,
+            TokenKind::Star => BinaryOp::Multiply,
+            TokenKind::Slash => BinaryOp::Divide,
+            TokenKind::BangEqual => BinaryOp::NotEqual,
+            TokenKind::EqualEqual => BinaryOp::Equal,
+            TokenKind::Greater => BinaryOp::Greater,
+            TokenKind::GreaterEqual => BinaryOp::GreaterEqual,
+            TokenKind::Less => BinaryOp::Less,
+            TokenKind::LessEqual => BinaryOp::LessEqual,
+            _ => panic!("Invalid binary operator"),
+        };
+
+        Ok(Expr {
+            span: Span::new(left.span.start, right.span.end),
+            kind: ExprKind::Binary {
+                operator: op,
+                operator_span,
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+        })
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> RoxResult<Expr> {
+        let operator = self.previous.kind();
+        let operator_span = self.previous.span();
+        let operand = self.parse_precedence(Precedence::Unary)?;
+
+        let op = match operator {
+            TokenKind::Minus => UnaryOp::Negate,
+            TokenKind::Bang => UnaryOp::Not,
+            _ => panic!("Invalid unary operator"),
+        };
+
+        Ok(Expr {
+            span: Span::new(operator_span.start, operand.span.end),
+            kind: ExprKind::Unary {
+                operator: op,
+                operator_span,
+                operand: Box::new(operand),
+            },
+        })
+    }
+
+    fn call(&mut self, callee: Expr, _can_assign: bool) -> RoxResult<Expr> {
+        let paren = self.previous.span();
+        let arguments = self.argument_list()?;
+
+        Ok(Expr {
+            span: Span::new(callee.span.start, self.previous.span().end),
+            kind: ExprKind::Call {
+                callee: Box::new(callee),
+                paren,
+                arguments,
+            },
+        })
+    }
+
+    fn dot(&mut self, object: Expr, can_assign: bool) -> RoxResult<Expr> {
+        let name = self.identifier(CompilationError::MissingPropertyName)?;
+
+        if can_assign && self.matches(TokenKind::Equal) {
+            let value = self.expression()?;
+            return Ok(Expr {
+                span: Span::new(object.span.start, value.span.end),
+                kind: ExprKind::Set {
+                    object: Box::new(object),
+                    name,
+                    value: Box::new(value),
+                },
+            });
+        }
+
+        Ok(Expr {
+            span: Span::new(object.span.start, name.span.end),
+            kind: ExprKind::Get {
+                object: Box::new(object),
+                name,
+            },
+        })
+    }
+
+    fn argument_list(&mut self) -> RoxResult<Vec<Expr>> {
+        let mut arguments = Vec::new();
+
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                let argument = self.expression()?;
+
+                if arguments.len() == MAX_ARITY {
+                    return Err(self.error(CompilationError::TooManyArguments(MAX_ARITY as u64)));
+                }
+                arguments.push(argument);
+
+                if !self.matches(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(
+            TokenKind::RightParen,
+            CompilationError::MissingClosingParenthesis,
+        )?;
+
+        Ok(arguments)
+    }
+
+    fn and(&mut self, left: Expr, _can_assign: bool) -> RoxResult<Expr> {
+        self.logical(left, LogicalOp::And, Precedence::And)
+    }
+
+    fn or(&mut self, left: Expr, _can_assign: bool) -> RoxResult<Expr> {
+        self.logical(left, LogicalOp::Or, Precedence::Or)
+    }
+
+    fn logical(&mut self, left: Expr, op: LogicalOp, precedence: Precedence) -> RoxResult<Expr> {
+        let operator_span = self.previous.span();
+        let right = self.parse_precedence(precedence)?;
+
+        Ok(Expr {
+            span: Span::new(left.span.start, right.span.end),
+            kind: ExprKind::Logical {
+                operator: op,
+                operator_span,
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+        })
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> RoxResult<Expr> {
+        let literal = match self.previous.kind() {
+            TokenKind::False => Literal::Bool(false),
+            TokenKind::True => Literal::Bool(true),
+            TokenKind::Nil => Literal::Nil,
+            _ => panic!("Invalid literal token"),
+        };
+
+        Ok(self.literal_expr(literal))
+    }
+
+    fn number(&mut self, _can_assign: bool) -> RoxResult<Expr> {
+        assert!(matches!(self.previous.kind(), TokenKind::Number));
+
+        match parse_number(self.previous.lexeme()) {
+            Some(value) => Ok(self.literal_expr(Literal::Number(value))),
+            None => Err(self.error(CompilationError::InvalidNumberLiteral(
+                self.previous.lexeme().into(),
+            ))),
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) -> RoxResult<Expr> {
+        assert!(matches!(self.previous.kind(), TokenKind::String));
+
+        // Invalid escapes are recorded without aborting the statement, like lexical errors.
+        let value = match unescape(&self.previous.as_token()) {
+            Ok(value) => value,
+            Err(errors) => {
+                for error in errors {
+                    let kind = match error.kind {
+                        EscapeErrorKind::UnknownEscape => {
+                            CompilationError::InvalidEscape(error.sequence.into())
+                        }
+                        EscapeErrorKind::InvalidUnicodeEscape => {
+                            CompilationError::InvalidUnicodeEscape(error.sequence.into())
+                        }
+                    };
+                    let error = RoxError::new(RoxErrorKind::CompilationError(kind), error.span);
+                    self.errors.push(error);
+                }
+                String::new()
+            }
+        };
+
+        Ok(self.literal_expr(Literal::String(value)))
+    }
+
+    fn literal_expr(&self, literal: Literal) -> Expr {
+        Expr {
+            kind: ExprKind::Literal(literal),
+            span: self.previous.span(),
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> RoxResult<Expr> {
+        let name = Identifier {
+            name: self.previous.lexeme().into(),
+            span: self.previous.span(),
+        };
+
+        if can_assign && self.matches(TokenKind::Equal) {
+            let value = self.expression()?;
+            return Ok(Expr {
+                span: Span::new(name.span.start, value.span.end),
+                kind: ExprKind::Assign {
+                    target: name,
+                    value: Box::new(value),
+                },
+            });
+        }
+
+        Ok(Expr {
+            span: name.span,
+            kind: ExprKind::Variable(name),
+        })
+    }
+
+    fn identifier(&mut self, error: CompilationError) -> RoxResult<Identifier> {
+        self.consume(TokenKind::Identifier, error)?;
+
+        Ok(Identifier {
+            name: self.previous.lexeme().into(),
+            span: self.previous.span(),
+        })
+    }
+
+    fn get_rule(&mut self, kind: TokenKind) -> ParseRule<'sourcecode> {
+        let rule: (
+            Option<PrefixFn<'sourcecode>>,
+            Option<InfixFn<'sourcecode>>,
+            Precedence,
+        ) = match kind {
+            TokenKind::LeftParen => (Some(Self::grouping), Some(Self::call), Precedence::Call),
+            TokenKind::RightParen => (None, None, Precedence::None),
+            TokenKind::LeftBrace => (None, None, Precedence::None),
+            TokenKind::RightBrace => (None, None, Precedence::None),
+            TokenKind::Comma => (None, None, Precedence::None),
+            TokenKind::Dot => (None, Some(Self::dot), Precedence::Call),
+            TokenKind::Minus => (Some(Self::unary), Some(Self::binary), Precedence::Term),
+            TokenKind::Plus => (None, Some(Self::binary), Precedence::Term),
+            TokenKind::Semicolon => (None, None, Precedence::None),
+            TokenKind::Slash => (None, Some(Self::binary), Precedence::Factor),
+            TokenKind::Star => (None, Some(Self::binary), Precedence::Factor),
+            TokenKind::Bang => (Some(Self::unary), None, Precedence::None),
+            TokenKind::BangEqual => (None, Some(Self::binary), Precedence::Equality),
+            TokenKind::Equal => (None, None, Precedence::None),
+            TokenKind::EqualEqual => (None, Some(Self::binary), Precedence::Equality),
+            TokenKind::Greater => (None, Some(Self::binary), Precedence::Comparison),
+            TokenKind::GreaterEqual => (None, Some(Self::binary), Precedence::Comparison),
+            TokenKind::Less => (None, Some(Self::binary), Precedence::Comparison),
+            TokenKind::LessEqual => (None, Some(Self::binary), Precedence::Comparison),
+            TokenKind::Identifier => (Some(Self::variable), None, Precedence::None),
+            TokenKind::String => (Some(Self::string), None, Precedence::None),
+            TokenKind::Number => (Some(Self::number), None, Precedence::None),
+            TokenKind::And => (None, Some(Self::and), Precedence::And),
+            TokenKind::Class => (None, None, Precedence::None),
+            TokenKind::Else => (None, None, Precedence::None),
+            TokenKind::False => (Some(Self::literal), None, Precedence::None),
+            TokenKind::Fun => (None, None, Precedence::None),
+            TokenKind::For => (None, None, Precedence::None),
+            TokenKind::If => (None, None, Precedence::None),
+            TokenKind::Nil => (Some(Self::literal), None, Precedence::None),
+            TokenKind::Or => (None, Some(Self::or), Precedence::Or),
+            TokenKind::Print => (None, None, Precedence::None),
+            TokenKind::Return => (None, None, Precedence::None),
+            TokenKind::Super => (None, None, Precedence::None),
+            TokenKind::This => (None, None, Precedence::None),
+            TokenKind::True => (Some(Self::literal), None, Precedence::None),
+            TokenKind::Var => (None, None, Precedence::None),
+            TokenKind::While => (None, None, Precedence::None),
+            TokenKind::Error(_) => (None, None, Precedence::None),
+            TokenKind::Eof => (None, None, Precedence::None),
+        };
+
+        ParseRule::from(rule)
+    }
+
+    /// Span from the start of `start` to the end of the last consumed token.
+    fn span_from(&self, start: Span) -> Span {
+        Span::new(start.start, self.previous.span().end)
+    }
+
+    fn consume(&mut self, kind: TokenKind, error: CompilationError) -> RoxResult<()> {
+        if self.current.kind() == kind {
+            self.advance();
+            return Ok(());
+        }
+
+        Err(self.error_at_current(error))
+    }
+
+    fn check(&self, kind: TokenKind) -> bool {
+        self.current.kind() == kind
+    }
+
+    fn matches(&mut self, kind: TokenKind) -> bool {
+        if !self.check(kind) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
+    /// Moves to the next valid token. Lexical errors found along the way are recorded and
+    /// skipped, as they do not affect the parser's state.
+    fn advance(&mut self) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+
+        loop {
+            let result = match &mut self.tokens {
+                Some(tokens) => tokens.next_token_into(&mut self.current),
+                None => {
+                    let eof = self.eof();
+                    self.current.set(eof);
+                    Ok(())
+                }
+            };
+            if let Err(error) = result {
+                // The code ends where it could not be read anymore.
+                self.tokens = None;
+                self.current.set(self.eof());
+                let error = CompilationError::UnreadableCode(error.to_string());
+                let error = self.error_at_current(error);
+                self.errors.push(error);
+                break;
+            }
+
+            let error = match self.current.kind() {
+                TokenKind::Error(TokenErrorKind::InvalidLexeme) => {
+                    CompilationError::InvalidLexeme(self.current.lexeme().into())
+                }
+                TokenKind::Error(TokenErrorKind::SyntheticToken) => {
+                    panic!("Unexpected synthetic token, this is a bug in the parser");
+                }
+                TokenKind::Error(TokenErrorKind::UnterminatedString) => {
+                    CompilationError::UnterminatedString
+                }
+                TokenKind::Error(TokenErrorKind::UnterminatedBlockComment) => {
+                    CompilationError::UnterminatedBlockComment
+                }
+                _ => break,
+            };
+
+            let error = self.error_at_current(error);
+            self.errors.push(error);
+        }
+    }
+
+    /// Parses a statement or expression containing the current token with `parse`. Parsing
+    /// stops if the code is nested too deeply, as recovering would mean going through the same
+    /// nesting again.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> RoxResult<T>) -> RoxResult<T> {
+        if self.depth == MAX_NESTING {
+            let kind = || CompilationError::TooDeeplyNested(MAX_NESTING as u64);
+            let error = self.error_at_current(kind());
+            self.errors.push(error);
+            self.stopped = true;
+            self.tokens = None;
+            self.current.set(self.eof());
+
+            // Unwinds the enclosing code, whose errors are dropped.
+            return Err(self.error_at_current(kind()));
+        }
+
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// End of the code, right after the last token.
+    fn eof(&self) -> Token<'static> {
+        let end = self.previous.span().end;
+        Token::new(TokenKind::Eof, "", end, end)
+    }
+
+    fn error_at_current(&mut self, kind: CompilationError) -> RoxError {
+        self.error_at(self.current.span(), kind)
+    }
+
+    fn error(&mut self, kind: CompilationError) -> RoxError {
+        self.error_at(self.previous.span(), kind)
+    }
+
+    fn error_at(&mut self, span: Span, kind: CompilationError) -> RoxError {
+        RoxError::new(RoxErrorKind::CompilationError(kind), span)
+    }
+}
+
+/// Parses `code`, returning the syntax tree of the declarations that could be parsed and the
+/// errors found in the rest.
+pub fn parse(code: &str) -> (Program, Vec<RoxError>) {
+    Parser::new(code).parse()
+}
+
+/// Parses the code read from `input` like `parse`, starting before all of it was read.
+pub fn parse_reader(input: impl Read) -> (Program, Vec<RoxError>) {
+    Parser::from_reader(input).parse()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+
+    use crate::ast::{BinaryOp, Decl, ExprKind, Literal, StmtKind};
+
+    use super::{parse, parse_reader, MAX_NESTING};
+
+    #[test]
+    fn builds_tree_with_precedence_and_spans() {
+        let (program, errors) = parse("print 1 + 2 * 3;");
+        assert!(errors.is_empty());
+
+        let stmt = match &program.declarations[0] {
+            Decl::Stmt(stmt) => stmt,
+            decl => panic!("Expected a statement, got {:?}", decl),
+        };
+        assert_eq!(stmt.span.start.offset(), 0);
+        assert_eq!(stmt.span.end.offset(), 16);
+
+        let (left, right) = match &stmt.kind {
+            StmtKind::Print(expr) => match &expr.kind {
+                ExprKind::Binary {
+                    operator: BinaryOp::Add,
+                    left,
+                    right,
+                    ..
+                } => (left, right),
+                kind => panic!("Expected an addition, got {:?}", kind),
+            },
+            kind => panic!("Expected a print statement, got {:?}", kind),
+        };
+
+        assert_eq!(left.kind, ExprKind::Literal(Literal::Number(1.0)));
+        assert!(matches!(
+            right.kind,
+            ExprKind::Binary {
+                operator: BinaryOp::Multiply,
+                ..
+            }
+        ));
+        assert_eq!(right.span.start.offset(), 10);
+        assert_eq!(right.span.end.offset(), 15);
+    }
+
+    #[test]
+    fn keeps_declarations_around_syntax_errors() {
+        let (program, errors) = parse("var a = 1;\nvar = 2;\nfun f(x) { return x; }");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(program.declarations.len(), 2);
+        assert!(matches!(&program.declarations[0], Decl::Var(decl) if decl.name.name == "a"));
+        assert!(matches!(
+            &program.declarations[1],
+            Decl::Fun(decl) if decl.name.name == "f" && decl.params.len() == 1
+        ));
+    }
+
+    #[test]
+    fn parses_property_access_and_assignment() {
+        let (program, errors) = parse("a.b(1).c = d.e;");
+        assert!(errors.is_empty());
+
+        let (object, name, value) = match &program.declarations[0] {
+            Decl::Stmt(stmt) => match &stmt.kind {
+                StmtKind::Expression(expr) => match &expr.kind {
+                    ExprKind::Set {
+                        object,
+                        name,
+                        value,
+                    } => (object, name, value),
+                    kind => panic!("Expected a property assignment, got {:?}", kind),
+                },
+                kind => panic!("Expected an expression statement, got {:?}", kind),
+            },
+            decl => panic!("Expected a statement, got {:?}", decl),
+        };
+
+        assert_eq!(name.name, "c");
+        assert!(matches!(&object.kind, ExprKind::Call { callee, .. }
+            if matches!(&callee.kind, ExprKind::Get { name, .. } if name.name == "b")));
+        assert_eq!(object.span.end.offset(), 6);
+        assert!(matches!(&value.kind, ExprKind::Get { name, .. } if name.name == "e"));
+
+        let (_, errors) = parse("a.1; a + b.c = 2;");
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Missing property name after '.'",
+                "Invalid assignment target"
+            ]
+        );
+    }
+
+    /// Input that fails to be read, like a connection that was reset.
+    struct Reset;
+
+    impl Read for Reset {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset",
+            ))
+        }
+    }
+
+    #[test]
+    fn parses_code_as_it_is_read() {
+        let code = "fun f(a) { return a * 2; }\nprint f(\"ünï\" + \"cødé\") != 1.5;";
+        let (streamed, errors) = parse_reader(code.as_bytes());
+        assert!(errors.is_empty());
+        assert_eq!(streamed, parse(code).0);
+
+        let (program, errors) = parse_reader(b"print 1;\nprint 2;\npri".chain(Reset));
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(program.declarations.len(), 2);
+        assert_eq!(messages, vec!["Could not read the code: connection reset"]);
+        assert_eq!(errors[0].span.start.offset(), 17);
+    }
+
+    #[test]
+    fn stops_at_code_nested_too_deeply() {
+        let deep = MAX_NESTING * 5;
+        let programs = [
+            format!("print {}1{};", "(".repeat(deep), ")".repeat(deep)),
+            format!("print {}1;", "-".repeat(deep)),
+            format!("{}{}", "{".repeat(deep), "}".repeat(deep)),
+            format!("{}{}", "fun f() {".repeat(deep), "}".repeat(deep)),
+            format!("{}print 1;", "while (true) ".repeat(deep)),
+        ];
+
+        for code in programs.iter() {
+            let (_, errors) = parse(&format!("print 0;\n{}\nprint 2;", code));
+            let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            assert_eq!(
+                messages,
+                vec![format!(
+                    "Code is nested too deeply, limit is {} levels",
+                    MAX_NESTING
+                )]
+            );
+            assert_eq!(errors[0].span.start.line(), 1);
+        }
+    }
+}
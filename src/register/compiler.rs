@@ -0,0 +1,442 @@
+use std::{collections::HashSet, convert::TryFrom};
+
+use crate::{
+    chunk::{self, Instruction as Stack},
+    location::Span,
+    optimizer::jump_target,
+    verifier,
+};
+
+use super::{Chunk, Instruction, Register};
+
+/// Translates the stack bytecode of a function taking `arity` arguments into register code.
+///
+/// The value at depth `n` of the frame's stack lives in register `n`, so locals keep their slot
+/// and calls keep their layout. Reading a local pushes nothing: the local is used as operand
+/// directly until it is stored to or control flow joins, where the read is made with a move.
+/// Results stored to a local right after being computed are written there in the first place.
+pub fn compile(chunk: &chunk::Chunk, arity: usize) -> Chunk {
+    let targets: HashSet<usize> = (0..chunk.code.len())
+        .filter_map(|index| jump_target(chunk.code[index], index))
+        .collect();
+
+    let mut compiler = Compiler {
+        chunk: Chunk::default(),
+        stack: vec![None; arity + 1],
+        produced: None,
+    };
+    compiler.chunk.registers = arity + 1;
+
+    let depths = verifier::verify(chunk, arity).expect("Compiled code is valid");
+    // Where each instruction starts in the new code.
+    let mut starts = vec![0; chunk.code.len() + 1];
+    let mut jumps = Vec::new();
+    let mut reachable = true;
+
+    let mut index = 0;
+    while index < chunk.code.len() {
+        let instruction = chunk.code[index];
+        let span = chunk.get_span(index);
+
+        if targets.contains(&index) {
+            if reachable {
+                compiler.materialize(0, span);
+            }
+            if let Some(depth) = depths[index] {
+                compiler.stack = vec![None; depth];
+                reachable = true;
+            }
+            compiler.produced = None;
+        }
+        starts[index] = compiler.chunk.code.len();
+
+        if !reachable || depths[index].is_none() {
+            reachable = false;
+            index += 1;
+            continue;
+        }
+
+        match instruction {
+            Stack::Return => {
+                let src = compiler.pop();
+                compiler.emit(Instruction::Return(src), span);
+                reachable = false;
+            }
+            Stack::Constant(constant) => compiler.push(
+                |dst| Instruction::Constant {
+                    dst,
+                    index: constant,
+                },
+                span,
+            ),
+            Stack::Nil => compiler.push(Instruction::Nil, span),
+            Stack::True => compiler.push(Instruction::True, span),
+            Stack::False => compiler.push(Instruction::False, span),
+            Stack::Negate => compiler.unary(|dst, src| Instruction::Negate { dst, src }, span),
+            Stack::Not => compiler.unary(|dst, src| Instruction::Not { dst, src }, span),
+            Stack::Add => compiler.binary(
+                |dst, left, right| Instruction::Add { dst, left, right },
+                span,
+            ),
+            Stack::Subtract => compiler.binary(
+                |dst, left, right| Instruction::Subtract { dst, left, right },
+                span,
+            ),
+            Stack::Multiply => compiler.binary(
+                |dst, left, right| Instruction::Multiply { dst, left, right },
+                span,
+            ),
+            Stack::Divide => compiler.binary(
+                |dst, left, right| Instruction::Divide { dst, left, right },
+                span,
+            ),
+            Stack::Equal => compiler.binary(
+                |dst, left, right| Instruction::Equal { dst, left, right },
+                span,
+            ),
+            Stack::NotEqual => compiler.binary(
+                |dst, left, right| Instruction::NotEqual { dst, left, right },
+                span,
+            ),
+            Stack::GreaterEqual => compiler.binary(
+                |dst, left, right| Instruction::GreaterEqual { dst, left, right },
+                span,
+            ),
+            Stack::LessEqual => compiler.binary(
+                |dst, left, right| Instruction::LessEqual { dst, left, right },
+                span,
+            ),
+            // `>=` and `<=` compiled into the opposite comparison and a `Not` for the same
+            // operator are translated into one instruction, to report errors with the operator
+            // that was written.
+            Stack::Greater | Stack::Less => {
+                let negated = chunk.code.get(index + 1) == Some(&Stack::Not)
+                    && chunk.get_span(index + 1) == span
+                    && !targets.contains(&(index + 1));
+
+                let make: fn(Register, Register, Register) -> Instruction =
+                    match (instruction, negated) {
+                        (Stack::Greater, false) => {
+                            |dst, left, right| Instruction::Greater { dst, left, right }
+                        }
+                        (Stack::Greater, true) => {
+                            |dst, left, right| Instruction::LessEqual { dst, left, right }
+                        }
+                        (_, false) => |dst, left, right| Instruction::Less { dst, left, right },
+                        (_, true) => {
+                            |dst, left, right| Instruction::GreaterEqual { dst, left, right }
+                        }
+                    };
+                compiler.binary(make, span);
+
+                if negated {
+                    index += 1;
+                    starts[index] = compiler.chunk.code.len();
+                }
+            }
+            Stack::Print => {
+                let src = compiler.pop();
+                compiler.emit(Instruction::Print(src), span);
+            }
+            Stack::Pop => {
+                compiler.pop();
+                compiler.produced = None;
+            }
+            Stack::DefineGlobal(name) => {
+                let src = compiler.pop();
+                compiler.emit(Instruction::DefineGlobal { index: name, src }, span);
+            }
+            Stack::GetGlobal(name) => {
+                compiler.push(|dst| Instruction::GetGlobal { dst, index: name }, span)
+            }
+            Stack::SetGlobal(name) => {
+                let src = compiler.top();
+                compiler.emit(Instruction::SetGlobal { index: name, src }, span);
+            }
+            Stack::GetProperty(name) => compiler.unary(
+                |dst, object| Instruction::GetProperty {
+                    dst,
+                    object,
+                    index: name,
+                },
+                span,
+            ),
+            // The value is the result, which takes the place of the object.
+            Stack::SetProperty(name) => {
+                let src = compiler.pop();
+                let object = compiler.pop();
+                compiler.emit(
+                    Instruction::SetProperty {
+                        object,
+                        index: name,
+                        src,
+                    },
+                    span,
+                );
+                compiler.push(|dst| Instruction::Move { dst, src }, span);
+            }
+            Stack::GetLocal(slot) => compiler.push_read(slot),
+            Stack::SetLocal(slot) => compiler.set_local(slot, span),
+            Stack::SetLocalPop(slot) => {
+                compiler.set_local(slot, span);
+                compiler.pop();
+            }
+            Stack::AddConstant(constant) | Stack::SubtractConstant(constant) => {
+                compiler.push(
+                    |dst| Instruction::Constant {
+                        dst,
+                        index: constant,
+                    },
+                    span,
+                );
+                let make: fn(Register, Register, Register) -> Instruction = match instruction {
+                    Stack::AddConstant(_) => {
+                        |dst, left, right| Instruction::Add { dst, left, right }
+                    }
+                    _ => |dst, left, right| Instruction::Subtract { dst, left, right },
+                };
+                compiler.binary(make, span);
+            }
+            Stack::AddLocals(a, b) => {
+                compiler.push_read(a.into());
+                compiler.push_read(b.into());
+                compiler.binary(
+                    |dst, left, right| Instruction::Add { dst, left, right },
+                    span,
+                );
+            }
+            Stack::Jump(_) | Stack::Loop(_) => {
+                compiler.materialize(0, span);
+                let target = jump_target(instruction, index).expect("Jumps have a target");
+                jumps.push((compiler.emit(Instruction::Jump(0), span), target));
+                reachable = false;
+            }
+            Stack::JumpIfFalse(_) => {
+                compiler.materialize(0, span);
+                let target = jump_target(instruction, index).expect("Jumps have a target");
+                let condition = compiler.top();
+                let jump = Instruction::JumpIfFalse {
+                    condition,
+                    target: 0,
+                };
+                jumps.push((compiler.emit(jump, span), target));
+            }
+            Stack::Call(arguments) => {
+                let callee = compiler.stack.len() - arguments as usize - 1;
+                compiler.materialize(callee, span);
+                compiler.stack.truncate(callee + 1);
+                let callee = register(callee);
+                compiler.emit(Instruction::Call { callee, arguments }, span);
+                compiler.produced = None;
+            }
+        }
+
+        index += 1;
+    }
+    starts[chunk.code.len()] = compiler.chunk.code.len();
+
+    for (jump, target) in jumps {
+        let target =
+            u32::try_from(starts[target]).expect("Register code fits in 2^32 instructions");
+        match &mut compiler.chunk.code[jump] {
+            Instruction::Jump(to) | Instruction::JumpIfFalse { target: to, .. } => *to = target,
+            _ => unreachable!("Only jumps are patched"),
+        }
+    }
+
+    compiler.chunk
+}
+
+fn register(depth: usize) -> Register {
+    Register::try_from(depth).expect("Stack depth fits in a register index")
+}
+
+struct Compiler {
+    chunk: Chunk,
+    /// Values on the stack at this point, `None` for those in the register of their depth and
+    /// `Some` for reads of a local not made yet, with the register they are read from.
+    stack: Vec<Option<Register>>,
+    /// Index of the instruction that just produced the value on top of the stack in its register.
+    produced: Option<usize>,
+}
+
+impl Compiler {
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.produced = None;
+        self.chunk.write(instruction, span)
+    }
+
+    /// Pushes the value computed by an instruction given the register of the new top.
+    fn push(&mut self, make: impl FnOnce(Register) -> Instruction, span: Span) {
+        let dst = register(self.stack.len());
+        self.stack.push(None);
+        self.chunk.registers = self.chunk.registers.max(self.stack.len());
+        let index = self.emit(make(dst), span);
+        self.produced = Some(index);
+    }
+
+    fn unary(&mut self, make: impl FnOnce(Register, Register) -> Instruction, span: Span) {
+        let src = self.pop();
+        self.push(|dst| make(dst, src), span);
+    }
+
+    fn binary(
+        &mut self,
+        make: impl FnOnce(Register, Register, Register) -> Instruction,
+        span: Span,
+    ) {
+        let right = self.pop();
+        let left = self.pop();
+        self.push(|dst| make(dst, left, right), span);
+    }
+
+    /// Register holding the value on top of the stack.
+    fn top(&self) -> Register {
+        let depth = self.stack.len() - 1;
+        self.stack[depth].unwrap_or_else(|| register(depth))
+    }
+
+    fn pop(&mut self) -> Register {
+        let top = self.top();
+        self.stack.pop();
+        top
+    }
+
+    /// Pushes the local in `slot`, without reading it yet.
+    fn push_read(&mut self, slot: u16) {
+        let src = self.stack[slot as usize].unwrap_or(slot);
+        self.stack.push(Some(src));
+        self.chunk.registers = self.chunk.registers.max(self.stack.len());
+        self.produced = None;
+    }
+
+    fn set_local(&mut self, slot: u16, span: Span) {
+        let depth = self.stack.len() - 1;
+        let src = self.top();
+        if src == slot {
+            return;
+        }
+
+        // Pending reads of the local need its old value.
+        for index in 0..self.stack.len() {
+            if self.stack[index] == Some(slot) && index != depth {
+                self.stack[index] = None;
+                self.emit(
+                    Instruction::Move {
+                        dst: register(index),
+                        src: slot,
+                    },
+                    span,
+                );
+            }
+        }
+        self.stack[slot as usize] = None;
+
+        match self.produced {
+            Some(index) if self.stack[depth].is_none() => {
+                let instruction = &mut self.chunk.code[index];
+                *instruction
+                    .dst_mut()
+                    .expect("Produced values have a destination") = slot;
+            }
+            _ => {
+                self.emit(Instruction::Move { dst: slot, src }, span);
+            }
+        }
+        self.stack[depth] = Some(slot);
+        self.produced = None;
+    }
+
+    /// Makes the reads of locals pending from `depth` up, so values are in their registers.
+    fn materialize(&mut self, depth: usize, span: Span) {
+        for index in depth..self.stack.len() {
+            if let Some(src) = self.stack[index] {
+                self.stack[index] = None;
+                self.emit(
+                    Instruction::Move {
+                        dst: register(index),
+                        src,
+                    },
+                    span,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{heap::Heap, lints::Lints, optimizer::OptLevel, register::Instruction};
+
+    use super::compile;
+
+    fn translate(code: &str) -> Vec<Instruction> {
+        let mut heap = Heap::new();
+        let script = crate::compiler::compile(
+            code,
+            "register.lox",
+            &mut heap,
+            &Lints::new(),
+            OptLevel::O0,
+            &mut Vec::new(),
+        )
+        .expect("Program should compile");
+
+        compile(&heap.deref(script).chunk, 0).code
+    }
+
+    #[test]
+    fn locals_are_operands_and_destinations() {
+        assert_eq!(
+            translate("{ var a = 1; var b = a + a; b = b * a; print b >= a; }"),
+            vec![
+                Instruction::Constant { dst: 1, index: 0 },
+                Instruction::Add {
+                    dst: 2,
+                    left: 1,
+                    right: 1
+                },
+                Instruction::Multiply {
+                    dst: 2,
+                    left: 2,
+                    right: 1
+                },
+                Instruction::GreaterEqual {
+                    dst: 3,
+                    left: 2,
+                    right: 1
+                },
+                Instruction::Print(3),
+                Instruction::Nil(1),
+                Instruction::Return(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_are_made_before_stores_and_jumps() {
+        // The old value of `a` is added, so it is copied before `a` changes.
+        let code = translate("{ var a = 1; print a + (a = 2); }");
+        assert_eq!(
+            code[1..5],
+            [
+                Instruction::Constant { dst: 3, index: 1 },
+                Instruction::Move { dst: 2, src: 1 },
+                Instruction::Move { dst: 1, src: 3 },
+                Instruction::Add {
+                    dst: 2,
+                    left: 2,
+                    right: 1
+                },
+            ]
+        );
+
+        // Calls and branches need their operands in the registers of their depth.
+        let code = translate("fun f(x) { return x; } { var a = 1; if (a) f(a); }");
+        assert!(code.contains(&Instruction::Move { dst: 2, src: 1 }));
+        assert!(code.contains(&Instruction::Call {
+            callee: 2,
+            arguments: 1
+        }));
+    }
+}
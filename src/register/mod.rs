@@ -0,0 +1,257 @@
+mod compiler;
+
+use std::fmt::{self, Display};
+
+use crate::location::Span;
+
+pub use compiler::compile;
+
+/// Index of a register, relative to the start of the frame, which holds the function being
+/// called, then its arguments and locals, then temporaries.
+pub type Register = u16;
+
+/// Instruction of the register machine, reading its operands from registers and writing its
+/// result to one instead of going through the stack.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Constant {
+        dst: Register,
+        index: u16,
+    },
+    Nil(Register),
+    True(Register),
+    False(Register),
+    Move {
+        dst: Register,
+        src: Register,
+    },
+    Negate {
+        dst: Register,
+        src: Register,
+    },
+    Not {
+        dst: Register,
+        src: Register,
+    },
+    Add {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    Subtract {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    Multiply {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    Divide {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    Equal {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    NotEqual {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    Greater {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    GreaterEqual {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    Less {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    LessEqual {
+        dst: Register,
+        left: Register,
+        right: Register,
+    },
+    Print(Register),
+    DefineGlobal {
+        index: u16,
+        src: Register,
+    },
+    GetGlobal {
+        dst: Register,
+        index: u16,
+    },
+    SetGlobal {
+        index: u16,
+        src: Register,
+    },
+    /// Reads the property of `object` named by the constant at `index`.
+    GetProperty {
+        dst: Register,
+        object: Register,
+        index: u16,
+    },
+    SetProperty {
+        object: Register,
+        index: u16,
+        src: Register,
+    },
+    /// Goes to the instruction at `target`.
+    Jump(u32),
+    JumpIfFalse {
+        condition: Register,
+        target: u32,
+    },
+    /// Calls the value in `callee` with the `arguments` registers after it, the result being
+    /// written to `callee`.
+    Call {
+        callee: Register,
+        arguments: u16,
+    },
+    Return(Register),
+}
+
+impl Instruction {
+    /// Register the instruction writes its result to, if any.
+    fn dst_mut(&mut self) -> Option<&mut Register> {
+        match self {
+            Instruction::Constant { dst, .. }
+            | Instruction::Nil(dst)
+            | Instruction::True(dst)
+            | Instruction::False(dst)
+            | Instruction::Move { dst, .. }
+            | Instruction::Negate { dst, .. }
+            | Instruction::Not { dst, .. }
+            | Instruction::Add { dst, .. }
+            | Instruction::Subtract { dst, .. }
+            | Instruction::Multiply { dst, .. }
+            | Instruction::Divide { dst, .. }
+            | Instruction::Equal { dst, .. }
+            | Instruction::NotEqual { dst, .. }
+            | Instruction::Greater { dst, .. }
+            | Instruction::GreaterEqual { dst, .. }
+            | Instruction::Less { dst, .. }
+            | Instruction::LessEqual { dst, .. }
+            | Instruction::GetGlobal { dst, .. }
+            | Instruction::GetProperty { dst, .. } => Some(dst),
+            _ => None,
+        }
+    }
+
+    /// Symbol of the operator an arithmetic or comparison instruction was compiled from.
+    pub fn operator_symbol(&self) -> &'static str {
+        match self {
+            Instruction::Add { .. } => "+",
+            Instruction::Subtract { .. } | Instruction::Negate { .. } => "-",
+            Instruction::Multiply { .. } => "*",
+            Instruction::Divide { .. } => "/",
+            Instruction::Greater { .. } => ">",
+            Instruction::GreaterEqual { .. } => ">=",
+            Instruction::Less { .. } => "<",
+            Instruction::LessEqual { .. } => "<=",
+            inst => panic!("Instruction {:?} is not an operator", inst),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let binary = |f: &mut fmt::Formatter<'_>, name: &str, dst, left, right| {
+            write!(f, "{:<16} r{}, r{}, r{}", name, dst, left, right)
+        };
+
+        match *self {
+            Instruction::Constant { dst, index } => {
+                write!(f, "{:<16} r{}, {}", "OP_CONSTANT", dst, index)
+            }
+            Instruction::Nil(dst) => write!(f, "{:<16} r{}", "OP_NIL", dst),
+            Instruction::True(dst) => write!(f, "{:<16} r{}", "OP_TRUE", dst),
+            Instruction::False(dst) => write!(f, "{:<16} r{}", "OP_FALSE", dst),
+            Instruction::Move { dst, src } => write!(f, "{:<16} r{}, r{}", "OP_MOVE", dst, src),
+            Instruction::Negate { dst, src } => {
+                write!(f, "{:<16} r{}, r{}", "OP_NEGATE", dst, src)
+            }
+            Instruction::Not { dst, src } => write!(f, "{:<16} r{}, r{}", "OP_NOT", dst, src),
+            Instruction::Add { dst, left, right } => binary(f, "OP_ADD", dst, left, right),
+            Instruction::Subtract { dst, left, right } => binary(f, "OP_SUB", dst, left, right),
+            Instruction::Multiply { dst, left, right } => binary(f, "OP_MUL", dst, left, right),
+            Instruction::Divide { dst, left, right } => binary(f, "OP_DIV", dst, left, right),
+            Instruction::Equal { dst, left, right } => binary(f, "OP_EQUAL", dst, left, right),
+            Instruction::NotEqual { dst, left, right } => {
+                binary(f, "OP_NOT_EQUAL", dst, left, right)
+            }
+            Instruction::Greater { dst, left, right } => binary(f, "OP_GREATER", dst, left, right),
+            Instruction::GreaterEqual { dst, left, right } => {
+                binary(f, "OP_GREATER_EQUAL", dst, left, right)
+            }
+            Instruction::Less { dst, left, right } => binary(f, "OP_LESS", dst, left, right),
+            Instruction::LessEqual { dst, left, right } => {
+                binary(f, "OP_LESS_EQUAL", dst, left, right)
+            }
+            Instruction::Print(src) => write!(f, "{:<16} r{}", "OP_PRINT", src),
+            Instruction::DefineGlobal { index, src } => {
+                write!(f, "{:<16} {}, r{}", "OP_DEFINE_GLOBAL", index, src)
+            }
+            Instruction::GetGlobal { dst, index } => {
+                write!(f, "{:<16} r{}, {}", "OP_GET_GLOBAL", dst, index)
+            }
+            Instruction::SetGlobal { index, src } => {
+                write!(f, "{:<16} {}, r{}", "OP_SET_GLOBAL", index, src)
+            }
+            Instruction::GetProperty { dst, object, index } => {
+                write!(
+                    f,
+                    "{:<16} r{}, r{}, {}",
+                    "OP_GET_PROPERTY", dst, object, index
+                )
+            }
+            Instruction::SetProperty { object, index, src } => {
+                write!(
+                    f,
+                    "{:<16} r{}, {}, r{}",
+                    "OP_SET_PROPERTY", object, index, src
+                )
+            }
+            Instruction::Jump(target) => write!(f, "{:<16} {:04}", "OP_JUMP", target),
+            Instruction::JumpIfFalse { condition, target } => {
+                write!(
+                    f,
+                    "{:<16} r{}, {:04}",
+                    "OP_JUMP_IF_FALSE", condition, target
+                )
+            }
+            Instruction::Call { callee, arguments } => {
+                write!(f, "{:<16} r{}, {}", "OP_CALL", callee, arguments)
+            }
+            Instruction::Return(src) => write!(f, "{:<16} r{}", "OP_RETURN", src),
+        }
+    }
+}
+
+/// Register code of a function, sharing the constants of its stack bytecode.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Instruction>,
+    pub spans: Vec<Span>,
+    /// Number of registers a frame running the code needs.
+    pub registers: usize,
+}
+
+impl Chunk {
+    fn write(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.code.push(instruction);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+}
@@ -1,14 +1,22 @@
-use crate::runner;
+use rox::{ErrorFormat, Vm};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::io;
+use std::{io, sync::atomic::Ordering};
+
+use crate::runner;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub fn repl() -> io::Result<()> {
+pub fn repl(mut vm: Vm, format: ErrorFormat) -> io::Result<()> {
     // `()` can be used when no completer is required
     let mut rl = Editor::<()>::new();
 
+    // Ctrl-C while a line is being read is handled by rustyline, and otherwise stops the code
+    // being run instead of the REPL.
+    let interrupt = vm.interrupt_handle();
+    ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed))
+        .expect("Failed to set the Ctrl-C handler");
+
     println!("rox {}", VERSION);
 
     loop {
@@ -21,11 +29,12 @@ pub fn repl() -> io::Result<()> {
 
                 rl.add_history_entry(line.as_str());
 
-                runner::eval(&line);
+                // An interruption while no code was running is not meant for this line.
+                vm.interrupt_handle().store(false, Ordering::Relaxed);
+                runner::eval(&mut vm, &line, "<repl>", format);
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
-                break;
             }
             Err(ReadlineError::Eof) => {
                 println!("CTRL-D");
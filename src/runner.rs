@@ -1,19 +1,198 @@
-use std::fs;
+use std::{
+    fs, io,
+    time::{Duration, Instant},
+};
 
-use crate::{chunk::Chunk, vm::Vm};
+use rox::{
+    cfg, functions, report, ssa, tac, Chunk, ErrorFormat, Lints, Mode, OptLevel, RoxError, Value,
+    Vm,
+};
 
-pub fn eval_file(path: &str) {
+/// Name given to the script read from the standard input in diagnostics.
+const STDIN: &str = "<stdin>";
+
+/// Runs the script at `path`, or the one on the standard input if `path` is `-`. The script is
+/// compiled as it is read, so it can be piped from a program that is still writing it.
+pub fn eval_file(vm: &mut Vm, path: &str, format: ErrorFormat) {
+    let (script, file) = if path == "-" {
+        (vm.compile_reader(io::stdin(), STDIN), STDIN)
+    } else {
+        let input = fs::File::open(path).expect("Something went wrong reading the file");
+        (vm.compile_reader(input, path), path)
+    };
+
+    run(vm, script, file, format);
+}
+
+pub fn eval(vm: &mut Vm, code: &str, file: &str, format: ErrorFormat) {
+    let script = vm.compile(code, file);
+    run(vm, script, file, format);
+}
+
+/// Reports the warnings found compiling a script before running it, if it compiled.
+fn run(vm: &mut Vm, script: Result<Value, Vec<RoxError>>, file: &str, format: ErrorFormat) {
+    report(&vm.take_warnings(), file, format);
+
+    if let Err(errors) = script.and_then(|script| vm.call(script, &[])) {
+        report(&errors, file, format);
+    }
+}
+
+/// Prints the three-address code of a script, annotated with the lines it comes from.
+pub fn emit_tac(path: &str, lints: &Lints, format: ErrorFormat) {
+    let contents = fs::read_to_string(path).expect("Something went wrong reading the file");
+
+    let mut warnings = Vec::new();
+    let result = tac::compile(&contents, path, lints, &mut warnings);
+
+    report(&warnings, path, format);
+    match result {
+        Ok(program) => print!("{}", program.listing(&contents)),
+        Err(errors) => report(&errors, path, format),
+    }
+}
+
+/// Runs a three-address code program, printing the variables and temporaries of its top-level
+/// code afterwards if `show_environment` is set, even when it fails.
+pub fn run_tac(path: &str, show_environment: bool, format: ErrorFormat) {
+    let contents = fs::read_to_string(path).expect("Something went wrong reading the file");
+
+    let program = match tac::parse(&contents) {
+        Ok(program) => program,
+        Err(errors) => return report(&errors, path, format),
+    };
+
+    let mut interpreter = tac::Interpreter::new(&program, path);
+    let result = interpreter.run();
+
+    if show_environment {
+        for (name, value) in interpreter.environment() {
+            println!("{} = {}", name, value);
+        }
+    }
+    if let Err(error) = result {
+        report(&[error], path, format);
+    }
+}
+
+/// Writes the control-flow graph of a script and of every function it declares to `output`.
+pub fn write_cfg(vm: &mut Vm, path: &str, output: &str, format: ErrorFormat) {
     let contents = fs::read_to_string(path).expect("Something went wrong reading the file");
 
-    let _ = eval(&contents);
+    let result = vm.compile(&contents, path);
+
+    report(&vm.take_warnings(), path, format);
+    let script = match result {
+        Ok(Value::Function(script)) => script,
+        Ok(_) => unreachable!("Scripts compile to functions"),
+        Err(errors) => return report(&errors, path, format),
+    };
+
+    let heap = vm.heap();
+    let functions = functions(heap, script);
+
+    let names: Vec<String> = functions
+        .iter()
+        .map(|function| Value::Function(*function).format(heap))
+        .collect();
+    let chunks: Vec<(&str, &Chunk)> = names
+        .iter()
+        .zip(functions.iter())
+        .map(|(name, function)| (name.as_str(), heap.deref(*function).chunk()))
+        .collect();
+
+    fs::write(output, cfg::to_dot(&chunks, heap)).expect("Something went wrong writing the graph");
 }
 
-pub fn eval(expr: &str) {
-    let mut vm = Vm::new(Chunk::new());
+/// Prints the SSA form of a script and of every function it declares, optimized if `opt_level`
+/// is at least `O2`.
+pub fn emit_ssa(vm: &mut Vm, path: &str, opt_level: OptLevel, format: ErrorFormat) {
+    let contents = fs::read_to_string(path).expect("Something went wrong reading the file");
+
+    vm.set_opt_level(OptLevel::O0);
+    let result = vm.compile(&contents, path);
+
+    report(&vm.take_warnings(), path, format);
+    let script = match result {
+        Ok(Value::Function(script)) => script,
+        Ok(_) => unreachable!("Scripts compile to functions"),
+        Err(errors) => return report(&errors, path, format),
+    };
 
-    if let Err(errors) = vm.interpret(expr) {
-        for err in errors {
-            eprintln!("[line {}] Error: {}", err.line, err.src);
+    let heap = vm.heap_mut();
+    for (index, function) in functions(heap, script).into_iter().enumerate() {
+        let data = heap.deref(function);
+        let name = Value::Function(function).format(heap);
+        let lifted = ssa::lift(data.chunk(), data.arity());
+
+        if index > 0 {
+            println!();
+        }
+        println!("== {} ==", name);
+        match lifted {
+            Some(mut lifted) => {
+                if opt_level >= OptLevel::O2 {
+                    ssa::run_passes(&mut lifted, heap);
+                }
+                print!("{}", lifted.listing(heap));
+            }
+            None => println!("(can not be lifted)"),
         }
     }
 }
+
+/// Runs each script `runs` times in every virtual machine mode, discarding what they print, and
+/// shows the median time each mode took along with how much faster registers are.
+pub fn bench(
+    scripts: &[String],
+    runs: usize,
+    lints: &Lints,
+    opt_level: OptLevel,
+    format: ErrorFormat,
+) {
+    let modes = [Mode::Stack, Mode::Register];
+
+    println!(
+        "{:<24} {:>12} {:>12} {:>8}",
+        "script", "stack", "register", "speedup"
+    );
+    for path in scripts.iter() {
+        let contents = fs::read_to_string(path).expect("Something went wrong reading the file");
+        let mut medians = Vec::with_capacity(modes.len());
+
+        for mode in modes.iter() {
+            let mut times = Vec::with_capacity(runs);
+            for _ in 0..runs.max(1) {
+                let mut vm = Vm::new();
+                vm.set_lints(lints.clone());
+                vm.set_opt_level(opt_level);
+                vm.set_mode(*mode);
+                vm.set_output(io::sink());
+
+                let start = Instant::now();
+                let result = vm.interpret(&contents, path);
+                times.push(start.elapsed());
+
+                if let Err(errors) = result {
+                    return report(&errors, path, format);
+                }
+            }
+
+            times.sort();
+            medians.push(times[times.len() / 2]);
+        }
+
+        let speedup = medians[0].as_secs_f64() / medians[1].as_secs_f64();
+        println!(
+            "{:<24} {:>12} {:>12} {:>7.2}x",
+            path,
+            milliseconds(medians[0]),
+            milliseconds(medians[1]),
+            speedup
+        );
+    }
+}
+
+fn milliseconds(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
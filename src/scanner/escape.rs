@@ -0,0 +1,129 @@
+use super::{Token, TokenKind};
+use crate::location::{Location, Span};
+
+/// Highest number of hexadecimal digits accepted in a `\u{...}` escape.
+const MAX_UNICODE_DIGITS: usize = 6;
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum EscapeErrorKind {
+    /// Backslash followed by a character without a meaning, e.g. `\q`.
+    UnknownEscape,
+    /// `\u` not followed by `{X}`, with 1 to 6 hex digits forming a valid code point.
+    InvalidUnicodeEscape,
+}
+
+/// Invalid escape sequence found in a string literal.
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub struct EscapeError<'sourcecode> {
+    pub kind: EscapeErrorKind,
+    /// Text of the sequence, starting at its backslash.
+    pub sequence: &'sourcecode str,
+    pub span: Span,
+}
+
+/// Value of a string literal token with its escape sequences processed. The token's lexeme
+/// is left untouched, so tools can still access the literal as written.
+///
+/// Every invalid escape sequence is reported, not only the first one.
+pub fn unescape<'sourcecode>(
+    token: &Token<'sourcecode>,
+) -> Result<String, Vec<EscapeError<'sourcecode>>> {
+    assert!(matches!(token.kind(), TokenKind::String));
+
+    let lexeme = token.lexeme();
+    let body = &lexeme[1..(lexeme.len() - 1)];
+
+    let mut value = String::with_capacity(body.len());
+    let mut errors = Vec::new();
+
+    // Location of `body[consumed]`, only moved forward when an error needs it.
+    let mut location = token.location();
+    location.advance(b'"');
+    let mut consumed = 0;
+
+    let mut chars = body.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+
+        let rest = &body[(idx + 1)..];
+        let (len, result) = match rest.chars().next() {
+            Some('n') => (1, Ok('\n')),
+            Some('t') => (1, Ok('\t')),
+            Some('r') => (1, Ok('\r')),
+            Some('0') => (1, Ok('\0')),
+            Some('"') => (1, Ok('"')),
+            Some('\\') => (1, Ok('\\')),
+            Some('u') => {
+                let (len, result) = unicode_escape(&rest[1..]);
+                (len + 1, result)
+            }
+            Some(c) => (c.len_utf8(), Err(EscapeErrorKind::UnknownEscape)),
+            None => (0, Err(EscapeErrorKind::UnknownEscape)),
+        };
+
+        let end = idx + 1 + len;
+        while chars.peek().is_some_and(|(i, _)| *i < end) {
+            chars.next();
+        }
+
+        match result {
+            Ok(c) => value.push(c),
+            Err(kind) => {
+                let start = advance_to(&mut location, &mut consumed, body, idx);
+                let end = advance_to(&mut location, &mut consumed, body, end);
+
+                errors.push(EscapeError {
+                    kind,
+                    sequence: &body[idx..consumed],
+                    span: Span::new(start, end),
+                });
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(value)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parses the `{X}` part of a unicode escape, returning how many bytes it spans.
+fn unicode_escape(text: &str) -> (usize, Result<char, EscapeErrorKind>) {
+    if !text.starts_with('{') {
+        return (0, Err(EscapeErrorKind::InvalidUnicodeEscape));
+    }
+
+    let digits = text[1..]
+        .bytes()
+        .take_while(|c| c.is_ascii_hexdigit())
+        .count();
+
+    if !text[(1 + digits)..].starts_with('}') {
+        return (1 + digits, Err(EscapeErrorKind::InvalidUnicodeEscape));
+    }
+
+    let result = if digits == 0 || digits > MAX_UNICODE_DIGITS {
+        None
+    } else {
+        u32::from_str_radix(&text[1..(1 + digits)], 16)
+            .ok()
+            .and_then(char::from_u32)
+    };
+
+    (
+        digits + 2,
+        result.ok_or(EscapeErrorKind::InvalidUnicodeEscape),
+    )
+}
+
+fn advance_to(location: &mut Location, consumed: &mut usize, body: &str, idx: usize) -> Location {
+    for c in body[*consumed..idx].bytes() {
+        location.advance(c);
+    }
+    *consumed = idx;
+    *location
+}
@@ -1,8 +1,16 @@
+pub mod escape;
+pub mod number;
+#[allow(clippy::module_inception)]
 pub mod scanner;
+pub mod stream;
 pub mod token;
 
+pub use escape::unescape;
+pub use number::parse_number;
 pub use scanner::Scanner;
+pub use stream::{OwnedToken, StreamScanner, TokenSource};
 pub use token::{Token, TokenKind};
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod test;
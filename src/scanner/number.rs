@@ -0,0 +1,47 @@
+/// Value of a number literal token, `None` if it is malformed.
+///
+/// Literals are decimal, optionally with a fraction and an exponent (`6.02E23`), or integers
+/// with a `0x`, `0o` or `0b` radix prefix. Digits can be separated by single underscores.
+pub fn parse_number(lexeme: &str) -> Option<f64> {
+    let (radix, digits) = match lexeme.get(..2) {
+        Some("0x") | Some("0X") => (16, &lexeme[2..]),
+        Some("0o") | Some("0O") => (8, &lexeme[2..]),
+        Some("0b") | Some("0B") => (2, &lexeme[2..]),
+        _ => (10, lexeme),
+    };
+
+    if digits.is_empty() || !valid_separators(digits, radix) {
+        return None;
+    }
+
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+
+    if radix == 10 {
+        // `f64::from_str` also accepts words like `inf` and `NaN`.
+        let is_decimal = digits
+            .bytes()
+            .all(|c| c.is_ascii_digit() || matches!(c, b'.' | b'e' | b'E' | b'+' | b'-'));
+
+        if is_decimal {
+            digits.parse().ok()
+        } else {
+            None
+        }
+    } else {
+        // Folded into a float, so large literals lose precision instead of overflowing.
+        digits.chars().try_fold(0.0, |value, c| {
+            c.to_digit(radix)
+                .map(|digit| value * f64::from(radix) + f64::from(digit))
+        })
+    }
+}
+
+/// Whether every `_` sits between two digits.
+fn valid_separators(digits: &str, radix: u32) -> bool {
+    let is_digit = |c: Option<&u8>| c.is_some_and(|c| (*c as char).is_digit(radix));
+    let bytes = digits.as_bytes();
+
+    bytes.iter().enumerate().all(|(idx, c)| {
+        *c != b'_' || (idx > 0 && is_digit(bytes.get(idx - 1)) && is_digit(bytes.get(idx + 1)))
+    })
+}
@@ -1,26 +1,85 @@
-use super::{token::TokenErrorKind, Token, TokenKind};
+use unicode_xid::UnicodeXID;
+
+use super::{
+    token::{LosslessToken, TokenErrorKind, Trivia, TriviaKind},
+    Token, TokenKind,
+};
 use crate::location::Location;
 
 pub struct Scanner<'sourcecode> {
     code: &'sourcecode str,
     code_bytes: &'sourcecode [u8],
+    /// Offset of `code` in the whole program, not zero when scanning a part of it.
+    base: usize,
     start: Location,
     current: Location,
 }
 
 impl<'sourcecode> Scanner<'sourcecode> {
-    pub fn new(code: &'sourcecode str) -> Scanner {
+    pub fn new(code: &'sourcecode str) -> Scanner<'sourcecode> {
+        Self::resume(code, Location::default())
+    }
+
+    /// Scanner for a part of a program, `code` being the text that starts at `location`.
+    pub fn resume(code: &'sourcecode str, location: Location) -> Scanner<'sourcecode> {
         Scanner {
             code,
             code_bytes: code.as_bytes(),
-            start: Location::default(),
-            current: Location::default(),
+            base: location.offset(),
+            start: location,
+            current: location,
         }
     }
 
-    #[allow(unused)]
     pub fn next_token(&mut self) -> Token<'sourcecode> {
-        self.skip_non_tokens();
+        if let Some(error) = self.skip_non_tokens() {
+            return error;
+        }
+
+        self.scan_token()
+    }
+
+    /// Next token along with the trivia around it, so the source can be reconstructed exactly.
+    ///
+    /// Trailing trivia extends up to and including the end of the token's line, everything
+    /// after that is leading trivia of the following token. Trivia at the end of the code is
+    /// attached to the `Eof` token. A block comment that is not terminated is returned as an
+    /// error token covering it.
+    pub fn next_lossless_token(&mut self) -> LosslessToken<'sourcecode> {
+        let mut leading = Vec::new();
+        while let Some(trivia) = self.trivia() {
+            match trivia {
+                Ok(trivia) => leading.push(trivia),
+                Err(error) => return LosslessToken::new(leading, error, Vec::new()),
+            }
+        }
+
+        let token = self.scan_token();
+
+        let mut trailing = Vec::new();
+        if token.kind() != TokenKind::Eof {
+            loop {
+                let checkpoint = self.current;
+                match self.trivia() {
+                    Some(Ok(trivia)) if trivia.kind() == TriviaKind::Newline => {
+                        trailing.push(trivia);
+                        break;
+                    }
+                    Some(Ok(trivia)) if !trivia.text().contains('\n') => trailing.push(trivia),
+                    Some(_) => {
+                        // Scanned again as leading trivia of the next token.
+                        self.current = checkpoint;
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        LosslessToken::new(leading, token, trailing)
+    }
+
+    fn scan_token(&mut self) -> Token<'sourcecode> {
         self.start = self.current;
 
         if self.is_at_end() {
@@ -40,7 +99,7 @@ impl<'sourcecode> Scanner<'sourcecode> {
 
         macro_rules! token {
             ($kind:expr) => {
-                token!($kind, 1);
+                token!($kind, 1)
             };
             ($kind:expr,$qty:expr) => {{
                 check_invalid_lexeme! {{
@@ -78,42 +137,118 @@ impl<'sourcecode> Scanner<'sourcecode> {
                 b'>' if self.peek_next() == b'=' => token!(TokenKind::GreaterEqual, 2),
                 b'>' => token!(TokenKind::Greater),
 
+                b' ' | b'\r' | b'\t' | b'\n' if invalid_lexeme => {
+                    return self.error_token(TokenErrorKind::InvalidLexeme)
+                }
+
                 b'"' => check_invalid_lexeme!(self.string()),
                 c if is_digit(c) => check_invalid_lexeme!(self.number()),
-                c if is_alpha(c) => check_invalid_lexeme!(self.identifier()),
+                _ if is_identifier_start(self.peek_char()) => {
+                    check_invalid_lexeme!(self.identifier())
+                }
+                _ if invalid_lexeme && self.is_at_end() => {
+                    return self.error_token(TokenErrorKind::InvalidLexeme)
+                }
                 _ => {
-                    self.advance();
+                    // Whole characters are skipped so error lexemes are always valid UTF-8.
+                    self.advance_char();
                     invalid_lexeme = true;
                 }
             };
         }
     }
 
-    fn skip_non_tokens(&mut self) {
-        while !self.is_at_end() {
-            match self.peek() {
-                b' ' | b'\r' | b'\t' | b'\n' => {
+    /// Skips whitespace and comments, returning an error token if a block comment is not closed.
+    fn skip_non_tokens(&mut self) -> Option<Token<'sourcecode>> {
+        while let Some(trivia) = self.trivia() {
+            if let Err(error) = trivia {
+                return Some(error);
+            }
+        }
+
+        None
+    }
+
+    /// Scans the whitespace or comment at the current location, if there is one.
+    fn trivia(&mut self) -> Option<Result<Trivia<'sourcecode>, Token<'sourcecode>>> {
+        self.start = self.current;
+
+        let kind = match self.peek() {
+            b'\n' => {
+                self.advance();
+                TriviaKind::Newline
+            }
+            b' ' | b'\r' | b'\t' => {
+                while matches!(self.peek(), b' ' | b'\r' | b'\t') {
                     self.advance();
                 }
-                b'/' if self.peek_next() == b'/' => {
-                    while self.peek() != b'\n' && !self.is_at_end() {
-                        self.advance();
-                    }
+                TriviaKind::Whitespace
+            }
+            b'/' if self.peek_next() == b'/' => {
+                while self.peek() != b'\n' && !self.is_at_end() {
+                    self.advance();
+                }
+                TriviaKind::LineComment
+            }
+            b'/' if self.peek_next() == b'*' => {
+                if let Some(error) = self.block_comment() {
+                    return Some(Err(error));
+                }
+                TriviaKind::BlockComment
+            }
+            _ => return None,
+        };
+
+        Some(Ok(Trivia::new(
+            kind,
+            self.cur_lexeme(),
+            self.start,
+            self.current,
+        )))
+    }
+
+    /// Skips a `/* ... */` comment, which can contain nested block comments. If it is not
+    /// terminated, the error token covers the rest of the code and starts at the opening `/*`.
+    fn block_comment(&mut self) -> Option<Token<'sourcecode>> {
+        self.advance();
+        self.advance();
+
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return Some(self.error_token(TokenErrorKind::UnterminatedBlockComment));
+            }
+
+            match (self.peek(), self.peek_next()) {
+                (b'/', b'*') => {
+                    self.advance();
+                    self.advance();
+                    depth += 1;
+                }
+                (b'*', b'/') => {
+                    self.advance();
+                    self.advance();
+                    depth -= 1;
+                }
+                _ => {
+                    self.advance();
                 }
-                _ => return,
             }
         }
+
+        None
     }
 
     fn string(&mut self) -> Token<'sourcecode> {
         self.advance();
 
         while self.peek() != b'"' && !self.is_at_end() {
-            self.advance();
+            // Escape sequences are processed by the compiler, only `\"` matters here.
+            if self.advance() == b'\\' && !self.is_at_end() {
+                self.advance();
+            }
         }
 
-        let x = 3 u64;
-
         if self.is_at_end() {
             self.error_token(TokenErrorKind::UnterminatedString)
         } else {
@@ -122,17 +257,29 @@ impl<'sourcecode> Scanner<'sourcecode> {
         }
     }
 
+    /// Scans everything that looks like part of a number literal, so malformed ones like `0x`
+    /// or `1__0` become a single token. The parser validates and converts the lexeme.
     fn number(&mut self) -> Token<'sourcecode> {
         self.advance();
 
-        while is_digit(self.peek()) {
-            self.advance();
-        }
+        let is_decimal = !matches!(
+            (self.code_bytes[self.index(self.start)], self.peek()),
+            (b'0', b'x' | b'X' | b'o' | b'O' | b'b' | b'B')
+        );
 
-        if self.peek() == b'.' && is_digit(self.peek_next()) {
-            self.advance();
-            while is_digit(self.peek()) {
-                self.advance();
+        loop {
+            match self.peek() {
+                b'e' | b'E' if is_decimal && matches!(self.peek_next(), b'+' | b'-') => {
+                    self.advance();
+                    self.advance();
+                }
+                b'.' if is_decimal && is_digit(self.peek_next()) => {
+                    self.advance();
+                }
+                c if is_alpha(c) || is_digit(c) => {
+                    self.advance();
+                }
+                _ => break,
             }
         }
 
@@ -140,9 +287,9 @@ impl<'sourcecode> Scanner<'sourcecode> {
     }
 
     fn identifier(&mut self) -> Token<'sourcecode> {
-        self.advance();
-        while is_alpha(self.peek()) || is_digit(self.peek()) {
-            self.advance();
+        self.advance_char();
+        while is_identifier_continue(self.peek_char()) {
+            self.advance_char();
         }
 
         if let Some(reserved) = reserved_token(self.cur_lexeme()) {
@@ -167,6 +314,24 @@ impl<'sourcecode> Scanner<'sourcecode> {
         c
     }
 
+    fn advance_char(&mut self) {
+        if self.is_at_end() {
+            return;
+        }
+
+        for _ in 0..self.peek_char().len_utf8() {
+            self.advance();
+        }
+    }
+
+    /// Character starting at the current byte, `'\0'` at the end of the code.
+    fn peek_char(&self) -> char {
+        self.code[self.index(self.current)..]
+            .chars()
+            .next()
+            .unwrap_or('\0')
+    }
+
     fn peek(&mut self) -> u8 {
         self.peek_far(0)
     }
@@ -176,7 +341,7 @@ impl<'sourcecode> Scanner<'sourcecode> {
     }
 
     fn peek_far(&mut self, dist: usize) -> u8 {
-        let idx = self.current.offset().saturating_add(dist);
+        let idx = self.index(self.current).saturating_add(dist);
         if idx >= self.code_bytes.len() {
             0
         } else {
@@ -185,21 +350,34 @@ impl<'sourcecode> Scanner<'sourcecode> {
     }
 
     fn cur_lexeme(&self) -> &'sourcecode str {
-        &self.code[self.start.offset()..self.current.offset()]
+        &self.code[self.index(self.start)..self.index(self.current)]
     }
 
     fn is_at_end(&self) -> bool {
-        self.current.offset() == self.code.len()
+        self.index(self.current) == self.code.len()
+    }
+
+    /// Index in `code` of a location.
+    #[inline]
+    fn index(&self, location: Location) -> usize {
+        location.offset() - self.base
     }
 
     fn make_token(&self, kind: TokenKind) -> Token<'sourcecode> {
         Token::new(kind, self.cur_lexeme(), self.start, self.current)
     }
+}
 
-    pub fn into_iter(self) -> TokenIter<'sourcecode> {
+impl<'sourcecode> IntoIterator for Scanner<'sourcecode> {
+    type Item = Token<'sourcecode>;
+    type IntoIter = TokenIter<'sourcecode>;
+
+    fn into_iter(self) -> Self::IntoIter {
         TokenIter { scanner: self }
     }
 }
+
+/// Tokens of the code up to, but not including, `Eof`.
 pub struct TokenIter<'sourcecode> {
     scanner: Scanner<'sourcecode>,
 }
@@ -228,6 +406,17 @@ fn is_alpha(c: u8) -> bool {
     c.is_ascii_alphabetic() || c == b'_'
 }
 
+/// Identifiers follow Unicode's XID rules, with `_` also allowed as first character.
+#[inline]
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || c.is_xid_start()
+}
+
+#[inline]
+fn is_identifier_continue(c: char) -> bool {
+    c.is_xid_continue()
+}
+
 #[inline]
 pub fn reserved_token(lexeme: &str) -> Option<TokenKind> {
     match lexeme {
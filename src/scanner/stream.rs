@@ -0,0 +1,188 @@
+use std::io::{self, ErrorKind, Read};
+
+use super::{Scanner, Token, TokenKind};
+use crate::location::{Location, Span};
+
+/// Bytes read from the input each time more code is needed, unless the token being scanned is
+/// already longer than that.
+const CHUNK_SIZE: usize = 4096;
+
+/// Bytes the scanner may look at past the end of a token to decide where it ends, e.g. the
+/// `.5` in `1.5`. A token is only final once this much code follows it.
+const LOOKAHEAD: usize = 2;
+
+/// Token that owns its lexeme, as produced by `StreamScanner`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnedToken {
+    kind: TokenKind,
+    lexeme: String,
+    start_loc: Location,
+    end_loc: Location,
+}
+
+impl OwnedToken {
+    pub fn kind(&self) -> TokenKind {
+        self.kind
+    }
+
+    pub fn lexeme(&self) -> &str {
+        &self.lexeme
+    }
+
+    pub fn location(&self) -> Location {
+        self.start_loc
+    }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.start_loc, self.end_loc)
+    }
+
+    /// Borrowed view of the token, to use it where a `Token` is expected.
+    pub fn as_token(&self) -> Token<'_> {
+        Token::new(self.kind, &self.lexeme, self.start_loc, self.end_loc)
+    }
+
+    /// Replaces this token with `token`, reusing the memory of the lexeme.
+    pub fn set(&mut self, token: Token<'_>) {
+        let span = token.span();
+        self.kind = token.kind();
+        self.lexeme.clear();
+        self.lexeme.push_str(token.lexeme());
+        self.start_loc = span.start;
+        self.end_loc = span.end;
+    }
+}
+
+impl Default for OwnedToken {
+    fn default() -> Self {
+        Self::from(Token::synthetic(""))
+    }
+}
+
+impl From<Token<'_>> for OwnedToken {
+    fn from(token: Token<'_>) -> Self {
+        let span = token.span();
+        Self {
+            kind: token.kind(),
+            lexeme: token.lexeme().into(),
+            start_loc: span.start,
+            end_loc: span.end,
+        }
+    }
+}
+
+/// Where a parser gets its tokens from, either code in memory or a stream read as it goes.
+pub trait TokenSource {
+    /// Replaces `token` with the next token, `Eof` once the code is exhausted. Fails if the code
+    /// can not be read.
+    fn next_token_into(&mut self, token: &mut OwnedToken) -> io::Result<()>;
+}
+
+impl TokenSource for Scanner<'_> {
+    fn next_token_into(&mut self, token: &mut OwnedToken) -> io::Result<()> {
+        token.set(self.next_token());
+        Ok(())
+    }
+}
+
+/// Scanner that reads the code incrementally from any `Read`, so tokens are available before
+/// the whole input is. Only the code that has not been turned into tokens yet is kept.
+pub struct StreamScanner<R: Read> {
+    input: R,
+    /// Code read so far, of which only what follows `start` is still to be scanned. Scanned code
+    /// is dropped when more is read rather than after every token.
+    buffer: String,
+    start: usize,
+    /// Bytes at the end of what was read that do not form a whole character yet.
+    partial: Vec<u8>,
+    /// Location of `buffer[start]` in the whole program.
+    location: Location,
+    finished: bool,
+}
+
+impl<R: Read> StreamScanner<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            buffer: String::new(),
+            start: 0,
+            partial: Vec::new(),
+            location: Location::default(),
+            finished: false,
+        }
+    }
+
+    /// Next token of the input, `Eof` once it is exhausted. Fails if reading the input does,
+    /// including when it is not valid UTF-8.
+    pub fn next_token(&mut self) -> io::Result<OwnedToken> {
+        let mut token = OwnedToken::default();
+        self.next_token_into(&mut token)?;
+        Ok(token)
+    }
+
+    /// Reads more of the input into the buffer, at least as much as is left to scan so that a
+    /// long token is scanned again only a logarithmic number of times.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.drain(..self.start);
+        self.start = 0;
+
+        let mut bytes = std::mem::take(&mut self.partial);
+        let filled = bytes.len();
+        bytes.resize(filled + CHUNK_SIZE.max(self.buffer.len()), 0);
+        let read = loop {
+            match self.input.read(&mut bytes[filled..]) {
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        bytes.truncate(filled + read);
+
+        if read == 0 {
+            self.finished = true;
+            if !bytes.is_empty() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "input ends in the middle of a UTF-8 character",
+                ));
+            }
+            return Ok(());
+        }
+
+        match std::str::from_utf8(&bytes) {
+            Ok(code) => self.buffer.push_str(code),
+            // A character cut by the end of the read is completed by the next one.
+            Err(error) if error.error_len().is_none() => {
+                let (code, partial) = bytes.split_at(error.valid_up_to());
+                self.buffer
+                    .push_str(std::str::from_utf8(code).expect("Code was validated"));
+                self.partial = partial.to_vec();
+            }
+            Err(error) => return Err(io::Error::new(ErrorKind::InvalidData, error)),
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> TokenSource for StreamScanner<R> {
+    fn next_token_into(&mut self, token: &mut OwnedToken) -> io::Result<()> {
+        loop {
+            let code = &self.buffer[self.start..];
+            let next = Scanner::resume(code, self.location).next_token();
+
+            // Without the whole input, a token touching the end of the buffer might continue
+            // in code that has not been read yet.
+            let end = next.span().end;
+            let length = end.offset() - self.location.offset();
+            if self.finished || (next.kind() != TokenKind::Eof && length + LOOKAHEAD <= code.len())
+            {
+                token.set(next);
+                self.start += length;
+                self.location = end;
+                return Ok(());
+            }
+
+            self.fill()?;
+        }
+    }
+}
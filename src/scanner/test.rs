@@ -1,7 +1,11 @@
 mod test {
     use crate::location::Location;
-    use crate::scanner::token::TokenErrorKind;
-    use crate::scanner::{Scanner, Token, TokenKind};
+    use crate::scanner::escape::EscapeErrorKind;
+    use crate::scanner::token::LosslessToken;
+    use crate::scanner::token::{TokenErrorKind, TriviaKind};
+    use crate::scanner::{
+        parse_number, unescape, OwnedToken, Scanner, StreamScanner, Token, TokenKind,
+    };
 
     macro_rules! token {
         ($kind:expr,$lexeme:expr,$start:expr,$end:expr) => {
@@ -77,6 +81,121 @@ mod test {
         ]
     );
 
+    test!(
+        number_literals,
+        "0x1F 1e-9 6.02E23 1_000.5 0x 1.foo",
+        vec![
+            token!(TokenKind::Number, "0x1F", (0, 0, 0), (4, 0, 4)),
+            token!(TokenKind::Number, "1e-9", (5, 0, 5), (9, 0, 9)),
+            token!(TokenKind::Number, "6.02E23", (10, 0, 10), (17, 0, 17)),
+            token!(TokenKind::Number, "1_000.5", (18, 0, 18), (25, 0, 25)),
+            token!(TokenKind::Number, "0x", (26, 0, 26), (28, 0, 28)),
+            token!(TokenKind::Number, "1", (29, 0, 29), (30, 0, 30)),
+            token!(TokenKind::Dot, ".", (30, 0, 30), (31, 0, 31)),
+            token!(TokenKind::Identifier, "foo", (31, 0, 31), (34, 0, 34)),
+            token!(TokenKind::Eof, "", (34, 0, 34), (34, 0, 34)),
+        ]
+    );
+
+    test!(
+        nested_block_comments,
+        "a /* one /* two\n */ still\n one */ b",
+        vec![
+            token!(TokenKind::Identifier, "a", (0, 0, 0), (1, 0, 1)),
+            token!(TokenKind::Identifier, "b", (34, 2, 8), (35, 2, 9)),
+            token!(TokenKind::Eof, "", (35, 2, 9), (35, 2, 9)),
+        ]
+    );
+
+    test!(
+        unterminated_block_comment,
+        "a\n  /* /* */\n",
+        vec![
+            token!(TokenKind::Identifier, "a", (0, 0, 0), (1, 0, 1)),
+            token!(
+                TokenKind::Error(TokenErrorKind::UnterminatedBlockComment),
+                "/* /* */\n",
+                (4, 1, 2),
+                (13, 2, 0)
+            ),
+            token!(TokenKind::Eof, "", (13, 2, 0), (13, 2, 0)),
+        ]
+    );
+
+    test!(
+        unicode_identifiers,
+        "var café = \"ñ\"; 変数_2",
+        vec![
+            token!(TokenKind::Var, "var", (0, 0, 0), (3, 0, 3)),
+            token!(TokenKind::Identifier, "café", (4, 0, 4), (9, 0, 8)),
+            token!(TokenKind::Equal, "=", (10, 0, 9), (11, 0, 10)),
+            token!(TokenKind::String, "\"ñ\"", (12, 0, 11), (16, 0, 14)),
+            token!(TokenKind::Semicolon, ";", (16, 0, 14), (17, 0, 15)),
+            token!(TokenKind::Identifier, "変数_2", (18, 0, 16), (26, 0, 20)),
+            token!(TokenKind::Eof, "", (26, 0, 20), (26, 0, 20)),
+        ]
+    );
+
+    test!(
+        invalid_multibyte_lexeme,
+        "a €😀 b",
+        vec![
+            token!(TokenKind::Identifier, "a", (0, 0, 0), (1, 0, 1)),
+            token!(
+                TokenKind::Error(TokenErrorKind::InvalidLexeme),
+                "€😀",
+                (2, 0, 2),
+                (9, 0, 4)
+            ),
+            token!(TokenKind::Identifier, "b", (10, 0, 5), (11, 0, 6)),
+            token!(TokenKind::Eof, "", (11, 0, 6), (11, 0, 6)),
+        ]
+    );
+
+    test!(
+        invalid_lexeme_at_end,
+        "print 1; @",
+        vec![
+            token!(TokenKind::Print, "print", (0, 0, 0), (5, 0, 5)),
+            token!(TokenKind::Number, "1", (6, 0, 6), (7, 0, 7)),
+            token!(TokenKind::Semicolon, ";", (7, 0, 7), (8, 0, 8)),
+            token!(
+                TokenKind::Error(TokenErrorKind::InvalidLexeme),
+                "@",
+                (9, 0, 9),
+                (10, 0, 10)
+            ),
+            token!(TokenKind::Eof, "", (10, 0, 10), (10, 0, 10)),
+        ]
+    );
+
+    test!(
+        invalid_multibyte_lexeme_at_end,
+        "print 1; €",
+        vec![
+            token!(TokenKind::Print, "print", (0, 0, 0), (5, 0, 5)),
+            token!(TokenKind::Number, "1", (6, 0, 6), (7, 0, 7)),
+            token!(TokenKind::Semicolon, ";", (7, 0, 7), (8, 0, 8)),
+            token!(
+                TokenKind::Error(TokenErrorKind::InvalidLexeme),
+                "€",
+                (9, 0, 9),
+                (12, 0, 10)
+            ),
+            token!(TokenKind::Eof, "", (12, 0, 10), (12, 0, 10)),
+        ]
+    );
+
+    test!(
+        escaped_quote_does_not_end_string,
+        r#""a\"b" ;"#,
+        vec![
+            token!(TokenKind::String, r#""a\"b""#, (0, 0, 0), (6, 0, 6)),
+            token!(TokenKind::Semicolon, ";", (7, 0, 7), (8, 0, 8)),
+            token!(TokenKind::Eof, "", (8, 0, 8), (8, 0, 8)),
+        ]
+    );
+
     test!(
         unterminated_string,
         "\"singleword\n\n",
@@ -85,9 +204,9 @@ mod test {
                 TokenKind::Error(TokenErrorKind::UnterminatedString),
                 "\"singleword\n\n",
                 (0, 0, 0),
-                (11, 0, 11)
+                (13, 2, 0)
             ),
-            token!(TokenKind::Eof, "", (11, 0, 11), (11, 0, 11)),
+            token!(TokenKind::Eof, "", (13, 2, 0), (13, 2, 0)),
         ]
     );
 
@@ -138,4 +257,187 @@ mod test {
             token!(TokenKind::Eof, "", (74, 2, 36), (74, 2, 36)),
         ]
     );
+
+    fn string_token(code: &str) -> Token<'_> {
+        Scanner::new(code).next_token()
+    }
+
+    #[test]
+    fn unescapes_string_literals() {
+        let token = string_token(r#""a\n\t\"\\\u{1F600}\u{e9}""#);
+
+        assert_eq!(unescape(&token), Ok("a\n\t\"\\\u{1F600}\u{e9}".to_string()));
+        assert_eq!(token.lexeme(), r#""a\n\t\"\\\u{1F600}\u{e9}""#);
+    }
+
+    #[test]
+    fn reports_every_invalid_escape_with_its_location() {
+        let token = string_token("\"ok\\q\n  \\u{110000} \\u{12\"");
+        let errors = unescape(&token).unwrap_err();
+
+        assert_eq!(errors.len(), 3);
+
+        assert_eq!(errors[0].kind, EscapeErrorKind::UnknownEscape);
+        assert_eq!(errors[0].sequence, "\\q");
+        assert_eq!(errors[0].span.start, Location::from((3, 0, 3)));
+        assert_eq!(errors[0].span.end, Location::from((5, 0, 5)));
+
+        assert_eq!(errors[1].kind, EscapeErrorKind::InvalidUnicodeEscape);
+        assert_eq!(errors[1].sequence, "\\u{110000}");
+        assert_eq!(errors[1].span.start, Location::from((8, 1, 2)));
+
+        assert_eq!(errors[2].kind, EscapeErrorKind::InvalidUnicodeEscape);
+        assert_eq!(errors[2].sequence, "\\u{12");
+    }
+
+    #[test]
+    fn parses_number_literals() {
+        assert_eq!(parse_number("0x1F"), Some(31.0));
+        assert_eq!(parse_number("0b1010"), Some(10.0));
+        assert_eq!(parse_number("0o17"), Some(15.0));
+        assert_eq!(parse_number("1e-9"), Some(1e-9));
+        assert_eq!(parse_number("6.02E23"), Some(6.02e23));
+        assert_eq!(parse_number("1_000_000"), Some(1_000_000.0));
+        assert_eq!(parse_number("0xFF_FF"), Some(65535.0));
+    }
+
+    #[test]
+    fn rejects_malformed_number_literals() {
+        for lexeme in &[
+            "0x", "1__0", "1_", "0x_1", "1_.5", "1e", "0b102", "0o8", "12abc", "1e_5",
+        ] {
+            assert_eq!(parse_number(lexeme), None, "{} should be rejected", lexeme);
+        }
+    }
+
+    fn lossless_tokens(code: &str) -> Vec<LosslessToken<'_>> {
+        let mut scanner = Scanner::new(code);
+        let mut tokens = Vec::new();
+
+        loop {
+            let token = scanner.next_lossless_token();
+            let is_eof = token.token.kind() == TokenKind::Eof;
+            tokens.push(token);
+            if is_eof {
+                return tokens;
+            }
+        }
+    }
+
+    #[test]
+    fn lossless_tokens_reproduce_source() {
+        for code in &[
+            "",
+            "  // only a comment",
+            "var a = 1; // one\n\n/* two\n lines */ print a ;\r\n\t",
+            "print \"unterminated\n",
+            "a /* unterminated /* */\n",
+            "x $ € y",
+        ] {
+            let source: String = lossless_tokens(code)
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            assert_eq!(&source, code);
+        }
+    }
+
+    #[test]
+    fn trailing_trivia_ends_with_the_line() {
+        let tokens = lossless_tokens("a; // one\n/* two\n */ b");
+
+        let trailing: Vec<_> = tokens[1].trailing.iter().map(|t| t.kind()).collect();
+        assert_eq!(
+            trailing,
+            vec![
+                TriviaKind::Whitespace,
+                TriviaKind::LineComment,
+                TriviaKind::Newline
+            ]
+        );
+
+        let leading: Vec<_> = tokens[2].leading.iter().map(|t| t.kind()).collect();
+        assert_eq!(
+            leading,
+            vec![TriviaKind::BlockComment, TriviaKind::Whitespace]
+        );
+        assert_eq!(
+            tokens[2].leading[0].span().start,
+            Location::from((10, 1, 0))
+        );
+        assert_eq!(tokens[2].token.lexeme(), "b");
+    }
+
+    /// Input that hands out 1 to 5 bytes per read, like a slow pipe, cycling through these sizes
+    /// from `reads`.
+    struct Chunked<'a> {
+        code: &'a [u8],
+        reads: usize,
+    }
+
+    impl std::io::Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = (self.reads % 5 + 1).min(buf.len()).min(self.code.len());
+            let (read, rest) = self.code.split_at(size);
+            buf[..size].copy_from_slice(read);
+            self.code = rest;
+            self.reads += 1;
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn stream_scanner_matches_scanner() {
+        let programs = [
+            "var café = 1.5e3 != 0x1F; /* a\n /* b */ */ print \"two\nlines\"; // end\n\"open",
+            "a€b;",
+            "print 1; €",
+            "x = 😀 + y€€ - \"ü\";\n@ é😀",
+        ];
+
+        for code in programs.iter() {
+            for reads in 0..5 {
+                let mut stream = StreamScanner::new(Chunked {
+                    code: code.as_bytes(),
+                    reads,
+                });
+                let mut scanner = Scanner::new(code);
+
+                loop {
+                    let expected = scanner.next_token();
+                    let token = stream.next_token().expect("Reading should not fail");
+                    assert_eq!(token, OwnedToken::from(expected), "{}", code);
+
+                    if expected.kind() == TokenKind::Eof {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stream_scanner_reports_invalid_utf8() {
+        let mut stream = StreamScanner::new(&b"print \xff;"[..]);
+
+        assert_eq!(stream.next_token().ok().map(|t| t.kind()), None);
+    }
+
+    #[test]
+    fn stream_scanner_reads_tokens_longer_than_a_chunk() {
+        let code = format!("print \"{}\"; print 1;", "é".repeat(10_000));
+
+        let mut stream = StreamScanner::new(code.as_bytes());
+        let tokens: Vec<OwnedToken> = std::iter::from_fn(|| {
+            let token = stream.next_token().expect("Reading should not fail");
+            Some(token).filter(|token| token.kind() != TokenKind::Eof)
+        })
+        .collect();
+
+        let expected: Vec<OwnedToken> = Scanner::new(&code)
+            .into_iter()
+            .map(OwnedToken::from)
+            .collect();
+        assert_eq!(tokens, expected);
+    }
 }
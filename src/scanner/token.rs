@@ -1,9 +1,10 @@
-use crate::location::Location;
+use crate::location::{Location, Span};
 use std::fmt::Display;
 
 #[derive(Eq, PartialEq, Debug, Copy, Clone, Hash)]
 pub enum TokenErrorKind {
     UnterminatedString,
+    UnterminatedBlockComment,
     InvalidLexeme,
     SyntheticToken,
 }
@@ -151,4 +152,90 @@ impl<'sourcecode> Token<'sourcecode> {
     pub fn location(&self) -> Location {
         self.start_loc
     }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.start_loc, self.end_loc)
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone, Hash)]
+pub enum TriviaKind {
+    /// Spaces, tabs and carriage returns.
+    Whitespace,
+    Newline,
+    LineComment,
+    BlockComment,
+}
+
+/// Part of the source code that is not a token, like whitespace or a comment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Trivia<'sourcecode> {
+    kind: TriviaKind,
+    text: &'sourcecode str,
+    start_loc: Location,
+    end_loc: Location,
+}
+
+impl<'sourcecode> Trivia<'sourcecode> {
+    pub fn new(
+        kind: TriviaKind,
+        text: &'sourcecode str,
+        start_loc: Location,
+        end_loc: Location,
+    ) -> Self {
+        Self {
+            kind,
+            text,
+            start_loc,
+            end_loc,
+        }
+    }
+
+    pub fn kind(&self) -> TriviaKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &'sourcecode str {
+        self.text
+    }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.start_loc, self.end_loc)
+    }
+}
+
+/// Token with the trivia surrounding it, as produced by `Scanner::next_lossless_token`.
+/// Displaying every token of a program in order reproduces its source code exactly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LosslessToken<'sourcecode> {
+    pub leading: Vec<Trivia<'sourcecode>>,
+    pub token: Token<'sourcecode>,
+    pub trailing: Vec<Trivia<'sourcecode>>,
+}
+
+impl<'sourcecode> LosslessToken<'sourcecode> {
+    pub fn new(
+        leading: Vec<Trivia<'sourcecode>>,
+        token: Token<'sourcecode>,
+        trailing: Vec<Trivia<'sourcecode>>,
+    ) -> Self {
+        Self {
+            leading,
+            token,
+            trailing,
+        }
+    }
+}
+
+impl Display for LosslessToken<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for trivia in &self.leading {
+            write!(f, "{}", trivia.text)?;
+        }
+        write!(f, "{}", self.token.lexeme)?;
+        for trivia in &self.trailing {
+            write!(f, "{}", trivia.text)?;
+        }
+        Ok(())
+    }
 }
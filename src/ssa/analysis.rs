@@ -0,0 +1,388 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::heap::Ref;
+
+use super::ir::{Function, InstrKind, Var};
+
+/// Immediate dominator of each block, computed with the algorithm of Cooper, Harvey and
+/// Kennedy.
+#[derive(Debug, Clone)]
+pub struct Dominators {
+    idom: Vec<Option<usize>>,
+}
+
+impl Dominators {
+    pub fn compute(function: &Function) -> Self {
+        let order = function.reverse_postorder();
+        let predecessors = function.predecessors();
+
+        let mut position = vec![usize::MAX; function.blocks.len()];
+        for (index, block) in order.iter().enumerate() {
+            position[*block] = index;
+        }
+
+        let mut idom = vec![None; function.blocks.len()];
+        idom[0] = Some(0);
+
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while position[a] > position[b] {
+                    a = idom[a].expect("Processed block has a dominator");
+                }
+                while position[b] > position[a] {
+                    b = idom[b].expect("Processed block has a dominator");
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for block in order.iter().skip(1) {
+                let mut processed = predecessors[*block]
+                    .iter()
+                    .copied()
+                    .filter(|pred| idom[*pred].is_some());
+                let first = processed
+                    .next()
+                    .expect("Block has a predecessor before it in reverse postorder");
+                let new_idom =
+                    processed.fold(first, |new_idom, pred| intersect(&idom, pred, new_idom));
+
+                if idom[*block] != Some(new_idom) {
+                    idom[*block] = Some(new_idom);
+                    changed = true;
+                }
+            }
+        }
+
+        idom[0] = None;
+        Self { idom }
+    }
+
+    /// Closest block, other than itself, through which every path to `block` goes. `None` for
+    /// the entry and for unreachable blocks.
+    pub fn idom(&self, block: usize) -> Option<usize> {
+        self.idom[block]
+    }
+
+    /// Whether every path to `b` goes through `a`, which is the case if they are the same.
+    pub fn dominates(&self, a: usize, mut b: usize) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom[b] {
+                Some(idom) => b = idom,
+                None => return false,
+            }
+        }
+    }
+
+    /// Blocks immediately dominated by each block, which form the dominator tree.
+    pub fn children(&self) -> Vec<Vec<usize>> {
+        let mut children = vec![Vec::new(); self.idom.len()];
+        for (block, idom) in self.idom.iter().enumerate() {
+            if let Some(idom) = idom {
+                children[*idom].push(block);
+            }
+        }
+
+        children
+    }
+}
+
+/// Variables whose value may still be used on entry to and exit from each block.
+///
+/// A phi uses its arguments at the end of the corresponding predecessor, so they are live out
+/// of that block only, and it defines its variable at the start of its own block.
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    pub live_in: Vec<HashSet<Var>>,
+    pub live_out: Vec<HashSet<Var>>,
+}
+
+impl Liveness {
+    pub fn compute(function: &Function) -> Self {
+        let blocks = function.blocks.len();
+        let mut uses: Vec<HashSet<Var>> = vec![HashSet::new(); blocks];
+        let mut defs: Vec<HashSet<Var>> = vec![HashSet::new(); blocks];
+        let mut phi_uses: Vec<HashSet<Var>> = vec![HashSet::new(); blocks];
+
+        for (index, block) in function.blocks.iter().enumerate() {
+            for phi in block.phis.iter() {
+                defs[index].insert(phi.dest);
+                for (pred, arg) in phi.args.iter() {
+                    phi_uses[*pred].insert(*arg);
+                }
+            }
+            for instr in block.instrs.iter() {
+                for operand in instr.kind.operands() {
+                    if !defs[index].contains(&operand) {
+                        uses[index].insert(operand);
+                    }
+                }
+                if let Some(dest) = instr.dest {
+                    defs[index].insert(dest);
+                }
+            }
+            if let Some(operand) = block.terminator.operand() {
+                if !defs[index].contains(&operand) {
+                    uses[index].insert(operand);
+                }
+            }
+        }
+
+        let mut live_in: Vec<HashSet<Var>> = uses.clone();
+        let mut live_out: Vec<HashSet<Var>> = phi_uses;
+        let mut order = function.reverse_postorder();
+        order.reverse();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for block in order.iter() {
+                let mut out = live_out[*block].clone();
+                for successor in function.blocks[*block].terminator.successors() {
+                    out.extend(live_in[successor].iter().copied());
+                }
+
+                let mut live: HashSet<Var> = uses[*block].clone();
+                live.extend(out.difference(&defs[*block]).copied());
+
+                if live.len() != live_in[*block].len() || out.len() != live_out[*block].len() {
+                    live_in[*block] = live;
+                    live_out[*block] = out;
+                    changed = true;
+                }
+            }
+        }
+
+        Self { live_in, live_out }
+    }
+}
+
+/// Something that may change the value of a global.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Definition {
+    /// Whatever the global held when the function was called.
+    Entry { global: Ref<String> },
+    /// `SetGlobal` or `DefineGlobal` of the global, instruction `index` of `block`.
+    Store {
+        block: usize,
+        index: usize,
+        global: Ref<String>,
+        value: Var,
+    },
+    /// Call at instruction `index` of `block`, which may store the global.
+    Call {
+        block: usize,
+        index: usize,
+        global: Ref<String>,
+    },
+}
+
+impl Definition {
+    pub fn global(&self) -> Ref<String> {
+        match self {
+            Definition::Entry { global }
+            | Definition::Store { global, .. }
+            | Definition::Call { global, .. } => *global,
+        }
+    }
+}
+
+/// Definitions of globals that may reach each block, i.e. for which there is a path from the
+/// definition to the block along which the global is not stored again.
+///
+/// Only the globals the function refers to are tracked, calls defining each of them.
+#[derive(Debug, Clone)]
+pub struct ReachingDefinitions {
+    pub definitions: Vec<Definition>,
+    /// Indices in `definitions` of those reaching the start of each block.
+    pub reach_in: Vec<HashSet<usize>>,
+    /// Definitions of each global.
+    by_global: HashMap<Ref<String>, Vec<usize>>,
+    /// Definitions made by each instruction, for each block.
+    at: Vec<HashMap<usize, Vec<usize>>>,
+}
+
+impl ReachingDefinitions {
+    pub fn compute(function: &Function) -> Self {
+        let blocks = function.blocks.len();
+        let mut globals = Vec::new();
+        for instr in function.blocks.iter().flat_map(|block| block.instrs.iter()) {
+            if let InstrKind::GetGlobal(global)
+            | InstrKind::SetGlobal(global, _)
+            | InstrKind::DefineGlobal(global, _) = instr.kind
+            {
+                if !globals.contains(&global) {
+                    globals.push(global);
+                }
+            }
+        }
+
+        let mut definitions: Vec<Definition> = globals
+            .iter()
+            .map(|global| Definition::Entry { global: *global })
+            .collect();
+        let mut at = vec![HashMap::new(); blocks];
+
+        for (block, data) in function.blocks.iter().enumerate() {
+            for (index, instr) in data.instrs.iter().enumerate() {
+                let made = match instr.kind {
+                    InstrKind::SetGlobal(global, value)
+                    | InstrKind::DefineGlobal(global, value) => vec![Definition::Store {
+                        block,
+                        index,
+                        global,
+                        value,
+                    }],
+                    InstrKind::Call { .. } => globals
+                        .iter()
+                        .map(|global| Definition::Call {
+                            block,
+                            index,
+                            global: *global,
+                        })
+                        .collect(),
+                    _ => continue,
+                };
+
+                at[block].insert(index, (definitions.len()..).take(made.len()).collect());
+                definitions.extend(made);
+            }
+        }
+
+        let mut by_global: HashMap<Ref<String>, Vec<usize>> = HashMap::new();
+        for (id, definition) in definitions.iter().enumerate() {
+            by_global.entry(definition.global()).or_default().push(id);
+        }
+
+        let mut analysis = Self {
+            definitions,
+            reach_in: vec![HashSet::new(); blocks],
+            by_global,
+            at,
+        };
+        analysis.reach_in[0].extend(0..globals.len());
+
+        let predecessors = function.predecessors();
+        let order = function.reverse_postorder();
+        let mut reach_out: Vec<Option<HashSet<usize>>> = vec![None; blocks];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for block in order.iter() {
+                let mut reaching = analysis.reach_in[*block].clone();
+                for pred in predecessors[*block].iter() {
+                    if let Some(out) = &reach_out[*pred] {
+                        reaching.extend(out.iter().copied());
+                    }
+                }
+                analysis.reach_in[*block] = reaching.clone();
+
+                for index in 0..function.blocks[*block].instrs.len() {
+                    analysis.transfer(&mut reaching, *block, index);
+                }
+                if reach_out[*block].as_ref() != Some(&reaching) {
+                    reach_out[*block] = Some(reaching);
+                    changed = true;
+                }
+            }
+        }
+
+        analysis
+    }
+
+    /// Updates the definitions reaching instruction `index` of `block` into those reaching the
+    /// next instruction.
+    pub fn transfer(&self, reaching: &mut HashSet<usize>, block: usize, index: usize) {
+        let made = match self.at[block].get(&index) {
+            Some(made) => made,
+            None => return,
+        };
+
+        for id in made.iter() {
+            // A store is the only definition reaching past it, while a call may not define.
+            if let Definition::Store { global, .. } = self.definitions[*id] {
+                for other in self.by_global[&global].iter() {
+                    reaching.remove(other);
+                }
+            }
+            reaching.insert(*id);
+        }
+    }
+
+    /// Definitions among `reaching` that may give `global` its value.
+    pub fn of<'a>(
+        &'a self,
+        reaching: &'a HashSet<usize>,
+        global: Ref<String>,
+    ) -> impl Iterator<Item = &'a Definition> + 'a {
+        reaching
+            .iter()
+            .map(move |id| &self.definitions[*id])
+            .filter(move |definition| definition.global() == global)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::ssa::{test::lift_script, Var};
+
+    use super::{Definition, Dominators, Liveness, ReachingDefinitions};
+
+    #[test]
+    fn loop_header_dominates_body_and_exit() {
+        let (_, function) = lift_script("{ var i = 0; while (i < 3) i = i + 1; print i; }");
+        let dominators = Dominators::compute(&function);
+
+        let idoms: Vec<Option<usize>> = (0..4).map(|block| dominators.idom(block)).collect();
+        assert_eq!(idoms, vec![None, Some(0), Some(1), Some(1)]);
+        assert!(dominators.dominates(1, 3));
+        assert!(!dominators.dominates(3, 2));
+        assert_eq!(dominators.children()[1], vec![2, 3]);
+
+        // The loop variable is used after the loop and by the next iteration.
+        let liveness = Liveness::compute(&function);
+        let phi = function.blocks[1].phis[0].dest;
+        assert!(liveness.live_in[2].contains(&phi));
+        assert!(liveness.live_in[3].contains(&phi));
+        assert!(!liveness.live_in[1].contains(&phi));
+        let (_, next) = function.blocks[1].phis[0].args[1];
+        assert_eq!(
+            liveness.live_out[3],
+            [next].iter().copied().collect::<HashSet<Var>>()
+        );
+    }
+
+    #[test]
+    fn stores_reach_until_stored_again() {
+        let (mut heap, function) = lift_script("var g = 1; if (g) g = 2; print g;");
+        let reaching = ReachingDefinitions::compute(&function);
+        let g = heap.alloc_string("g".to_string());
+
+        let values = |block: usize| {
+            let mut values: Vec<Var> = reaching
+                .of(&reaching.reach_in[block], g)
+                .map(|definition| match definition {
+                    Definition::Store { value, .. } => *value,
+                    _ => panic!("Only stores of g should reach"),
+                })
+                .collect();
+            values.sort();
+            values
+        };
+
+        assert_eq!(values(1), vec![Var(1)]);
+        assert_eq!(values(3), vec![Var(1), Var(3)]);
+    }
+}
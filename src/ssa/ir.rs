@@ -0,0 +1,538 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+};
+
+use crate::{
+    chunk::{Instruction, Value},
+    heap::{Heap, Ref},
+    location::Span,
+};
+
+use super::analysis::{Dominators, Liveness};
+
+/// Value defined exactly once, by an instruction, a phi or on entry to the function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Var(pub u32);
+
+impl Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// Function in SSA form, its first block being the entry.
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// Values of the frame's slots on entry: the function being called, then its arguments.
+    pub params: Vec<Var>,
+    pub blocks: Vec<Block>,
+    /// Number of variables, which are numbered from 0.
+    pub vars: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub phis: Vec<Phi>,
+    pub instrs: Vec<Instr>,
+    pub terminator: Terminator,
+}
+
+/// Value of `dest` on entry to a block, chosen by the predecessor control came from.
+#[derive(Debug, Clone)]
+pub struct Phi {
+    pub dest: Var,
+    pub args: Vec<(usize, Var)>,
+}
+
+/// Instruction along with the code it was compiled from, so errors point at the same place.
+#[derive(Debug, Clone)]
+pub struct Instr {
+    pub dest: Option<Var>,
+    pub kind: InstrKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum InstrKind {
+    Const(Value),
+    /// `Negate` or `Not`.
+    Unary {
+        operator: Instruction,
+        operand: Var,
+    },
+    /// Any of the arithmetic or comparison instructions.
+    Binary {
+        operator: Instruction,
+        left: Var,
+        right: Var,
+    },
+    GetGlobal(Ref<String>),
+    SetGlobal(Ref<String>, Var),
+    DefineGlobal(Ref<String>, Var),
+    Call {
+        callee: Var,
+        arguments: Vec<Var>,
+    },
+    GetProperty {
+        object: Var,
+        name: Ref<String>,
+    },
+    /// Assigns the property, its result being `value`.
+    SetProperty {
+        object: Var,
+        name: Ref<String>,
+        value: Var,
+    },
+    Print(Var),
+}
+
+#[derive(Debug, Clone)]
+pub struct Terminator {
+    pub kind: TerminatorKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum TerminatorKind {
+    Jump(usize),
+    /// Goes to `then` unless `condition` is falsey.
+    Branch {
+        condition: Var,
+        then: usize,
+        otherwise: usize,
+    },
+    Return(Var),
+}
+
+impl Function {
+    pub fn new_var(&mut self) -> Var {
+        self.vars += 1;
+        Var(self.vars - 1)
+    }
+
+    /// Blocks that can jump to each block, in order.
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (index, block) in self.blocks.iter().enumerate() {
+            for successor in block.terminator.successors() {
+                predecessors[successor].push(index);
+            }
+        }
+
+        predecessors
+    }
+
+    /// Blocks reachable from the entry, each one before its successors except along back edges.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::with_capacity(self.blocks.len());
+        // Blocks along with how many of their successors were already pushed.
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+
+        while let Some((block, next)) = stack.pop() {
+            let successors = self.blocks[block].terminator.successors();
+            match successors.get(next) {
+                Some(&successor) => {
+                    stack.push((block, next + 1));
+                    if !visited[successor] {
+                        visited[successor] = true;
+                        stack.push((successor, 0));
+                    }
+                }
+                None => order.push(block),
+            }
+        }
+
+        order.reverse();
+        order
+    }
+
+    /// Number of times each variable is used, by instructions, phis and terminators.
+    pub fn uses(&self) -> Vec<usize> {
+        let mut uses = vec![0; self.vars as usize];
+        for block in self.blocks.iter() {
+            for phi in block.phis.iter() {
+                for (_, arg) in phi.args.iter() {
+                    uses[arg.0 as usize] += 1;
+                }
+            }
+            for instr in block.instrs.iter() {
+                for operand in instr.kind.operands() {
+                    uses[operand.0 as usize] += 1;
+                }
+            }
+            if let Some(operand) = block.terminator.operand() {
+                uses[operand.0 as usize] += 1;
+            }
+        }
+
+        uses
+    }
+
+    /// Replaces every use of the keys of `replacements` by their value, following chains.
+    pub fn replace_uses(&mut self, replacements: &HashMap<Var, Var>) {
+        if replacements.is_empty() {
+            return;
+        }
+
+        let resolve = |var: &mut Var| {
+            while let Some(replacement) = replacements.get(var) {
+                *var = *replacement;
+            }
+        };
+
+        for block in self.blocks.iter_mut() {
+            for phi in block.phis.iter_mut() {
+                phi.args.iter_mut().for_each(|(_, arg)| resolve(arg));
+            }
+            for instr in block.instrs.iter_mut() {
+                instr.kind.operands_mut().into_iter().for_each(resolve);
+            }
+            if let Some(operand) = block.terminator.operand_mut() {
+                resolve(operand);
+            }
+        }
+    }
+
+    /// Removes the blocks that can not be reached anymore and the phi arguments coming from
+    /// edges that no longer exist, then the phis left choosing between a single value. Blocks
+    /// only reached by a jump from another one are then merged into it.
+    pub fn cleanup(&mut self) {
+        self.renumber();
+
+        let predecessors = self.predecessors();
+        for (index, block) in self.blocks.iter_mut().enumerate() {
+            for phi in block.phis.iter_mut() {
+                phi.args
+                    .retain(|(pred, _)| predecessors[index].contains(pred));
+            }
+        }
+
+        self.remove_trivial_phis();
+        if self.merge_blocks() {
+            self.renumber();
+        }
+    }
+
+    /// Appends blocks to the block jumping to them if it is their only predecessor, returning
+    /// whether any was. Merged blocks are left unreachable.
+    fn merge_blocks(&mut self) -> bool {
+        let mut predecessors = self.predecessors();
+        let mut merged = false;
+
+        for block in 0..self.blocks.len() {
+            while let TerminatorKind::Jump(target) = self.blocks[block].terminator.kind {
+                if target == block || target == 0 || predecessors[target] != [block] {
+                    break;
+                }
+
+                let span = self.blocks[target].terminator.span;
+                let next = std::mem::replace(
+                    &mut self.blocks[target],
+                    Block {
+                        phis: Vec::new(),
+                        instrs: Vec::new(),
+                        terminator: Terminator {
+                            kind: TerminatorKind::Jump(target),
+                            span,
+                        },
+                    },
+                );
+                debug_assert!(
+                    next.phis.is_empty(),
+                    "Phis of a single predecessor are removed"
+                );
+
+                for successor in next.terminator.successors() {
+                    for pred in predecessors[successor].iter_mut() {
+                        if *pred == target {
+                            *pred = block;
+                        }
+                    }
+                    for phi in self.blocks[successor].phis.iter_mut() {
+                        for (pred, _) in phi.args.iter_mut() {
+                            if *pred == target {
+                                *pred = block;
+                            }
+                        }
+                    }
+                }
+                predecessors[target].clear();
+
+                self.blocks[block].instrs.extend(next.instrs);
+                self.blocks[block].terminator = next.terminator;
+                merged = true;
+            }
+        }
+
+        merged
+    }
+
+    /// Numbers blocks in reverse postorder, removing those that can not be reached along with
+    /// the phi arguments coming from them.
+    fn renumber(&mut self) {
+        let order = self.reverse_postorder();
+        let mut new_index = vec![None; self.blocks.len()];
+        for (index, block) in order.iter().enumerate() {
+            new_index[*block] = Some(index);
+        }
+
+        let mut blocks: Vec<Option<Block>> = std::mem::take(&mut self.blocks)
+            .into_iter()
+            .map(Some)
+            .collect();
+        self.blocks = order
+            .iter()
+            .map(|block| blocks[*block].take().expect("Block is visited once"))
+            .collect();
+
+        for block in self.blocks.iter_mut() {
+            for successor in block.terminator.successors_mut() {
+                *successor = new_index[*successor].expect("Successor is reachable");
+            }
+            for phi in block.phis.iter_mut() {
+                phi.args = phi
+                    .args
+                    .iter()
+                    .filter_map(|(pred, arg)| new_index[*pred].map(|pred| (pred, *arg)))
+                    .collect();
+            }
+        }
+    }
+
+    /// Removes the phis that always have the same value, using that value instead.
+    pub fn remove_trivial_phis(&mut self) {
+        let mut replacements: HashMap<Var, Var> = HashMap::new();
+        let resolve = |replacements: &HashMap<Var, Var>, mut var: Var| {
+            while let Some(replacement) = replacements.get(&var) {
+                var = *replacement;
+            }
+            var
+        };
+
+        loop {
+            let mut changed = false;
+
+            for block in self.blocks.iter() {
+                for phi in block.phis.iter() {
+                    if replacements.contains_key(&phi.dest) {
+                        continue;
+                    }
+
+                    let mut values = phi
+                        .args
+                        .iter()
+                        .map(|(_, arg)| resolve(&replacements, *arg))
+                        .filter(|arg| *arg != phi.dest);
+                    let value = match values.next() {
+                        Some(value) => value,
+                        // Only reachable from itself, which can not happen for a reachable block.
+                        None => continue,
+                    };
+
+                    if values.all(|other| other == value) {
+                        replacements.insert(phi.dest, value);
+                        changed = true;
+                    }
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        for block in self.blocks.iter_mut() {
+            block
+                .phis
+                .retain(|phi| !replacements.contains_key(&phi.dest));
+        }
+        self.replace_uses(&replacements);
+    }
+
+    /// Pretty-printed function, constants being shown the way scripts see them. Each block is
+    /// annotated with its immediate dominator and the variables live on entry to it.
+    pub fn listing(&self, heap: &Heap) -> String {
+        let dominators = Dominators::compute(self);
+        let liveness = Liveness::compute(self);
+        let join = |vars: &mut dyn Iterator<Item = Var>| {
+            let vars: Vec<String> = vars.map(|var| var.to_string()).collect();
+            vars.join(", ")
+        };
+
+        let mut listing = format!("params {}\n", join(&mut self.params.iter().copied()));
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut live: Vec<Var> = liveness.live_in[index].iter().copied().collect();
+            live.sort();
+            listing += &format!("B{}:", index);
+            if let Some(idom) = dominators.idom(index) {
+                listing += &format!(" ; idom B{}", idom);
+            }
+            if !live.is_empty() {
+                listing += &format!(" ; live {}", join(&mut live.into_iter()));
+            }
+            listing += "\n";
+
+            for phi in block.phis.iter() {
+                let args: Vec<String> = phi
+                    .args
+                    .iter()
+                    .map(|(pred, arg)| format!("B{}: {}", pred, arg))
+                    .collect();
+                listing += &format!("    {} = phi {}\n", phi.dest, args.join(", "));
+            }
+            for instr in block.instrs.iter() {
+                listing += "    ";
+                if let Some(dest) = instr.dest {
+                    listing += &format!("{} = ", dest);
+                }
+                listing += &format!("{}\n", instr.kind.display(heap));
+            }
+            listing += &format!("    {}\n", block.terminator.kind);
+        }
+
+        listing
+    }
+}
+
+impl InstrKind {
+    pub fn operands(&self) -> Vec<Var> {
+        match self {
+            InstrKind::Const(_) | InstrKind::GetGlobal(_) => Vec::new(),
+            InstrKind::Unary { operand, .. } => vec![*operand],
+            InstrKind::Binary { left, right, .. } => vec![*left, *right],
+            InstrKind::SetGlobal(_, value)
+            | InstrKind::DefineGlobal(_, value)
+            | InstrKind::Print(value) => vec![*value],
+            InstrKind::Call { callee, arguments } => std::iter::once(*callee)
+                .chain(arguments.iter().copied())
+                .collect(),
+            InstrKind::GetProperty { object, .. } => vec![*object],
+            InstrKind::SetProperty { object, value, .. } => vec![*object, *value],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Var> {
+        match self {
+            InstrKind::Const(_) | InstrKind::GetGlobal(_) => Vec::new(),
+            InstrKind::Unary { operand, .. } => vec![operand],
+            InstrKind::Binary { left, right, .. } => vec![left, right],
+            InstrKind::SetGlobal(_, value)
+            | InstrKind::DefineGlobal(_, value)
+            | InstrKind::Print(value) => vec![value],
+            InstrKind::Call { callee, arguments } => std::iter::once(callee)
+                .chain(arguments.iter_mut())
+                .collect(),
+            InstrKind::GetProperty { object, .. } => vec![object],
+            InstrKind::SetProperty { object, value, .. } => vec![object, value],
+        }
+    }
+
+    fn display(&self, heap: &Heap) -> String {
+        match self {
+            InstrKind::Const(value @ Value::String(_)) => {
+                format!("const {:?}", value.format(heap))
+            }
+            InstrKind::Const(value) => format!("const {}", value.format(heap)),
+            InstrKind::Unary { operator, operand } => {
+                format!("{}{}", operator_symbol(*operator), operand)
+            }
+            InstrKind::Binary {
+                operator,
+                left,
+                right,
+            } => format!("{} {} {}", left, operator_symbol(*operator), right),
+            InstrKind::GetGlobal(name) => format!("global {}", heap.deref(*name)),
+            InstrKind::SetGlobal(name, value) => format!("set {} {}", heap.deref(*name), value),
+            InstrKind::DefineGlobal(name, value) => {
+                format!("define {} {}", heap.deref(*name), value)
+            }
+            InstrKind::Call { callee, arguments } => {
+                let arguments: Vec<String> = arguments.iter().map(|arg| arg.to_string()).collect();
+                format!("call {}({})", callee, arguments.join(", "))
+            }
+            InstrKind::GetProperty { object, name } => {
+                format!("{}.{}", object, heap.deref(*name))
+            }
+            InstrKind::SetProperty {
+                object,
+                name,
+                value,
+            } => format!("{}.{} = {}", object, heap.deref(*name), value),
+            InstrKind::Print(value) => format!("print {}", value),
+        }
+    }
+}
+
+/// Symbol of the operator an instruction implements.
+fn operator_symbol(operator: Instruction) -> &'static str {
+    match operator {
+        Instruction::Negate | Instruction::Subtract => "-",
+        Instruction::Not => "!",
+        Instruction::Add => "+",
+        Instruction::Multiply => "*",
+        Instruction::Divide => "/",
+        Instruction::Equal => "==",
+        Instruction::NotEqual => "!=",
+        Instruction::Greater => ">",
+        Instruction::Less => "<",
+        Instruction::GreaterEqual => ">=",
+        Instruction::LessEqual => "<=",
+        inst => panic!("Instruction {:?} is not an operator", inst),
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<usize> {
+        match self.kind {
+            TerminatorKind::Jump(target) => vec![target],
+            TerminatorKind::Branch {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            TerminatorKind::Return(_) => Vec::new(),
+        }
+    }
+
+    fn successors_mut(&mut self) -> Vec<&mut usize> {
+        match &mut self.kind {
+            TerminatorKind::Jump(target) => vec![target],
+            TerminatorKind::Branch {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            TerminatorKind::Return(_) => Vec::new(),
+        }
+    }
+
+    pub fn operand(&self) -> Option<Var> {
+        match self.kind {
+            TerminatorKind::Jump(_) => None,
+            TerminatorKind::Branch { condition, .. } => Some(condition),
+            TerminatorKind::Return(value) => Some(value),
+        }
+    }
+
+    pub fn operand_mut(&mut self) -> Option<&mut Var> {
+        match &mut self.kind {
+            TerminatorKind::Jump(_) => None,
+            TerminatorKind::Branch { condition, .. } => Some(condition),
+            TerminatorKind::Return(value) => Some(value),
+        }
+    }
+}
+
+impl Display for TerminatorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerminatorKind::Jump(target) => write!(f, "jump B{}", target),
+            TerminatorKind::Branch {
+                condition,
+                then,
+                otherwise,
+            } => write!(f, "branch {} B{} B{}", condition, then, otherwise),
+            TerminatorKind::Return(value) => write!(f, "return {}", value),
+        }
+    }
+}
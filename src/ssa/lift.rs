@@ -0,0 +1,390 @@
+use crate::{
+    cfg::Cfg,
+    chunk::{Chunk, Instruction, Value},
+    heap::Ref,
+    location::Span,
+};
+
+use super::ir::{Block, Function, Instr, InstrKind, Phi, Terminator, TerminatorKind, Var};
+
+/// Lifts the bytecode of a function taking `arity` arguments into SSA form.
+///
+/// The stack is simulated through each basic block, each slot holding the variable last pushed
+/// or stored there, so locals and temporaries alike become variables. Blocks reached from
+/// several places start with a phi for every slot, the ones that turn out to always have the
+/// same value being removed afterwards along with jumps to blocks that only have one predecessor. Returns `None` for code the compiler does not generate,
+/// e.g. if the stack does not have the same height on every path to a block.
+pub fn lift(chunk: &Chunk, arity: usize) -> Option<Function> {
+    if chunk.code.is_empty() {
+        return None;
+    }
+
+    let cfg = Cfg::from_chunk(chunk);
+    let order = cfg_order(&cfg);
+
+    // Blocks are numbered in reverse postorder after an entry block, which holds the parameters
+    // and is needed in case a loop jumps back to the first instruction.
+    let mut new_index = vec![None; cfg.blocks.len()];
+    for (index, block) in order.iter().enumerate() {
+        new_index[*block] = Some(index + 1);
+    }
+    let mut block_at = vec![None; chunk.code.len() + 1];
+    for (index, block) in cfg.blocks.iter().enumerate() {
+        block_at[block.start] = new_index[index];
+    }
+
+    let mut function = Function {
+        params: Vec::new(),
+        blocks: Vec::with_capacity(order.len() + 1),
+        vars: 0,
+    };
+    function.params = (0..=arity).map(|_| function.new_var()).collect();
+    function.blocks.push(Block {
+        phis: Vec::new(),
+        instrs: Vec::new(),
+        terminator: Terminator {
+            kind: TerminatorKind::Jump(1),
+            span: chunk.get_span(0),
+        },
+    });
+
+    // Stack at the end of each block, and the blocks that can jump to each one.
+    let mut exits: Vec<Option<Vec<Var>>> = vec![None; order.len() + 1];
+    exits[0] = Some(function.params.clone());
+    let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); order.len() + 1];
+
+    for (index, block) in order.iter().enumerate() {
+        let index = index + 1;
+        let data = &cfg.blocks[*block];
+        predecessors[index] = data
+            .predecessors
+            .iter()
+            .filter_map(|pred| new_index[*pred])
+            .collect();
+        if *block == 0 {
+            predecessors[index].insert(0, 0);
+        }
+
+        let mut phis = Vec::new();
+        let stack = match predecessors[index].as_slice() {
+            [pred] => exits[*pred].clone()?,
+            preds => {
+                let height = preds.iter().find_map(|pred| exits[*pred].as_ref())?.len();
+                (0..height)
+                    .map(|_| {
+                        let dest = function.new_var();
+                        phis.push(Phi {
+                            dest,
+                            args: Vec::new(),
+                        });
+                        dest
+                    })
+                    .collect()
+            }
+        };
+
+        let mut lifter = Lifter {
+            chunk,
+            function: &mut function,
+            stack,
+            instrs: Vec::new(),
+            end: data.end,
+            merged: None,
+        };
+        for index in data.start..data.end - 1 {
+            lifter.instruction(index)?;
+        }
+
+        let last = data.end - 1;
+        let block_of = |offset: usize| block_at.get(offset).copied().flatten();
+
+        let kind = match chunk.code[last] {
+            Instruction::Jump(offset) => {
+                TerminatorKind::Jump(block_of(last + 1 + offset as usize)?)
+            }
+            Instruction::Loop(offset) => {
+                TerminatorKind::Jump(block_of(last + 1 - offset as usize)?)
+            }
+            Instruction::JumpIfFalse(0) => TerminatorKind::Jump(block_of(data.end)?),
+            Instruction::JumpIfFalse(offset) => TerminatorKind::Branch {
+                condition: *lifter.stack.last()?,
+                then: block_of(data.end)?,
+                otherwise: block_of(last + 1 + offset as usize)?,
+            },
+            Instruction::Return => TerminatorKind::Return(lifter.stack.pop()?),
+            _ => {
+                lifter.instruction(last)?;
+                TerminatorKind::Jump(block_of(data.end)?)
+            }
+        };
+
+        let instrs = lifter.instrs;
+        exits[index] = Some(lifter.stack);
+        function.blocks.push(Block {
+            phis,
+            instrs,
+            terminator: Terminator {
+                kind,
+                span: chunk.get_span(last),
+            },
+        });
+    }
+
+    for (index, block) in function.blocks.iter_mut().enumerate() {
+        if block.phis.is_empty() {
+            continue;
+        }
+
+        for pred in predecessors[index].iter() {
+            let exit = exits[*pred].as_ref()?;
+            if exit.len() != block.phis.len() {
+                return None;
+            }
+
+            for (phi, arg) in block.phis.iter_mut().zip(exit.iter()) {
+                phi.args.push((*pred, *arg));
+            }
+        }
+    }
+
+    function.cleanup();
+    Some(function)
+}
+
+/// Reverse postorder of the blocks reachable from the first one.
+fn cfg_order(cfg: &Cfg) -> Vec<usize> {
+    let mut visited = vec![false; cfg.blocks.len()];
+    let mut order = Vec::with_capacity(cfg.blocks.len());
+    let mut stack = vec![(0, 0)];
+    visited[0] = true;
+
+    while let Some((block, next)) = stack.pop() {
+        match cfg.blocks[block].successors.get(next) {
+            Some(&successor) => {
+                stack.push((block, next + 1));
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            }
+            None => order.push(block),
+        }
+    }
+
+    order.reverse();
+    order
+}
+
+/// Simulates the stack through the instructions of a block, emitting those that compute values.
+struct Lifter<'a> {
+    chunk: &'a Chunk,
+    function: &'a mut Function,
+    stack: Vec<Var>,
+    instrs: Vec<Instr>,
+    /// End of the block being lifted.
+    end: usize,
+    /// Index of a `Not` merged into the comparison before it.
+    merged: Option<usize>,
+}
+
+impl<'a> Lifter<'a> {
+    fn instruction(&mut self, index: usize) -> Option<()> {
+        if self.merged == Some(index) {
+            return Some(());
+        }
+
+        let chunk = self.chunk;
+        let span = chunk.get_span(index);
+        let constant = |idx: u16| chunk.constants.get(idx as usize).copied();
+        let name = |idx: u16| match constant(idx) {
+            Some(Value::String(name)) => Some(name),
+            _ => None,
+        };
+
+        match self.chunk.code[index] {
+            Instruction::Constant(idx) => {
+                let value = constant(idx)?;
+                self.push(InstrKind::Const(value), index);
+            }
+            Instruction::Nil => self.push(InstrKind::Const(Value::Nil), index),
+            Instruction::True => self.push(InstrKind::Const(Value::Bool(true)), index),
+            Instruction::False => self.push(InstrKind::Const(Value::Bool(false)), index),
+            operator @ Instruction::Negate | operator @ Instruction::Not => {
+                let operand = self.stack.pop()?;
+                self.push(InstrKind::Unary { operator, operand }, index);
+            }
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Equal
+            | Instruction::Greater
+            | Instruction::Less
+            | Instruction::NotEqual
+            | Instruction::GreaterEqual
+            | Instruction::LessEqual => {
+                let right = self.stack.pop()?;
+                let left = self.stack.pop()?;
+                let operator = self.negated(index);
+                self.push(
+                    InstrKind::Binary {
+                        operator,
+                        left,
+                        right,
+                    },
+                    index,
+                );
+            }
+            Instruction::Print => {
+                let value = self.stack.pop()?;
+                self.emit(None, InstrKind::Print(value), span);
+            }
+            Instruction::Pop => {
+                self.stack.pop()?;
+            }
+            Instruction::DefineGlobal(idx) => {
+                let value = self.stack.pop()?;
+                self.emit(None, InstrKind::DefineGlobal(name(idx)?, value), span);
+            }
+            Instruction::GetGlobal(idx) => {
+                let name: Ref<String> = name(idx)?;
+                self.push(InstrKind::GetGlobal(name), index);
+            }
+            Instruction::SetGlobal(idx) => {
+                let value = *self.stack.last()?;
+                self.emit(None, InstrKind::SetGlobal(name(idx)?, value), span);
+            }
+            Instruction::GetLocal(slot) => {
+                let value = *self.stack.get(slot as usize)?;
+                self.stack.push(value);
+            }
+            Instruction::SetLocal(slot) => {
+                let value = *self.stack.last()?;
+                *self.stack.get_mut(slot as usize)? = value;
+            }
+            Instruction::Call(arg_count) => {
+                let start = self.stack.len().checked_sub(arg_count as usize + 1)?;
+                let mut operands = self.stack.split_off(start).into_iter();
+                let callee = operands.next()?;
+                let arguments = operands.collect();
+                self.push(InstrKind::Call { callee, arguments }, index);
+            }
+            Instruction::GetProperty(idx) => {
+                let object = self.stack.pop()?;
+                let name = name(idx)?;
+                self.push(InstrKind::GetProperty { object, name }, index);
+            }
+            Instruction::SetProperty(idx) => {
+                let value = self.stack.pop()?;
+                let object = self.stack.pop()?;
+                let name = name(idx)?;
+                self.emit(
+                    None,
+                    InstrKind::SetProperty {
+                        object,
+                        name,
+                        value,
+                    },
+                    span,
+                );
+                self.stack.push(value);
+            }
+            Instruction::Jump(_)
+            | Instruction::JumpIfFalse(_)
+            | Instruction::Loop(_)
+            | Instruction::Return => return None,
+            // Only fused by the peephole optimizer, which runs after the SSA passes.
+            Instruction::AddConstant(_)
+            | Instruction::SubtractConstant(_)
+            | Instruction::AddLocals(..)
+            | Instruction::SetLocalPop(_) => return None,
+        }
+
+        Some(())
+    }
+
+    /// Operator of the comparison at `index`, the `Not` that follows it for the same operator
+    /// being merged into it so errors still name that operator, e.g. `Less, Not` is `>=`.
+    fn negated(&mut self, index: usize) -> Instruction {
+        let operator = self.chunk.code[index];
+        let negated = match operator {
+            Instruction::Equal => Instruction::NotEqual,
+            Instruction::Less => Instruction::GreaterEqual,
+            Instruction::Greater => Instruction::LessEqual,
+            _ => return operator,
+        };
+
+        let next = index + 1;
+        let merged = next < self.end
+            && self.chunk.code[next] == Instruction::Not
+            && self.chunk.get_span(next) == self.chunk.get_span(index);
+        if merged {
+            self.merged = Some(next);
+            negated
+        } else {
+            operator
+        }
+    }
+
+    fn push(&mut self, kind: InstrKind, index: usize) {
+        let dest = self.function.new_var();
+        self.emit(Some(dest), kind, self.chunk.get_span(index));
+        self.stack.push(dest);
+    }
+
+    fn emit(&mut self, dest: Option<Var>, kind: InstrKind, span: Span) {
+        self.instrs.push(Instr { dest, kind, span });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::Instruction,
+        ssa::{test::lift_script, InstrKind, TerminatorKind},
+    };
+
+    #[test]
+    fn loop_variables_get_phis() {
+        let (_, function) = lift_script("{ var i = 0; while (i < 3) i = i + 1; print i; }");
+
+        let header = &function.blocks[1];
+        assert_eq!(header.phis.len(), 1);
+        let preds: Vec<usize> = header.phis[0].args.iter().map(|(pred, _)| *pred).collect();
+        assert_eq!(preds, vec![0, 3]);
+        assert!(matches!(
+            header.terminator.kind,
+            TerminatorKind::Branch {
+                then: 3,
+                otherwise: 2,
+                ..
+            }
+        ));
+
+        // Blocks with a single predecessor read the stack of that predecessor instead.
+        assert!(function
+            .blocks
+            .iter()
+            .skip(2)
+            .all(|block| block.phis.is_empty()));
+    }
+
+    #[test]
+    fn negated_comparisons_become_one_instruction() {
+        let (_, function) = lift_script("{ var a = 1; print a >= 2; print a != 3; }");
+
+        let operators: Vec<Instruction> = function.blocks[0]
+            .instrs
+            .iter()
+            .filter_map(|instr| match instr.kind {
+                InstrKind::Binary { operator, .. } => Some(operator),
+                InstrKind::Unary { .. } => panic!("Negation should be merged"),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            operators,
+            vec![Instruction::GreaterEqual, Instruction::NotEqual]
+        );
+    }
+}
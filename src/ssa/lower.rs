@@ -0,0 +1,507 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+};
+
+use crate::{
+    chunk::{Chunk, Instruction, Value},
+    heap::Ref,
+    location::Span,
+    objects::Function as Object,
+};
+
+use super::{
+    analysis::Liveness,
+    ir::{Function, Instr, InstrKind, TerminatorKind, Var},
+};
+
+/// Lowers a function back to bytecode, or returns `None` if it needs more slots, constants or
+/// longer jumps than instructions can hold.
+///
+/// Variables are kept in the frame's slots, variables that are never live at the same time
+/// sharing a slot. Constants are loaded again where they are used instead, and values used
+/// once, right where the stack has them on top, are left there. Phis are assigned on each edge
+/// by pushing all their arguments before storing any, so they are assigned at the same time.
+pub fn lower(function: &Function) -> Option<Chunk> {
+    let uses = function.uses();
+    let constants = constants(function);
+    let pending = pending(function, &uses, &constants);
+    let slots = allocate_slots(function, &uses, &constants, &pending)?;
+
+    let mut lowering = Lowering {
+        function,
+        chunk: Chunk::new(),
+        constants: &constants,
+        pending: &pending,
+        slots: &slots,
+        uses: &uses,
+        pool: HashMap::new(),
+        block_start: vec![None; function.blocks.len()],
+        jumps: Vec::new(),
+    };
+    lowering.emit()?;
+
+    Some(lowering.chunk)
+}
+
+/// Value of the variables defined by constants.
+fn constants(function: &Function) -> HashMap<Var, Value> {
+    let mut constants = HashMap::new();
+    for block in function.blocks.iter() {
+        for instr in block.instrs.iter() {
+            if let (Some(dest), InstrKind::Const(value)) = (instr.dest, &instr.kind) {
+                constants.insert(dest, *value);
+            }
+        }
+    }
+
+    constants
+}
+
+/// Operands of an instruction, then of the terminator of its block.
+fn operands(function: &Function, block: usize, index: usize) -> Vec<Var> {
+    let data = &function.blocks[block];
+    match data.instrs.get(index) {
+        Some(instr) => instr.kind.operands(),
+        None => data.terminator.operand().into_iter().collect(),
+    }
+}
+
+/// Variables that stay on the stack from the instruction defining them to the one using them.
+///
+/// Candidates are used once, later in the same block, and their use must find them on top of
+/// the stack in the order it takes its operands. Candidates that would not be are given up on
+/// until the stack works out for every instruction of the block.
+fn pending(function: &Function, uses: &[usize], constants: &HashMap<Var, Value>) -> HashSet<Var> {
+    let mut pending = HashSet::new();
+
+    for (block, data) in function.blocks.iter().enumerate() {
+        let mut defined = HashSet::new();
+        let mut candidates = HashSet::new();
+        for index in 0..=data.instrs.len() {
+            for operand in operands(function, block, index) {
+                if defined.contains(&operand) && uses[operand.0 as usize] == 1 {
+                    candidates.insert(operand);
+                }
+            }
+            if let Some(dest) = data.instrs.get(index).and_then(|instr| instr.dest) {
+                if !constants.contains_key(&dest) {
+                    defined.insert(dest);
+                }
+            }
+        }
+
+        loop {
+            let mut stack: Vec<Var> = Vec::new();
+            let mut rejected = None;
+
+            'simulation: for index in 0..=data.instrs.len() {
+                let operands = operands(function, block, index);
+                let count = operands
+                    .iter()
+                    .take_while(|operand| candidates.contains(operand))
+                    .count();
+
+                if let Some(operand) = operands[count..]
+                    .iter()
+                    .find(|operand| candidates.contains(operand))
+                {
+                    rejected = Some(*operand);
+                    break 'simulation;
+                }
+                for operand in operands[..count].iter().rev() {
+                    if stack.pop() != Some(*operand) {
+                        rejected = Some(*operand);
+                        break 'simulation;
+                    }
+                }
+
+                if let Some(dest) = data.instrs.get(index).and_then(|instr| instr.dest) {
+                    if candidates.contains(&dest) {
+                        stack.push(dest);
+                    }
+                }
+            }
+
+            match rejected {
+                Some(var) => {
+                    candidates.remove(&var);
+                }
+                None => break,
+            }
+        }
+
+        pending.extend(candidates);
+    }
+
+    pending
+}
+
+/// Slot of each variable that needs one. The arguments stay in the slots they are passed in,
+/// and a phi preferably gets the slot of its arguments, so no copy is needed to assign it.
+fn allocate_slots(
+    function: &Function,
+    uses: &[usize],
+    constants: &HashMap<Var, Value>,
+    pending: &HashSet<Var>,
+) -> Option<HashMap<Var, u16>> {
+    let needs_slot = |var: &Var| {
+        uses[var.0 as usize] > 0 && !constants.contains_key(var) && !pending.contains(var)
+    };
+
+    let liveness = Liveness::compute(function);
+    let mut interference: HashMap<Var, HashSet<Var>> = HashMap::new();
+    let mut interfere = |a: Var, live: &HashSet<Var>| {
+        for b in live.iter().filter(|b| **b != a) {
+            interference.entry(a).or_default().insert(*b);
+            interference.entry(*b).or_default().insert(a);
+        }
+    };
+
+    for (index, block) in function.blocks.iter().enumerate() {
+        let mut live: HashSet<Var> = liveness.live_out[index]
+            .iter()
+            .copied()
+            .filter(needs_slot)
+            .collect();
+        live.extend(block.terminator.operand().filter(needs_slot));
+
+        for instr in block.instrs.iter().rev() {
+            if let Some(dest) = instr.dest.filter(needs_slot) {
+                live.remove(&dest);
+                interfere(dest, &live);
+            }
+            live.extend(instr.kind.operands().into_iter().filter(needs_slot));
+        }
+
+        let dests: Vec<Var> = block.phis.iter().map(|phi| phi.dest).collect();
+        live.extend(dests.iter().copied().filter(needs_slot));
+        for dest in dests.iter().filter(|dest| needs_slot(dest)) {
+            interfere(*dest, &live);
+        }
+        if index == 0 {
+            for param in function.params.iter() {
+                interfere(*param, &live);
+            }
+        }
+    }
+
+    let mut related: HashMap<Var, Vec<Var>> = HashMap::new();
+    for block in function.blocks.iter() {
+        for phi in block.phis.iter() {
+            for (_, arg) in phi.args.iter() {
+                related.entry(phi.dest).or_default().push(*arg);
+                related.entry(*arg).or_default().push(phi.dest);
+            }
+        }
+    }
+
+    let mut slots: HashMap<Var, u16> = HashMap::new();
+    for (slot, param) in function.params.iter().enumerate() {
+        slots.insert(*param, u16::try_from(slot).ok()?);
+    }
+
+    for var in (0..function.vars).map(Var) {
+        if slots.contains_key(&var) || !needs_slot(&var) {
+            continue;
+        }
+
+        let taken: HashSet<u16> = interference
+            .get(&var)
+            .into_iter()
+            .flatten()
+            .filter_map(|other| slots.get(other).copied())
+            .collect();
+        let preferred = related
+            .get(&var)
+            .into_iter()
+            .flatten()
+            .filter_map(|other| slots.get(other).copied())
+            .find(|slot| *slot != 0 && !taken.contains(slot));
+
+        // The first slot holds the function being called.
+        let slot = match preferred {
+            Some(slot) => slot,
+            None => (1..=u16::MAX).find(|slot| !taken.contains(slot))?,
+        };
+        slots.insert(var, slot);
+    }
+
+    Some(slots)
+}
+
+struct Lowering<'a> {
+    function: &'a Function,
+    chunk: Chunk,
+    constants: &'a HashMap<Var, Value>,
+    pending: &'a HashSet<Var>,
+    slots: &'a HashMap<Var, u16>,
+    uses: &'a [usize],
+    /// Index of the constants already added to the chunk.
+    pool: HashMap<Constant, u16>,
+    block_start: Vec<Option<usize>>,
+    /// Jumps to patch once every block has been emitted, along with the block they go to.
+    jumps: Vec<(usize, usize)>,
+}
+
+/// Constant of the chunk, compared by identity so each one is only added once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Constant {
+    Number(u64),
+    String(Ref<String>),
+    Function(Ref<Object>),
+}
+
+impl<'a> Lowering<'a> {
+    fn emit(&mut self) -> Option<()> {
+        let function = self.function;
+        let frame = self.slots.values().copied().max().unwrap_or(0) as usize + 1;
+        let entry = function.blocks[0].terminator.span;
+        for _ in function.params.len()..frame {
+            self.chunk.write(Instruction::Nil, entry);
+        }
+
+        // Branches jump to code after the last block that pops the condition, assigns the phis
+        // of the block they go to and jumps there.
+        let mut otherwise: Vec<(usize, usize, usize, Span)> = Vec::new();
+
+        for (index, block) in function.blocks.iter().enumerate() {
+            self.block_start[index] = Some(self.chunk.code.len());
+
+            for instr in block.instrs.iter() {
+                self.instruction(instr)?;
+            }
+
+            let span = block.terminator.span;
+            match block.terminator.kind {
+                TerminatorKind::Jump(target) => {
+                    self.assign_phis(index, target, span)?;
+                    if target != index + 1 {
+                        self.jump(target, span);
+                    }
+                }
+                TerminatorKind::Branch {
+                    condition,
+                    then,
+                    otherwise: target,
+                } => {
+                    self.push(condition, span)?;
+                    let branch = self.chunk.write(Instruction::JumpIfFalse(0), span);
+                    otherwise.push((branch, index, target, span));
+
+                    self.chunk.write(Instruction::Pop, span);
+                    self.assign_phis(index, then, span)?;
+                    if then != index + 1 {
+                        self.jump(then, span);
+                    }
+                }
+                TerminatorKind::Return(value) => {
+                    self.push(value, span)?;
+                    self.chunk.write(Instruction::Return, span);
+                }
+            }
+        }
+
+        for (branch, block, target, span) in otherwise {
+            let offset = self.chunk.code.len() - branch - 1;
+            self.chunk.code[branch] = Instruction::JumpIfFalse(u16::try_from(offset).ok()?);
+
+            self.chunk.write(Instruction::Pop, span);
+            self.assign_phis(block, target, span)?;
+            self.jump(target, span);
+        }
+
+        for (jump, target) in std::mem::take(&mut self.jumps) {
+            let start = self.block_start[target].expect("Every block is emitted");
+            self.chunk.code[jump] = if start > jump {
+                Instruction::Jump(u16::try_from(start - jump - 1).ok()?)
+            } else {
+                Instruction::Loop(u16::try_from(jump + 1 - start).ok()?)
+            };
+        }
+
+        Some(())
+    }
+
+    fn instruction(&mut self, instr: &Instr) -> Option<()> {
+        let span = instr.span;
+        let dest = match instr.dest {
+            Some(dest) if self.constants.contains_key(&dest) => return Some(()),
+            dest => dest,
+        };
+
+        for operand in instr.kind.operands() {
+            self.push(operand, span)?;
+        }
+
+        let (instruction, pushes) = match &instr.kind {
+            InstrKind::Const(_) => unreachable!("Constants are loaded where they are used"),
+            InstrKind::Unary { operator, .. } | InstrKind::Binary { operator, .. } => {
+                (*operator, true)
+            }
+            InstrKind::GetGlobal(name) => {
+                let index = self.constant(Value::String(*name))?;
+                (Instruction::GetGlobal(index), true)
+            }
+            InstrKind::SetGlobal(name, _) => {
+                let index = self.constant(Value::String(*name))?;
+                self.chunk.write(Instruction::SetGlobal(index), span);
+                (Instruction::Pop, false)
+            }
+            InstrKind::DefineGlobal(name, _) => {
+                let index = self.constant(Value::String(*name))?;
+                (Instruction::DefineGlobal(index), false)
+            }
+            InstrKind::Call { arguments, .. } => (
+                Instruction::Call(u16::try_from(arguments.len()).ok()?),
+                true,
+            ),
+            InstrKind::GetProperty { name, .. } => {
+                let index = self.constant(Value::String(*name))?;
+                (Instruction::GetProperty(index), true)
+            }
+            InstrKind::SetProperty { name, .. } => {
+                let index = self.constant(Value::String(*name))?;
+                self.chunk.write(Instruction::SetProperty(index), span);
+                (Instruction::Pop, false)
+            }
+            InstrKind::Print(_) => (Instruction::Print, false),
+        };
+        self.chunk.write(instruction, span);
+
+        if pushes {
+            match dest {
+                Some(dest) if self.pending.contains(&dest) => {}
+                Some(dest) if self.uses[dest.0 as usize] > 0 => {
+                    self.chunk
+                        .write(Instruction::SetLocal(self.slots[&dest]), span);
+                    self.chunk.write(Instruction::Pop, span);
+                }
+                _ => {
+                    self.chunk.write(Instruction::Pop, span);
+                }
+            }
+        }
+
+        Some(())
+    }
+
+    /// Pushes the value of `var`, unless it was left on the stack.
+    fn push(&mut self, var: Var, span: Span) -> Option<()> {
+        if let Some(value) = self.constants.get(&var) {
+            let instruction = match value {
+                Value::Nil => Instruction::Nil,
+                Value::Bool(true) => Instruction::True,
+                Value::Bool(false) => Instruction::False,
+                value => Instruction::Constant(self.constant(*value)?),
+            };
+            self.chunk.write(instruction, span);
+        } else if !self.pending.contains(&var) {
+            self.chunk
+                .write(Instruction::GetLocal(self.slots[&var]), span);
+        }
+
+        Some(())
+    }
+
+    /// Assigns the phis of `target` their value when coming from `block`.
+    fn assign_phis(&mut self, block: usize, target: usize, span: Span) -> Option<()> {
+        let moves: Vec<(Var, u16)> = self.function.blocks[target]
+            .phis
+            .iter()
+            .filter_map(|phi| {
+                let dest = *self.slots.get(&phi.dest)?;
+                let (_, arg) = phi.args.iter().find(|(pred, _)| *pred == block)?;
+                match self.slots.get(arg) {
+                    Some(slot) if *slot == dest => None,
+                    _ => Some((*arg, dest)),
+                }
+            })
+            .collect();
+
+        for (arg, _) in moves.iter() {
+            self.push(*arg, span)?;
+        }
+        for (_, dest) in moves.iter().rev() {
+            self.chunk.write(Instruction::SetLocal(*dest), span);
+            self.chunk.write(Instruction::Pop, span);
+        }
+
+        Some(())
+    }
+
+    fn jump(&mut self, target: usize, span: Span) {
+        let jump = self.chunk.write(Instruction::Jump(0), span);
+        self.jumps.push((jump, target));
+    }
+
+    fn constant(&mut self, value: Value) -> Option<u16> {
+        let key = match value {
+            Value::Number(number) => Constant::Number(number.to_bits()),
+            Value::String(string) => Constant::String(string),
+            Value::Function(function) => Constant::Function(function),
+            _ => return self.chunk.add_constant(value).ok(),
+        };
+
+        match self.pool.get(&key) {
+            Some(index) => Some(*index),
+            None => {
+                let index = self.chunk.add_constant(value).ok()?;
+                self.pool.insert(key, index);
+                Some(index)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::Instruction,
+        ssa::{run_passes, test::lift_script},
+    };
+
+    use super::lower;
+
+    #[test]
+    fn optimized_loop_keeps_values_in_slots() {
+        let (mut heap, mut function) =
+            lift_script("{ var i = 0; var unused = i; while (i < 3) i = i + 1; print i; }");
+        run_passes(&mut function, &mut heap);
+        let chunk = lower(&function).expect("Function should be lowered");
+
+        // The loop variable gets the first slot after the script itself, and `unused` none as it
+        // is the same value.
+        assert_eq!(
+            chunk.code,
+            vec![
+                Instruction::Nil,
+                Instruction::Constant(0),
+                Instruction::SetLocal(1),
+                Instruction::Pop,
+                // Header, branching to the body past the exit.
+                Instruction::GetLocal(1),
+                Instruction::Constant(1),
+                Instruction::Less,
+                Instruction::JumpIfFalse(12),
+                Instruction::Pop,
+                Instruction::Jump(4),
+                // Exit.
+                Instruction::GetLocal(1),
+                Instruction::Print,
+                Instruction::Nil,
+                Instruction::Return,
+                // Body.
+                Instruction::GetLocal(1),
+                Instruction::Constant(2),
+                Instruction::Add,
+                Instruction::SetLocal(1),
+                Instruction::Pop,
+                Instruction::Loop(16),
+                // Condition being false, popped before going to the exit.
+                Instruction::Pop,
+                Instruction::Loop(12),
+            ]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+mod analysis;
+mod ir;
+mod lift;
+mod lower;
+mod passes;
+
+pub use analysis::{Definition, Dominators, Liveness, ReachingDefinitions};
+pub use ir::{Block, Function, Instr, InstrKind, Phi, Terminator, TerminatorKind, Var};
+pub use lift::lift;
+pub use lower::lower;
+pub use passes::{
+    eliminate_common_subexpressions, eliminate_dead_code, forward_globals, propagate_constants,
+};
+
+use crate::{chunk::Chunk, heap::Heap};
+
+/// Optimizes the bytecode of a function taking `arity` arguments by lifting it to SSA form,
+/// forwarding globals, propagating constants, eliminating common subexpressions and dead code,
+/// then lowering it back. The chunk is left as is if it can not be lifted or lowered.
+pub fn optimize(chunk: &mut Chunk, arity: usize, heap: &mut Heap) {
+    let mut function = match lift(chunk, arity) {
+        Some(function) => function,
+        None => return,
+    };
+
+    run_passes(&mut function, heap);
+
+    if let Some(optimized) = lower(&function) {
+        *chunk = optimized;
+    }
+}
+
+/// Runs every optimization pass on a function, in an order where each one benefits from the
+/// previous ones.
+pub fn run_passes(function: &mut Function, heap: &mut Heap) {
+    forward_globals(function);
+    propagate_constants(function, heap);
+    eliminate_common_subexpressions(function);
+    eliminate_dead_code(function);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use crate::{heap::Heap, lints::Lints, optimizer::OptLevel, vm::Vm};
+
+    use super::{lift, Function};
+
+    /// Script compiled without optimizations then lifted to SSA form.
+    pub(super) fn lift_script(code: &str) -> (Heap, Function) {
+        let mut heap = Heap::new();
+        let script = crate::compiler::compile(
+            code,
+            "ssa.lox",
+            &mut heap,
+            &Lints::new(),
+            OptLevel::O0,
+            &mut Vec::new(),
+        )
+        .expect("Program should compile");
+
+        let function = lift(&heap.deref(script).chunk, 0).expect("Script should be lifted");
+        (heap, function)
+    }
+
+    /// Output shared with the VM printing to it.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// What the program prints, followed by its runtime error and where it happened.
+    fn run(code: &str, opt_level: OptLevel) -> String {
+        let output = Output::default();
+        let mut vm = Vm::new();
+        vm.set_opt_level(opt_level);
+        vm.set_output(output.clone());
+
+        let result = vm.interpret(code, "ssa.lox");
+        let mut printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        if let Err(errors) = result {
+            for error in errors {
+                printed += &format!("{} at {:?}\n", error, error.span);
+            }
+        }
+
+        printed
+    }
+
+    #[test]
+    fn optimized_programs_behave_the_same() {
+        let programs = [
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+             for (var i = 0; i < 10; i = i + 1) print fib(i);",
+            "var total = 0;
+             for (var i = 0; i < 5; i = i + 1) {
+               for (var j = i; j < 5; j = j + 1) total = total + i * j;
+             }
+             print total;",
+            "var a = 1; var b = nil;
+             print a and b; print a or b; print b or \"right\"; print !(a and 2);
+             print a >= 1 and a <= 1 and a != 2;",
+            "var s = \"a\"; var n = 3;
+             while (n > 0) { s = s + s; n = n - 1; }
+             print s; print s == \"aaaaaaaa\";",
+            "fun outer(x) {
+               fun inner(y) { return y * 2; }
+               var unused = x * x;
+               var same = x + 1;
+               return inner(x + 1) + same;
+             }
+             print outer(4);",
+            "var g = 1; fun set() { g = 10; }
+             g = 2; print g; set(); print g;",
+            "{ var x = 2; var y = x * 3; if (y > 5) print y; else print x; }",
+            "fun f(a) { var b = a + 1; return b - nil; } print 1; f(2);",
+            "var x = 1; var y = x + 1; print y; print -\"no\";",
+            "fun f(n) { while (true) { if (n > 3) return n; n = n + 1; } } print f(0);",
+        ];
+
+        for code in programs.iter() {
+            assert_eq!(run(code, OptLevel::O2), run(code, OptLevel::O0), "{}", code);
+        }
+    }
+}
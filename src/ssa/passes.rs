@@ -0,0 +1,529 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    chunk::{Instruction, Value},
+    heap::Heap,
+    optimizer::fold_binary,
+};
+
+use super::{
+    analysis::{Definition, Dominators, ReachingDefinitions},
+    ir::{Function, Instr, InstrKind, TerminatorKind, Var},
+};
+
+/// Replaces loads of globals by the value last stored there, when the same store reaches the
+/// load along every path. A load can then not fail, as the store made sure the global exists.
+pub fn forward_globals(function: &mut Function) {
+    let reaching_definitions = ReachingDefinitions::compute(function);
+    let mut replacements = HashMap::new();
+
+    for (block, data) in function.blocks.iter_mut().enumerate() {
+        let mut reaching = reaching_definitions.reach_in[block].clone();
+        let mut forwarded = HashSet::new();
+
+        for (index, instr) in data.instrs.iter().enumerate() {
+            if let (Some(dest), InstrKind::GetGlobal(global)) = (instr.dest, &instr.kind) {
+                let mut definitions = reaching_definitions.of(&reaching, *global);
+                if let (Some(Definition::Store { value, .. }), None) =
+                    (definitions.next(), definitions.next())
+                {
+                    replacements.insert(dest, *value);
+                    forwarded.insert(index);
+                }
+            }
+
+            reaching_definitions.transfer(&mut reaching, block, index);
+        }
+
+        let mut index = 0;
+        data.instrs.retain(|_| {
+            index += 1;
+            !forwarded.contains(&(index - 1))
+        });
+    }
+
+    function.replace_uses(&replacements);
+}
+
+/// What is known of the value of a variable while propagating constants.
+#[derive(Debug, Clone, Copy)]
+enum Lattice {
+    /// The variable was not found to be defined yet.
+    Unknown,
+    Constant(Value),
+    Varying,
+}
+
+/// Sparse conditional constant propagation: computes the variables that always have the same
+/// value, assuming branches on constants only go one way, and replaces them by that constant.
+/// Branches on constants become jumps and the code they skip is removed.
+///
+/// Instructions are only folded when they can not fail, so runtime errors are kept.
+pub fn propagate_constants(function: &mut Function, heap: &mut Heap) {
+    let order = function.reverse_postorder();
+    let mut values = vec![Lattice::Unknown; function.vars as usize];
+    let mut executable = vec![false; function.blocks.len()];
+    let mut edges: HashSet<(usize, usize)> = HashSet::new();
+
+    for param in function.params.iter() {
+        values[param.0 as usize] = Lattice::Varying;
+    }
+    executable[0] = true;
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for block in order.iter() {
+            if !executable[*block] {
+                continue;
+            }
+            let data = &function.blocks[*block];
+
+            for phi in data.phis.iter() {
+                let value = phi
+                    .args
+                    .iter()
+                    .filter(|(pred, _)| edges.contains(&(*pred, *block)))
+                    .map(|(_, arg)| values[arg.0 as usize])
+                    .fold(Lattice::Unknown, meet);
+                changed |= update(&mut values, phi.dest, value);
+            }
+
+            for instr in data.instrs.iter() {
+                if let Some(dest) = instr.dest {
+                    let value = evaluate(&instr.kind, &values, heap);
+                    changed |= update(&mut values, dest, value);
+                }
+            }
+
+            let successors = match data.terminator.kind {
+                TerminatorKind::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => match values[condition.0 as usize] {
+                    Lattice::Unknown => Vec::new(),
+                    Lattice::Constant(value) if value.is_falsey() => vec![otherwise],
+                    Lattice::Constant(_) => vec![then],
+                    Lattice::Varying => vec![then, otherwise],
+                },
+                _ => data.terminator.successors(),
+            };
+            for successor in successors {
+                if edges.insert((*block, successor)) {
+                    executable[successor] = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    for (index, block) in function.blocks.iter_mut().enumerate() {
+        if !executable[index] {
+            continue;
+        }
+
+        let mut constants = Vec::new();
+        block.phis.retain(|phi| match values[phi.dest.0 as usize] {
+            Lattice::Constant(value) => {
+                constants.push((phi.dest, value));
+                false
+            }
+            _ => true,
+        });
+
+        let span = block.terminator.span;
+        let phis = constants.into_iter().map(|(dest, value)| Instr {
+            dest: Some(dest),
+            kind: InstrKind::Const(value),
+            span,
+        });
+        block.instrs.splice(0..0, phis);
+
+        for instr in block.instrs.iter_mut() {
+            if let Some(Lattice::Constant(value)) = instr.dest.map(|dest| values[dest.0 as usize]) {
+                instr.kind = InstrKind::Const(value);
+            }
+        }
+
+        if let TerminatorKind::Branch {
+            condition,
+            then,
+            otherwise,
+        } = block.terminator.kind
+        {
+            if let Lattice::Constant(value) = values[condition.0 as usize] {
+                let target = if value.is_falsey() { otherwise } else { then };
+                block.terminator.kind = TerminatorKind::Jump(target);
+            }
+        }
+    }
+
+    function.cleanup();
+}
+
+/// Sets what is known of `var`, returning whether it changed.
+fn update(values: &mut [Lattice], var: Var, value: Lattice) -> bool {
+    let old = &mut values[var.0 as usize];
+    let changed = !same(*old, value);
+    *old = value;
+    changed
+}
+
+fn evaluate(kind: &InstrKind, values: &[Lattice], heap: &mut Heap) -> Lattice {
+    let value = |var: &Var| values[var.0 as usize];
+
+    match kind {
+        InstrKind::Const(value) => Lattice::Constant(*value),
+        InstrKind::Unary { operator, operand } => match (operator, value(operand)) {
+            (_, Lattice::Unknown) => Lattice::Unknown,
+            (Instruction::Not, Lattice::Constant(value)) => {
+                Lattice::Constant(Value::Bool(value.is_falsey()))
+            }
+            (Instruction::Negate, Lattice::Constant(Value::Number(value))) => {
+                Lattice::Constant(Value::Number(-value))
+            }
+            _ => Lattice::Varying,
+        },
+        InstrKind::Binary {
+            operator,
+            left,
+            right,
+        } => match (value(left), value(right)) {
+            (Lattice::Constant(left), Lattice::Constant(right)) => {
+                match fold_binary(*operator, left, right, heap) {
+                    Some(value) => Lattice::Constant(value),
+                    None => Lattice::Varying,
+                }
+            }
+            (Lattice::Varying, _) | (_, Lattice::Varying) => Lattice::Varying,
+            _ => Lattice::Unknown,
+        },
+        _ => Lattice::Varying,
+    }
+}
+
+fn meet(a: Lattice, b: Lattice) -> Lattice {
+    match (a, b) {
+        (Lattice::Unknown, other) | (other, Lattice::Unknown) => other,
+        (Lattice::Constant(a), Lattice::Constant(b)) if same_value(a, b) => Lattice::Constant(a),
+        _ => Lattice::Varying,
+    }
+}
+
+fn same(a: Lattice, b: Lattice) -> bool {
+    match (a, b) {
+        (Lattice::Unknown, Lattice::Unknown) | (Lattice::Varying, Lattice::Varying) => true,
+        (Lattice::Constant(a), Lattice::Constant(b)) => same_value(a, b),
+        _ => false,
+    }
+}
+
+/// Whether two constants are the same, strings being interned. Unlike `==`, `NaN` is the same
+/// as itself.
+fn same_value(a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Nil, Value::Nil) => true,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Function(a), Value::Function(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Operation computed by a pure instruction, the same key meaning the same result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Expression {
+    Unary(Instruction, Var),
+    Binary(Instruction, Var, Var),
+}
+
+/// Replaces operations computed again by the value of the same operation in a dominating
+/// block, or earlier in the same block. Operations whose first occurrence fails never reach
+/// the second one, so only their successful results are ever reused.
+pub fn eliminate_common_subexpressions(function: &mut Function) {
+    let dominators = Dominators::compute(function);
+    let children = dominators.children();
+
+    let mut available: HashMap<Expression, Var> = HashMap::new();
+    let mut replacements = HashMap::new();
+    // Blocks to visit, along with the expressions to forget once they and the blocks they
+    // dominate are visited.
+    let mut stack: Vec<(usize, bool)> = vec![(0, false)];
+    let mut scopes: Vec<Vec<Expression>> = Vec::new();
+
+    while let Some((block, visited)) = stack.pop() {
+        if visited {
+            for expression in scopes.pop().expect("Scope was pushed").iter() {
+                available.remove(expression);
+            }
+            continue;
+        }
+
+        let mut scope = Vec::new();
+        let resolve = |replacements: &HashMap<Var, Var>, var: Var| {
+            replacements.get(&var).copied().unwrap_or(var)
+        };
+
+        function.blocks[block].instrs.retain(|instr| {
+            let expression = match (&instr.kind, instr.dest) {
+                (InstrKind::Unary { operator, operand }, Some(_)) => {
+                    Expression::Unary(*operator, resolve(&replacements, *operand))
+                }
+                (
+                    InstrKind::Binary {
+                        operator,
+                        left,
+                        right,
+                    },
+                    Some(_),
+                ) => {
+                    let (mut left, mut right) = (
+                        resolve(&replacements, *left),
+                        resolve(&replacements, *right),
+                    );
+                    let commutative = matches!(
+                        operator,
+                        Instruction::Multiply | Instruction::Equal | Instruction::NotEqual
+                    );
+                    if commutative && right < left {
+                        std::mem::swap(&mut left, &mut right);
+                    }
+                    Expression::Binary(*operator, left, right)
+                }
+                _ => return true,
+            };
+
+            let dest = instr.dest.expect("Expression has a destination");
+            match available.get(&expression) {
+                Some(var) => {
+                    replacements.insert(dest, *var);
+                    false
+                }
+                None => {
+                    available.insert(expression, dest);
+                    scope.push(expression);
+                    true
+                }
+            }
+        });
+
+        scopes.push(scope);
+        stack.push((block, true));
+        for child in children[block].iter().rev() {
+            stack.push((*child, false));
+        }
+    }
+
+    function.replace_uses(&replacements);
+}
+
+/// Removes the instructions and phis whose value is never used, unless they have side effects
+/// or may fail, which arithmetic does not when its operands are known to be numbers.
+pub fn eliminate_dead_code(function: &mut Function) {
+    let numbers = numbers(function);
+    let mut definitions: HashMap<Var, (usize, Option<usize>)> = HashMap::new();
+    let mut live = vec![false; function.vars as usize];
+    let mut worklist = Vec::new();
+
+    for (index, block) in function.blocks.iter().enumerate() {
+        for phi in block.phis.iter() {
+            definitions.insert(phi.dest, (index, None));
+        }
+        for (position, instr) in block.instrs.iter().enumerate() {
+            if let Some(dest) = instr.dest {
+                definitions.insert(dest, (index, Some(position)));
+            }
+            if has_effects(&instr.kind, &numbers) {
+                worklist.extend(instr.kind.operands());
+                if let Some(dest) = instr.dest {
+                    worklist.push(dest);
+                }
+            }
+        }
+        worklist.extend(block.terminator.operand());
+    }
+
+    while let Some(var) = worklist.pop() {
+        if std::mem::replace(&mut live[var.0 as usize], true) {
+            continue;
+        }
+
+        match definitions.get(&var) {
+            Some((block, None)) => {
+                let block = &function.blocks[*block];
+                let phi = block.phis.iter().find(|phi| phi.dest == var);
+                let args = phi.expect("Phi defines the variable").args.iter();
+                worklist.extend(args.map(|(_, arg)| *arg));
+            }
+            Some((block, Some(position))) => {
+                let instr = &function.blocks[*block].instrs[*position];
+                worklist.extend(instr.kind.operands());
+            }
+            // Parameters.
+            None => {}
+        }
+    }
+
+    for block in function.blocks.iter_mut() {
+        block.phis.retain(|phi| live[phi.dest.0 as usize]);
+        block.instrs.retain(|instr| match instr.dest {
+            Some(dest) => live[dest.0 as usize],
+            None => true,
+        });
+    }
+}
+
+/// Whether an instruction does more than compute its value, failing being an effect.
+fn has_effects(kind: &InstrKind, numbers: &[bool]) -> bool {
+    let number = |var: &Var| numbers[var.0 as usize];
+
+    match kind {
+        InstrKind::Const(_) => false,
+        InstrKind::Unary { operator, operand } => {
+            *operator == Instruction::Negate && !number(operand)
+        }
+        InstrKind::Binary {
+            operator: Instruction::Equal | Instruction::NotEqual,
+            ..
+        } => false,
+        InstrKind::Binary { left, right, .. } => !(number(left) && number(right)),
+        InstrKind::GetGlobal(_)
+        | InstrKind::SetGlobal(..)
+        | InstrKind::DefineGlobal(..)
+        | InstrKind::Call { .. }
+        | InstrKind::GetProperty { .. }
+        | InstrKind::SetProperty { .. }
+        | InstrKind::Print(_) => true,
+    }
+}
+
+/// Whether each variable is always a number. Variables are first assumed to be numbers, which
+/// is given up for those computed from values that may not be, until no more change, so loop
+/// counters are found to be numbers.
+fn numbers(function: &Function) -> Vec<bool> {
+    let mut numbers = vec![true; function.vars as usize];
+    for param in function.params.iter() {
+        numbers[param.0 as usize] = false;
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for block in function.blocks.iter() {
+            for phi in block.phis.iter() {
+                let number = phi.args.iter().all(|(_, arg)| numbers[arg.0 as usize]);
+                changed |= std::mem::replace(&mut numbers[phi.dest.0 as usize], number) != number;
+            }
+
+            for instr in block.instrs.iter() {
+                let dest = match instr.dest {
+                    Some(dest) => dest,
+                    None => continue,
+                };
+
+                // Arithmetic that succeeds always results in a number.
+                let number = match &instr.kind {
+                    InstrKind::Const(value) => matches!(value, Value::Number(_)),
+                    InstrKind::Unary { operator, .. } => *operator == Instruction::Negate,
+                    InstrKind::Binary {
+                        operator: Instruction::Add,
+                        left,
+                        right,
+                    } => numbers[left.0 as usize] && numbers[right.0 as usize],
+                    InstrKind::Binary { operator, .. } => matches!(
+                        operator,
+                        Instruction::Subtract | Instruction::Multiply | Instruction::Divide
+                    ),
+                    _ => false,
+                };
+                changed |= std::mem::replace(&mut numbers[dest.0 as usize], number) != number;
+            }
+        }
+    }
+
+    numbers
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::Value,
+        ssa::{test::lift_script, InstrKind, TerminatorKind},
+    };
+
+    use super::{eliminate_common_subexpressions, eliminate_dead_code, propagate_constants};
+
+    fn kinds(function: &crate::ssa::Function) -> Vec<&InstrKind> {
+        function
+            .blocks
+            .iter()
+            .flat_map(|block| block.instrs.iter().map(|instr| &instr.kind))
+            .collect()
+    }
+
+    #[test]
+    fn constants_are_propagated_through_branches() {
+        let (mut heap, mut function) =
+            lift_script("{ var a = 2; var b; if (a > 1) b = a * 3; else b = 0; print b; }");
+        propagate_constants(&mut function, &mut heap);
+        eliminate_dead_code(&mut function);
+
+        assert_eq!(function.blocks.len(), 1);
+        let kinds = kinds(&function);
+        assert!(matches!(kinds[0], InstrKind::Const(Value::Number(n)) if *n == 6.0));
+        assert!(matches!(kinds[1], InstrKind::Print(_)));
+    }
+
+    #[test]
+    fn loop_phis_are_not_constant() {
+        let (mut heap, mut function) =
+            lift_script("{ var i = 0; while (i < 3) i = i + 1; print i; }");
+        propagate_constants(&mut function, &mut heap);
+
+        assert_eq!(function.blocks[1].phis.len(), 1);
+        assert!(matches!(
+            function.blocks[1].terminator.kind,
+            TerminatorKind::Branch { .. }
+        ));
+    }
+
+    #[test]
+    fn common_subexpressions_are_computed_once() {
+        let (_, mut function) = lift_script(
+            "var a = 1; { var b = a; var c = a; print b * c + c * b; print -b == -b; }",
+        );
+        eliminate_common_subexpressions(&mut function);
+        eliminate_dead_code(&mut function);
+
+        let binaries = kinds(&function)
+            .into_iter()
+            .filter(|kind| matches!(kind, InstrKind::Binary { .. }))
+            .count();
+        let unaries = kinds(&function)
+            .into_iter()
+            .filter(|kind| matches!(kind, InstrKind::Unary { .. }))
+            .count();
+        assert_eq!((binaries, unaries), (3, 1));
+    }
+
+    #[test]
+    fn dead_code_that_may_fail_is_kept() {
+        let (_, mut function) =
+            lift_script("var a = 1; { var b = a; var unused = b + 1; var sum = 1 + 2; }");
+        eliminate_dead_code(&mut function);
+
+        // `b` may not be a number, so adding it may fail, unlike adding two constants.
+        let kinds = kinds(&function);
+        assert_eq!(
+            kinds
+                .iter()
+                .filter(|kind| matches!(kind, InstrKind::Binary { .. }))
+                .count(),
+            1
+        );
+    }
+}
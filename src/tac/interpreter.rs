@@ -0,0 +1,536 @@
+use std::{cmp::Ordering, collections::HashMap, fmt, rc::Rc};
+
+use crate::{
+    ast::{BinaryOp, UnaryOp},
+    error::{RoxError, RoxErrorKind, RoxResult, RuntimeError, TraceFrame},
+    vm::DEFAULT_MAX_FRAMES,
+};
+
+use super::ir::{Constant, Instr, InstrKind, Label, Operand, Place, Program};
+
+/// Value of a variable or temporary while a three-address code program runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    String(Rc<str>),
+    Bool(bool),
+    Nil,
+    Function(Rc<str>),
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Bool(false) | Value::Nil)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Bool(_) => "boolean",
+            Value::Nil => "nil",
+            Value::Function(_) => "function",
+        }
+    }
+}
+
+/// Values are shown the way Lox prints them.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(value) => write!(f, "{}", value),
+            Value::String(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Nil => write!(f, "nil"),
+            Value::Function(name) => write!(f, "<fn {}>", name),
+        }
+    }
+}
+
+struct Frame<'program> {
+    /// Index of the function in the program, `None` for the top-level code.
+    function: Option<usize>,
+    code: &'program [Instr],
+    ip: usize,
+    locals: HashMap<String, Value>,
+    temps: HashMap<u32, Value>,
+    /// Arguments passed with `param` and not used by a `call` yet.
+    params: Vec<Value>,
+    /// Where the caller stores the returned value.
+    result: Option<&'program Place>,
+}
+
+impl<'program> Frame<'program> {
+    fn new(function: Option<usize>, code: &'program [Instr]) -> Self {
+        Self {
+            function,
+            code,
+            ip: 0,
+            locals: HashMap::new(),
+            temps: HashMap::new(),
+            params: Vec::new(),
+            result: None,
+        }
+    }
+}
+
+/// Runs a three-address code program.
+///
+/// The top-level code's variables are globals, visible from every function. In a function, a
+/// variable is read from its locals first and assigning one that is neither a local nor a
+/// global makes it a local. Temporaries always belong to the function using them. Once the
+/// program finishes, the globals and the temporaries of the top-level code can be inspected
+/// with `environment`.
+pub struct Interpreter<'program> {
+    program: &'program Program,
+    file: String,
+    functions: HashMap<&'program str, usize>,
+    /// Index of the instruction each label is at, for every function and, last, the top-level
+    /// code.
+    labels: Vec<HashMap<Label, usize>>,
+    globals: HashMap<String, Value>,
+    frames: Vec<Frame<'program>>,
+    max_frames: usize,
+}
+
+impl<'program> Interpreter<'program> {
+    pub fn new(program: &'program Program, file: &str) -> Self {
+        let functions = program
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(index, function)| (function.name.as_str(), index))
+            .collect();
+
+        let labels = program
+            .functions
+            .iter()
+            .map(|function| function.body.as_slice())
+            .chain(std::iter::once(program.main.as_slice()))
+            .map(|code| {
+                code.iter()
+                    .enumerate()
+                    .filter_map(|(index, instr)| match instr.kind {
+                        InstrKind::Label(label) => Some((label, index)),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            program,
+            file: file.to_string(),
+            functions,
+            labels,
+            globals: HashMap::new(),
+            frames: Vec::new(),
+            max_frames: DEFAULT_MAX_FRAMES,
+        }
+    }
+
+    /// Limits the number of nested calls, making more fail with `RuntimeError::StackOverflow`.
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    /// Runs the top-level code until its end or a `return`.
+    pub fn run(&mut self) -> RoxResult<()> {
+        self.globals.clear();
+        self.frames = vec![Frame::new(None, &self.program.main)];
+
+        loop {
+            let frame = self.frames.last_mut().expect("No active frame");
+            let code = frame.code;
+            let instr = match code.get(frame.ip) {
+                Some(instr) => instr,
+                None if self.frames.len() == 1 => return Ok(()),
+                None => {
+                    self.return_value(Value::Nil)?;
+                    continue;
+                }
+            };
+            frame.ip += 1;
+
+            match &instr.kind {
+                InstrKind::Copy { dest, src } => {
+                    let value = self.read(src)?;
+                    self.write(dest, value);
+                }
+                InstrKind::Unary {
+                    dest,
+                    operator,
+                    operand,
+                } => {
+                    let value = match (operator, self.read(operand)?) {
+                        (UnaryOp::Negate, Value::Number(value)) => Value::Number(-value),
+                        (UnaryOp::Negate, value) => {
+                            Err(self.error(RuntimeError::InvalidOperand {
+                                operator: "-",
+                                got: value.type_name(),
+                            }))?
+                        }
+                        (UnaryOp::Not, value) => Value::Bool(value.is_falsey()),
+                    };
+                    self.write(dest, value);
+                }
+                InstrKind::Binary {
+                    dest,
+                    operator,
+                    left,
+                    right,
+                } => {
+                    let left = self.read(left)?;
+                    let right = self.read(right)?;
+                    let value = binary(*operator, left, right).map_err(|kind| self.error(kind))?;
+                    self.write(dest, value);
+                }
+                InstrKind::Label(_) => {}
+                InstrKind::Goto(label) => self.jump(*label),
+                InstrKind::If { condition, target } => {
+                    if !self.read(condition)?.is_falsey() {
+                        self.jump(*target);
+                    }
+                }
+                InstrKind::IfFalse { condition, target } => {
+                    if self.read(condition)?.is_falsey() {
+                        self.jump(*target);
+                    }
+                }
+                InstrKind::Param(operand) => {
+                    let value = self.read(operand)?;
+                    self.frame_mut().params.push(value);
+                }
+                InstrKind::Call {
+                    dest,
+                    callee,
+                    arguments,
+                } => self.call(dest.as_ref(), callee, *arguments)?,
+                // Programs have no objects.
+                InstrKind::GetProperty { object, .. } | InstrKind::SetProperty { object, .. } => {
+                    let got = self.read(object)?.type_name();
+                    Err(self.error(RuntimeError::NoProperties(got)))?
+                }
+                InstrKind::Return(value) => {
+                    let value = match value {
+                        Some(value) => self.read(value)?,
+                        None => Value::Nil,
+                    };
+
+                    if self.frames.len() == 1 {
+                        return Ok(());
+                    }
+                    self.return_value(value)?;
+                }
+                InstrKind::Print(value) => println!("{}", self.read(value)?),
+            }
+        }
+    }
+
+    /// Globals, sorted by name, followed by the temporaries of the top-level code, sorted by
+    /// index.
+    pub fn environment(&self) -> Vec<(String, Value)> {
+        let mut globals: Vec<(String, Value)> = self
+            .globals
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut temps: Vec<(u32, Value)> = match self.frames.first() {
+            Some(frame) => frame
+                .temps
+                .iter()
+                .map(|(index, value)| (*index, value.clone()))
+                .collect(),
+            None => Vec::new(),
+        };
+        temps.sort_by_key(|(index, _)| *index);
+
+        globals
+            .into_iter()
+            .chain(
+                temps
+                    .into_iter()
+                    .map(|(index, value)| (Place::Temp(index).to_string(), value)),
+            )
+            .collect()
+    }
+
+    fn call(
+        &mut self,
+        dest: Option<&'program Place>,
+        callee: &Operand,
+        arguments: usize,
+    ) -> RoxResult<()> {
+        let name = match self.read(callee)? {
+            Value::Function(name) => name,
+            value => return Err(self.error(RuntimeError::NotCallable(value.type_name()))),
+        };
+
+        let index = self.functions[name.as_ref()];
+        let function = &self.program.functions[index];
+        if function.params.len() != arguments {
+            return Err(self.error(RuntimeError::ArityMismatch {
+                expected: function.params.len(),
+                got: arguments,
+            }));
+        }
+
+        if self.frames.len() >= self.max_frames {
+            return Err(self.error(RuntimeError::StackOverflow));
+        }
+
+        let params = &mut self.frame_mut().params;
+        if params.len() < arguments {
+            let got = params.len();
+            return Err(self.error(RuntimeError::MissingParams {
+                expected: arguments,
+                got,
+            }));
+        }
+        let values = params.split_off(params.len() - arguments);
+
+        let mut frame = Frame::new(Some(index), &function.body);
+        frame.locals = function.params.iter().cloned().zip(values).collect();
+        frame.result = dest;
+        self.frames.push(frame);
+
+        Ok(())
+    }
+
+    fn return_value(&mut self, value: Value) -> RoxResult<()> {
+        let frame = self.frames.pop().expect("No active frame");
+        if let Some(dest) = frame.result {
+            self.write(dest, value);
+        }
+
+        Ok(())
+    }
+
+    fn jump(&mut self, label: Label) {
+        let function = self.frame().function;
+        let labels = &self.labels[function.unwrap_or(self.program.functions.len())];
+        self.frame_mut().ip = labels[&label];
+    }
+
+    fn read(&self, operand: &Operand) -> RoxResult<Value> {
+        let frame = self.frame();
+        let value = match operand {
+            Operand::Const(constant) => return Ok(constant_value(constant)),
+            Operand::Place(Place::Temp(index)) => frame.temps.get(index),
+            Operand::Place(Place::Var(name)) => {
+                frame.locals.get(name).or_else(|| self.globals.get(name))
+            }
+        };
+
+        match value {
+            Some(value) => Ok(value.clone()),
+            None => {
+                let name = match operand {
+                    Operand::Place(place) => place.to_string(),
+                    Operand::Const(_) => unreachable!(),
+                };
+                Err(self.error(RuntimeError::UndefinedVariable(name)))
+            }
+        }
+    }
+
+    fn write(&mut self, place: &Place, value: Value) {
+        let frame = self.frames.last_mut().expect("No active frame");
+        match place {
+            Place::Temp(index) => {
+                frame.temps.insert(*index, value);
+            }
+            Place::Var(name) => {
+                if frame.function.is_some()
+                    && (frame.locals.contains_key(name) || !self.globals.contains_key(name))
+                {
+                    frame.locals.insert(name.clone(), value);
+                } else {
+                    self.globals.insert(name.clone(), value);
+                }
+            }
+        }
+    }
+
+    fn frame(&self) -> &Frame<'program> {
+        self.frames.last().expect("No active frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame<'program> {
+        self.frames.last_mut().expect("No active frame")
+    }
+
+    fn error(&self, kind: RuntimeError) -> RoxError {
+        // The instruction pointer is advanced before an instruction is executed.
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| TraceFrame {
+                function: match frame.function {
+                    Some(index) => self.program.functions[index].name.clone(),
+                    None => String::from("<script>"),
+                },
+                file: self.file.clone(),
+                span: frame
+                    .code
+                    .get(frame.ip.saturating_sub(1))
+                    .map(|instr| instr.span)
+                    .unwrap_or_default(),
+                repeated: 0,
+                cycle: 1,
+            })
+            .collect();
+
+        let span = trace.first().map(|frame| frame.span).unwrap_or_default();
+        RoxError::new(RoxErrorKind::RuntimeError(kind), span).with_trace(trace)
+    }
+}
+
+fn binary(operator: BinaryOp, left: Value, right: Value) -> Result<Value, RuntimeError> {
+    let value = match (operator, left, right) {
+        (BinaryOp::Equal, left, right) => Value::Bool(equals(&left, &right)),
+        (BinaryOp::NotEqual, left, right) => Value::Bool(!equals(&left, &right)),
+        (BinaryOp::Add, Value::String(a), Value::String(b)) => {
+            Value::String(format!("{}{}", a, b).into())
+        }
+        (operator, Value::Number(a), Value::Number(b)) => match operator {
+            BinaryOp::Add => Value::Number(a + b),
+            BinaryOp::Subtract => Value::Number(a - b),
+            BinaryOp::Multiply => Value::Number(a * b),
+            BinaryOp::Divide => Value::Number(a / b),
+            BinaryOp::Greater => Value::Bool(a > b),
+            BinaryOp::Less => Value::Bool(a < b),
+            // Same as the bytecode, where these are the negated opposite comparison.
+            BinaryOp::GreaterEqual => Value::Bool(a.partial_cmp(&b) != Some(Ordering::Less)),
+            BinaryOp::LessEqual => Value::Bool(a.partial_cmp(&b) != Some(Ordering::Greater)),
+            BinaryOp::Equal | BinaryOp::NotEqual => unreachable!(),
+        },
+        (operator, left, right) => {
+            let expected = match operator {
+                BinaryOp::Add => "two numbers or two strings",
+                _ => "numbers",
+            };
+            return Err(RuntimeError::InvalidOperands {
+                operator: operator.symbol(),
+                expected,
+                left: left.type_name(),
+                right: right.type_name(),
+            });
+        }
+    };
+
+    Ok(value)
+}
+
+fn equals(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Nil, Value::Nil) => true,
+        (Value::Function(a), Value::Function(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn constant_value(constant: &Constant) -> Value {
+    match constant {
+        Constant::Number(value) => Value::Number(*value),
+        Constant::String(value) => Value::String(value.as_str().into()),
+        Constant::Bool(value) => Value::Bool(*value),
+        Constant::Nil => Value::Nil,
+        Constant::Function(name) => Value::Function(name.as_str().into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::{RoxErrorKind, RuntimeError},
+        tac::parse,
+    };
+
+    use super::{Interpreter, Value};
+
+    #[test]
+    fn runs_functions_and_keeps_environment() {
+        let program = parse(
+            "func fib(n):
+    t1 = n < 2
+    ifFalse t1 goto L1
+    return n
+L1:
+    t2 = n - 1
+    param t2
+    t3 = call fib, 1
+    t4 = n - 2
+    param t4
+    t5 = call fib, 1
+    t6 = t3 + t5
+    return t6
+endfunc
+
+    fib = &fib
+    param 10
+    t1 = call fib, 1
+    result = t1
+",
+        )
+        .expect("Program should parse");
+
+        let mut interpreter = Interpreter::new(&program, "fib.tac");
+        interpreter.run().expect("Program should run");
+
+        assert_eq!(
+            interpreter.environment(),
+            vec![
+                ("fib".to_string(), Value::Function("fib".into())),
+                ("result".to_string(), Value::Number(55.0)),
+                ("t1".to_string(), Value::Number(55.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn runtime_errors_have_a_trace() {
+        let program = parse("func f():\n    t1 = -\"a\"\nendfunc\n    call &f, 0\n")
+            .expect("Program should parse");
+
+        let error = Interpreter::new(&program, "error.tac").run().unwrap_err();
+
+        assert!(matches!(
+            error.src,
+            RoxErrorKind::RuntimeError(RuntimeError::InvalidOperand { .. })
+        ));
+        let trace: Vec<(&str, usize)> = error
+            .trace
+            .iter()
+            .map(|frame| (frame.function.as_str(), frame.span.start.line()))
+            .collect();
+        assert_eq!(trace, vec![("f", 1), ("<script>", 3)]);
+    }
+
+    #[test]
+    fn runaway_recursion_overflows() {
+        let program = parse("func f():\n    call &f, 0\nendfunc\n    call &f, 0\n")
+            .expect("Program should parse");
+
+        let mut interpreter = Interpreter::new(&program, "overflow.tac");
+        interpreter.set_max_frames(50);
+        let error = interpreter.run().unwrap_err();
+
+        assert!(matches!(
+            error.src,
+            RoxErrorKind::RuntimeError(RuntimeError::StackOverflow)
+        ));
+        let trace: Vec<(&str, usize)> = error
+            .trace
+            .iter()
+            .map(|frame| (frame.function.as_str(), frame.repeated))
+            .collect();
+        assert_eq!(trace, vec![("f", 48), ("<script>", 0)]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    ast::{BinaryOp, UnaryOp},
+    location::Span,
+};
+
+/// Three-address code program: the functions it declares and the top-level code that runs
+/// when it starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub main: Vec<Instr>,
+}
+
+impl Program {
+    /// Pretty-printed program in which instructions are preceded by the line of `code` they
+    /// were lowered from, as a comment, each time that line changes.
+    pub fn listing(&self, code: &str) -> String {
+        let lines: Vec<&str> = code.lines().collect();
+        let mut listing = String::new();
+
+        for function in self.functions.iter() {
+            listing += &format!("func {}({}):\n", function.name, function.params.join(", "));
+            annotate(&mut listing, &function.body, &lines);
+            listing += "endfunc\n\n";
+        }
+        annotate(&mut listing, &self.main, &lines);
+
+        listing
+    }
+}
+
+fn annotate(listing: &mut String, instrs: &[Instr], lines: &[&str]) {
+    let mut current = None;
+
+    for instr in instrs.iter() {
+        let line = instr.span.start.line();
+        if current != Some(line) {
+            current = Some(line);
+            if let Some(text) = lines.get(line) {
+                listing.push_str(&format!("    // {}: {}\n", line + 1, text.trim()));
+            }
+        }
+
+        listing.push_str(&format!("{}\n", instr));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Instr>,
+    pub span: Span,
+}
+
+/// Instruction along with the code it was lowered or parsed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instr {
+    pub kind: InstrKind,
+    pub span: Span,
+}
+
+impl Instr {
+    pub fn new(kind: InstrKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstrKind {
+    /// `x = y`
+    Copy { dest: Place, src: Operand },
+    /// `x = op y`
+    Unary {
+        dest: Place,
+        operator: UnaryOp,
+        operand: Operand,
+    },
+    /// `x = y op z`
+    Binary {
+        dest: Place,
+        operator: BinaryOp,
+        left: Operand,
+        right: Operand,
+    },
+    /// `L:`
+    Label(Label),
+    /// `goto L`
+    Goto(Label),
+    /// `if x goto L`
+    If { condition: Operand, target: Label },
+    /// `ifFalse x goto L`
+    IfFalse { condition: Operand, target: Label },
+    /// `param x`, passes an argument to the next `call`.
+    Param(Operand),
+    /// `x = call f, n`, calls `f` with the last `n` parameters.
+    Call {
+        dest: Option<Place>,
+        callee: Operand,
+        arguments: usize,
+    },
+    /// `x = y.name`
+    GetProperty {
+        dest: Place,
+        object: Operand,
+        name: String,
+    },
+    /// `x.name = y`
+    SetProperty {
+        object: Operand,
+        name: String,
+        value: Operand,
+    },
+    /// `return x`, or `return` for `nil`.
+    Return(Option<Operand>),
+    /// `print x`
+    Print(Operand),
+}
+
+/// Where an instruction stores its result.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Place {
+    /// Temporary introduced by the lowering, `t1`, `t2`, ...
+    Temp(u32),
+    /// Variable of the program, local to the function or global.
+    Var(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Place(Place),
+    Const(Constant),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+    /// Function declared in the program, `&name`.
+    Function(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Label(pub u32);
+
+impl Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for function in self.functions.iter() {
+            writeln!(f, "{}", function)?;
+        }
+
+        for instr in self.main.iter() {
+            writeln!(f, "{}", instr)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "func {}({}):", self.name, self.params.join(", "))?;
+        for instr in self.body.iter() {
+            writeln!(f, "{}", instr)?;
+        }
+        writeln!(f, "endfunc")
+    }
+}
+
+/// Labels are written at the start of the line, every other instruction is indented.
+impl Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            InstrKind::Label(_) => write!(f, "{}", self.kind),
+            _ => write!(f, "    {}", self.kind),
+        }
+    }
+}
+
+impl Display for InstrKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstrKind::Copy { dest, src } => write!(f, "{} = {}", dest, src),
+            InstrKind::Unary {
+                dest,
+                operator,
+                operand,
+            } => write!(f, "{} = {}{}", dest, operator.symbol(), operand),
+            InstrKind::Binary {
+                dest,
+                operator,
+                left,
+                right,
+            } => write!(f, "{} = {} {} {}", dest, left, operator.symbol(), right),
+            InstrKind::Label(label) => write!(f, "{}:", label),
+            InstrKind::Goto(label) => write!(f, "goto {}", label),
+            InstrKind::If { condition, target } => write!(f, "if {} goto {}", condition, target),
+            InstrKind::IfFalse { condition, target } => {
+                write!(f, "ifFalse {} goto {}", condition, target)
+            }
+            InstrKind::Param(operand) => write!(f, "param {}", operand),
+            InstrKind::Call {
+                dest: Some(dest),
+                callee,
+                arguments,
+            } => write!(f, "{} = call {}, {}", dest, callee, arguments),
+            InstrKind::Call {
+                dest: None,
+                callee,
+                arguments,
+            } => write!(f, "call {}, {}", callee, arguments),
+            InstrKind::GetProperty { dest, object, name } => {
+                write!(f, "{} = {}.{}", dest, object, name)
+            }
+            InstrKind::SetProperty {
+                object,
+                name,
+                value,
+            } => write!(f, "{}.{} = {}", object, name, value),
+            InstrKind::Return(Some(value)) => write!(f, "return {}", value),
+            InstrKind::Return(None) => write!(f, "return"),
+            InstrKind::Print(value) => write!(f, "print {}", value),
+        }
+    }
+}
+
+impl Display for Place {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Place::Temp(index) => write!(f, "t{}", index),
+            Place::Var(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Place(place) => write!(f, "{}", place),
+            Operand::Const(constant) => write!(f, "{}", constant),
+        }
+    }
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constant::Number(value) => write!(f, "{}", value),
+            Constant::String(value) => {
+                write!(f, "\"")?;
+                for c in value.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\0' => write!(f, "\\0")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Constant::Bool(value) => write!(f, "{}", value),
+            Constant::Nil => write!(f, "nil"),
+            Constant::Function(name) => write!(f, "&{}", name),
+        }
+    }
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "L{}", self.0)
+    }
+}
@@ -0,0 +1,661 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::{
+    ast::{self, Decl, Expr, ExprKind, ForInit, FunDecl, Identifier, LogicalOp, Stmt, StmtKind},
+    location::Span,
+};
+
+use super::ir::{Constant, Function, Instr, InstrKind, Label, Operand, Place, Program};
+
+/// Lowers a program that compiled without errors to three-address code.
+///
+/// Every function, including nested ones, becomes a function of the TAC program with a unique
+/// name, and declaring it copies `&name` into its variable. Variables keep their names unless
+/// that would make them ambiguous, e.g. a local shadowing another one or a variable named like
+/// a temporary, in which case a `.N` suffix is added.
+pub fn lower(program: &ast::Program) -> Program {
+    // A variable that is not local to a function is a global, so locals must not be named like
+    // any global of the program. Globals are only all known once everything is lowered, and
+    // they do not depend on how locals are named, so a second pass is enough.
+    let mut reserved = HashSet::new();
+
+    loop {
+        let mut lowering = Lowering::new(reserved);
+        let lowered = lowering.program(program);
+
+        if lowering.locals.is_disjoint(&lowering.globals) {
+            return lowered;
+        }
+        reserved = lowering.globals;
+    }
+}
+
+struct Lowering<'ast> {
+    /// Names locals can not have.
+    reserved: HashSet<String>,
+    locals: HashSet<String>,
+    globals: HashSet<String>,
+    function_names: HashSet<String>,
+    /// Functions found but not lowered yet, with their unique names.
+    pending: VecDeque<(&'ast FunDecl, String)>,
+}
+
+impl<'ast> Lowering<'ast> {
+    fn new(reserved: HashSet<String>) -> Self {
+        Self {
+            reserved,
+            locals: HashSet::new(),
+            globals: HashSet::new(),
+            function_names: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn program(&mut self, program: &'ast ast::Program) -> Program {
+        let (_, main) = self.body(&[], &program.declarations, true);
+
+        let mut functions = Vec::new();
+        while let Some((decl, name)) = self.pending.pop_front() {
+            let (params, body) = self.body(&decl.params, &decl.body, false);
+            functions.push(Function {
+                name,
+                params,
+                body,
+                span: decl.span,
+            });
+        }
+
+        Program { functions, main }
+    }
+
+    /// Lowers the body of a function or of the script, returning the names of its parameters
+    /// and its code.
+    fn body(
+        &mut self,
+        params: &'ast [Identifier],
+        body: &'ast [Decl],
+        is_script: bool,
+    ) -> (Vec<String>, Vec<Instr>) {
+        let mut builder = Builder::new(
+            self.reserved.clone(),
+            std::mem::take(&mut self.function_names),
+        );
+        if !is_script {
+            builder.scopes.push(Vec::new());
+        }
+
+        let params: Vec<String> = params.iter().map(|param| builder.declare(param)).collect();
+        builder.declarations(body);
+
+        self.locals.extend(builder.locals);
+        self.globals.extend(builder.globals);
+        self.function_names = builder.function_names;
+        self.pending.extend(builder.functions);
+
+        (params, builder.code)
+    }
+}
+
+/// Lowering state of a single function.
+struct Builder<'ast> {
+    code: Vec<Instr>,
+    temps: u32,
+    labels: u32,
+    /// Local variables in scope, with their names in the TAC program. The script's top-level
+    /// declarations are globals, so they are not in any scope.
+    scopes: Vec<Vec<(&'ast str, String)>>,
+    /// Names that can not be given to a new local.
+    used: HashSet<String>,
+    locals: HashSet<String>,
+    globals: HashSet<String>,
+    function_names: HashSet<String>,
+    functions: Vec<(&'ast FunDecl, String)>,
+}
+
+impl<'ast> Builder<'ast> {
+    fn new(reserved: HashSet<String>, function_names: HashSet<String>) -> Self {
+        Self {
+            code: Vec::new(),
+            temps: 0,
+            labels: 0,
+            scopes: Vec::new(),
+            used: reserved,
+            locals: HashSet::new(),
+            globals: HashSet::new(),
+            function_names,
+            functions: Vec::new(),
+        }
+    }
+
+    fn declarations(&mut self, decls: &'ast [Decl]) {
+        for decl in decls.iter() {
+            self.declaration(decl);
+        }
+    }
+
+    fn declaration(&mut self, decl: &'ast Decl) {
+        match decl {
+            Decl::Fun(decl) => {
+                let function = unique_name(&decl.name.name, &self.function_names);
+                self.function_names.insert(function.clone());
+                self.functions.push((decl, function.clone()));
+
+                let name = self.declare(&decl.name);
+                self.emit(
+                    InstrKind::Copy {
+                        dest: Place::Var(name),
+                        src: Operand::Const(Constant::Function(function)),
+                    },
+                    decl.name.span,
+                );
+            }
+            Decl::Var(decl) => self.var_declaration(decl),
+            Decl::Stmt(stmt) => self.statement(stmt),
+        }
+    }
+
+    fn var_declaration(&mut self, decl: &'ast ast::VarDecl) {
+        let value = match &decl.initializer {
+            Some(initializer) => self.expression(initializer),
+            None => Operand::Const(Constant::Nil),
+        };
+
+        let name = self.declare(&decl.name);
+        self.emit(
+            InstrKind::Copy {
+                dest: Place::Var(name),
+                src: value,
+            },
+            decl.span,
+        );
+    }
+
+    fn statement(&mut self, stmt: &'ast Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) => match &expr.kind {
+                ExprKind::Call { .. } => {
+                    self.call(expr, false);
+                }
+                _ => {
+                    self.expression(expr);
+                }
+            },
+            StmtKind::Print(expr) => {
+                let value = self.expression(expr);
+                self.emit(InstrKind::Print(value), stmt.span);
+            }
+            StmtKind::Return(value) => {
+                let value = value.as_ref().map(|value| self.expression(value));
+                self.emit(InstrKind::Return(value), stmt.span);
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let condition_value = self.expression(condition);
+                let else_label = self.label();
+                self.emit(
+                    InstrKind::IfFalse {
+                        condition: condition_value,
+                        target: else_label,
+                    },
+                    condition.span,
+                );
+                self.statement(then_branch);
+
+                match else_branch {
+                    Some(else_branch) => {
+                        let end_label = self.label();
+                        self.emit(InstrKind::Goto(end_label), stmt.span);
+                        self.emit(InstrKind::Label(else_label), stmt.span);
+                        self.statement(else_branch);
+                        self.emit(InstrKind::Label(end_label), stmt.span);
+                    }
+                    None => self.emit(InstrKind::Label(else_label), stmt.span),
+                }
+            }
+            StmtKind::While { condition, body } => {
+                self.loop_statement(stmt.span, Some(condition), body, None);
+            }
+            StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.scopes.push(Vec::new());
+                match initializer.as_deref() {
+                    Some(ForInit::Var(decl)) => self.var_declaration(decl),
+                    Some(ForInit::Expression(expr)) => {
+                        self.expression(expr);
+                    }
+                    None => {}
+                }
+                self.loop_statement(stmt.span, condition.as_deref(), body, increment.as_deref());
+                self.scopes.pop();
+            }
+            StmtKind::Block(decls) => {
+                self.scopes.push(Vec::new());
+                self.declarations(decls);
+                self.scopes.pop();
+            }
+        }
+    }
+
+    /// Lowers `while` and `for` loops, the condition is checked before every iteration.
+    fn loop_statement(
+        &mut self,
+        span: Span,
+        condition: Option<&'ast Expr>,
+        body: &'ast Stmt,
+        increment: Option<&'ast Expr>,
+    ) {
+        let start_label = self.label();
+        let end_label = self.label();
+
+        self.emit(InstrKind::Label(start_label), span);
+        if let Some(condition) = condition {
+            let condition_value = self.expression(condition);
+            self.emit(
+                InstrKind::IfFalse {
+                    condition: condition_value,
+                    target: end_label,
+                },
+                condition.span,
+            );
+        }
+
+        self.statement(body);
+        if let Some(increment) = increment {
+            self.expression(increment);
+        }
+
+        self.emit(InstrKind::Goto(start_label), span);
+        self.emit(InstrKind::Label(end_label), span);
+    }
+
+    /// Lowers an expression, returning the operand that holds its value.
+    fn expression(&mut self, expr: &'ast Expr) -> Operand {
+        match &expr.kind {
+            ExprKind::Literal(literal) => Operand::Const(match literal {
+                ast::Literal::Number(value) => Constant::Number(*value),
+                ast::Literal::String(value) => Constant::String(value.clone()),
+                ast::Literal::Bool(value) => Constant::Bool(*value),
+                ast::Literal::Nil => Constant::Nil,
+            }),
+            ExprKind::Variable(name) => Operand::Place(Place::Var(self.resolve(name))),
+            ExprKind::Assign { target, value } => {
+                let value = self.expression(value);
+                let name = Place::Var(self.resolve(target));
+                self.emit(
+                    InstrKind::Copy {
+                        dest: name.clone(),
+                        src: value,
+                    },
+                    expr.span,
+                );
+                Operand::Place(name)
+            }
+            ExprKind::Unary {
+                operator,
+                operator_span,
+                operand,
+            } => {
+                let operand = self.expression(operand);
+                let dest = self.temp();
+                self.emit(
+                    InstrKind::Unary {
+                        dest: dest.clone(),
+                        operator: *operator,
+                        operand,
+                    },
+                    *operator_span,
+                );
+                Operand::Place(dest)
+            }
+            ExprKind::Binary {
+                operator,
+                operator_span,
+                left,
+                right,
+            } => {
+                let left = self.expression(left);
+                let left = self.protect(left, std::slice::from_ref(right), left_span(expr));
+                let right = self.expression(right);
+                let dest = self.temp();
+                self.emit(
+                    InstrKind::Binary {
+                        dest: dest.clone(),
+                        operator: *operator,
+                        left,
+                        right,
+                    },
+                    *operator_span,
+                );
+                Operand::Place(dest)
+            }
+            ExprKind::Logical {
+                operator,
+                operator_span,
+                left,
+                right,
+            } => {
+                // The right operand is only evaluated when the left one does not decide the
+                // result on its own.
+                let left = self.expression(left);
+                let dest = self.temp();
+                self.emit(
+                    InstrKind::Copy {
+                        dest: dest.clone(),
+                        src: left,
+                    },
+                    *operator_span,
+                );
+
+                let end_label = self.label();
+                let condition = Operand::Place(dest.clone());
+                let jump = match operator {
+                    LogicalOp::And => InstrKind::IfFalse {
+                        condition,
+                        target: end_label,
+                    },
+                    LogicalOp::Or => InstrKind::If {
+                        condition,
+                        target: end_label,
+                    },
+                };
+                self.emit(jump, *operator_span);
+
+                let right = self.expression(right);
+                self.emit(
+                    InstrKind::Copy {
+                        dest: dest.clone(),
+                        src: right,
+                    },
+                    *operator_span,
+                );
+                self.emit(InstrKind::Label(end_label), *operator_span);
+
+                Operand::Place(dest)
+            }
+            ExprKind::Call { .. } => self.call(expr, true),
+            ExprKind::Get { object, name } => {
+                let object = self.expression(object);
+                let dest = self.temp();
+                self.emit(
+                    InstrKind::GetProperty {
+                        dest: dest.clone(),
+                        object,
+                        name: name.name.clone(),
+                    },
+                    name.span,
+                );
+                Operand::Place(dest)
+            }
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                // Only places are written before the property, e.g. `t1.name = 1`.
+                let object_value = match self.expression(object) {
+                    Operand::Const(constant) => {
+                        let dest = self.temp();
+                        self.emit(
+                            InstrKind::Copy {
+                                dest: dest.clone(),
+                                src: Operand::Const(constant),
+                            },
+                            object.span,
+                        );
+                        Operand::Place(dest)
+                    }
+                    object_value => {
+                        self.protect(object_value, std::slice::from_ref(value), object.span)
+                    }
+                };
+                let value = self.expression(value);
+                self.emit(
+                    InstrKind::SetProperty {
+                        object: object_value,
+                        name: name.name.clone(),
+                        value: value.clone(),
+                    },
+                    name.span,
+                );
+                value
+            }
+            ExprKind::Grouping(expr) => self.expression(expr),
+        }
+    }
+
+    /// Lowers a call, `Nil` is returned when its result is not needed.
+    fn call(&mut self, expr: &'ast Expr, needs_result: bool) -> Operand {
+        let (callee, paren, arguments) = match &expr.kind {
+            ExprKind::Call {
+                callee,
+                paren,
+                arguments,
+            } => (callee, *paren, arguments),
+            _ => panic!("Expression is not a call"),
+        };
+
+        let callee_value = self.expression(callee);
+        let callee_value = self.protect(callee_value, arguments, callee.span);
+
+        let mut values = Vec::with_capacity(arguments.len());
+        for (index, argument) in arguments.iter().enumerate() {
+            let value = self.expression(argument);
+            values.push(self.protect(value, &arguments[index + 1..], argument.span));
+        }
+
+        for (value, argument) in values.into_iter().zip(arguments.iter()) {
+            self.emit(InstrKind::Param(value), argument.span);
+        }
+
+        let dest = if needs_result {
+            Some(self.temp())
+        } else {
+            None
+        };
+        self.emit(
+            InstrKind::Call {
+                dest: dest.clone(),
+                callee: callee_value,
+                arguments: arguments.len(),
+            },
+            paren,
+        );
+
+        match dest {
+            Some(dest) => Operand::Place(dest),
+            None => Operand::Const(Constant::Nil),
+        }
+    }
+
+    /// Copies a variable into a temporary if the expressions evaluated after it but before it
+    /// is used could assign it.
+    fn protect(&mut self, value: Operand, later: &'ast [Expr], span: Span) -> Operand {
+        match value {
+            Operand::Place(Place::Var(_)) if later.iter().any(has_side_effects) => {
+                let dest = self.temp();
+                self.emit(
+                    InstrKind::Copy {
+                        dest: dest.clone(),
+                        src: value,
+                    },
+                    span,
+                );
+                Operand::Place(dest)
+            }
+            value => value,
+        }
+    }
+
+    /// Declares a variable in the current scope, returning its name in the TAC program.
+    fn declare(&mut self, name: &'ast Identifier) -> String {
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => {
+                let global = global_name(&name.name);
+                self.globals.insert(global.clone());
+                return global;
+            }
+        };
+
+        let local = unique_name(&name.name, &self.used);
+        self.used.insert(local.clone());
+        self.locals.insert(local.clone());
+        scope.push((&name.name, local.clone()));
+
+        local
+    }
+
+    fn resolve(&mut self, name: &Identifier) -> String {
+        let local = self
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(source, _)| *source == name.name);
+
+        match local {
+            Some((_, local)) => local.clone(),
+            None => {
+                let global = global_name(&name.name);
+                self.globals.insert(global.clone());
+                global
+            }
+        }
+    }
+
+    fn temp(&mut self) -> Place {
+        self.temps += 1;
+        Place::Temp(self.temps)
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels += 1;
+        Label(self.labels)
+    }
+
+    fn emit(&mut self, kind: InstrKind, span: Span) {
+        self.code.push(Instr::new(kind, span));
+    }
+}
+
+/// `name`, or `name.N` with the smallest `N` that makes it unique if it is already taken or
+/// would read as a temporary or a label.
+fn unique_name(name: &str, taken: &HashSet<String>) -> String {
+    if !taken.contains(name) && !is_reserved(name) {
+        return name.to_string();
+    }
+
+    (1..)
+        .map(|suffix| format!("{}.{}", name, suffix))
+        .find(|candidate| !taken.contains(candidate))
+        .expect("Ran out of suffixes")
+}
+
+/// Name of a global in the TAC program. Globals are shared by all functions, so the name only
+/// depends on the one in the code, with `.0` added if it would read as a temporary or a label.
+fn global_name(name: &str) -> String {
+    if is_reserved(name) {
+        format!("{}.0", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Whether a name has the form of a temporary, `t1`, or of a label, `L1`.
+fn is_reserved(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some('t') | Some('L'))
+        && !chars.as_str().is_empty()
+        && chars.all(|c| c.is_ascii_digit())
+}
+
+fn has_side_effects(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Literal(_) | ExprKind::Variable(_) => false,
+        ExprKind::Assign { .. } | ExprKind::Call { .. } | ExprKind::Set { .. } => true,
+        // Getters are natives, which can not assign variables.
+        ExprKind::Get { object, .. } => has_side_effects(object),
+        ExprKind::Unary { operand, .. } => has_side_effects(operand),
+        ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+            has_side_effects(left) || has_side_effects(right)
+        }
+        ExprKind::Grouping(expr) => has_side_effects(expr),
+    }
+}
+
+fn left_span(expr: &Expr) -> Span {
+    match &expr.kind {
+        ExprKind::Binary { left, .. } => left.span,
+        _ => expr.span,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parser;
+
+    use super::lower;
+
+    fn lower_code(code: &str) -> String {
+        let (program, errors) = parser::parse(code);
+        assert!(errors.is_empty(), "Program should parse");
+        lower(&program).to_string()
+    }
+
+    #[test]
+    fn lowers_functions_and_control_flow() {
+        let tac = lower_code(
+            "fun max(a, b) { if (a > b) return a; else return b; }\nprint max(1, 2) * 3;",
+        );
+
+        assert_eq!(
+            tac,
+            "func max(a, b):
+    t1 = a > b
+    ifFalse t1 goto L1
+    return a
+    goto L2
+L1:
+    return b
+L2:
+endfunc
+
+    max = &max
+    param 1
+    param 2
+    t1 = call max, 2
+    t2 = t1 * 3
+    print t2
+"
+        );
+    }
+
+    #[test]
+    fn renames_ambiguous_variables() {
+        let tac = lower_code("var t1 = 1;\nfun f() { { var t1 = 2; print t1; } print t1; }");
+
+        assert!(tac.contains("    t1.1 = 2\n    print t1.1\n    print t1.0\n"));
+        assert!(tac.contains("    t1.0 = 1\n"));
+    }
+
+    #[test]
+    fn keeps_evaluation_order_of_operands() {
+        let tac = lower_code("var a = 1;\nprint a + (a = 2);");
+
+        assert!(tac.contains("    t1 = a\n    a = 2\n    t2 = t1 + a\n"));
+    }
+
+    #[test]
+    fn locals_are_not_named_like_globals() {
+        let tac = lower_code("var x = 0;\nfun f() { var x = 1; print x; }\nf();\nprint x;");
+
+        assert!(tac.contains("    x.1 = 1\n    print x.1\n"));
+    }
+}
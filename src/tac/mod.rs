@@ -0,0 +1,33 @@
+mod interpreter;
+mod ir;
+mod lower;
+mod parser;
+
+pub use interpreter::{Interpreter, Value};
+pub use ir::{Constant, Function, Instr, InstrKind, Label, Operand, Place, Program};
+pub(crate) use lower::lower;
+pub use parser::parse;
+
+use crate::{compiler, error::RoxError, heap::Heap, lints::Lints, optimizer::OptLevel};
+
+/// Compiles `code` to three-address code. The program is checked by the bytecode compiler
+/// first, so it fails with the same errors and reports the same warnings.
+pub fn compile(
+    code: &str,
+    file: &str,
+    lints: &Lints,
+    warnings: &mut Vec<RoxError>,
+) -> Result<Program, Vec<RoxError>> {
+    let (program, syntax_errors) = crate::parser::parse(code);
+    compiler::compile_program(
+        &program,
+        syntax_errors,
+        file,
+        &mut Heap::new(),
+        lints,
+        OptLevel::O0,
+        warnings,
+    )?;
+
+    Ok(lower(&program))
+}
@@ -0,0 +1,685 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ast::{BinaryOp, UnaryOp},
+    error::{RoxError, RoxErrorKind, RoxResult, TacError},
+    location::Span,
+    scanner::{parse_number, token::TokenErrorKind, unescape, Scanner, Token, TokenKind},
+};
+
+use super::ir::{Constant, Function, Instr, InstrKind, Label, Operand, Place, Program};
+
+/// Parses a program written in the format three-address code is printed in, one instruction
+/// per line:
+///
+/// ```text
+/// func max(a, b):
+///     t1 = a > b
+///     ifFalse t1 goto L1
+///     return a
+/// L1:
+///     return b
+/// endfunc
+///
+///     param 1
+///     param 2
+///     t1 = call &max, 2
+///     print t1
+/// ```
+///
+/// Instructions outside of functions form the top-level code. Comments, strings and numbers
+/// are written as in Lox. Every error is reported, along with labels and functions that are
+/// used but never defined.
+pub fn parse(code: &str) -> Result<Program, Vec<RoxError>> {
+    let mut parser = Parser::default();
+    let lines = parser.lines(code);
+    let program = parser.program(lines);
+
+    if parser.errors.is_empty() {
+        Ok(program)
+    } else {
+        parser.errors.sort_by_key(|error| error.span.start.offset());
+        Err(parser.errors)
+    }
+}
+
+/// Line of code along with the item it declares.
+enum Item {
+    Function {
+        name: String,
+        params: Vec<(String, Span)>,
+    },
+    EndFunction,
+    Instr(Instr),
+}
+
+/// Code of a function, or of the top level, with the labels it defines and jumps to.
+#[derive(Default)]
+struct Body {
+    code: Vec<Instr>,
+    labels: HashMap<Label, Span>,
+    jumps: Vec<(Label, Span)>,
+}
+
+#[derive(Default)]
+struct Parser {
+    errors: Vec<RoxError>,
+    /// Labels jumped to by the last parsed line.
+    jumps: Vec<(Label, Span)>,
+    /// Functions referenced with `&name`.
+    references: Vec<(String, Span)>,
+}
+
+impl Parser {
+    /// Splits the code into lines of tokens, names like `a.1` being scanned as a single
+    /// identifier. Lines with invalid tokens are left out once their errors are recorded.
+    fn lines<'code>(&mut self, code: &'code str) -> Vec<Line<'code>> {
+        let mut scanner = Scanner::new(code);
+        let mut lines: Vec<Line<'code>> = Vec::new();
+        let mut invalid_lines = HashSet::new();
+
+        loop {
+            let token = scanner.next_token();
+            let line = token.location().line();
+
+            let error = match token.kind() {
+                TokenKind::Eof => break,
+                TokenKind::Error(TokenErrorKind::InvalidLexeme)
+                    if matches!(token.lexeme(), ":" | "&") =>
+                {
+                    None
+                }
+                TokenKind::Error(TokenErrorKind::UnterminatedString) => {
+                    Some(TacError::UnterminatedString)
+                }
+                TokenKind::Error(TokenErrorKind::UnterminatedBlockComment) => {
+                    Some(TacError::UnterminatedBlockComment)
+                }
+                TokenKind::Error(_) => Some(TacError::InvalidToken(token.lexeme().into())),
+                _ => None,
+            };
+
+            if let Some(error) = error {
+                self.errors.push(tac_error(error, token.span()));
+                invalid_lines.insert(line);
+                continue;
+            }
+
+            match lines.last_mut() {
+                Some(last) if last.number == line => last.push(token, code),
+                _ => lines.push(Line::new(line, token)),
+            }
+        }
+
+        lines.retain(|line| !invalid_lines.contains(&line.number));
+        lines
+    }
+
+    fn program(&mut self, lines: Vec<Line<'_>>) -> Program {
+        let mut functions = Vec::new();
+        let mut function_spans: HashMap<String, Span> = HashMap::new();
+        let mut main = Body::default();
+        let mut current: Option<(Function, Body)> = None;
+
+        for mut line in lines {
+            let item = match self.line(&mut line) {
+                Ok(item) => item,
+                Err(error) => {
+                    self.errors.push(error);
+                    self.jumps.clear();
+                    continue;
+                }
+            };
+
+            match item {
+                Item::Function { name, params } => {
+                    if current.is_some() {
+                        self.errors
+                            .push(tac_error(TacError::NestedFunction, line.span()));
+                        continue;
+                    }
+
+                    for (index, (param, span)) in params.iter().enumerate() {
+                        if params[..index].iter().any(|(other, _)| other == param) {
+                            let error = TacError::DuplicateParameter(param.clone());
+                            self.errors.push(tac_error(error, *span));
+                        }
+                    }
+
+                    let function = Function {
+                        name,
+                        params: params.into_iter().map(|(param, _)| param).collect(),
+                        body: Vec::new(),
+                        span: line.span(),
+                    };
+                    current = Some((function, Body::default()));
+                }
+                Item::EndFunction => match current.take() {
+                    Some((function, body)) => {
+                        let function = self.end_function(function, body, &mut function_spans);
+                        functions.push(function);
+                    }
+                    None => self
+                        .errors
+                        .push(tac_error(TacError::UnexpectedEndfunc, line.span())),
+                },
+                Item::Instr(instr) => {
+                    let body = match current.as_mut() {
+                        Some((_, body)) => body,
+                        None => &mut main,
+                    };
+                    self.push(body, instr);
+                }
+            }
+        }
+
+        if let Some((function, body)) = current.take() {
+            let error = TacError::MissingEndfunc(function.name.clone());
+            self.errors.push(tac_error(error, function.span));
+            let function = self.end_function(function, body, &mut function_spans);
+            functions.push(function);
+        }
+
+        self.check_jumps(&main);
+        for (name, span) in std::mem::take(&mut self.references) {
+            if !function_spans.contains_key(&name) {
+                self.errors
+                    .push(tac_error(TacError::UndefinedFunction(name), span));
+            }
+        }
+
+        Program {
+            functions,
+            main: main.code,
+        }
+    }
+
+    fn push(&mut self, body: &mut Body, instr: Instr) {
+        if let InstrKind::Label(label) = instr.kind {
+            match body.labels.get(&label) {
+                Some(first) => {
+                    let error = tac_error(TacError::DuplicateLabel(label.to_string()), instr.span)
+                        .with_note(format!("First defined on line {}", first.start.line() + 1));
+                    self.errors.push(error);
+                }
+                None => {
+                    body.labels.insert(label, instr.span);
+                }
+            }
+        }
+
+        body.jumps.append(&mut self.jumps);
+        body.code.push(instr);
+    }
+
+    fn end_function(
+        &mut self,
+        mut function: Function,
+        body: Body,
+        function_spans: &mut HashMap<String, Span>,
+    ) -> Function {
+        self.check_jumps(&body);
+
+        match function_spans.get(&function.name) {
+            Some(first) => {
+                let error = tac_error(
+                    TacError::DuplicateFunction(function.name.clone()),
+                    function.span,
+                )
+                .with_note(format!("First defined on line {}", first.start.line() + 1));
+                self.errors.push(error);
+            }
+            None => {
+                function_spans.insert(function.name.clone(), function.span);
+            }
+        }
+
+        function.body = body.code;
+        function
+    }
+
+    fn check_jumps(&mut self, body: &Body) {
+        for (label, span) in body.jumps.iter() {
+            if !body.labels.contains_key(label) {
+                let error = TacError::UndefinedLabel(label.to_string());
+                self.errors.push(tac_error(error, *span));
+            }
+        }
+    }
+
+    fn line(&mut self, line: &mut Line<'_>) -> RoxResult<Item> {
+        let span = line.span();
+        let first = line.advance().expect("Lines are not empty");
+        let is_assignment = line.check(TokenKind::Equal);
+
+        let kind = match (first.kind(), first.lexeme()) {
+            (TokenKind::Identifier, "func") if !is_assignment => return self.function(line),
+            (TokenKind::Identifier, "endfunc") if !is_assignment => {
+                line.end()?;
+                return Ok(Item::EndFunction);
+            }
+            (TokenKind::Identifier, "goto") if !is_assignment => {
+                InstrKind::Goto(self.jump_target(line)?)
+            }
+            (TokenKind::Identifier, "ifFalse") if !is_assignment => {
+                let condition = self.operand(line)?;
+                line.keyword("goto")?;
+                InstrKind::IfFalse {
+                    condition,
+                    target: self.jump_target(line)?,
+                }
+            }
+            (TokenKind::If, _) => {
+                let condition = self.operand(line)?;
+                line.keyword("goto")?;
+                InstrKind::If {
+                    condition,
+                    target: self.jump_target(line)?,
+                }
+            }
+            (TokenKind::Identifier, "param") if !is_assignment => {
+                InstrKind::Param(self.operand(line)?)
+            }
+            (TokenKind::Identifier, "call") if !is_assignment => self.call(line, None)?,
+            (TokenKind::Return, _) if line.is_at_end() => InstrKind::Return(None),
+            (TokenKind::Return, _) => InstrKind::Return(Some(self.operand(line)?)),
+            (TokenKind::Print, _) => InstrKind::Print(self.operand(line)?),
+            (TokenKind::Identifier, _) if line.check_lexeme(":") => {
+                line.advance();
+                InstrKind::Label(label(&first)?)
+            }
+            (TokenKind::Identifier, _) if is_assignment => {
+                line.advance();
+                self.assignment(line, place(&first))?
+            }
+            (TokenKind::Identifier, _) if line.check(TokenKind::Dot) => {
+                line.advance();
+                let name = line.identifier("a property name")?;
+                line.expect(TokenKind::Equal, "'='")?;
+                InstrKind::SetProperty {
+                    object: Operand::Place(place(&first)),
+                    name: name.lexeme().to_string(),
+                    value: self.operand(line)?,
+                }
+            }
+            _ => return Err(line.expected("an instruction", Some(first))),
+        };
+
+        line.end()?;
+        Ok(Item::Instr(Instr::new(kind, span)))
+    }
+
+    /// `func name(a, b):`
+    fn function(&mut self, line: &mut Line<'_>) -> RoxResult<Item> {
+        let name = line.identifier("a function name")?;
+
+        line.expect(TokenKind::LeftParen, "'('")?;
+        let mut params = Vec::new();
+        if !line.check(TokenKind::RightParen) {
+            loop {
+                let param = line.identifier("a parameter name")?;
+                params.push((param.lexeme().to_string(), param.span()));
+
+                if !line.check(TokenKind::Comma) {
+                    break;
+                }
+                line.advance();
+            }
+        }
+        line.expect(TokenKind::RightParen, "')'")?;
+
+        if !line.check_lexeme(":") {
+            return Err(line.expected("':'", line.peek()));
+        }
+        line.advance();
+        line.end()?;
+
+        Ok(Item::Function {
+            name: name.lexeme().to_string(),
+            params,
+        })
+    }
+
+    /// Right-hand side of `x = ...`.
+    fn assignment(&mut self, line: &mut Line<'_>, dest: Place) -> RoxResult<InstrKind> {
+        // `call` is only an instruction when followed by its callee, it is a valid name too.
+        let is_call = matches!(line.peek(), Some(token) if token.lexeme() == "call")
+            && line
+                .peek_at(1)
+                .is_some_and(|token| binary_operator(token).is_none());
+        if is_call {
+            line.advance();
+            return self.call(line, Some(dest));
+        }
+
+        let unary = line.peek().and_then(|token| match token.kind() {
+            TokenKind::Minus => Some(UnaryOp::Negate),
+            TokenKind::Bang => Some(UnaryOp::Not),
+            _ => None,
+        });
+        if let Some(operator) = unary {
+            line.advance();
+            return Ok(InstrKind::Unary {
+                dest,
+                operator,
+                operand: self.operand(line)?,
+            });
+        }
+
+        let left = self.operand(line)?;
+        if line.is_at_end() {
+            return Ok(InstrKind::Copy { dest, src: left });
+        }
+        if line.check(TokenKind::Dot) {
+            line.advance();
+            let name = line.identifier("a property name")?;
+            return Ok(InstrKind::GetProperty {
+                dest,
+                object: left,
+                name: name.lexeme().to_string(),
+            });
+        }
+
+        let operator = match line.peek().and_then(binary_operator) {
+            Some(operator) => operator,
+            None => return Err(line.expected("an operator or the end of the line", line.peek())),
+        };
+        line.advance();
+
+        Ok(InstrKind::Binary {
+            dest,
+            operator,
+            left,
+            right: self.operand(line)?,
+        })
+    }
+
+    /// `call f, n`, once `call` is consumed.
+    fn call(&mut self, line: &mut Line<'_>, dest: Option<Place>) -> RoxResult<InstrKind> {
+        let callee = self.operand(line)?;
+        line.expect(TokenKind::Comma, "','")?;
+
+        let count = line.advance();
+        let arguments = count
+            .filter(|token| token.kind() == TokenKind::Number)
+            .and_then(|token| parse_number(token.lexeme()))
+            .filter(|count| count.fract() == 0.0 && *count >= 0.0);
+
+        match arguments {
+            Some(arguments) => Ok(InstrKind::Call {
+                dest,
+                callee,
+                arguments: arguments as usize,
+            }),
+            None => Err(line.expected("a number of arguments", count)),
+        }
+    }
+
+    fn operand(&mut self, line: &mut Line<'_>) -> RoxResult<Operand> {
+        let token = match line.advance() {
+            Some(token) => token,
+            None => return Err(line.expected("an operand", None)),
+        };
+
+        let constant = match token.kind() {
+            TokenKind::Number => Constant::Number(number(&token)?),
+            TokenKind::Minus if line.check(TokenKind::Number) => {
+                let number_token = line.advance().expect("Number was checked");
+                Constant::Number(-number(&number_token)?)
+            }
+            TokenKind::String => match unescape(&token) {
+                Ok(value) => Constant::String(value),
+                Err(errors) => {
+                    let error = errors[0];
+                    let kind = TacError::InvalidEscape(error.sequence.into());
+                    return Err(tac_error(kind, error.span));
+                }
+            },
+            TokenKind::True => Constant::Bool(true),
+            TokenKind::False => Constant::Bool(false),
+            TokenKind::Nil => Constant::Nil,
+            TokenKind::Error(_) if token.lexeme() == "&" => {
+                let name = line.identifier("a function name")?;
+                let span = Span::new(token.location(), name.span().end);
+                self.references.push((name.lexeme().to_string(), span));
+                Constant::Function(name.lexeme().to_string())
+            }
+            TokenKind::Identifier => return Ok(Operand::Place(place(&token))),
+            _ => return Err(line.expected("an operand", Some(token))),
+        };
+
+        Ok(Operand::Const(constant))
+    }
+
+    fn jump_target(&mut self, line: &mut Line<'_>) -> RoxResult<Label> {
+        match line.advance() {
+            Some(token) if token.kind() == TokenKind::Identifier => {
+                let target = label(&token)?;
+                self.jumps.push((target, token.span()));
+                Ok(target)
+            }
+            token => Err(line.expected("a label", token)),
+        }
+    }
+}
+
+struct Line<'code> {
+    number: usize,
+    tokens: Vec<Token<'code>>,
+    current: usize,
+}
+
+impl<'code> Line<'code> {
+    fn new(number: usize, token: Token<'code>) -> Self {
+        Self {
+            number,
+            tokens: vec![token],
+            current: 0,
+        }
+    }
+
+    /// Adds a token, merging `name`, `.` and a number written next to each other into a name.
+    fn push(&mut self, token: Token<'code>, code: &'code str) {
+        let len = self.tokens.len();
+        if token.kind() == TokenKind::Number && len >= 2 {
+            let (name, dot) = (self.tokens[len - 2], self.tokens[len - 1]);
+            if name.kind() == TokenKind::Identifier
+                && dot.kind() == TokenKind::Dot
+                && name.span().end == dot.location()
+                && dot.span().end == token.location()
+            {
+                let start = name.location();
+                let end = token.span().end;
+                let lexeme = &code[start.offset()..end.offset()];
+                self.tokens.truncate(len - 2);
+                self.tokens
+                    .push(Token::new(TokenKind::Identifier, lexeme, start, end));
+                return;
+            }
+        }
+
+        self.tokens.push(token);
+    }
+
+    fn span(&self) -> Span {
+        let first = self.tokens.first().expect("Lines are not empty");
+        let last = self.tokens.last().expect("Lines are not empty");
+        Span::new(first.location(), last.span().end)
+    }
+
+    fn peek(&self) -> Option<Token<'code>> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, distance: usize) -> Option<Token<'code>> {
+        self.tokens.get(self.current + distance).copied()
+    }
+
+    fn advance(&mut self) -> Option<Token<'code>> {
+        let token = self.peek();
+        if token.is_some() {
+            self.current += 1;
+        }
+        token
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current == self.tokens.len()
+    }
+
+    fn check(&self, kind: TokenKind) -> bool {
+        self.peek().is_some_and(|token| token.kind() == kind)
+    }
+
+    fn check_lexeme(&self, lexeme: &str) -> bool {
+        self.peek().is_some_and(|token| token.lexeme() == lexeme)
+    }
+
+    fn expect(&mut self, kind: TokenKind, description: &'static str) -> RoxResult<Token<'code>> {
+        match self.advance() {
+            Some(token) if token.kind() == kind => Ok(token),
+            token => Err(self.expected(description, token)),
+        }
+    }
+
+    fn identifier(&mut self, description: &'static str) -> RoxResult<Token<'code>> {
+        self.expect(TokenKind::Identifier, description)
+    }
+
+    fn keyword(&mut self, keyword: &'static str) -> RoxResult<()> {
+        match self.advance() {
+            Some(token) if token.kind() == TokenKind::Identifier && token.lexeme() == keyword => {
+                Ok(())
+            }
+            token => Err(self.expected(keyword, token)),
+        }
+    }
+
+    /// Error for a missing `description`, `found` being the token found instead of it, `None`
+    /// at the end of the line.
+    fn expected(&self, description: &'static str, found: Option<Token<'_>>) -> RoxError {
+        let (found, span) = match found {
+            Some(token) => (format!("'{}'", token.lexeme()), token.span()),
+            None => {
+                let end = self.span().end;
+                ("the end of the line".into(), Span::new(end, end))
+            }
+        };
+
+        tac_error(
+            TacError::Expected {
+                expected: description,
+                found,
+            },
+            span,
+        )
+    }
+
+    fn end(&mut self) -> RoxResult<()> {
+        match self.peek() {
+            None => Ok(()),
+            token => Err(self.expected("the end of the line", token)),
+        }
+    }
+}
+
+/// `t1` is a temporary, any other name a variable.
+fn place(token: &Token<'_>) -> Place {
+    let name = token.lexeme();
+    match name.strip_prefix('t').map(str::parse::<u32>) {
+        Some(Ok(index)) if name[1..].bytes().all(|c| c.is_ascii_digit()) => Place::Temp(index),
+        _ => Place::Var(name.to_string()),
+    }
+}
+
+fn label(token: &Token<'_>) -> RoxResult<Label> {
+    let name = token.lexeme();
+    match name.strip_prefix('L').map(str::parse::<u32>) {
+        Some(Ok(index)) if name[1..].bytes().all(|c| c.is_ascii_digit()) => Ok(Label(index)),
+        _ => Err(tac_error(TacError::InvalidLabel(name.into()), token.span())),
+    }
+}
+
+fn number(token: &Token<'_>) -> RoxResult<f64> {
+    parse_number(token.lexeme()).ok_or_else(|| {
+        let error = TacError::InvalidNumberLiteral(token.lexeme().into());
+        tac_error(error, token.span())
+    })
+}
+
+fn binary_operator(token: Token<'_>) -> Option<BinaryOp> {
+    let operator = match token.kind() {
+        TokenKind::Plus => BinaryOp::Add,
+        TokenKind::Minus => BinaryOp::Subtract,
+        TokenKind::Star => BinaryOp::Multiply,
+        TokenKind::Slash => BinaryOp::Divide,
+        TokenKind::EqualEqual => BinaryOp::Equal,
+        TokenKind::BangEqual => BinaryOp::NotEqual,
+        TokenKind::Greater => BinaryOp::Greater,
+        TokenKind::GreaterEqual => BinaryOp::GreaterEqual,
+        TokenKind::Less => BinaryOp::Less,
+        TokenKind::LessEqual => BinaryOp::LessEqual,
+        _ => return None,
+    };
+
+    Some(operator)
+}
+
+fn tac_error(kind: TacError, span: Span) -> RoxError {
+    RoxError::new(RoxErrorKind::TacError(kind), span)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::{RoxErrorKind, TacError};
+
+    use super::parse;
+
+    #[test]
+    fn parses_printed_programs() {
+        let code = "func f(n.1, b):
+    t1 = -n.1
+    t2 = t1 <= 2
+    if t2 goto L1
+    t3 = call f, 2
+    call &f, 0
+    return t3
+L1:
+    return
+endfunc
+
+    f = &f
+    param \"a\\n\"
+    param -1.5
+    t1 = call f, 2
+    t2 = t1.size
+    t1.size = t2
+    ifFalse t1 goto L1
+    print nil
+L1:
+";
+        let program = parse(code).expect("Program should parse");
+
+        assert_eq!(program.to_string(), code);
+    }
+
+    #[test]
+    fn reports_errors_at_their_token() {
+        let errors = parse("goto L1\nx = 1 +\nfunc f():\n  y = &g\n").unwrap_err();
+
+        let errors: Vec<(usize, usize, &TacError)> = errors
+            .iter()
+            .map(|error| match &error.src {
+                RoxErrorKind::TacError(kind) => {
+                    (error.span.start.line(), error.span.start.column(), kind)
+                }
+                kind => panic!("Unexpected error {}", kind),
+            })
+            .collect();
+
+        assert!(matches!(errors[0], (0, 5, TacError::UndefinedLabel(_))));
+        assert!(matches!(errors[1], (1, 7, TacError::Expected { .. })));
+        assert!(matches!(errors[2], (2, 0, TacError::MissingEndfunc(_))));
+        assert!(matches!(errors[3], (3, 6, TacError::UndefinedFunction(_))));
+    }
+}
@@ -0,0 +1,554 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::{self, Debug},
+    marker::PhantomData,
+    mem,
+    rc::Rc,
+};
+
+use crate::{
+    chunk::Value,
+    convert::{FromArgs, FromValue, IntoValue},
+    error::RuntimeError,
+    heap::{Heap, Object, Ref, Tracer},
+    objects::{Native, NativeFn},
+};
+
+/// Rust type whose values scripts can hold as objects, with the methods and properties given
+/// to its class by a `ClassBuilder`.
+///
+/// Values are put in the heap with `Heap::alloc_instance`, which gives a `Handle` to them.
+/// Objects that keep values of the heap, e.g. a `Handle` to another instance, must mark them
+/// in `trace` or they may be freed while still in use.
+pub trait UserData: Any {
+    /// Name of the class, which scripts see in errors and when printing instances.
+    const NAME: &'static str;
+
+    /// Marks the values the object holds so the collector keeps them.
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+/// `UserData` without its name, so instances can hold values of any type.
+trait Data {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+impl<T: UserData> Data for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        UserData::trace(self, tracer)
+    }
+}
+
+/// Object holding a value of a `UserData` type.
+pub struct Instance {
+    pub(crate) class: Ref<Class>,
+    data: Box<dyn Data>,
+    /// Size of the value, which is not known once it is boxed.
+    size: usize,
+}
+
+impl Instance {
+    pub(crate) fn new<T: UserData>(class: Ref<Class>, data: T) -> Self {
+        Self {
+            class,
+            data: Box::new(data),
+            size: mem::size_of::<T>(),
+        }
+    }
+
+    /// Value held by the instance, `None` if it is not a `T`.
+    pub fn downcast_ref<T: UserData>(&self) -> Option<&T> {
+        self.data.as_any().downcast_ref()
+    }
+
+    pub fn downcast_mut<T: UserData>(&mut self) -> Option<&mut T> {
+        self.data.as_any_mut().downcast_mut()
+    }
+
+    /// Whether the instance holds a `T`.
+    pub fn is<T: UserData>(&self) -> bool {
+        self.data.as_any().is::<T>()
+    }
+}
+
+impl Debug for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Instance")
+            .field("class", &self.class)
+            .finish()
+    }
+}
+
+impl Object for Instance {
+    fn size(&self) -> usize {
+        mem::size_of::<Instance>() + self.size
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.class);
+        self.data.trace(tracer);
+    }
+}
+
+/// Methods and properties of the instances of a `UserData` type, implemented by natives taking
+/// the instance as their first argument.
+#[derive(Debug)]
+pub struct Class {
+    pub(crate) name: &'static str,
+    pub(crate) methods: HashMap<Ref<String>, Ref<Native>>,
+    pub(crate) getters: HashMap<Ref<String>, Ref<Native>>,
+    pub(crate) setters: HashMap<Ref<String>, Ref<Native>>,
+}
+
+impl Class {
+    pub(crate) fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            methods: HashMap::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl Object for Class {
+    fn size(&self) -> usize {
+        mem::size_of::<Class>()
+            + (self.methods.capacity() + self.getters.capacity() + self.setters.capacity())
+                * mem::size_of::<(Ref<String>, Ref<Native>)>()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        let members = self
+            .methods
+            .iter()
+            .chain(&self.getters)
+            .chain(&self.setters);
+        for (name, native) in members {
+            tracer.mark(*name);
+            tracer.mark(*native);
+        }
+    }
+}
+
+/// Method read from an instance, which calls the method's native with the instance first.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub(crate) receiver: Ref<Instance>,
+    pub(crate) method: Ref<Native>,
+}
+
+impl Object for BoundMethod {
+    fn size(&self) -> usize {
+        mem::size_of::<BoundMethod>()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.receiver);
+        tracer.mark(self.method);
+    }
+}
+
+/// Reference to an instance holding a `T`, read with `Heap::get`.
+///
+/// Natives taking a handle can only be called with instances of `T`, others failing with
+/// `RuntimeError::InvalidArgument`.
+pub struct Handle<T> {
+    instance: Ref<Instance>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Handle<T> {
+    pub(crate) fn new(instance: Ref<Instance>) -> Self {
+        Self {
+            instance,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn instance(self) -> Ref<Instance> {
+        self.instance
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.instance == other.instance
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle({:?})", self.instance)
+    }
+}
+
+impl<T> IntoValue for Handle<T> {
+    fn into_value(self, _: &mut Heap) -> Value {
+        Value::Instance(self.instance)
+    }
+}
+
+impl<T: UserData> FromValue for Handle<T> {
+    fn expected() -> String {
+        format!("an instance of {}", T::NAME)
+    }
+
+    fn from_value(value: Value, heap: &Heap) -> Option<Self> {
+        match value {
+            Value::Instance(instance) if heap.deref(instance).is::<T>() => {
+                Some(Handle::new(instance))
+            }
+            _ => None,
+        }
+    }
+}
+
+enum Member {
+    Method,
+    Getter,
+    Setter,
+}
+
+/// Methods and properties to give the class of `T`, registered with `Vm::register_class`.
+///
+/// Like natives registered with `Vm::register_fn`, they take and return Rust types, the
+/// instance being given as a `Handle`. Properties are read with their getter and assigned with
+/// their setter, scripts failing to assign properties that have none.
+pub struct ClassBuilder<T> {
+    members: Vec<(Member, String, usize, Rc<NativeFn>)>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: UserData> Default for ClassBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: UserData> ClassBuilder<T> {
+    pub fn new() -> Self {
+        Self {
+            members: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Adds a method, called like `object.name(args)`.
+    pub fn method<A, R, F>(mut self, name: &str, method: F) -> Self
+    where
+        A: FromArgs,
+        R: IntoValue,
+        F: Fn(&mut Heap, Handle<T>, A) -> Result<R, RuntimeError> + 'static,
+    {
+        let function = move |heap: &mut Heap, args: &[Value]| {
+            let this = receiver(args, heap);
+            let args = A::from_args(&args[1..], heap)?;
+            Ok(method(heap, this, args)?.into_value(heap))
+        };
+        let arity = A::ARITY + 1;
+        self.members
+            .push((Member::Method, name.to_owned(), arity, Rc::new(function)));
+        self
+    }
+
+    /// Adds a property read with `object.name`.
+    pub fn getter<R, F>(mut self, name: &str, getter: F) -> Self
+    where
+        R: IntoValue,
+        F: Fn(&mut Heap, Handle<T>) -> Result<R, RuntimeError> + 'static,
+    {
+        let function = move |heap: &mut Heap, args: &[Value]| {
+            let this = receiver(args, heap);
+            Ok(getter(heap, this)?.into_value(heap))
+        };
+        self.members
+            .push((Member::Getter, name.to_owned(), 1, Rc::new(function)));
+        self
+    }
+
+    /// Lets scripts assign the property `name` with `object.name = value`.
+    pub fn setter<V, F>(mut self, name: &str, setter: F) -> Self
+    where
+        V: FromValue,
+        F: Fn(&mut Heap, Handle<T>, V) -> Result<(), RuntimeError> + 'static,
+    {
+        let property = name.to_owned();
+        let function = move |heap: &mut Heap, args: &[Value]| {
+            let this = receiver(args, heap);
+            let value =
+                V::from_value(args[1], heap).ok_or_else(|| RuntimeError::InvalidPropertyValue {
+                    name: property.clone(),
+                    expected: V::expected(),
+                    got: args[1].type_name_in(heap),
+                })?;
+            setter(heap, this, value)?;
+            Ok(Value::Nil)
+        };
+        self.members
+            .push((Member::Setter, name.to_owned(), 2, Rc::new(function)));
+        self
+    }
+
+    /// Adds the members to the class of `T`, replacing those with the same name.
+    pub(crate) fn build(self, heap: &mut Heap) {
+        let class = heap.class::<T>();
+
+        for (member, name, arity, function) in self.members {
+            let name = heap.alloc_string(name);
+            let native = heap.alloc(Native {
+                name,
+                arity,
+                function,
+            });
+
+            let class = heap.deref_mut(class);
+            let members = match member {
+                Member::Method => &mut class.methods,
+                Member::Getter => &mut class.getters,
+                Member::Setter => &mut class.setters,
+            };
+            members.insert(name, native);
+        }
+    }
+}
+
+/// Instance a member was called on, which is always one of its class.
+fn receiver<T: UserData>(args: &[Value], heap: &Heap) -> Handle<T> {
+    Handle::from_value(args[0], heap).expect("Members are called on instances of their class")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use crate::{
+        chunk::Value,
+        convert::{FromValue, IntoValue},
+        error::RuntimeError,
+        heap::Tracer,
+        optimizer::OptLevel,
+        vm::{Mode, Vm},
+    };
+
+    use super::{ClassBuilder, Handle, UserData};
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+
+    impl UserData for Point {
+        const NAME: &'static str = "Point";
+    }
+
+    struct Node {
+        value: f64,
+        next: Option<Handle<Node>>,
+    }
+
+    impl UserData for Node {
+        const NAME: &'static str = "Node";
+
+        fn trace(&self, tracer: &mut Tracer) {
+            if let Some(next) = self.next {
+                tracer.mark(next.instance());
+            }
+        }
+    }
+
+    fn point_vm(output: &Output) -> Vm {
+        let mut vm = Vm::new();
+        vm.set_output(output.clone());
+        vm.register_fn("point", |heap, (x, y): (f64, f64)| {
+            Ok(heap.alloc_instance(Point { x, y }))
+        });
+        vm.register_fn("node", |heap, (value,): (f64,)| {
+            Ok(heap.alloc_instance(Node { value, next: None }))
+        });
+        vm.register_class(
+            ClassBuilder::<Point>::new()
+                .getter("x", |heap, this| Ok(heap.get(this).x))
+                .setter("x", |heap, this, x: f64| {
+                    heap.get_mut(this).x = x;
+                    Ok(())
+                })
+                .getter("y", |heap, this| Ok(heap.get(this).y))
+                .method("plus", |heap, this, (other,): (Handle<Point>,)| {
+                    let (a, b) = (heap.get(this), heap.get(other));
+                    let sum = Point {
+                        x: a.x + b.x,
+                        y: a.y + b.y,
+                    };
+                    Ok(heap.alloc_instance(sum))
+                })
+                .method("fail", |_, _, ()| -> Result<(), _> {
+                    Err(RuntimeError::Native("Failed".into()))
+                }),
+        );
+        vm
+    }
+
+    #[test]
+    fn objects_have_methods_and_properties() {
+        let code = "var p = point(1, 2);
+                    print p;
+                    print p.x + p.y;
+                    print p.x = 5;
+                    var q = p.plus(point(1, 1));
+                    print q.x;
+                    var plus = p.plus;
+                    print plus;
+                    print plus(q).y;
+                    fun move(p) { p.x = p.x + 1; return p; }
+                    for (var i = 0; i < 3; i = i + 1) move(q);
+                    print q.x;
+                    print p == p and p != q;";
+
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2].iter() {
+                let output = Output::default();
+                let mut vm = point_vm(&output);
+                vm.set_mode(*mode);
+                vm.set_opt_level(*opt_level);
+
+                assert!(vm.interpret(code, "point.lox").is_ok());
+                assert_eq!(
+                    String::from_utf8(output.0.borrow().clone()).unwrap(),
+                    "Point instance\n3\n5\n6\n<method Point.plus>\n5\n9\ntrue\n"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn members_check_what_they_are_given() {
+        let output = Output::default();
+        let mut vm = point_vm(&output);
+        vm.interpret("var p = point(1, 2);", "point.lox").unwrap();
+
+        let cases = [
+            ("p.z;", "Undefined property \"z\" of Point"),
+            ("p.z = 1;", "Undefined property \"z\" of Point"),
+            ("p.y = 1;", "Property \"y\" of Point can not be assigned"),
+            (
+                "p.plus = 1;",
+                "Property \"plus\" of Point can not be assigned",
+            ),
+            (
+                "p.x = \"a\";",
+                "Property \"x\" must be a number, got string",
+            ),
+            (
+                "p.plus(node(1));",
+                "Argument 1 must be an instance of Point, got Node",
+            ),
+            ("p.plus();", "Expected 1 arguments but got 0"),
+            ("p.fail();", "Failed"),
+            ("1.5.x;", "Only objects have properties, got number"),
+            ("\"a\".x = 1;", "Only objects have properties, got string"),
+        ];
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            vm.set_mode(*mode);
+            for (code, message) in cases.iter() {
+                let errors = vm.interpret(code, "point.lox").unwrap_err();
+                assert_eq!(errors[0].to_string(), *message, "{}", code);
+            }
+        }
+
+        let p = vm.get_global("p").unwrap();
+        let p = Handle::<Point>::from_value(p, vm.heap()).unwrap();
+        assert_eq!(vm.heap().get(p).x, 1.0);
+        assert!(Handle::<Node>::from_value(Value::Instance(p.instance()), vm.heap()).is_none());
+    }
+
+    #[test]
+    fn objects_keep_what_they_trace() {
+        let output = Output::default();
+        let mut vm = point_vm(&output);
+
+        let first = {
+            let heap = vm.heap_mut();
+            let tail = heap.alloc_instance(Node {
+                value: 2.0,
+                next: None,
+            });
+            heap.alloc_instance(Node {
+                value: 1.0,
+                next: Some(tail),
+            })
+        };
+        let list = first.into_value(vm.heap_mut());
+        vm.set_global("list", list);
+        vm.interpret("node(3); point(0, 0);", "node.lox").unwrap();
+
+        let before = vm.heap().bytes_allocated();
+        vm.collect_garbage();
+        assert!(vm.heap().bytes_allocated() < before);
+
+        let next = vm.heap().get(first).next.unwrap();
+        assert_eq!(vm.heap().get(next).value, 2.0);
+    }
+}
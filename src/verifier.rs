@@ -0,0 +1,231 @@
+use crate::{
+    chunk::{Chunk, Instruction, Value},
+    error::VerificationError,
+    optimizer::jump_target,
+};
+
+/// Checks that running the bytecode of a function taking `arity` arguments never reads past its
+/// code or constants, nor uses values below its frame, whatever path it takes. Returns the depth
+/// of the frame's stack before each instruction, `None` for those that can not be reached.
+///
+/// Instructions may then be read and operands popped without checking they exist.
+pub fn verify(chunk: &Chunk, arity: usize) -> Result<Vec<Option<usize>>, VerificationError> {
+    let mut depths = vec![None; chunk.code.len()];
+    let mut pending = vec![(0, arity + 1)];
+
+    while let Some((index, depth)) = pending.pop() {
+        match depths.get(index) {
+            Some(Some(known)) if *known == depth => continue,
+            Some(Some(_)) => return Err(VerificationError::InconsistentDepth(index)),
+            Some(None) => depths[index] = Some(depth),
+            None => return Err(VerificationError::FallsOffEnd),
+        }
+
+        let instruction = chunk.code[index];
+        let (popped, pushed) = effect(instruction);
+        let after = match depth.checked_sub(popped) {
+            Some(after) => after + pushed,
+            None => return Err(VerificationError::StackUnderflow(index)),
+        };
+
+        // The value stored by `SetLocalPop` is popped first, so it can not go back in its slot.
+        let slots = match instruction {
+            Instruction::GetLocal(slot) | Instruction::SetLocal(slot) => [Some(slot), None],
+            Instruction::SetLocalPop(slot) => [Some(slot), Some(slot)],
+            Instruction::AddLocals(a, b) => [Some(a.into()), Some(b.into())],
+            _ => [None, None],
+        };
+        let limit = match instruction {
+            Instruction::SetLocalPop(_) => after,
+            _ => depth,
+        };
+        if slots.iter().flatten().any(|slot| *slot as usize >= limit) {
+            return Err(VerificationError::StackUnderflow(index));
+        }
+
+        let constant = match instruction {
+            Instruction::Constant(constant)
+            | Instruction::DefineGlobal(constant)
+            | Instruction::GetGlobal(constant)
+            | Instruction::SetGlobal(constant)
+            | Instruction::AddConstant(constant)
+            | Instruction::SubtractConstant(constant)
+            | Instruction::GetProperty(constant)
+            | Instruction::SetProperty(constant) => Some(constant),
+            _ => None,
+        };
+        match constant.map(|constant| chunk.constants.get(constant as usize)) {
+            Some(None) => return Err(VerificationError::ConstantOutOfBounds(index)),
+            Some(Some(value)) if !matches!(value, Value::String(_)) && names(instruction) => {
+                return Err(VerificationError::InvalidName(index))
+            }
+            _ => {}
+        }
+
+        if let Instruction::Jump(_) | Instruction::JumpIfFalse(_) | Instruction::Loop(_) =
+            instruction
+        {
+            match offset_target(instruction, index).filter(|target| *target < chunk.code.len()) {
+                Some(target) => pending.push((target, after)),
+                None => return Err(VerificationError::JumpOutOfBounds(index)),
+            }
+        }
+        if !matches!(
+            instruction,
+            Instruction::Jump(_) | Instruction::Loop(_) | Instruction::Return
+        ) {
+            pending.push((index + 1, after));
+        }
+    }
+
+    Ok(depths)
+}
+
+/// Whether the constant an instruction reads is the name of a global or of a property.
+fn names(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::DefineGlobal(_)
+            | Instruction::GetGlobal(_)
+            | Instruction::SetGlobal(_)
+            | Instruction::GetProperty(_)
+            | Instruction::SetProperty(_)
+    )
+}
+
+/// Where a jump lands, `None` if it would be before the start of the code.
+fn offset_target(instruction: Instruction, index: usize) -> Option<usize> {
+    match instruction {
+        Instruction::Loop(offset) => (index + 1).checked_sub(offset as usize),
+        _ => jump_target(instruction, index),
+    }
+}
+
+/// Number of values an instruction pops, or needs on the stack, and then pushes.
+fn effect(instruction: Instruction) -> (usize, usize) {
+    match instruction {
+        Instruction::Constant(_)
+        | Instruction::Nil
+        | Instruction::True
+        | Instruction::False
+        | Instruction::GetGlobal(_)
+        | Instruction::GetLocal(_)
+        | Instruction::AddLocals(..) => (0, 1),
+        Instruction::Negate
+        | Instruction::Not
+        | Instruction::SetGlobal(_)
+        | Instruction::SetLocal(_)
+        | Instruction::JumpIfFalse(_)
+        | Instruction::AddConstant(_)
+        | Instruction::SubtractConstant(_)
+        | Instruction::GetProperty(_) => (1, 1),
+        Instruction::Add
+        | Instruction::Subtract
+        | Instruction::Multiply
+        | Instruction::Divide
+        | Instruction::Equal
+        | Instruction::NotEqual
+        | Instruction::Greater
+        | Instruction::GreaterEqual
+        | Instruction::Less
+        | Instruction::LessEqual
+        | Instruction::SetProperty(_) => (2, 1),
+        Instruction::Print
+        | Instruction::Pop
+        | Instruction::DefineGlobal(_)
+        | Instruction::SetLocalPop(_)
+        | Instruction::Return => (1, 0),
+        Instruction::Jump(_) | Instruction::Loop(_) => (0, 0),
+        // The callee and its arguments are replaced by the result.
+        Instruction::Call(arguments) => (arguments as usize + 1, 1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::{Chunk, Instruction, Value},
+        error::VerificationError,
+        heap::Heap,
+        lints::Lints,
+        location::Span,
+        optimizer::OptLevel,
+    };
+
+    use super::verify;
+
+    fn chunk(code: &[Instruction]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for instruction in code {
+            chunk.write(*instruction, Span::default());
+        }
+        chunk.add_constant(Value::Number(1.0)).unwrap();
+        chunk
+    }
+
+    #[test]
+    fn accepts_compiled_code() {
+        let code =
+            "fun f(n) { var total = 0; for (var i = 0; i < n; i = i + 1) total = total + i; \
+                    return total; } print f(3) or \"none\";";
+        for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2].iter() {
+            let mut heap = Heap::new();
+            let script = crate::compiler::compile(
+                code,
+                "verifier.lox",
+                &mut heap,
+                &Lints::new(),
+                *opt_level,
+                &mut Vec::new(),
+            )
+            .expect("Program should compile");
+
+            for function in crate::objects::functions(&heap, script) {
+                let function = heap.deref(function);
+                assert!(verify(&function.chunk, function.arity).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_code_reading_out_of_bounds() {
+        use Instruction::*;
+
+        let cases = [
+            (
+                vec![Pop, Pop, Nil, Return],
+                VerificationError::StackUnderflow(1),
+            ),
+            (
+                vec![GetLocal(1), Return],
+                VerificationError::StackUnderflow(0),
+            ),
+            (
+                vec![Nil, SetLocalPop(1), Nil, Return],
+                VerificationError::StackUnderflow(1),
+            ),
+            (
+                vec![Constant(1), Return],
+                VerificationError::ConstantOutOfBounds(0),
+            ),
+            (
+                vec![GetGlobal(0), Return],
+                VerificationError::InvalidName(0),
+            ),
+            (
+                vec![Jump(2), Nil, Return],
+                VerificationError::JumpOutOfBounds(0),
+            ),
+            (vec![Loop(2), Return], VerificationError::JumpOutOfBounds(0)),
+            (vec![Nil], VerificationError::FallsOffEnd),
+            (
+                vec![True, JumpIfFalse(1), Nil, Return],
+                VerificationError::InconsistentDepth(3),
+            ),
+        ];
+
+        for (code, error) in cases.iter() {
+            assert_eq!(verify(&chunk(code), 0), Err(error.clone()), "{:?}", code);
+        }
+    }
+}
@@ -1,155 +1,1302 @@
+mod registers;
+
+#[cfg(feature = "debug_trace_execution")]
+use crate::debug::Disassembler;
 use crate::{
     chunk::{Chunk, Instruction, Value},
-    compiler::compile,
-    debug::Disassembler,
-    error::{RoxError, RoxErrorKind, RoxResult, RuntimeError},
-    heap::Heap,
+    compiler::{compile, compile_program},
+    convert::{FromArgs, IntoValue},
+    error::{RoxError, RoxErrorKind, RoxResult, RuntimeError, TraceFrame},
+    heap::{Heap, Ref},
+    lints::Lints,
+    objects::{Function, Native},
+    optimizer::OptLevel,
+    parser, register,
+    userdata::{BoundMethod, ClassBuilder, UserData},
+    verifier,
 };
 use core::panic;
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
-pub struct Vm {
+/// Number of nested calls scripts can make unless configured otherwise.
+pub const DEFAULT_MAX_FRAMES: usize = 1024;
+/// Number of values scripts can have on the stack unless configured otherwise, enough for each
+/// frame to use 256 of them.
+pub const DEFAULT_MAX_STACK: usize = DEFAULT_MAX_FRAMES * 256;
+
+struct CallFrame {
+    function: Ref<Function>,
     ip: usize,
-    chunk: Chunk,
+    /// Index of the stack where the frame's slots start.
+    slots: usize,
+}
+
+/// Instruction set functions are run in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// The stack bytecode, as compiled.
+    Stack,
+    /// Register code translated from the bytecode when functions are first called, where
+    /// instructions name the registers of their operands and result.
+    Register,
+}
+
+pub struct Vm {
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
     heap: Heap,
+    globals: HashMap<Ref<String>, Value>,
+    lints: Lints,
+    opt_level: OptLevel,
+    /// Warnings found while compiling, until they are taken by `take_warnings`.
+    warnings: Vec<RoxError>,
+    /// Where `print` writes to.
+    output: Box<dyn Write>,
+    mode: Mode,
+    /// Number of instructions left to run, if limited.
+    fuel: Option<u64>,
+    /// Time after which scripts stop, checked at backward jumps and calls.
+    deadline: Option<Instant>,
+    /// Set from other threads to cancel the running script, checked along with the deadline.
+    interrupt: Arc<AtomicBool>,
+    max_frames: usize,
+    max_stack: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Vm {
-    pub fn new(chunk: Chunk) -> Self {
+    pub fn new() -> Self {
         Self {
-            chunk,
-            ip: 0,
+            frames: Vec::new(),
             stack: Vec::new(),
             heap: Heap::new(),
+            globals: HashMap::new(),
+            lints: Lints::new(),
+            opt_level: OptLevel::default(),
+            warnings: Vec::new(),
+            output: Box::new(io::stdout()),
+            mode: Mode::Stack,
+            fuel: None,
+            deadline: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: DEFAULT_MAX_STACK,
+        }
+    }
+
+    /// Makes `print` write to `output` instead of the standard output.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Configures how warnings found while compiling are reported.
+    pub fn set_lints(&mut self, lints: Lints) {
+        self.lints = lints;
+    }
+
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+
+    /// Switches the instruction set, discarding any suspended execution as its frames are laid
+    /// out for the other one.
+    pub fn set_mode(&mut self, mode: Mode) {
+        if mode != self.mode {
+            self.reset_stack();
+        }
+        self.mode = mode;
+    }
+
+    /// Limits the number of instructions run from now on, execution stopping with
+    /// `RuntimeError::BudgetExhausted` when there are none left.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Number of instructions that can still be run, if limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Makes execution stop with `RuntimeError::DeadlineExceeded` once `deadline` has passed.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Limits the number of nested calls, making more fail with `RuntimeError::StackOverflow`.
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    /// Limits the number of values on the stack, making calls that could use more fail with
+    /// `RuntimeError::StackOverflow`.
+    pub fn set_max_stack(&mut self, max_stack: usize) {
+        self.max_stack = max_stack;
+    }
+
+    /// Flag that, once set, makes the running script stop with `RuntimeError::Interrupted`. It
+    /// is cleared when the script stops.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// Returns the warnings found since the last call.
+    pub fn take_warnings(&mut self) -> Vec<RoxError> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Runs a script. Execution that was suspended is discarded.
+    pub fn interpret(&mut self, code: &str, file: &str) -> Result<(), Vec<RoxError>> {
+        let script = self.compile(code, file)?;
+        self.call(script, &[]).map(|_| ())
+    }
+
+    /// Compiles a script into a function taking no arguments, which runs it when called.
+    pub fn compile(&mut self, code: &str, file: &str) -> Result<Value, Vec<RoxError>> {
+        let function = compile(
+            code,
+            file,
+            &mut self.heap,
+            &self.lints,
+            self.opt_level,
+            &mut self.warnings,
+        )?;
+
+        Ok(Value::Function(function))
+    }
+
+    /// Runs a script read from `input`, like `interpret`. The script is parsed as it is read, so
+    /// it can come from a pipe or socket that is still being written to.
+    pub fn interpret_reader(&mut self, input: impl Read, file: &str) -> Result<(), Vec<RoxError>> {
+        let script = self.compile_reader(input, file)?;
+        self.call(script, &[]).map(|_| ())
+    }
+
+    /// Compiles a script read from `input`, like `compile`.
+    pub fn compile_reader(&mut self, input: impl Read, file: &str) -> Result<Value, Vec<RoxError>> {
+        let (program, syntax_errors) = parser::parse_reader(input);
+        let function = compile_program(
+            &program,
+            syntax_errors,
+            file,
+            &mut self.heap,
+            &self.lints,
+            self.opt_level,
+            &mut self.warnings,
+        )?;
+
+        Ok(Value::Function(function))
+    }
+
+    /// Calls a function with `args`, returning what it returns. Execution that was suspended is
+    /// discarded.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value, Vec<RoxError>> {
+        self.reset_stack();
+        self.stack.push(callee);
+        self.stack.extend_from_slice(args);
+
+        // Natives return right away, without a frame to run.
+        let result = self.call_value(callee, args.len()).and_then(|_| {
+            if self.frames.is_empty() {
+                Ok(())
+            } else {
+                self.execute()
+            }
+        });
+        self.finish(result)
+    }
+
+    /// Continues the execution that stopped because it ran out of fuel or time, from the
+    /// instruction it stopped at, once more was given. Returns what the interrupted call
+    /// returns, `nil` if there was none.
+    pub fn resume(&mut self) -> Result<Value, Vec<RoxError>> {
+        if self.frames.is_empty() {
+            return Ok(Value::Nil);
+        }
+
+        let result = self.execute();
+        self.finish(result)
+    }
+
+    /// Whether execution was stopped and can be resumed.
+    pub fn is_suspended(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// Makes a function implemented in Rust available to scripts as the global `name`.
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut Heap, &[Value]) -> Result<Value, RuntimeError> + 'static,
+    {
+        let name = self.heap.alloc_string(name.to_owned());
+        let native = self.heap.alloc(Native {
+            name,
+            arity,
+            function: Rc::new(function),
+        });
+        self.globals.insert(name, Value::Native(native));
+    }
+
+    /// Makes a function implemented in Rust available to scripts as the global `name`, its
+    /// arguments being converted to the types it takes and its result to a value. It can not be
+    /// called with other arguments, e.g. `add` taking `(f64, f64)` fails when given a string.
+    pub fn register_fn<A, R, F>(&mut self, name: &str, function: F)
+    where
+        A: FromArgs,
+        R: IntoValue,
+        F: Fn(&mut Heap, A) -> Result<R, RuntimeError> + 'static,
+    {
+        self.register_native(name, A::ARITY, move |heap, args| {
+            let args = A::from_args(args, heap)?;
+            Ok(function(heap, args)?.into_value(heap))
+        });
+    }
+
+    /// Gives scripts the methods and properties of the class of `T`, replacing those already
+    /// registered with the same names.
+    pub fn register_class<T: UserData>(&mut self, class: ClassBuilder<T>) {
+        class.build(&mut self.heap);
+    }
+
+    /// Value of the global variable `name`, if it was defined.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let name = self.heap.find_string(name)?;
+        self.globals.get(&name).copied()
+    }
+
+    /// Defines the global variable `name`, or assigns it if it already was.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name = self.heap.alloc_string(name.to_owned());
+        self.globals.insert(name, value);
+    }
+
+    /// Frees the objects that can not be reached from the globals nor the suspended execution.
+    /// Collections also happen on their own while scripts run, once enough was allocated.
+    pub fn collect_garbage(&mut self) {
+        let mut tracer = self.heap.tracer();
+        for value in self.stack.iter() {
+            value.trace(&mut tracer);
+        }
+        for frame in self.frames.iter() {
+            tracer.mark(frame.function);
+        }
+        for (name, value) in self.globals.iter() {
+            tracer.mark(*name);
+            value.trace(&mut tracer);
+        }
+
+        self.heap.collect(tracer);
+    }
+
+    /// Heap holding the objects values refer to, e.g. to read strings.
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Heap holding the objects values refer to, e.g. to allocate strings.
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    fn execute(&mut self) -> RoxResult<()> {
+        match self.mode {
+            Mode::Stack => self.run(),
+            Mode::Register => self.run_registers(),
         }
     }
 
-    pub fn interpret(&mut self, code: &str) -> Result<(), Vec<RoxError>> {
-        self.chunk = compile(code, &mut self.heap)?;
-        self.ip = 0;
+    /// Takes the result of the call that completed, or resets the stack after errors except
+    /// when execution can be resumed.
+    fn finish(&mut self, result: RoxResult<()>) -> Result<Value, Vec<RoxError>> {
+        // An interrupt that came after the last check is for the script that just stopped.
+        self.interrupt.store(false, Ordering::Relaxed);
 
-        self.run().map_err(|err| vec![err])
+        match result {
+            Ok(()) => Ok(self.stack.pop().expect("Calls leave their result")),
+            Err(err) => {
+                let resumable = match &err.src {
+                    RoxErrorKind::RuntimeError(err) => err.is_resumable(),
+                    _ => false,
+                };
+                if !resumable {
+                    self.reset_stack();
+                }
+                Err(vec![err])
+            }
+        }
     }
 
     fn run(&mut self) -> RoxResult<()> {
+        // The running function's code and constants, its instruction pointer and the start of
+        // its frame are kept here, and only looked up again when a call starts or ends. The
+        // frame's instruction pointer is updated before anything that may read it.
+        let (mut code, mut constants, mut ip, mut slots) = self.enter_frame();
+
         loop {
-            let inst = match self.chunk.code.get(self.ip) {
-                Some(inst) => inst,
-                None => panic!("Reached out-of-bounds of program"),
-            };
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    self.frame_mut().ip = ip;
+                    return Err(self.suspend(RuntimeError::BudgetExhausted));
+                }
+                *fuel -= 1;
+            }
+
+            // SAFETY: the code was verified when the function was first called, so jumps land
+            // inside it and execution can not continue past its last instruction.
+            let inst = unsafe { *code.add(ip) };
 
             #[cfg(feature = "debug_trace_execution")]
             {
-                let dis = Disassembler::new(&self.chunk, Some(&self.stack));
-                dis.instruction(self.ip, *inst);
+                let dis = Disassembler::new(self.chunk()).with_stack(&self.stack);
+                dis.instruction(ip, inst);
+            }
+
+            ip += 1;
+
+            // Stores the instruction pointer before building an error, which locates it.
+            macro_rules! throw {
+                ($error:expr) => {{
+                    self.frame_mut().ip = ip;
+                    let error = $error;
+                    return Err(error);
+                }};
             }
 
-            self.ip = self.ip.saturating_add(1);
+            // SAFETY: verification made sure the operands of each instruction are on the stack,
+            // above the start of the frame, and that local slots are inside the frame.
+            macro_rules! pop {
+                () => {
+                    unsafe { self.stack.pop().unwrap_unchecked() }
+                };
+            }
+            macro_rules! top {
+                () => {
+                    unsafe { self.stack.last_mut().unwrap_unchecked() }
+                };
+            }
+            macro_rules! local {
+                ($slot:expr) => {
+                    unsafe { self.stack.get_unchecked_mut(slots + $slot as usize) }
+                };
+            }
+            // SAFETY: verification made sure constant indices are in bounds.
+            macro_rules! constant {
+                ($index:expr) => {
+                    unsafe { *constants.add($index as usize) }
+                };
+            }
+            macro_rules! name {
+                ($index:expr) => {
+                    match constant!($index) {
+                        Value::String(name) => name,
+                        _ => unreachable!("Verified globals are named by strings"),
+                    }
+                };
+            }
 
             macro_rules! binary_op {
-                ($oper:tt,$type:tt) => {{
-                    let b = match self.stack.pop() {
-                        Some(Value::Number(val)) => val,
-                        Some(_) => Err(self.runtime_error(RuntimeError::InvalidOperand))?,
-                        None => Err(self.runtime_error(RuntimeError::MissingOperand))?,
-                    };
-                    let a = match self.stack.pop() {
-                        Some(Value::Number(val)) => val,
-                        Some(_) => Err(self.runtime_error(RuntimeError::InvalidOperand))?,
-                        None => Err(self.runtime_error(RuntimeError::MissingOperand))?,
-                    };
-                    let res = a $oper b;
-                    self.stack.push(Value::$type(res));
+                ($a:expr, $b:expr, $oper:tt, $type:tt) => {{
+                    match ($a, $b) {
+                        (Value::Number(a), Value::Number(b)) => *top!() = Value::$type(a $oper b),
+                        (a, b) => throw!(self.invalid_operands(inst, "numbers", a, b)),
+                    }
+                }};
+            }
+            macro_rules! add {
+                ($a:expr, $b:expr) => {{
+                    match ($a, $b) {
+                        (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                        (Value::String(a), Value::String(b)) => {
+                            let result = format!("{}{}", self.heap.deref(a), self.heap.deref(b));
+                            Value::String(self.heap.alloc_string(result))
+                        }
+                        (a, b) => {
+                            throw!(self.invalid_operands(inst, "two numbers or two strings", a, b))
+                        }
+                    }
                 }};
             }
 
             match inst {
                 Instruction::Return => {
-                    if let Some(val) = self.stack.pop() {
-                        match val {
-                            Value::String(val) => println!("String({})", self.heap.deref(val)),
-                            val => println!("{:?}", val),
-                        }
-                    }
+                    let result = pop!();
+                    let frame = self.frames.pop().expect("No active call frame");
 
-                    return Ok(());
-                }
-                Instruction::Constant(val) => {
-                    let val = match self.chunk.constants.get(*val as usize) {
-                        Some(val) => val,
-                        None => Err(self.runtime_error(RuntimeError::InvalidConstantAddress))?,
-                    };
+                    self.stack.truncate(frame.slots);
+                    self.stack.push(result);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
 
-                    self.stack.push(*val);
+                    let caller = self.enter_frame();
+                    code = caller.0;
+                    constants = caller.1;
+                    ip = caller.2;
+                    slots = caller.3;
                 }
-                Instruction::Negate => match self.stack.last_mut() {
-                    Some(Value::Number(val)) => {
-                        *val *= -1.0;
+                Instruction::Constant(idx) => self.stack.push(constant!(idx)),
+                Instruction::Negate => match top!() {
+                    Value::Number(val) => *val *= -1.0,
+                    val => {
+                        let got = val.type_name();
+                        throw!(
+                            self.runtime_error(RuntimeError::InvalidOperand { operator: "-", got })
+                        )
                     }
-                    Some(_) => Err(self.runtime_error(RuntimeError::InvalidOperand))?,
-                    None => Err(self.runtime_error(RuntimeError::MissingOperand))?,
                 },
                 Instruction::Add => {
-                    let b = match self.stack.pop() {
-                        Some(val) => val,
-                        None => Err(self.runtime_error(RuntimeError::MissingOperand))?,
-                    };
-                    let a = match self.stack.pop() {
-                        Some(val) => val,
-                        None => Err(self.runtime_error(RuntimeError::MissingOperand))?,
-                    };
-                    let res = match (a, b) {
-                        (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
-                        (Value::String(a), Value::String(b)) => {
-                            let result = format!("{}{}", self.heap.deref(a), self.heap.deref(b));
-                            let result = self.heap.alloc_string(result);
-                            Value::String(result)
-                        }
-                        _ => Err(self.runtime_error(RuntimeError::InvalidOperand))?,
-                    };
-                    self.stack.push(res);
+                    let b = pop!();
+                    let res = add!(*top!(), b);
+                    *top!() = res;
+                }
+                Instruction::Subtract => {
+                    let b = pop!();
+                    binary_op!(*top!(), b, -, Number)
+                }
+                Instruction::Multiply => {
+                    let b = pop!();
+                    binary_op!(*top!(), b, *, Number)
+                }
+                Instruction::Divide => {
+                    let b = pop!();
+                    binary_op!(*top!(), b, /, Number)
+                }
+                Instruction::Greater => {
+                    let b = pop!();
+                    binary_op!(*top!(), b, >, Bool)
+                }
+                Instruction::Less => {
+                    let b = pop!();
+                    binary_op!(*top!(), b, <, Bool)
+                }
+                // Same as the `Less, Not` and `Greater, Not` they replace, including for NaN.
+                Instruction::GreaterEqual => {
+                    let b = pop!();
+                    binary_op!(*top!(), b, <, Bool);
+                    self.negate_top();
+                }
+                Instruction::LessEqual => {
+                    let b = pop!();
+                    binary_op!(*top!(), b, >, Bool);
+                    self.negate_top();
                 }
-                Instruction::Subtract => binary_op!(-, Number),
-                Instruction::Multiply => binary_op!(*, Number),
-                Instruction::Divide => binary_op!(/, Number),
-                Instruction::Greater => binary_op!(>, Bool),
-                Instruction::Less => binary_op!(<, Bool),
                 Instruction::False => self.stack.push(Value::Bool(false)),
                 Instruction::True => self.stack.push(Value::Bool(true)),
                 Instruction::Nil => self.stack.push(Value::Nil),
-                Instruction::Not => match self.stack.pop() {
-                    Some(val) => self.stack.push(Value::Bool(val.is_falsey())),
-                    None => Err(self.runtime_error(RuntimeError::MissingOperand))?,
-                },
-                Instruction::Equal => {
-                    let b = match self.stack.pop() {
-                        Some(val) => val,
-                        None => Err(self.runtime_error(RuntimeError::MissingOperand))?,
-                    };
-                    let a = match self.stack.pop() {
-                        Some(val) => val,
-                        None => Err(self.runtime_error(RuntimeError::MissingOperand))?,
-                    };
-
-                    let equals = match (a, b) {
-                        (Value::Number(a), Value::Number(b)) => a == b,
-                        (Value::Bool(a), Value::Bool(b)) => a == b,
-                        (Value::Nil, Value::Nil) => true,
-                        (Value::String(a), Value::String(b)) => {
-                            self.heap.deref(a) == self.heap.deref(b)
-                        }
-                        _ => false,
-                    };
-                    self.stack.push(Value::Bool(equals));
+                Instruction::Not => {
+                    let top = top!();
+                    *top = Value::Bool(top.is_falsey());
+                }
+                Instruction::Equal | Instruction::NotEqual => {
+                    let b = pop!();
+                    let a = top!();
+                    let equals = a.equals(&b, &self.heap);
+                    *a = Value::Bool(equals == (inst == Instruction::Equal));
+                }
+                Instruction::Print => {
+                    let val = pop!();
+                    writeln!(self.output, "{}", val.format(&self.heap))
+                        .expect("Failed to write output");
+                }
+                Instruction::Pop => {
+                    pop!();
+                }
+                Instruction::DefineGlobal(idx) => {
+                    let val = pop!();
+                    self.globals.insert(name!(idx), val);
+                }
+                Instruction::GetGlobal(idx) => {
+                    let name = name!(idx);
+                    match self.globals.get(&name) {
+                        Some(val) => self.stack.push(*val),
+                        None => throw!(self.undefined_variable(name)),
+                    }
+                }
+                Instruction::SetGlobal(idx) => {
+                    let name = name!(idx);
+                    let val = *top!();
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = val,
+                        None => throw!(self.undefined_variable(name)),
+                    }
+                }
+                Instruction::GetLocal(slot) => {
+                    let val = *local!(slot);
+                    self.stack.push(val);
+                }
+                Instruction::SetLocal(slot) => *local!(slot) = *top!(),
+                Instruction::SetLocalPop(slot) => *local!(slot) = pop!(),
+                Instruction::AddConstant(idx) => {
+                    let res = add!(*top!(), constant!(idx));
+                    *top!() = res;
+                }
+                Instruction::SubtractConstant(idx) => {
+                    binary_op!(*top!(), constant!(idx), -, Number)
+                }
+                Instruction::AddLocals(a, b) => {
+                    let res = add!(*local!(a), *local!(b));
+                    self.stack.push(res);
+                }
+                Instruction::GetProperty(idx) => {
+                    let object = *top!();
+                    self.frame_mut().ip = ip;
+                    let val = self.get_property(object, name!(idx))?;
+                    *top!() = val;
+                }
+                Instruction::SetProperty(idx) => {
+                    let val = pop!();
+                    let object = *top!();
+                    self.frame_mut().ip = ip;
+                    self.set_property(object, name!(idx), val)?;
+                    *top!() = val;
+                }
+                Instruction::Jump(offset) => ip += offset as usize,
+                Instruction::JumpIfFalse(offset) => {
+                    if top!().is_falsey() {
+                        ip += offset as usize;
+                    }
+                }
+                Instruction::Loop(offset) => {
+                    if let Some(error) = self.poll() {
+                        self.frame_mut().ip = ip - 1;
+                        return Err(self.suspend(error));
+                    }
+                    ip -= offset as usize;
+                }
+                Instruction::Call(arg_count) => {
+                    if let Some(error) = self.poll() {
+                        self.frame_mut().ip = ip - 1;
+                        return Err(self.suspend(error));
+                    }
+
+                    let arg_count = arg_count as usize;
+                    let callee = self.stack[self.stack.len() - arg_count - 1];
+                    self.frame_mut().ip = ip;
+                    self.call_value(callee, arg_count)?;
+
+                    let callee = self.enter_frame();
+                    code = callee.0;
+                    constants = callee.1;
+                    ip = callee.2;
+                    slots = callee.3;
                 }
             }
         }
     }
 
-    fn runtime_error(&mut self, kind: RuntimeError) -> RoxError {
-        RoxError::new(
-            RoxErrorKind::RuntimeError(kind),
-            self.chunk.get_line(self.ip),
+    /// Code and constants of the function running in the innermost frame, along with the
+    /// frame's instruction pointer and the index of its first slot.
+    ///
+    /// The pointers stay valid while the function is running, as its chunk is not changed and
+    /// objects are boxed, so they do not move when the heap grows.
+    fn enter_frame(&self) -> (*const Instruction, *const Value, usize, usize) {
+        let frame = self.frame();
+        let chunk = self.chunk();
+        (
+            chunk.code.as_ptr(),
+            chunk.constants.as_ptr(),
+            frame.ip,
+            frame.slots,
         )
     }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> RoxResult<()> {
+        match callee {
+            Value::Function(function) => self.call_function(function, arg_count),
+            Value::Native(native) => self.call_native(native, arg_count),
+            Value::BoundMethod(method) => self.call_method(method, arg_count),
+            _ => Err(self.runtime_error(RuntimeError::NotCallable(callee.type_name()))),
+        }
+    }
+
+    /// Calls the native of a method with the instance it was read from before the arguments.
+    fn call_method(&mut self, method: Ref<BoundMethod>, arg_count: usize) -> RoxResult<()> {
+        let BoundMethod { receiver, method } = *self.heap.deref(method);
+        let arity = self.heap.deref(method).arity - 1;
+        if arg_count != arity {
+            return Err(self.runtime_error(RuntimeError::ArityMismatch {
+                expected: arity,
+                got: arg_count,
+            }));
+        }
+
+        let args = self.stack.len() - arg_count;
+        self.stack.insert(args, Value::Instance(receiver));
+        self.call_native(method, arg_count + 1)
+    }
+
+    /// Value of the property `name` of `object`, either what its getter returns or its method
+    /// bound to it.
+    fn get_property(&mut self, object: Value, name: Ref<String>) -> RoxResult<Value> {
+        let instance = match object {
+            Value::Instance(instance) => instance,
+            _ => return Err(self.runtime_error(RuntimeError::NoProperties(object.type_name()))),
+        };
+        let class = self.heap.deref(self.heap.deref(instance).class);
+
+        if let Some(getter) = class.getters.get(&name) {
+            let function = Rc::clone(&self.heap.deref(*getter).function);
+            return function(&mut self.heap, &[object]).map_err(|kind| self.runtime_error(kind));
+        }
+        if let Some(method) = class.methods.get(&name) {
+            let method = *method;
+            let bound = self.heap.alloc(BoundMethod {
+                receiver: instance,
+                method,
+            });
+            return Ok(Value::BoundMethod(bound));
+        }
+
+        let kind = RuntimeError::UndefinedProperty {
+            class: class.name,
+            name: self.heap.deref(name).clone(),
+        };
+        Err(self.runtime_error(kind))
+    }
+
+    /// Assigns `value` to the property `name` of `object` with its setter.
+    fn set_property(&mut self, object: Value, name: Ref<String>, value: Value) -> RoxResult<()> {
+        let instance = match object {
+            Value::Instance(instance) => instance,
+            _ => return Err(self.runtime_error(RuntimeError::NoProperties(object.type_name()))),
+        };
+        let class = self.heap.deref(self.heap.deref(instance).class);
+
+        let setter = match class.setters.get(&name) {
+            Some(setter) => *setter,
+            None => {
+                let class_name = class.name;
+                let exists = class.getters.contains_key(&name) || class.methods.contains_key(&name);
+                let name = self.heap.deref(name).clone();
+                let kind = if exists {
+                    RuntimeError::ReadOnlyProperty {
+                        class: class_name,
+                        name,
+                    }
+                } else {
+                    RuntimeError::UndefinedProperty {
+                        class: class_name,
+                        name,
+                    }
+                };
+                return Err(self.runtime_error(kind));
+            }
+        };
+
+        let function = Rc::clone(&self.heap.deref(setter).function);
+        function(&mut self.heap, &[object, value])
+            .map(|_| ())
+            .map_err(|kind| self.runtime_error(kind))
+    }
+
+    fn call_native(&mut self, native: Ref<Native>, arg_count: usize) -> RoxResult<()> {
+        let native = self.heap.deref(native);
+        if arg_count != native.arity {
+            return Err(self.runtime_error(RuntimeError::ArityMismatch {
+                expected: native.arity,
+                got: arg_count,
+            }));
+        }
+
+        // The function is taken out of the heap, which it is given.
+        let function = Rc::clone(&native.function);
+        let args = self.stack.split_off(self.stack.len() - arg_count);
+        let result = match function(&mut self.heap, &args) {
+            Ok(result) => result,
+            Err(kind) => return Err(self.runtime_error(kind)),
+        };
+
+        // The result replaces the callee.
+        *self.stack.last_mut().expect("Callee is on the stack") = result;
+        Ok(())
+    }
+
+    fn call_function(&mut self, function: Ref<Function>, arg_count: usize) -> RoxResult<()> {
+        let arity = self.heap.deref(function).arity;
+        if arg_count != arity {
+            return Err(self.runtime_error(RuntimeError::ArityMismatch {
+                expected: arity,
+                got: arg_count,
+            }));
+        }
+
+        // Number of values the frame may have on the stack, which only ever grows in calls as
+        // the frame's code never goes above it.
+        let object = self.heap.deref_mut(function);
+        let size = match self.mode {
+            Mode::Stack => match object.max_depth {
+                Some(depth) => depth,
+                None => {
+                    let depths = verifier::verify(&object.chunk, arity);
+                    let depths = depths.expect("Compiled code is valid");
+                    let depth = depths.into_iter().flatten().max().unwrap_or(arity + 1);
+                    object.max_depth = Some(depth);
+                    depth
+                }
+            },
+            // The frame's registers start with its slots and also hold its temporaries.
+            Mode::Register => match &object.registers {
+                Some(registers) => registers.registers,
+                None => {
+                    let registers = register::compile(&object.chunk, arity);
+                    let count = registers.registers;
+                    object.registers = Some(registers);
+                    count
+                }
+            },
+        };
+
+        let slots = self.stack.len() - arg_count - 1;
+        if self.frames.len() >= self.max_frames || slots + size > self.max_stack {
+            return Err(self.runtime_error(RuntimeError::StackOverflow));
+        }
+
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slots,
+        });
+        if self.mode == Mode::Register {
+            self.stack.resize(slots + size, Value::Nil);
+        }
+
+        Ok(())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No active call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No active call frame")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.heap.deref(self.frame().function).chunk
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+    }
+
+    fn invalid_operands(
+        &mut self,
+        inst: Instruction,
+        expected: &'static str,
+        left: Value,
+        right: Value,
+    ) -> RoxError {
+        let operator = self.operator_symbol(inst);
+
+        self.runtime_error(RuntimeError::InvalidOperands {
+            operator,
+            expected,
+            left: left.type_name(),
+            right: right.type_name(),
+        })
+    }
+
+    /// Symbol of the operator that was compiled into the instruction being executed.
+    fn operator_symbol(&self, inst: Instruction) -> &'static str {
+        // `>=` and `<=` are compiled into the opposite comparison followed by a `Not`
+        // attributed to the same operator token.
+        let frame = self.frame();
+        let chunk = self.chunk();
+        let negated = matches!(chunk.code.get(frame.ip), Some(Instruction::Not))
+            && chunk.get_span(frame.ip) == chunk.get_span(frame.ip - 1);
+
+        match inst {
+            Instruction::Add | Instruction::AddConstant(_) | Instruction::AddLocals(..) => "+",
+            Instruction::Subtract | Instruction::SubtractConstant(_) => "-",
+            Instruction::Multiply => "*",
+            Instruction::Divide => "/",
+            Instruction::Greater if negated => "<=",
+            Instruction::Greater => ">",
+            Instruction::Less if negated => ">=",
+            Instruction::Less => "<",
+            Instruction::GreaterEqual => ">=",
+            Instruction::LessEqual => "<=",
+            inst => panic!("Instruction {:?} is not an operator", inst),
+        }
+    }
+
+    /// Negates the boolean just pushed by a comparison.
+    fn negate_top(&mut self) {
+        if let Some(Value::Bool(val)) = self.stack.last_mut() {
+            *val = !*val;
+        }
+    }
+
+    /// Error to stop with when interrupted or once the deadline has passed, checked at backward
+    /// jumps and calls so neither loops nor recursion can keep running. Garbage is collected
+    /// there too when due, as every value in use is then on the stack.
+    fn poll(&mut self) -> Option<RuntimeError> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        if self.interrupt.load(Ordering::Relaxed) {
+            self.interrupt.store(false, Ordering::Relaxed);
+            return Some(RuntimeError::Interrupted);
+        }
+
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Some(RuntimeError::DeadlineExceeded),
+            _ => None,
+        }
+    }
+
+    /// Builds an error located at the instruction the innermost frame would run next, which is
+    /// where execution resumes if it can.
+    fn suspend(&mut self, kind: RuntimeError) -> RoxError {
+        self.frame_mut().ip += 1;
+        let error = self.runtime_error(kind);
+        self.frame_mut().ip -= 1;
+        error
+    }
+
+    fn undefined_variable(&mut self, name: Ref<String>) -> RoxError {
+        let name = self.heap.deref(name).clone();
+        self.runtime_error(RuntimeError::UndefinedVariable(name))
+    }
+
+    /// Builds an error located at the instruction being executed, along with the trace of every
+    /// active call.
+    fn runtime_error(&mut self, kind: RuntimeError) -> RoxError {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.deref(frame.function);
+                let name = match function.name {
+                    Some(name) => self.heap.deref(name).clone(),
+                    None => String::from("<script>"),
+                };
+
+                // The instruction pointer is advanced before an instruction is executed.
+                let ip = frame.ip.saturating_sub(1);
+                let span = match (&function.registers, self.mode) {
+                    (Some(registers), Mode::Register) => registers.spans[ip],
+                    _ => function.chunk.get_span(ip),
+                };
+
+                TraceFrame {
+                    function: name,
+                    file: self.heap.deref(function.file).clone(),
+                    span,
+                    repeated: 0,
+                    cycle: 1,
+                }
+            })
+            .collect();
+
+        let span = trace.first().map(|frame| frame.span).unwrap_or_default();
+
+        RoxError::new(RoxErrorKind::RuntimeError(kind), span).with_trace(trace)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        io::Write,
+        rc::Rc,
+        sync::atomic::Ordering,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        chunk::Value,
+        error::{RoxErrorKind, RuntimeError},
+        optimizer::OptLevel,
+    };
+
+    use super::{Mode, Vm};
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn runtime_error(result: Result<(), Vec<crate::error::RoxError>>) -> RuntimeError {
+        match result.unwrap_err().remove(0).src {
+            RoxErrorKind::RuntimeError(err) => err,
+            err => panic!("Expected a runtime error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn runtime_error_has_stack_trace_innermost_first() {
+        let mut vm = Vm::new();
+        let code = "fun inner() {\n  return -nil;\n}\nfun outer() {\n  inner();\n}\nouter();";
+
+        let errors = vm.interpret(code, "trace.lox").unwrap_err();
+        let trace: Vec<(&str, usize, usize)> = errors[0]
+            .trace
+            .iter()
+            .map(|frame| {
+                assert_eq!(frame.file, "trace.lox");
+                (
+                    frame.function.as_str(),
+                    frame.span.start.line(),
+                    frame.span.start.column(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            trace,
+            vec![("inner", 1, 9), ("outer", 4, 7), ("<script>", 6, 5)]
+        );
+        assert_eq!(errors[0].span, errors[0].trace[0].span);
+    }
+
+    #[test]
+    fn operand_errors_name_operator_and_types() {
+        let cases = [
+            (
+                "\"a\" - 1;",
+                "Operands of '-' must be numbers, got string and number",
+            ),
+            ("-true;", "Operand of '-' must be a number, got boolean"),
+            (
+                "1 + nil;",
+                "Operands of '+' must be two numbers or two strings, got number and nil",
+            ),
+            (
+                "\"a\" >= 1;",
+                "Operands of '>=' must be numbers, got string and number",
+            ),
+            (
+                "!(1 < \"b\");",
+                "Operands of '<' must be numbers, got number and string",
+            ),
+            ("3();", "Can only call functions, got number"),
+        ];
+
+        for (code, message) in cases.iter() {
+            let errors = Vm::new().interpret(code, "types.lox").unwrap_err();
+            assert_eq!(errors[0].to_string(), *message);
+        }
+    }
+
+    #[test]
+    fn stack_is_reset_after_runtime_error() {
+        let mut vm = Vm::new();
+
+        assert!(vm.interpret("var a = 1; -\"a\";", "reset.lox").is_err());
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+
+        assert!(vm
+            .interpret("{ var b = a; b = b + 1; }", "reset.lox")
+            .is_ok());
+    }
+
+    #[test]
+    fn running_out_of_fuel_can_be_resumed() {
+        let code =
+            "fun f(n) { var total = 0; for (var i = 0; i < n; i = i + 1) total = total + i; \
+                    return total; } for (var i = 0; i < 5; i = i + 1) print f(i);";
+
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            let expected = Output::default();
+            let mut vm = Vm::new();
+            vm.set_mode(*mode);
+            vm.set_output(expected.clone());
+            assert!(vm.interpret(code, "fuel.lox").is_ok());
+
+            let output = Output::default();
+            let mut vm = Vm::new();
+            vm.set_mode(*mode);
+            vm.set_output(output.clone());
+            vm.set_fuel(Some(10));
+
+            let mut result = vm.interpret(code, "fuel.lox");
+            let mut stops = 0;
+            while let Err(errors) = result {
+                assert!(matches!(
+                    errors[0].src,
+                    RoxErrorKind::RuntimeError(RuntimeError::BudgetExhausted)
+                ));
+                assert_eq!(errors[0].span, errors[0].trace[0].span);
+                assert!(vm.is_suspended());
+                assert_eq!(vm.fuel(), Some(0));
+
+                stops += 1;
+                vm.set_fuel(Some(3));
+                result = vm.resume().map(|_| ());
+            }
+
+            assert!(stops > 10);
+            assert!(vm.stack.is_empty());
+            assert_eq!(*output.0.borrow(), *expected.0.borrow());
+        }
+    }
+
+    #[test]
+    fn deadline_stops_loops_and_recursion() {
+        let programs = ["while (true) {}", "fun f() { return f(); } f();"];
+
+        for code in programs.iter() {
+            for mode in [Mode::Stack, Mode::Register].iter() {
+                let mut vm = Vm::new();
+                vm.set_mode(*mode);
+                vm.set_output(std::io::sink());
+                vm.set_deadline(Some(Instant::now()));
+                assert!(matches!(
+                    runtime_error(vm.interpret(code, "deadline.lox")),
+                    RuntimeError::DeadlineExceeded
+                ));
+
+                // Running other code discards the suspended execution.
+                vm.set_deadline(None);
+                assert!(vm.interpret("print 1;", "deadline.lox").is_ok());
+                assert!(!vm.is_suspended());
+            }
+        }
+    }
+
+    #[test]
+    fn interrupt_cancels_the_running_script() {
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            let mut vm = Vm::new();
+            vm.set_mode(*mode);
+            let interrupt = vm.interrupt_handle();
+            let interrupter = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                interrupt.store(true, Ordering::Relaxed);
+            });
+
+            let code = "fun spin() { while (true) {} } spin();";
+            assert!(matches!(
+                runtime_error(vm.interpret(code, "interrupt.lox")),
+                RuntimeError::Interrupted
+            ));
+            interrupter.join().unwrap();
+
+            // It can not be resumed, and does not stop the next script.
+            assert!(!vm.is_suspended());
+            assert!(!vm.interrupt_handle().load(Ordering::Relaxed));
+            assert!(vm.interpret("fun f() {} f();", "interrupt.lox").is_ok());
+        }
+    }
+
+    #[test]
+    fn interrupt_is_cleared_when_the_script_stops_without_checking_it() {
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            let mut vm = Vm::new();
+            vm.set_mode(*mode);
+            vm.set_output(Output::default());
+
+            // Straight-line code never checks the flag.
+            vm.interrupt_handle().store(true, Ordering::Relaxed);
+            assert!(vm.interpret("print 1;", "interrupt.lox").is_ok());
+
+            assert!(!vm.interrupt_handle().load(Ordering::Relaxed));
+            assert!(vm.interpret("fun f() {} f();", "interrupt.lox").is_ok());
+        }
+    }
+
+    #[test]
+    fn runaway_recursion_overflows_with_a_collapsed_trace() {
+        let code = "fun f(n) { return f(n + 1); }\nfun g() { f(0); }\ng();";
+
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            let mut vm = Vm::new();
+            vm.set_mode(*mode);
+            vm.set_max_frames(50);
+            let errors = vm.interpret(code, "overflow.lox").unwrap_err();
+            assert!(matches!(
+                errors[0].src,
+                RoxErrorKind::RuntimeError(RuntimeError::StackOverflow)
+            ));
+            let trace: Vec<(&str, usize)> = errors[0]
+                .trace
+                .iter()
+                .map(|frame| (frame.function.as_str(), frame.repeated))
+                .collect();
+            assert_eq!(trace, vec![("f", 47), ("g", 0), ("<script>", 0)]);
+            assert!(vm.stack.is_empty());
+
+            // Calls that do not nest as deep still run.
+            assert!(vm
+                .interpret("fun h(n) { if (n > 0) h(n - 1); } h(40);", "overflow.lox")
+                .is_ok());
+
+            let mut vm = Vm::new();
+            vm.set_mode(*mode);
+            vm.set_max_stack(64);
+            assert!(matches!(
+                runtime_error(vm.interpret(code, "overflow.lox")),
+                RuntimeError::StackOverflow
+            ));
+        }
+    }
+
+    #[test]
+    fn mutual_recursion_overflows_with_a_collapsed_trace() {
+        let code = "fun a(n) { return b(n); }\nfun b(n) { return a(n); }\na(0);";
+
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            let mut vm = Vm::new();
+            vm.set_mode(*mode);
+            vm.set_max_frames(50);
+            let errors = vm.interpret(code, "overflow.lox").unwrap_err();
+            assert!(matches!(
+                errors[0].src,
+                RoxErrorKind::RuntimeError(RuntimeError::StackOverflow)
+            ));
+            let trace: Vec<(&str, usize, usize)> = errors[0]
+                .trace
+                .iter()
+                .map(|frame| (frame.function.as_str(), frame.repeated, frame.cycle))
+                .collect();
+            // The 49 calls are 24 cycles of `a` and `b`, and the call to `a` made by the script.
+            assert_eq!(
+                trace,
+                vec![("a", 0, 1), ("b", 23, 2), ("a", 0, 1), ("<script>", 0, 1)]
+            );
+        }
+    }
+
+    #[test]
+    fn natives_are_called_like_functions() {
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            let output = Output::default();
+            let mut vm = Vm::new();
+            vm.set_mode(*mode);
+            vm.set_output(output.clone());
+            vm.register_native("join", 2, |heap, args| match (args[0], args[1]) {
+                (Value::String(a), Value::String(b)) => {
+                    let joined = format!("{}-{}", heap.deref(a), heap.deref(b));
+                    Ok(Value::String(heap.alloc_string(joined)))
+                }
+                _ => Err(RuntimeError::Native("Can only join strings".into())),
+            });
+
+            let code = "fun f(a) { var b = \"b\"; return join(a, b) + \"!\"; } print f(\"a\"); print join;";
+            assert!(vm.interpret(code, "native.lox").is_ok());
+            assert_eq!(*output.0.borrow(), b"a-b!\n<native fn join>\n");
+
+            let errors = vm.interpret("\n join(1, 2);", "native.lox").unwrap_err();
+            assert_eq!(errors[0].to_string(), "Can only join strings");
+            assert_eq!(errors[0].span.start.line(), 1);
+            assert!(matches!(
+                runtime_error(vm.interpret("join(\"a\");", "native.lox")),
+                RuntimeError::ArityMismatch {
+                    expected: 2,
+                    got: 1
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn host_reads_globals_and_calls_functions() {
+        let mut vm = Vm::new();
+        let name = vm.heap_mut().alloc_string("rox".into());
+        vm.set_global("name", Value::String(name));
+        vm.set_global("count", Value::Number(2.0));
+
+        let script = vm
+            .compile(
+                "var greeting = \"hi \" + name; fun add(a, b) { return a + b + count; }",
+                "host.lox",
+            )
+            .unwrap();
+        assert!(vm.get_global("greeting").is_none());
+        assert!(matches!(vm.call(script, &[]), Ok(Value::Nil)));
+
+        match vm.get_global("greeting") {
+            Some(Value::String(greeting)) => assert_eq!(vm.heap().deref(greeting), "hi rox"),
+            value => panic!("Expected a string, got {:?}", value),
+        }
+
+        let add = vm.get_global("add").unwrap();
+        let sum = vm.call(add, &[Value::Number(3.0), Value::Number(4.0)]);
+        assert!(matches!(sum, Ok(Value::Number(n)) if n == 9.0));
+        assert!(vm.call(add, &[Value::Nil]).is_err());
+        assert!(vm.stack.is_empty());
+
+        vm.register_native("answer", 0, |_, _| Ok(Value::Number(42.0)));
+        let answer = vm.get_global("answer").unwrap();
+        assert!(matches!(vm.call(answer, &[]), Ok(Value::Number(n)) if n == 42.0));
+    }
+
+    #[test]
+    fn unreachable_objects_are_collected() {
+        let mut vm = Vm::new();
+        vm.set_output(Output::default());
+        let code = "var kept = \"kept\"; { var dropped = \"dropped\"; } fun f() {}";
+        assert!(vm.interpret(code, "gc.lox").is_ok());
+
+        // The script and its constants are only reachable while it runs.
+        vm.collect_garbage();
+        assert!(vm.heap().find_string("kept").is_some());
+        assert!(vm.heap().find_string("dropped").is_none());
+        let f = vm.get_global("f").unwrap();
+        assert!(vm.call(f, &[]).is_ok());
+
+        // Strings built in a loop are freed as it runs, rather than all kept until it ends.
+        let code = "var s = \"\"; for (var i = 0; i < 5000; i = i + 1) s = s + \"x\";";
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            vm.set_mode(*mode);
+            assert!(vm.interpret(code, "gc.lox").is_ok());
+            assert!(vm.heap().bytes_allocated() < 4 * 1024 * 1024);
+        }
+    }
+
+    #[test]
+    fn code_nested_up_to_the_limit_runs() {
+        // Each negation and grouping is a level, as is each block and `if`.
+        let depth = crate::parser::MAX_NESTING - 4;
+        let code = format!(
+            "print {}1{}; {}{} fun f() {{ {}return 2; }} print f();",
+            "-(".repeat(depth / 2),
+            ")".repeat(depth / 2),
+            "{".repeat(depth),
+            "}".repeat(depth),
+            "if (true) ".repeat(depth),
+        );
+
+        for mode in [Mode::Stack, Mode::Register].iter() {
+            for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2].iter() {
+                let output = Output::default();
+                let mut vm = Vm::new();
+                vm.set_output(output.clone());
+                vm.set_mode(*mode);
+                vm.set_opt_level(*opt_level);
+
+                assert!(vm.interpret(&code, "deep.lox").is_ok());
+                assert_eq!(output.0.borrow().as_slice(), b"1\n2\n");
+            }
+        }
+    }
 }
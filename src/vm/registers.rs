@@ -0,0 +1,369 @@
+use std::cmp::Ordering;
+
+use crate::{
+    chunk::Value,
+    error::{RoxError, RoxResult, RuntimeError},
+    register::Instruction,
+};
+
+use super::Vm;
+
+impl Vm {
+    /// Runs the register code of the functions being called, which is the same as running their
+    /// bytecode except for how values move around.
+    pub(super) fn run_registers(&mut self) -> RoxResult<()> {
+        // Like the stack machine, the running function's code, its instruction pointer and the
+        // start and size of its frame are only looked up again when a call starts or ends.
+        let (mut code, mut constants, mut ip, mut base, mut frame_size) = self.enter_registers();
+
+        loop {
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    self.frame_mut().ip = ip;
+                    return Err(self.suspend(RuntimeError::BudgetExhausted));
+                }
+                *fuel -= 1;
+            }
+
+            // SAFETY: register code is compiled from verified bytecode, so its jumps land
+            // inside it and execution can not continue past its last instruction.
+            let inst = unsafe { *code.add(ip) };
+
+            #[cfg(feature = "debug_trace_execution")]
+            {
+                print!(" R: ");
+                for value in self.stack[base..].iter() {
+                    print!("[{:?}]", value);
+                }
+                println!();
+                println!("{:04} {}", ip, inst);
+            }
+
+            ip += 1;
+
+            // Stores the instruction pointer before building an error, which locates it.
+            macro_rules! throw {
+                ($error:expr) => {{
+                    self.frame_mut().ip = ip;
+                    let error = $error;
+                    return Err(error);
+                }};
+            }
+
+            // SAFETY: the registers an instruction names are below the frame's size, which the
+            // stack is resized to whenever the frame starts running again.
+            macro_rules! reg {
+                ($register:expr) => {
+                    *unsafe { self.stack.get_unchecked_mut(base + $register as usize) }
+                };
+            }
+            // SAFETY: the constant indices are those of the verified bytecode.
+            macro_rules! name {
+                ($index:expr) => {
+                    match unsafe { *constants.add($index as usize) } {
+                        Value::String(name) => name,
+                        _ => unreachable!("Verified globals are named by strings"),
+                    }
+                };
+            }
+
+            macro_rules! binary_op {
+                ($dst:expr, $left:expr, $right:expr, $result:expr) => {{
+                    match (reg!($left), reg!($right)) {
+                        (Value::Number(a), Value::Number(b)) => reg!($dst) = $result(a, b),
+                        (a, b) => throw!(self.invalid_register_operands(inst, "numbers", a, b)),
+                    }
+                }};
+            }
+
+            // Loops jump backward, and recursion goes through calls.
+            let polled = match inst {
+                Instruction::Jump(target) | Instruction::JumpIfFalse { target, .. } => {
+                    (target as usize) < ip
+                }
+                Instruction::Call { .. } => true,
+                _ => false,
+            };
+            if polled {
+                if let Some(error) = self.poll() {
+                    self.frame_mut().ip = ip - 1;
+                    return Err(self.suspend(error));
+                }
+            }
+
+            match inst {
+                Instruction::Return(src) => {
+                    let result = reg!(src);
+                    let frame = self.frames.pop().expect("No active call frame");
+                    if self.frames.is_empty() {
+                        self.stack.truncate(frame.slots);
+                        self.stack.push(result);
+                        return Ok(());
+                    }
+
+                    // The callee was in the register the caller gets the result in.
+                    self.stack[frame.slots] = result;
+                    let caller = self.enter_registers();
+                    code = caller.0;
+                    constants = caller.1;
+                    ip = caller.2;
+                    base = caller.3;
+                    frame_size = caller.4;
+                    self.stack.resize(base + frame_size, Value::Nil);
+                }
+                Instruction::Constant { dst, index } => {
+                    reg!(dst) = unsafe { *constants.add(index as usize) };
+                }
+                Instruction::Nil(dst) => reg!(dst) = Value::Nil,
+                Instruction::True(dst) => reg!(dst) = Value::Bool(true),
+                Instruction::False(dst) => reg!(dst) = Value::Bool(false),
+                Instruction::Move { dst, src } => reg!(dst) = reg!(src),
+                Instruction::Negate { dst, src } => match reg!(src) {
+                    Value::Number(val) => reg!(dst) = Value::Number(-val),
+                    val => {
+                        let got = val.type_name();
+                        throw!(
+                            self.runtime_error(RuntimeError::InvalidOperand { operator: "-", got })
+                        )
+                    }
+                },
+                Instruction::Not { dst, src } => reg!(dst) = Value::Bool(reg!(src).is_falsey()),
+                Instruction::Add { dst, left, right } => {
+                    reg!(dst) = match (reg!(left), reg!(right)) {
+                        (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                        (Value::String(a), Value::String(b)) => {
+                            let result = format!("{}{}", self.heap.deref(a), self.heap.deref(b));
+                            Value::String(self.heap.alloc_string(result))
+                        }
+                        (a, b) => throw!(self.invalid_register_operands(
+                            inst,
+                            "two numbers or two strings",
+                            a,
+                            b,
+                        )),
+                    };
+                }
+                Instruction::Subtract { dst, left, right } => {
+                    binary_op!(dst, left, right, |a, b| Value::Number(a - b))
+                }
+                Instruction::Multiply { dst, left, right } => {
+                    binary_op!(dst, left, right, |a, b| Value::Number(a * b))
+                }
+                Instruction::Divide { dst, left, right } => {
+                    binary_op!(dst, left, right, |a, b| Value::Number(a / b))
+                }
+                Instruction::Greater { dst, left, right } => {
+                    binary_op!(dst, left, right, |a, b| Value::Bool(a > b))
+                }
+                Instruction::Less { dst, left, right } => {
+                    binary_op!(dst, left, right, |a, b| Value::Bool(a < b))
+                }
+                // Same as the stack machine's, including for NaN.
+                Instruction::GreaterEqual { dst, left, right } => {
+                    binary_op!(dst, left, right, |a: f64, b| {
+                        Value::Bool(a.partial_cmp(&b) != Some(Ordering::Less))
+                    })
+                }
+                Instruction::LessEqual { dst, left, right } => {
+                    binary_op!(dst, left, right, |a: f64, b| {
+                        Value::Bool(a.partial_cmp(&b) != Some(Ordering::Greater))
+                    })
+                }
+                Instruction::Equal { dst, left, right } => {
+                    let (left, right) = (reg!(left), reg!(right));
+                    reg!(dst) = Value::Bool(left.equals(&right, &self.heap));
+                }
+                Instruction::NotEqual { dst, left, right } => {
+                    let (left, right) = (reg!(left), reg!(right));
+                    reg!(dst) = Value::Bool(!left.equals(&right, &self.heap));
+                }
+                Instruction::Print(src) => {
+                    let text = reg!(src).format(&self.heap);
+                    writeln!(self.output, "{}", text).expect("Failed to write output");
+                }
+                Instruction::DefineGlobal { index, src } => {
+                    let val = reg!(src);
+                    self.globals.insert(name!(index), val);
+                }
+                Instruction::GetGlobal { dst, index } => {
+                    let name = name!(index);
+                    reg!(dst) = match self.globals.get(&name) {
+                        Some(val) => *val,
+                        None => throw!(self.undefined_variable(name)),
+                    };
+                }
+                Instruction::SetGlobal { index, src } => {
+                    let name = name!(index);
+                    let val = reg!(src);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = val,
+                        None => throw!(self.undefined_variable(name)),
+                    }
+                }
+                Instruction::GetProperty { dst, object, index } => {
+                    self.frame_mut().ip = ip;
+                    let object = reg!(object);
+                    let val = self.get_property(object, name!(index))?;
+                    reg!(dst) = val;
+                }
+                Instruction::SetProperty { object, index, src } => {
+                    self.frame_mut().ip = ip;
+                    let (object, val) = (reg!(object), reg!(src));
+                    self.set_property(object, name!(index), val)?;
+                }
+                Instruction::Jump(target) => ip = target as usize,
+                Instruction::JumpIfFalse { condition, target } => {
+                    if reg!(condition).is_falsey() {
+                        ip = target as usize;
+                    }
+                }
+                Instruction::Call { callee, arguments } => {
+                    // The callee's frame starts at its register, so the stack ends at the last
+                    // argument like when calling from the stack machine.
+                    let callee = base + callee as usize;
+                    let frames = self.frames.len();
+                    self.stack.truncate(callee + arguments as usize + 1);
+                    self.frame_mut().ip = ip;
+                    self.call_value(self.stack[callee], arguments as usize)?;
+
+                    // Natives return right away, leaving the frame without its registers.
+                    if self.frames.len() == frames {
+                        self.stack.resize(base + frame_size, Value::Nil);
+                    } else {
+                        let callee = self.enter_registers();
+                        code = callee.0;
+                        constants = callee.1;
+                        ip = callee.2;
+                        base = callee.3;
+                        frame_size = callee.4;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Register code and constants of the function running in the innermost frame, along with
+    /// the frame's instruction pointer, the index of its first register and how many it has.
+    fn enter_registers(&self) -> (*const Instruction, *const Value, usize, usize, usize) {
+        let frame = self.frame();
+        let function = self.heap.deref(frame.function);
+        let registers = function
+            .registers
+            .as_ref()
+            .expect("Functions are translated when called");
+        (
+            registers.code.as_ptr(),
+            function.chunk.constants.as_ptr(),
+            frame.ip,
+            frame.slots,
+            registers.registers,
+        )
+    }
+
+    fn invalid_register_operands(
+        &mut self,
+        inst: Instruction,
+        expected: &'static str,
+        left: Value,
+        right: Value,
+    ) -> RoxError {
+        self.runtime_error(RuntimeError::InvalidOperands {
+            operator: inst.operator_symbol(),
+            expected,
+            left: left.type_name(),
+            right: right.type_name(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use crate::{
+        optimizer::OptLevel,
+        vm::{Mode, Vm},
+    };
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// What the program prints, then its runtime error along with the trace.
+    fn run(code: &str, mode: Mode, opt_level: OptLevel) -> String {
+        let output = Output::default();
+        let mut vm = Vm::new();
+        vm.set_mode(mode);
+        vm.set_opt_level(opt_level);
+        vm.set_output(output.clone());
+
+        let result = vm.interpret(code, "registers.lox");
+        let mut printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        if let Err(errors) = result {
+            for error in errors {
+                printed += &format!("{} at {:?}\n", error, error.trace);
+            }
+        }
+
+        printed
+    }
+
+    #[test]
+    fn runs_programs_like_the_stack_machine() {
+        let programs = [
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+             for (var i = 0; i < 12; i = i + 1) print fib(i);",
+            "var total = 0;
+             for (var i = 0; i < 5; i = i + 1) {
+               for (var j = i; j <= 5; j = j + 1) total = total + i * j;
+             }
+             print total;",
+            "{ var a = 1; var b = nil; print a and b; print b or \"right\"; print !(a >= 1); }",
+            "{ var s = \"a\"; var t = s; s = s + s; print s + t; print s != t; }",
+            "fun add(a, b) { var sum = a + b; return sum; }
+             fun twice(f, x) { return f(x, x); }
+             print twice(add, 3) + add(1, 2);",
+            "fun f(n) { while (true) { if (n > 3) return n; n = n + 1; } } print f(0);",
+            "fun inner(x) { return -x; } fun outer() { return inner(\"no\"); } print 1; outer();",
+            "fun two(a, b) {} two(1);",
+            "{ var a = 3; a(); }",
+            "print undefined;",
+        ];
+
+        for code in programs.iter() {
+            for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2].iter() {
+                assert_eq!(
+                    run(code, Mode::Register, *opt_level),
+                    run(code, Mode::Stack, *opt_level),
+                    "{}",
+                    code
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn functions_declared_in_stack_mode_run_in_register_mode() {
+        let output = Output::default();
+        let mut vm = Vm::new();
+        vm.set_output(output.clone());
+
+        assert!(vm
+            .interpret("fun square(x) { return x * x; }", "switch.lox")
+            .is_ok());
+        vm.set_mode(Mode::Register);
+        assert!(vm.interpret("print square(7);", "switch.lox").is_ok());
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(*output.0.borrow(), b"49\n");
+    }
+}